@@ -0,0 +1,149 @@
+//! A Unix domain socket which operators can send admin commands to, one JSON object per line, without going through
+//! Janus. Access is controlled by the socket file's permissions, which only allow its owner in.
+
+use serde_json::Value as JsonValue;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::process;
+use std::thread;
+
+/// A command sent over the admin socket.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminCommand {
+    /// Lists the users in every room.
+    List,
+
+    /// Disconnects every connection belonging to the given user in the given room.
+    Kick { room_id: String, user_id: String },
+
+    /// Disconnects the connection with the given handle ID.
+    KillSession { handle_id: String },
+
+    /// Describes everything the server knows about the connection with the given handle ID.
+    Diagnostics { handle_id: String },
+
+    /// Checks the server's records for inconsistencies, optionally cleaning them up.
+    Verify {
+        #[serde(default)]
+        prune: bool
+    },
+
+    /// Reports whether the message processing thread is still making progress.
+    Health,
+
+    /// Describes the settings the server is running with, without the values of any secrets.
+    GetConfig,
+
+    /// Turns the server's audio-only mode on or off.
+    AudioOnly { enabled: bool },
+
+    /// Starts or stops turning away new connections and joins, so that the server can be taken down once its existing
+    /// calls finish.
+    SetDraining { draining: bool },
+}
+
+/// Creates the admin socket at the given path, readable and writable only by its owner. If there's a socket there
+/// already, e.g. left over from a previous run, it's replaced; anything else there is an error.
+pub fn listen<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    let path = path.as_ref();
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file which isn't a socket is in the way"));
+        }
+        fs::remove_file(path)?;
+    }
+    // bind in a directory nobody else can get into, so that nobody can connect before the socket's permissions are
+    // locked down, and then move the socket into place
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name"))?;
+    let staging = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("socket");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    if listener.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    fs::remove_dir(&staging)?;
+    listener
+}
+
+/// Accepts connections to the admin socket forever, answering each command on each connection with the handler's
+/// response on a line of its own.
+pub fn serve(listener: UnixListener, handler: fn(AdminCommand) -> JsonValue) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = answer_commands(stream, handler) {
+                        janus_warn!("Error talking to admin socket client: {}", e);
+                    }
+                });
+            }
+            Err(e) => janus_err!("Error accepting admin socket connection: {}", e),
+        }
+    }
+}
+
+fn answer_commands(stream: UnixStream, handler: fn(AdminCommand) -> JsonValue) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str(&line) {
+            Ok(command) => handler(command),
+            Err(e) => json!({ "success": false, "error": { "msg": format!("Invalid admin command: {}", e) }}),
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn parse_commands() {
+        let list: AdminCommand = serde_json::from_str(r#"{"command": "list"}"#).unwrap();
+        assert_eq!(list, AdminCommand::List);
+        let kick: AdminCommand = serde_json::from_str(r#"{"command": "kick", "room_id": "a", "user_id": "b"}"#).unwrap();
+        assert_eq!(kick, AdminCommand::Kick { room_id: "a".into(), user_id: "b".into() });
+        let kill: AdminCommand = serde_json::from_str(r#"{"command": "kill_session", "handle_id": "0x1"}"#).unwrap();
+        assert_eq!(kill, AdminCommand::KillSession { handle_id: "0x1".into() });
+        let drain: AdminCommand = serde_json::from_str(r#"{"command": "set_draining", "draining": true}"#).unwrap();
+        assert_eq!(drain, AdminCommand::SetDraining { draining: true });
+        let health: AdminCommand = serde_json::from_str(r#"{"command": "health"}"#).unwrap();
+        assert_eq!(health, AdminCommand::Health);
+        let config: AdminCommand = serde_json::from_str(r#"{"command": "get_config"}"#).unwrap();
+        assert_eq!(config, AdminCommand::GetConfig);
+    }
+
+    #[test]
+    fn answers_each_line() {
+        let path = env::temp_dir().join(format!("janus-plugin-sfu-admin-{}.sock", process::id()));
+        let listener = listen(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        thread::spawn(move || serve(listener, |command| json!({ "success": true, "response": format!("{:?}", command) })));
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"{\"command\": \"list\"}\n\n{\"command\": \"bogus\"}\n").unwrap();
+        let mut lines = BufReader::new(client).lines();
+        let first: JsonValue = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(first, json!({ "success": true, "response": "List" }));
+        let second: JsonValue = serde_json::from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(second["success"], json!(false));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+//! An append-only trail of moderation actions, one JSON object per line, for operators who need to account for who did
+//! what to whom. Recording an entry doesn't return until it's synced to disk, so a crash can't lose an entry once it's
+//! been recorded.
+
+use messages::{RoomId, UserId};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// One moderation action.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// When the action was taken, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+    /// What was done, e.g. "kick" or "lock_room".
+    pub action: String,
+    /// The user who did it, or none if it came from the admin socket.
+    pub actor: Option<UserId>,
+    /// Who or what it was done to, if anyone.
+    pub target: Option<String>,
+    /// The room it was done in, if any.
+    pub room_id: Option<RoomId>,
+}
+
+/// The open audit log file.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    /// Opens the audit log at the given path for appending, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Appends the given entry to the log and waits for it to reach the disk.
+    pub fn record(&mut self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn appends_across_reopens() {
+        let path = env::temp_dir().join(format!("janus-plugin-sfu-audit-{}.jsonl", process::id()));
+        let kick = AuditEntry {
+            timestamp_ms: 1234,
+            action: "kick".into(),
+            actor: Some("alice".into()),
+            target: Some("bob".into()),
+            room_id: Some("room".into()),
+        };
+        let kill = AuditEntry {
+            timestamp_ms: 5678,
+            action: "kill_session".into(),
+            actor: None,
+            target: Some("0x1".into()),
+            room_id: None,
+        };
+        AuditLog::open(&path).unwrap().record(&kick).unwrap();
+        AuditLog::open(&path).unwrap().record(&kill).unwrap();
+        let entries: Vec<AuditEntry> = fs::read_to_string(&path).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries, vec![kick, kill]);
+        fs::remove_file(&path).unwrap();
+    }
+}
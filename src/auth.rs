@@ -6,12 +6,21 @@ use std::error::Error;
 pub struct ValidatedToken {
     pub join_hub: bool,
     pub kick_users: bool,
+    pub admin: bool,
+    pub publish_codecs: Option<Vec<String>>,
+    pub user_id: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct UserClaims {
    join_hub: bool,
    kick_users: bool,
+   #[serde(default)]
+   admin: bool,
+   #[serde(default)]
+   publish_codecs: Option<Vec<String>>,
+   #[serde(default)]
+   user_id: Option<String>,
 }
 
 impl ValidatedToken {
@@ -21,6 +30,9 @@ impl ValidatedToken {
         Ok(ValidatedToken {
            join_hub: token_data.claims.join_hub,
            kick_users: token_data.claims.kick_users,
+           admin: token_data.claims.admin,
+           publish_codecs: token_data.claims.publish_codecs,
+           user_id: token_data.claims.user_id,
         })
     }
 }
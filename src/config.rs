@@ -1,16 +1,228 @@
 /// Code for reading the event handler config file into memory.
 use ini::Ini;
+use ini::ini::Properties;
+use janus::sdp::{AudioCodec, VideoCodec};
+use serde::Serializer;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
+use std::ops::RangeInclusive;
 use std::path::Path;
+use std::str::FromStr;
 
-/// All of the runtime configuration for the plugin.
-#[derive(Debug, Clone)]
+/// Every audio codec that Janus knows how to negotiate.
+pub static KNOWN_AUDIO_CODECS: [AudioCodec; 6] = [
+    AudioCodec::Opus,
+    AudioCodec::G722,
+    AudioCodec::Pcmu,
+    AudioCodec::Pcma,
+    AudioCodec::Isac16,
+    AudioCodec::Isac32,
+];
+
+/// Finds the audio codec with the given name, e.g. "opus" or "pcmu", ignoring case.
+fn parse_audio_codec(name: &str) -> Option<AudioCodec> {
+    KNOWN_AUDIO_CODECS.iter().cloned().find(|codec| codec.to_str().eq_ignore_ascii_case(name))
+}
+
+/// Every video codec that Janus knows how to negotiate.
+pub static KNOWN_VIDEO_CODECS: [VideoCodec; 3] = [
+    VideoCodec::H264,
+    VideoCodec::Vp8,
+    VideoCodec::Vp9,
+];
+
+/// Finds the video codec with the given name, e.g. "h264" or "vp8", ignoring case.
+pub fn parse_video_codec(name: &str) -> Option<VideoCodec> {
+    KNOWN_VIDEO_CODECS.iter().cloned().find(|codec| codec.to_str().eq_ignore_ascii_case(name))
+}
+
+/// The codecs a user may publish with, as listed in their join token. Each kind of media is only restricted if the
+/// list names a codec of that kind. H.264 can be named with a profile-level-id, e.g. `h264:42e01f`, to only allow
+/// that profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublishCodecs {
+    pub audio: Option<Vec<AudioCodec>>,
+    pub video: Option<Vec<VideoCodec>>,
+    pub h264_profiles: Option<Vec<String>>,
+}
+
+impl PublishCodecs {
+    /// Sorts the given codec names into audio and video codecs. Names of codecs we don't know of are ignored, since
+    /// those codecs can't be negotiated anyway.
+    pub fn from_names(names: &[String]) -> Self {
+        let audio: Vec<_> = names.iter().filter_map(|name| parse_audio_codec(name)).collect();
+        let mut video = Vec::new();
+        let mut h264_profiles = Some(Vec::new());
+        for name in names {
+            let (codec, profile_level_id) = match name.split_once(':') {
+                Some((codec, id)) => (parse_video_codec(codec), Some(id)),
+                None => (parse_video_codec(name), None),
+            };
+            match (codec, profile_level_id) {
+                (Some(VideoCodec::H264), Some(id)) => h264_profiles.iter_mut().for_each(|ids| ids.push(id.to_ascii_lowercase())),
+                // H.264 without a profile allows any profile
+                (Some(VideoCodec::H264), None) => h264_profiles = None,
+                _ => {}
+            }
+            if let Some(codec) = codec.filter(|codec| !video.contains(codec)) {
+                video.push(codec);
+            }
+        }
+        Self {
+            audio: if audio.is_empty() { None } else { Some(audio) },
+            video: if video.is_empty() { None } else { Some(video) },
+            h264_profiles: h264_profiles.filter(|ids| !ids.is_empty()),
+        }
+    }
+
+    /// Narrows these restrictions down to what the given ones allow too, e.g. to combine the restrictions on all of a
+    /// user's connections.
+    pub fn restrict(&mut self, other: &PublishCodecs) {
+        fn intersect<T: Clone + PartialEq>(ours: &mut Option<Vec<T>>, theirs: &Option<Vec<T>>) {
+            match (ours.as_mut(), theirs) {
+                (Some(ours), Some(theirs)) => ours.retain(|x| theirs.contains(x)),
+                (None, Some(theirs)) => *ours = Some(theirs.clone()),
+                (_, None) => {}
+            }
+        }
+        intersect(&mut self.audio, &other.audio);
+        intersect(&mut self.video, &other.video);
+        intersect(&mut self.h264_profiles, &other.h264_profiles);
+    }
+
+    pub fn allows_audio(&self, codec: AudioCodec) -> bool {
+        self.audio.as_ref().is_none_or(|codecs| codecs.contains(&codec))
+    }
+
+    pub fn allows_video(&self, codec: VideoCodec) -> bool {
+        self.video.as_ref().is_none_or(|codecs| codecs.contains(&codec))
+    }
+
+    pub fn allows_h264_profile(&self, profile_level_id: &str) -> bool {
+        self.h264_profiles.as_ref().is_none_or(|ids| ids.iter().any(|id| id.eq_ignore_ascii_case(profile_level_id)))
+    }
+}
+
+/// Writes out whether a secret setting is set, without giving away its value.
+fn serialize_redacted<T, S: Serializer>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_str("[redacted]"),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_audio_codecs<S: Serializer>(codecs: &[AudioCodec], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(codecs.iter().map(|codec| codec.to_str()))
+}
+
+fn serialize_video_codecs<S: Serializer>(codecs: &[VideoCodec], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(codecs.iter().map(|codec| codec.to_str()))
+}
+
+/// The prefix of the names of config file sections with settings for a particular room, e.g. `[room:podcast]`.
+const ROOM_SECTION_PREFIX: &str = "room:";
+
+/// Settings which can differ between rooms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RoomSettings {
+    pub audio_enabled: bool,
+    pub video_enabled: bool,
+    pub record_data: bool,
+    pub max_framerate: Option<u32>,
+    pub audio_payload_type: Option<u8>,
+    pub video_payload_type: Option<u8>,
+    pub publish_delay_ms: u64,
+    /// The most video the room's publishers may send between them, in bits per second.
+    pub max_bitrate: Option<u32>,
+    /// Whether the room's recent events, lock, and secret are kept while nobody is in it.
+    pub retain_state: bool,
+    /// The largest data channel message the room's connections may send, overriding `max_data_message_bytes`.
+    pub max_data_message_bytes: Option<usize>,
+    /// Whether to negotiate redundant Opus audio (RED) with publishers and subscribers who support it.
+    pub audio_red: bool,
+    /// Whether publishers may renegotiate different media lines or codecs after their first offer.
+    pub allow_renegotiation: bool,
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        Self {
+            audio_enabled: true,
+            video_enabled: true,
+            record_data: false,
+            max_framerate: None,
+            audio_payload_type: None,
+            video_payload_type: None,
+            publish_delay_ms: 0,
+            max_bitrate: None,
+            retain_state: false,
+            max_data_message_bytes: None,
+            audio_red: false,
+            allow_renegotiation: true,
+        }
+    }
+}
+
+/// All of the runtime configuration for the plugin. Serializes without the values of secrets, for showing operators.
+#[derive(Debug, Clone, Serialize)]
 pub struct Config {
+    #[serde(serialize_with = "serialize_redacted")]
     pub auth_key: Option<Vec<u8>>,
     pub max_room_size: usize,
-    pub max_ccu: usize
+    pub max_ccu: usize,
+    pub max_data_message_bytes: usize,
+    pub room_event_buffer_size: usize,
+    pub message_rate_limit: usize,
+    pub message_rate_burst: usize,
+    pub default_room: Option<String>,
+    pub rewrite_rtcp_cnames: bool,
+    pub publisher_idle_timeout_secs: Option<u64>,
+    pub max_join_metadata_bytes: usize,
+    #[serde(serialize_with = "serialize_audio_codecs")]
+    pub audio_codecs: Vec<AudioCodec>,
+    #[serde(serialize_with = "serialize_video_codecs")]
+    pub video_codecs: Vec<VideoCodec>,
+    #[serde(serialize_with = "serialize_audio_codecs")]
+    pub egress_audio_codecs: Vec<AudioCodec>,
+    #[serde(serialize_with = "serialize_video_codecs")]
+    pub egress_video_codecs: Vec<VideoCodec>,
+    pub negotiate_video_orientation: bool,
+    pub forwarded_header_extensions: Vec<String>,
+    pub max_sdp_bytes: usize,
+    pub locked_rooms_allow_reconnect: bool,
+    pub rtp_reorder_depth: usize,
+    pub h264_profile_level_id: Option<String>,
+    pub h264_packetization_mode: Option<u8>,
+    pub auto_promote_moderator: bool,
+    pub max_rooms: usize,
+    pub anonymize_payloads: bool,
+    pub keepalive_interval_secs: Option<u64>,
+    pub video_stall_timeout_ms: Option<u64>,
+    pub blocks_end_subscriptions: bool,
+    pub resume_token_ttl_secs: Option<u64>,
+    pub video_recovery_interval_secs: usize,
+    pub data_log_dir: Option<String>,
+    pub data_log_max_bytes: usize,
+    pub audit_log_path: Option<String>,
+    pub admin_socket_path: Option<String>,
+    pub message_thread_stale_secs: usize,
+    pub notification_batch_window_ms: Option<u64>,
+    pub advertise_sfu_identity: bool,
+    pub single_session_per_user: bool,
+    pub standby_state_path: Option<String>,
+    pub standby_window_secs: usize,
+    pub max_packet_rate: Option<usize>,
+    pub pacing_rate_kbps: Option<u64>,
+    pub notify_moderators_of_packet_floods: bool,
+    pub max_subscriptions_per_session: usize,
+    pub audio_only_load_threshold: Option<f64>,
+    pub offer_retry_attempts: usize,
+    pub offer_retry_interval_ms: usize,
+    pub supersede_stale_offers: bool,
+    pub require_join_for_media: bool,
+    pub rooms: HashMap<String, RoomSettings>,
 }
 
 impl Default for Config {
@@ -18,41 +230,522 @@ impl Default for Config {
         Self {
             auth_key: None,
             max_room_size: usize::max_value(),
-            max_ccu: usize::max_value()
+            max_ccu: usize::max_value(),
+            max_data_message_bytes: usize::max_value(),
+            room_event_buffer_size: 32,
+            message_rate_limit: usize::max_value(),
+            message_rate_burst: usize::max_value(),
+            default_room: None,
+            rewrite_rtcp_cnames: false,
+            publisher_idle_timeout_secs: None,
+            max_join_metadata_bytes: usize::max_value(),
+            audio_codecs: vec![AudioCodec::Opus],
+            // H.264 is cross-compatible with modern Firefox, Chrome, Safari, and Edge; VP8/9 unfortunately isn't
+            // compatible with Safari
+            video_codecs: vec![VideoCodec::H264],
+            egress_audio_codecs: vec![AudioCodec::Opus],
+            egress_video_codecs: vec![VideoCodec::H264],
+            negotiate_video_orientation: true,
+            forwarded_header_extensions: Vec::new(),
+            max_sdp_bytes: usize::max_value(),
+            locked_rooms_allow_reconnect: true,
+            rtp_reorder_depth: 0,
+            h264_profile_level_id: None,
+            h264_packetization_mode: None,
+            auto_promote_moderator: false,
+            max_rooms: usize::max_value(),
+            anonymize_payloads: false,
+            keepalive_interval_secs: None,
+            video_stall_timeout_ms: None,
+            blocks_end_subscriptions: false,
+            resume_token_ttl_secs: None,
+            video_recovery_interval_secs: 10,
+            data_log_dir: None,
+            data_log_max_bytes: 64 * 1024 * 1024,
+            audit_log_path: None,
+            admin_socket_path: None,
+            message_thread_stale_secs: 10,
+            notification_batch_window_ms: None,
+            advertise_sfu_identity: true,
+            single_session_per_user: false,
+            standby_state_path: None,
+            standby_window_secs: 60,
+            max_packet_rate: None,
+            pacing_rate_kbps: None,
+            notify_moderators_of_packet_floods: false,
+            max_subscriptions_per_session: usize::max_value(),
+            audio_only_load_threshold: None,
+            offer_retry_attempts: 0,
+            offer_retry_interval_ms: 500,
+            supersede_stale_offers: true,
+            require_join_for_media: true,
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+/// How the value of a single setting was arrived at when reading the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingOutcome {
+    /// The value was read from the config file.
+    Loaded,
+    /// The setting wasn't in the config file, so the default was used.
+    Defaulted,
+    /// The setting's value in the config file was unusable for the given reason, so the default was used.
+    Rejected(String),
+}
+
+/// The outcome of reading each setting, in the order they were read.
+pub type Diagnostics = Vec<(&'static str, SettingOutcome)>;
+
+/// Reads settings out of a config file section, keeping track of what happened with each one.
+struct SettingsReader<'a> {
+    section: &'a Properties,
+    diagnostics: Diagnostics,
+}
+
+impl<'a> SettingsReader<'a> {
+    /// Reads the named setting using the given parser, falling back to the default if it's missing or the parser
+    /// rejects it.
+    fn read_with<T, F>(&mut self, name: &'static str, default: T, parse: F) -> T where F: Fn(&str) -> Result<T, String> {
+        match self.section.get(name) {
+            None => {
+                self.diagnostics.push((name, SettingOutcome::Defaulted));
+                default
+            }
+            Some(value) => match parse(value) {
+                Ok(result) => {
+                    self.diagnostics.push((name, SettingOutcome::Loaded));
+                    result
+                }
+                Err(reason) => {
+                    self.diagnostics.push((name, SettingOutcome::Rejected(format!("{:?} {}", value, reason))));
+                    default
+                }
+            }
         }
     }
+
+    /// Reads the named setting as a `T`, falling back to the default if it's missing, doesn't parse, or doesn't pass
+    /// the given validation.
+    fn read<T: FromStr>(&mut self, name: &'static str, default: T, validate: fn(&T) -> Result<(), &'static str>) -> T {
+        self.read_with(name, default, |value| {
+            let result = value.parse().map_err(|_| String::from("isn't a valid value"))?;
+            validate(&result).map_err(String::from)?;
+            Ok(result)
+        })
+    }
+}
+
+fn any<T>(_: &T) -> Result<(), &'static str> {
+    Ok(())
+}
+
+fn positive(x: &usize) -> Result<(), &'static str> {
+    if *x > 0 { Ok(()) } else { Err("must be greater than zero") }
+}
+
+fn parse_payload_type(value: &str) -> Result<Option<u8>, String> {
+    match value.parse() {
+        Ok(pt @ 96..=127) => Ok(Some(pt)),
+        Ok(_) => Err(String::from("must be a dynamic payload type, from 96 to 127")),
+        Err(_) => Err(String::from("isn't a valid value")),
+    }
+}
+
+fn parse_audio_codecs(value: &str) -> Result<Vec<AudioCodec>, String> {
+    let mut codecs = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        codecs.push(parse_audio_codec(name).ok_or_else(|| format!("contains unknown audio codec {:?}", name))?);
+    }
+    Ok(codecs)
+}
+
+fn parse_video_codecs(value: &str) -> Result<Vec<VideoCodec>, String> {
+    let mut codecs = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        codecs.push(parse_video_codec(name).ok_or_else(|| format!("contains unknown video codec {:?}", name))?);
+    }
+    Ok(codecs)
+}
+
+/// The most RTP header extensions we can forward besides video orientation, given that the one-byte header format only
+/// has IDs from 1 to 14 and video orientation keeps the ID its publisher picked.
+pub const MAX_FORWARDED_HEADER_EXTENSIONS: usize = 13;
+
+/// The sizes a room may allow for data channel messages: room for ordinary messages at the low end, and no more than we
+/// want to buffer for a single message at the high end.
+pub const ROOM_DATA_MESSAGE_BYTES: RangeInclusive<usize> = 1024..=16 * 1024 * 1024;
+
+fn parse_header_extensions(value: &str) -> Result<Vec<String>, String> {
+    let uris: Vec<String> = value.split(',').map(str::trim).filter(|uri| !uri.is_empty()).map(String::from).collect();
+    if uris.len() > MAX_FORWARDED_HEADER_EXTENSIONS {
+        return Err(format!("lists more than {} extensions", MAX_FORWARDED_HEADER_EXTENSIONS));
+    }
+    Ok(uris)
 }
 
 impl Config {
     /// Reads the runtime configuration from an INI config file at the given path, applying defaults for individual
-    /// configuration values that aren't present, or returning an error if no readable configuration is present at all.
-    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<Error>>
-    {
-        let conf = Ini::load_from_file(path)?;
+    /// configuration values that aren't present or aren't sensible, or returning an error if no readable configuration
+    /// is present at all. Also returns what happened with each individual setting.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<(Self, Diagnostics), Box<Error>> {
+        Self::from_ini(&Ini::load_from_file(path)?)
+    }
+
+    /// Reads the runtime configuration out of a parsed INI config file. See `from_path`.
+    pub fn from_ini(conf: &Ini) -> Result<(Self, Diagnostics), Box<Error>> {
         let section = conf.section(Some("general"))
             .ok_or("No 'general' section present in the config file.")?;
         let defaults: Config = Default::default();
+        let mut reader = SettingsReader { section, diagnostics: Vec::new() };
 
         let auth_key = match section.get("auth_key") {
             Some(keyfile) => {
                 let mut buffer = Vec::<u8>::new();
                 let mut file = File::open(keyfile)?;
                 file.read_to_end(&mut buffer)?;
+                reader.diagnostics.push(("auth_key", SettingOutcome::Loaded));
                 Some(buffer)
             }
-            None => None
+            None => {
+                reader.diagnostics.push(("auth_key", SettingOutcome::Defaulted));
+                None
+            }
         };
 
-        Ok(Self {
+        // we don't transcode, so subscribers can only ever be sent codecs which publishers were allowed to send
+        let audio_codecs = reader.read_with("audio_codecs", defaults.audio_codecs, parse_audio_codecs);
+        let video_codecs = reader.read_with("video_codecs", defaults.video_codecs, parse_video_codecs);
+        let egress_audio_codecs = reader.read_with("egress_audio_codecs", None, |x| {
+            let codecs = parse_audio_codecs(x)?;
+            match codecs.iter().find(|codec| !audio_codecs.contains(codec)) {
+                Some(codec) => Err(format!("contains {:?}, which isn't in audio_codecs, so it would need transcoding", codec.to_str())),
+                None => Ok(Some(codecs)),
+            }
+        });
+        let egress_video_codecs = reader.read_with("egress_video_codecs", None, |x| {
+            let codecs = parse_video_codecs(x)?;
+            match codecs.iter().find(|codec| !video_codecs.contains(codec)) {
+                Some(codec) => Err(format!("contains {:?}, which isn't in video_codecs, so it would need transcoding", codec.to_str())),
+                None => Ok(Some(codecs)),
+            }
+        });
+
+        let mut config = Self {
             auth_key: auth_key,
-            max_room_size: section
-                .get("max_room_size")
-                .and_then(|x| x.parse().ok())
-                .unwrap_or(defaults.max_room_size),
-            max_ccu: section
-                .get("max_ccu")
-                .and_then(|x| x.parse().ok())
-                .unwrap_or(defaults.max_ccu),
-        })
+            max_room_size: reader.read("max_room_size", defaults.max_room_size, positive),
+            max_ccu: reader.read("max_ccu", defaults.max_ccu, positive),
+            max_data_message_bytes: reader.read("max_data_message_bytes", defaults.max_data_message_bytes, positive),
+            room_event_buffer_size: reader.read("room_event_buffer_size", defaults.room_event_buffer_size, any),
+            message_rate_limit: reader.read("message_rate_limit", defaults.message_rate_limit, positive),
+            message_rate_burst: reader.read("message_rate_burst", defaults.message_rate_burst, positive),
+            default_room: reader.read_with("default_room", defaults.default_room, |x| {
+                if x.is_empty() { Err(String::from("must not be empty")) } else { Ok(Some(x.to_owned())) }
+            }),
+            rewrite_rtcp_cnames: reader.read("rewrite_rtcp_cnames", defaults.rewrite_rtcp_cnames, any),
+            publisher_idle_timeout_secs: reader.read_with("publisher_idle_timeout_secs", defaults.publisher_idle_timeout_secs, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(secs) => Ok(Some(secs)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            max_join_metadata_bytes: reader.read("max_join_metadata_bytes", defaults.max_join_metadata_bytes, positive),
+            egress_audio_codecs: egress_audio_codecs.unwrap_or_else(|| audio_codecs.clone()),
+            egress_video_codecs: egress_video_codecs.unwrap_or_else(|| video_codecs.clone()),
+            audio_codecs,
+            video_codecs,
+            negotiate_video_orientation: reader.read("negotiate_video_orientation", defaults.negotiate_video_orientation, any),
+            forwarded_header_extensions: reader.read_with("forwarded_header_extensions", defaults.forwarded_header_extensions, parse_header_extensions),
+            max_sdp_bytes: reader.read("max_sdp_bytes", defaults.max_sdp_bytes, positive),
+            locked_rooms_allow_reconnect: reader.read("locked_rooms_allow_reconnect", defaults.locked_rooms_allow_reconnect, any),
+            rtp_reorder_depth: reader.read("rtp_reorder_depth", defaults.rtp_reorder_depth, any),
+            h264_profile_level_id: reader.read_with("h264_profile_level_id", defaults.h264_profile_level_id, |x| {
+                if x.len() == 6 && x.chars().all(|c| c.is_ascii_hexdigit()) {
+                    Ok(Some(x.to_ascii_lowercase()))
+                } else {
+                    Err(String::from("must be six hexadecimal digits"))
+                }
+            }),
+            h264_packetization_mode: reader.read_with("h264_packetization_mode", defaults.h264_packetization_mode, |x| {
+                match x {
+                    "0" => Ok(Some(0)),
+                    "1" => Ok(Some(1)),
+                    _ => Err(String::from("must be 0 or 1")),
+                }
+            }),
+            auto_promote_moderator: reader.read("auto_promote_moderator", defaults.auto_promote_moderator, any),
+            max_rooms: reader.read("max_rooms", defaults.max_rooms, positive),
+            anonymize_payloads: reader.read("anonymize_payloads", defaults.anonymize_payloads, any),
+            keepalive_interval_secs: reader.read_with("keepalive_interval_secs", defaults.keepalive_interval_secs, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(secs) => Ok(Some(secs)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            video_stall_timeout_ms: reader.read_with("video_stall_timeout_ms", defaults.video_stall_timeout_ms, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(ms) => Ok(Some(ms)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            blocks_end_subscriptions: reader.read("blocks_end_subscriptions", defaults.blocks_end_subscriptions, any),
+            resume_token_ttl_secs: reader.read_with("resume_token_ttl_secs", defaults.resume_token_ttl_secs, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(secs) => Ok(Some(secs)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            video_recovery_interval_secs: reader.read("video_recovery_interval_secs", defaults.video_recovery_interval_secs, positive),
+            data_log_dir: reader.read_with("data_log_dir", defaults.data_log_dir, |x| Ok(Some(x.to_owned()))),
+            data_log_max_bytes: reader.read("data_log_max_bytes", defaults.data_log_max_bytes, positive),
+            audit_log_path: reader.read_with("audit_log_path", defaults.audit_log_path, |x| Ok(Some(x.to_owned()))),
+            admin_socket_path: reader.read_with("admin_socket_path", defaults.admin_socket_path, |x| Ok(Some(x.to_owned()))),
+            message_thread_stale_secs: reader.read("message_thread_stale_secs", defaults.message_thread_stale_secs, positive),
+            notification_batch_window_ms: reader.read_with("notification_batch_window_ms", defaults.notification_batch_window_ms, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(ms) => Ok(Some(ms)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            advertise_sfu_identity: reader.read("advertise_sfu_identity", defaults.advertise_sfu_identity, any),
+            single_session_per_user: reader.read("single_session_per_user", defaults.single_session_per_user, any),
+            standby_state_path: reader.read_with("standby_state_path", defaults.standby_state_path, |x| Ok(Some(x.to_owned()))),
+            standby_window_secs: reader.read("standby_window_secs", defaults.standby_window_secs, positive),
+            max_packet_rate: reader.read_with("max_packet_rate", defaults.max_packet_rate, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(rate) => Ok(Some(rate)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            pacing_rate_kbps: reader.read_with("pacing_rate_kbps", defaults.pacing_rate_kbps, |x| {
+                match x.parse() {
+                    Ok(0) => Err(String::from("must be greater than zero")),
+                    Ok(kbps) => Ok(Some(kbps)),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            notify_moderators_of_packet_floods: reader.read("notify_moderators_of_packet_floods", defaults.notify_moderators_of_packet_floods, any),
+            max_subscriptions_per_session: reader.read("max_subscriptions_per_session", defaults.max_subscriptions_per_session, positive),
+            audio_only_load_threshold: reader.read_with("audio_only_load_threshold", defaults.audio_only_load_threshold, |x| {
+                match x.parse::<f64>() {
+                    Ok(load) if load > 0.0 && load.is_finite() => Ok(Some(load)),
+                    Ok(_) => Err(String::from("must be greater than zero")),
+                    Err(_) => Err(String::from("isn't a valid value")),
+                }
+            }),
+            offer_retry_attempts: reader.read("offer_retry_attempts", defaults.offer_retry_attempts, any),
+            offer_retry_interval_ms: reader.read("offer_retry_interval_ms", defaults.offer_retry_interval_ms, positive),
+            supersede_stale_offers: reader.read("supersede_stale_offers", defaults.supersede_stale_offers, any),
+            require_join_for_media: reader.read("require_join_for_media", defaults.require_join_for_media, any),
+            rooms: HashMap::new(),
+        };
+        for (name, section) in conf {
+            if let Some(room) = name.as_ref().and_then(|n| n.strip_prefix(ROOM_SECTION_PREFIX)) {
+                let room_defaults = RoomSettings::default();
+                reader.section = section;
+                let audio_payload_type = reader.read_with("audio_payload_type", room_defaults.audio_payload_type, parse_payload_type);
+                // subscribers couldn't tell the audio and video apart
+                let video_payload_type = reader.read_with("video_payload_type", room_defaults.video_payload_type, |x| {
+                    match parse_payload_type(x)? {
+                        Some(pt) if Some(pt) == audio_payload_type => Err(String::from("is the same as audio_payload_type")),
+                        pt => Ok(pt),
+                    }
+                });
+                let settings = RoomSettings {
+                    audio_enabled: reader.read("audio_enabled", room_defaults.audio_enabled, any),
+                    video_enabled: reader.read("video_enabled", room_defaults.video_enabled, any),
+                    record_data: reader.read("record_data", room_defaults.record_data, any),
+                    audio_payload_type,
+                    video_payload_type,
+                    max_framerate: reader.read_with("max_framerate", room_defaults.max_framerate, |x| {
+                        match x.parse() {
+                            Ok(0) => Err(String::from("must be greater than zero")),
+                            Ok(fps) => Ok(Some(fps)),
+                            Err(_) => Err(String::from("isn't a valid value")),
+                        }
+                    }),
+                    publish_delay_ms: reader.read("publish_delay_ms", room_defaults.publish_delay_ms, any),
+                    max_bitrate: reader.read_with("max_bitrate", room_defaults.max_bitrate, |x| {
+                        match x.parse() {
+                            Ok(0) => Err(String::from("must be greater than zero")),
+                            Ok(bps) => Ok(Some(bps)),
+                            Err(_) => Err(String::from("isn't a valid value")),
+                        }
+                    }),
+                    retain_state: reader.read("retain_state", room_defaults.retain_state, any),
+                    audio_red: reader.read("audio_red", room_defaults.audio_red, any),
+                    allow_renegotiation: reader.read("allow_renegotiation", room_defaults.allow_renegotiation, any),
+                    max_data_message_bytes: reader.read_with("max_data_message_bytes", room_defaults.max_data_message_bytes, |x| {
+                        match x.parse() {
+                            Ok(bytes) if !ROOM_DATA_MESSAGE_BYTES.contains(&bytes) => {
+                                Err(format!("must be between {} and {}", ROOM_DATA_MESSAGE_BYTES.start(), ROOM_DATA_MESSAGE_BYTES.end()))
+                            }
+                            Ok(bytes) => Ok(Some(bytes)),
+                            Err(_) => Err(String::from("isn't a valid value")),
+                        }
+                    }),
+                };
+                config.rooms.insert(room.to_owned(), settings);
+            }
+        }
+        Ok((config, reader.diagnostics))
+    }
+
+    /// Returns the settings for the given room, which are the defaults unless the config file says otherwise.
+    pub fn room_settings(&self, room: &str) -> RoomSettings {
+        self.rooms.get(room).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use serde_json::{self, Value as JsonValue};
+
+    fn outcome<'a>(diagnostics: &'a Diagnostics, name: &str) -> &'a SettingOutcome {
+        &diagnostics.iter().find(|&&(setting, _)| setting == name).unwrap().1
+    }
+
+    #[test]
+    fn describes_config_without_secrets() {
+        let config = Config {
+            auth_key: Some(b"hunter2".to_vec()),
+            video_codecs: vec![VideoCodec::Vp8, VideoCodec::H264],
+            ..Default::default()
+        };
+        let described = serde_json::to_value(&config).unwrap();
+        assert_eq!(described["auth_key"], "[redacted]");
+        assert_eq!(described["video_codecs"], json!(["vp8", "h264"]));
+        assert_eq!(described["max_packet_rate"], JsonValue::Null);
+        assert!(!described.to_string().contains("hunter2"));
+        let described = serde_json::to_value(Config::default()).unwrap();
+        assert_eq!(described["auth_key"], JsonValue::Null);
+    }
+
+    #[test]
+    fn reports_loaded_and_defaulted_settings() {
+        let ini = Ini::load_from_str("[general]\nmax_room_size = 24\naudio_codecs = opus, PCMU\nvideo_codecs = vp8, h264\n\
+                                      forwarded_header_extensions = http://www.webrtc.org/experiments/rtp-hdrext/playout-delay\n").unwrap();
+        let (config, diagnostics) = Config::from_ini(&ini).unwrap();
+        assert_eq!(config.max_room_size, 24);
+        assert_eq!(config.forwarded_header_extensions, vec!["http://www.webrtc.org/experiments/rtp-hdrext/playout-delay"]);
+        assert_eq!(config.audio_codecs, vec![AudioCodec::Opus, AudioCodec::Pcmu]);
+        assert_eq!(config.video_codecs, vec![VideoCodec::Vp8, VideoCodec::H264]);
+        assert_eq!(config.max_ccu, usize::MAX);
+        assert_eq!(outcome(&diagnostics, "max_room_size"), &SettingOutcome::Loaded);
+        assert_eq!(outcome(&diagnostics, "audio_codecs"), &SettingOutcome::Loaded);
+        assert_eq!(outcome(&diagnostics, "max_ccu"), &SettingOutcome::Defaulted);
+        assert_eq!(outcome(&diagnostics, "auth_key"), &SettingOutcome::Defaulted);
+    }
+
+    #[test]
+    fn rejects_nonsensical_settings() {
+        let ini = Ini::load_from_str("[general]\nmax_room_size = 0\nmax_ccu = lots\naudio_codecs = opus, aac\n").unwrap();
+        let (config, diagnostics) = Config::from_ini(&ini).unwrap();
+        assert_eq!(config.max_room_size, usize::MAX);
+        assert_eq!(config.max_ccu, usize::MAX);
+        assert_eq!(config.audio_codecs, vec![AudioCodec::Opus]);
+        assert_eq!(outcome(&diagnostics, "max_room_size"), &SettingOutcome::Rejected(String::from("\"0\" must be greater than zero")));
+        assert_eq!(outcome(&diagnostics, "max_ccu"), &SettingOutcome::Rejected(String::from("\"lots\" isn't a valid value")));
+        let unknown_codec = String::from("\"opus, aac\" contains unknown audio codec \"aac\"");
+        assert_eq!(outcome(&diagnostics, "audio_codecs"), &SettingOutcome::Rejected(unknown_codec));
+    }
+
+    #[test]
+    fn reads_room_sections() {
+        let ini = Ini::load_from_str("[general]\n[room:podcast]\nvideo_enabled = false\nmax_framerate = 15\naudio_payload_type = 111\nvideo_payload_type = 35\npublish_delay_ms = 500\nmax_bitrate = 2000000\nretain_state = true\nmax_data_message_bytes = 1048576\naudio_red = true\nallow_renegotiation = false\n[other]\naudio_enabled = false\n").unwrap();
+        let (config, _) = Config::from_ini(&ini).unwrap();
+        assert_eq!(config.room_settings("podcast"), RoomSettings {
+            video_enabled: false,
+            max_framerate: Some(15),
+            audio_payload_type: Some(111),
+            publish_delay_ms: 500,
+            max_bitrate: Some(2_000_000),
+            retain_state: true,
+            max_data_message_bytes: Some(1_048_576),
+            audio_red: true,
+            allow_renegotiation: false,
+            ..Default::default()
+        });
+        assert_eq!(config.room_settings("other"), RoomSettings::default());
+        assert_eq!(config.rooms.len(), 1);
+    }
+
+    #[test]
+    fn rejects_clashing_payload_types() {
+        let ini = Ini::load_from_str("[general]\n[room:lobby]\naudio_payload_type = 111\nvideo_payload_type = 111\n").unwrap();
+        let (config, diagnostics) = Config::from_ini(&ini).unwrap();
+        let room = config.room_settings("lobby");
+        assert_eq!((room.audio_payload_type, room.video_payload_type), (Some(111), None));
+        let clash = String::from("\"111\" is the same as audio_payload_type");
+        assert_eq!(outcome(&diagnostics, "video_payload_type"), &SettingOutcome::Rejected(clash));
+    }
+
+    #[test]
+    fn reads_egress_codecs() {
+        let ini = Ini::load_from_str("[general]\nvideo_codecs = h264, vp8\negress_video_codecs = vp8\n").unwrap();
+        let (config, diagnostics) = Config::from_ini(&ini).unwrap();
+        assert_eq!(config.video_codecs, vec![VideoCodec::H264, VideoCodec::Vp8]);
+        assert_eq!(config.egress_video_codecs, vec![VideoCodec::Vp8]);
+        assert_eq!(config.egress_audio_codecs, vec![AudioCodec::Opus]);
+        assert_eq!(outcome(&diagnostics, "egress_video_codecs"), &SettingOutcome::Loaded);
+        assert_eq!(outcome(&diagnostics, "egress_audio_codecs"), &SettingOutcome::Defaulted);
+    }
+
+    #[test]
+    fn rejects_egress_codecs_needing_transcoding() {
+        let ini = Ini::load_from_str("[general]\nvideo_codecs = h264\negress_video_codecs = h264, vp8\n").unwrap();
+        let (config, diagnostics) = Config::from_ini(&ini).unwrap();
+        assert_eq!(config.egress_video_codecs, vec![VideoCodec::H264]);
+        let transcoding = String::from("\"h264, vp8\" contains \"vp8\", which isn't in video_codecs, so it would need transcoding");
+        assert_eq!(outcome(&diagnostics, "egress_video_codecs"), &SettingOutcome::Rejected(transcoding));
+    }
+
+    #[test]
+    fn sorts_publish_codecs() {
+        let names = vec![String::from("VP8"), String::from("opus"), String::from("av1")];
+        let codecs = PublishCodecs::from_names(&names);
+        assert_eq!(codecs, PublishCodecs { audio: Some(vec![AudioCodec::Opus]), video: Some(vec![VideoCodec::Vp8]), h264_profiles: None });
+        assert!(codecs.allows_video(VideoCodec::Vp8));
+        assert!(!codecs.allows_video(VideoCodec::H264));
+        let video_only = PublishCodecs::from_names(&[String::from("vp9")]);
+        assert!(video_only.allows_audio(AudioCodec::Pcmu));
+        assert!(!video_only.allows_video(VideoCodec::Vp8));
+    }
+
+    #[test]
+    fn sorts_h264_profiles() {
+        let low = PublishCodecs::from_names(&[String::from("vp8"), String::from("H264:42E01F")]);
+        assert_eq!(low.video, Some(vec![VideoCodec::Vp8, VideoCodec::H264]));
+        assert!(low.allows_h264_profile("42e01f"));
+        assert!(!low.allows_h264_profile("640c1f"));
+        let any = PublishCodecs::from_names(&[String::from("h264:42e01f"), String::from("h264")]);
+        assert_eq!(any.video, Some(vec![VideoCodec::H264]));
+        assert!(any.allows_h264_profile("640c1f"));
+    }
+
+    #[test]
+    fn combines_publish_codecs() {
+        let mut codecs = PublishCodecs::default();
+        codecs.restrict(&PublishCodecs::from_names(&[String::from("vp8"), String::from("h264:640c1f")]));
+        codecs.restrict(&PublishCodecs::from_names(&[String::from("h264:42e01f"), String::from("opus")]));
+        assert_eq!(codecs.audio, Some(vec![AudioCodec::Opus]));
+        assert_eq!(codecs.video, Some(vec![VideoCodec::H264]));
+        assert!(!codecs.allows_h264_profile("640c1f"));
+        assert!(!codecs.allows_h264_profile("42e01f"));
+    }
+
+    #[test]
+    fn requires_general_section() {
+        let ini = Ini::load_from_str("[other]\nmax_room_size = 24\n").unwrap();
+        assert!(Config::from_ini(&ini).is_err());
     }
 }
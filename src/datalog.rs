@@ -0,0 +1,94 @@
+//! Append-only logs of the data messages relayed in each room, one JSON object per line.
+
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// The data logs for every room that's had a message logged, kept open for appending.
+#[derive(Debug)]
+pub struct DataLogs {
+    /// The directory that the log files go in.
+    dir: PathBuf,
+    /// How big a log file can get before it's rotated out.
+    max_bytes: u64,
+    /// The open log file for each room, and how big it is.
+    files: HashMap<String, (File, u64)>,
+}
+
+impl DataLogs {
+    pub fn new<P: AsRef<Path>>(dir: P, max_bytes: u64) -> Self {
+        Self { dir: dir.as_ref().to_owned(), max_bytes, files: HashMap::new() }
+    }
+
+    /// Returns the path of the given room's log file. The previous file, once it's been rotated out, has the same path
+    /// with ".1" on the end.
+    pub fn path(&self, room: &str) -> PathBuf {
+        // escape anything in the room ID which might mean something in a path
+        let mut name = String::new();
+        for byte in room.bytes() {
+            match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+                _ => name.push_str(&format!("%{:02X}", byte)),
+            }
+        }
+        self.dir.join(format!("{}.jsonl", name))
+    }
+
+    /// Appends the given entry to the given room's log, first rotating the log if the entry would make it too big.
+    pub fn append(&mut self, room: &str, entry: &JsonValue) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let path = self.path(room);
+        let needs_rotation = match self.files.get(room) {
+            Some(&(_, size)) => size > 0 && size + line.len() as u64 > self.max_bytes,
+            None => false,
+        };
+        if needs_rotation {
+            self.files.remove(room);
+            let mut rotated = path.clone().into_os_string();
+            rotated.push(".1");
+            fs::rename(&path, rotated)?;
+        }
+        if !self.files.contains_key(room) {
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            let size = file.metadata()?.len();
+            self.files.insert(room.to_owned(), (file, size));
+        }
+        let (file, size) = self.files.get_mut(room).unwrap();
+        file.write_all(&line)?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn read(path: &Path) -> Vec<JsonValue> {
+        fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn appends_and_rotates() {
+        let dir = env::temp_dir().join(format!("janus-plugin-sfu-datalog-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut logs = DataLogs::new(&dir, 40);
+        let path = logs.path("whiteboard/1");
+        assert_eq!(path, dir.join("whiteboard%2F1.jsonl"));
+
+        logs.append("whiteboard/1", &json!({ "body": "first" })).unwrap();
+        logs.append("whiteboard/1", &json!({ "body": "second" })).unwrap();
+        assert_eq!(read(&path), vec![json!({ "body": "first" }), json!({ "body": "second" })]);
+
+        logs.append("whiteboard/1", &json!({ "body": "third" })).unwrap();
+        assert_eq!(read(&path), vec![json!({ "body": "third" })]);
+        assert_eq!(read(&dir.join("whiteboard%2F1.jsonl.1")), vec![json!({ "body": "first" }), json!({ "body": "second" })]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -12,36 +12,71 @@ extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
 
+// the Janus logging macros read the log level out of the Janus executable, which doesn't exist when we're running unit
+// tests, so just throw log messages away there instead
+#[cfg(test)]
+macro_rules! janus_log {
+    ($lvl:expr, $($arg:tt)+) => ({
+        let _ = $lvl;
+        let _ = format!($($arg)+);
+    })
+}
+
+mod admin;
+mod audit;
 mod auth;
+mod datalog;
 mod messages;
+mod notifications;
+mod ratelimit;
+mod resume;
+mod rtcp;
+mod rtp;
 mod sessions;
+mod standby;
 mod switchboard;
 mod config;
 mod txid;
 
 use atom::AtomSetOnce;
+use admin::AdminCommand;
+use audit::{AuditEntry, AuditLog};
 use auth::ValidatedToken;
+use datalog::DataLogs;
 use messages::{RoomId, UserId};
-use config::Config;
+use config::{Config, Diagnostics, PublishCodecs, SettingOutcome, KNOWN_AUDIO_CODECS, KNOWN_VIDEO_CODECS};
 use janus::{JanusError, JanusResult, JanssonDecodingFlags, JanssonEncodingFlags, JanssonValue, Plugin, PluginCallbacks,
             LibraryMetadata, PluginResult, PluginSession, RawPluginResult, RawJanssonValue};
 use janus::sdp::{AudioCodec, MediaDirection, OfferAnswerParameters, Sdp, VideoCodec};
 use janus::utils::LibcString;
-use messages::{JsepKind, MessageKind, OptionalField, Subscription};
+use notifications::NotificationBatches;
+use messages::{ClientStats, CodedError, JsepKind, MessageKind, OptionalField, Subscription, UnparsedJsep};
+use ratelimit::{Pacer, TokenBucket};
+use resume::{ResumeState, ResumeTokens};
+use rtcp::Goodbyes;
+use rtp::{ActiveLayers, FramerateLimiter, LayerSelection, ReorderBuffer, RtpHeader, SwitchingContext, VideoConstraints};
 use serde::de::DeserializeOwned;
 use serde_json::Value as JsonValue;
-use sessions::{JoinState, Session, SessionState};
+use sessions::{JoinState, LogNamed, Session, SessionState};
+use standby::{SavedUser, Snapshot};
 use txid::TransactionId;
+use std::cmp::Reverse;
+use std::collections::{HashSet, VecDeque};
+use std::collections::hash_map::RandomState;
 use std::error::Error;
 use std::ffi::{CStr, CString};
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::mem;
 use std::os::raw::{c_char, c_int};
 use std::path::Path;
 use std::ptr;
 use std::slice;
 use std::sync::{mpsc, Arc, Mutex, RwLock, Weak};
-use std::sync::atomic::{Ordering, AtomicIsize, AtomicBool};
+use std::sync::atomic::{Ordering, AtomicIsize, AtomicBool, AtomicUsize};
 use std::thread;
-use switchboard::Switchboard;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use switchboard::{Connection, ConsistencyReport, Mute, RoomRefusal, Switchboard};
 
 // courtesy of c_string crate, which also has some other stuff we aren't interested in
 // taking in as a dependency here.
@@ -65,11 +100,15 @@ struct RawMessage {
     /// The transaction ID used to mark any responses to this message.
     pub txn: TransactionId,
 
-    /// An arbitrary message from the client. Will be deserialized as a MessageKind.
-    pub msg: Option<JanssonValue>,
+    /// An arbitrary message from the client, if it sent a non-empty one.
+    pub msg: Option<MessageKind>,
 
-    /// A JSEP message (SDP offer or answer) from the client. Will be deserialized as a JsepKind.
-    pub jsep: Option<JanssonValue>,
+    /// A JSEP message (SDP offer or answer) from the client, if it sent a non-empty one, with its SDP not yet parsed.
+    pub jsep: Option<UnparsedJsep>,
+
+    /// If the JSEP is an offer, its number in the sequence of offers from this session, so that we can tell whether a
+    /// newer one came in while it was waiting.
+    pub offer_version: Option<usize>,
 }
 
 /// Inefficiently converts a serde JSON value to a Jansson JSON value.
@@ -81,14 +120,6 @@ fn jansson_to_str(json: &JanssonValue) -> Result<LibcString, Box<Error>> {
     Ok(json.to_libcstring(JanssonEncodingFlags::empty()))
 }
 
-fn transpose<T, E>(val: Result<Option<T>, E>) -> Option<Result<T, E>> {
-    match val {
-        Ok(None) => None,
-        Ok(Some(y)) => Some(Ok(y)),
-        Err(e) => Some(Err(e))
-    }
-}
-
 /// A response to a signalling message. May carry either a response body, a JSEP, or both.
 struct MessageResponse {
     pub body: Option<JsonValue>,
@@ -110,15 +141,14 @@ type MessageResult = Result<MessageResponse, Box<Error>>;
 /// A result which carries a JSEP to send to a client.
 type JsepResult = Result<JsonValue, Box<Error>>;
 
-/// The audio codec Janus will negotiate with all participants. Opus is cross-compatible with everything we care about.
-static AUDIO_CODEC: AudioCodec = AudioCodec::Opus;
-
-/// The video codec Janus will negotiate with all participants. H.264 is cross-compatible with modern Firefox, Chrome,
-/// Safari, and Edge; VP8/9 unfortunately isn't compatible with Safari.
-static VIDEO_CODEC: VideoCodec = VideoCodec::H264;
+/// Audio quieter than this level, in -dBov, doesn't count as speaking when deciding whether a publisher is idle.
+const SPEAKING_AUDIO_LEVEL: u8 = 50;
 
 static mut CALLBACKS: Option<&PluginCallbacks> = None;
 
+/// How many user IDs we've made up for sessions that were implicitly joined to the default room.
+static ANONYMOUS_USER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 /// Returns a ref to the callback struct provided by Janus containing function pointers to pass data back to the gateway.
 fn gateway_callbacks() -> &'static PluginCallbacks {
     unsafe { CALLBACKS.expect("Callbacks not initialized -- did plugin init() succeed?") }
@@ -129,6 +159,49 @@ struct State {
     pub switchboard: RwLock<Switchboard>,
     pub message_channel: AtomSetOnce<Box<mpsc::SyncSender<RawMessage>>>,
     pub config: AtomSetOnce<Box<Config>>,
+    pub cname_keys: RandomState,
+    pub resume_tokens: Mutex<ResumeTokens>,
+    pub data_logs: AtomSetOnce<Box<Mutex<DataLogs>>>,
+    /// The channel to the thread writing out the audit log, and the thread, for waiting on it to finish when we're done.
+    pub audit_log: Mutex<Option<(mpsc::Sender<AuditEntry>, thread::JoinHandle<()>)>>,
+    pub notification_batches: Mutex<NotificationBatches<Arc<Session>>>,
+    pub standby: Mutex<Option<(Snapshot, Instant)>>,
+    pub audio_only: Mutex<AudioOnly>,
+    /// Whether `audio_only` is enabled, for checking on every video packet without taking its lock.
+    pub audio_only_enabled: AtomicBool,
+    /// Whether new connections and joins are being turned away ahead of the server being taken down.
+    pub draining: AtomicBool,
+    /// When the message processing thread last went around its loop, to tell whether it's wedged.
+    pub message_thread_heartbeat: Mutex<Instant>,
+    pub offer_retries: Mutex<Vec<OfferRetry>>,
+}
+
+/// Whether the server has stopped forwarding video in every room, and why.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct AudioOnly {
+    /// Because an admin asked it to.
+    forced: bool,
+    /// Because the server's load went over the configured threshold.
+    overloaded: bool,
+}
+
+impl AudioOnly {
+    fn enabled(&self) -> bool {
+        self.forced || self.overloaded
+    }
+}
+
+/// An offer which Janus couldn't deliver to a subscriber, waiting to be sent again.
+#[derive(Debug)]
+struct OfferRetry {
+    subscriber: Arc<Session>,
+    offer: JsonValue,
+    /// Which of the offers sent to the subscriber this is, counting from 1.
+    sequence: usize,
+    /// How many times we've tried sending it again so far.
+    attempts: usize,
+    /// When to try sending it again next.
+    due: Instant,
 }
 
 lazy_static! {
@@ -136,6 +209,17 @@ lazy_static! {
         switchboard: RwLock::new(Switchboard::new()),
         message_channel: AtomSetOnce::empty(),
         config: AtomSetOnce::empty(),
+        cname_keys: RandomState::new(),
+        resume_tokens: Mutex::new(ResumeTokens::new()),
+        data_logs: AtomSetOnce::empty(),
+        audit_log: Mutex::new(None),
+        notification_batches: Mutex::new(NotificationBatches::default()),
+        standby: Mutex::new(None),
+        audio_only: Mutex::new(AudioOnly::default()),
+        audio_only_enabled: AtomicBool::new(false),
+        draining: AtomicBool::new(false),
+        message_thread_heartbeat: Mutex::new(Instant::now()),
+        offer_retries: Mutex::new(Vec::new()),
     };
 }
 
@@ -143,37 +227,37 @@ lazy_static! {
 
 fn send_data_user<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) {
     let receivers = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
         let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
+        match (subscription_state.as_ref(), join_state) {
             (Some(subscription), Some(joined)) => {
                 subscription.data && &joined.user_id == target
             }
             _ => false
         }
     });
-    send_message(json, receivers)
+    send_message(json, receivers);
 }
 
 fn send_data_except<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) {
     let receivers = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
         let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
+        match (subscription_state.as_ref(), join_state) {
             (Some(subscription), Some(joined)) => {
                 subscription.data && &joined.user_id != myself
             }
             _ => false
         }
     });
-    send_message(json, receivers)
+    send_message(json, receivers);
 }
 
-fn notify_user<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) {
+fn notify_user<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, target: &UserId, everyone: T) -> DeliveryReport {
     let notifiees = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
+        let subscription_state = s.as_ref().subscription.lock().unwrap();
         let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
+        match (subscription_state.as_ref(), join_state) {
             (Some(subscription), Some(joined)) => {
                 subscription.notifications && &joined.user_id == target
             }
@@ -183,55 +267,209 @@ fn notify_user<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, tar
     send_message(json, notifiees)
 }
 
-fn notify_except<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) {
-    let notifiees = everyone.into_iter().filter(|s| {
-        let subscription_state = s.as_ref().subscription.get();
-        let join_state = s.as_ref().join_state.get();
-        match (subscription_state, join_state) {
-            (Some(subscription), Some(joined)) => {
-                subscription.notifications && &joined.user_id != myself
-            }
-            _ => false
-        }
-    });
+fn notify_except<T: IntoIterator<Item=U>, U: AsRef<Session>>(json: &JsonValue, myself: &UserId, everyone: T) -> DeliveryReport {
+    let notifiees = everyone.into_iter().filter(|s| wants_notifications_except(s.as_ref(), myself));
     send_message(json, notifiees)
 }
 
-fn send_message<T: IntoIterator<Item=U>, U: AsRef<Session>>(body: &JsonValue, sessions: T) {
+/// Whether the given session gets notifications, and isn't the given user's.
+fn wants_notifications_except(sess: &Session, myself: &UserId) -> bool {
+    let subscription_state = sess.subscription.lock().unwrap();
+    let join_state = sess.join_state.get();
+    match (subscription_state.as_ref(), join_state) {
+        (Some(subscription), Some(joined)) => {
+            subscription.notifications && &joined.user_id != myself
+        }
+        _ => false
+    }
+}
+
+/// Notifies everyone in the given room except the given user about something that happened there. If notification
+/// batching is on, the notification waits to go out with the others from the same batch window, to whoever was in the
+/// room when it happened.
+fn notify_roommates(switchboard: &Switchboard, room_id: &RoomId, myself: &UserId, json: &JsonValue) {
+    let config = STATE.config.get().unwrap();
+    if config.notification_batch_window_ms.is_some() {
+        let recipients = switchboard.roommates_to_notify(room_id, myself).cloned();
+        STATE.notification_batches.lock().unwrap().push(recipients, json.clone());
+    } else {
+        send_message(json, switchboard.roommates_to_notify(room_id, myself));
+    }
+}
+
+/// Sends out every notification that's been batched up since the last time, one message per recipient.
+fn flush_notification_batches() {
+    let pending = STATE.notification_batches.lock().unwrap().take();
+    for (sess, batch) in pending {
+        if !sess.destroyed.load(Ordering::Relaxed) {
+            send_message(&batch, [sess]);
+        }
+    }
+}
+
+/// Which of the recipients of a message Janus did and didn't accept it for.
+#[derive(Debug, Default)]
+struct DeliveryReport {
+    /// How many recipients the message was sent to.
+    attempted: usize,
+    /// The handle of each recipient the message couldn't be delivered to, and why.
+    failed: Vec<(String, String)>,
+}
+
+impl DeliveryReport {
+    /// Logs any recipients that the given kind of message didn't reach. For notifications about moderation actions,
+    /// which operators need to know went undelivered.
+    fn warn_if_undelivered(&self, what: &str) {
+        if !self.failed.is_empty() {
+            let failures: Vec<_> = self.failed.iter().map(|(handle, reason)| format!("{} ({})", handle, reason)).collect();
+            janus_warn!("{} of {} {} notifications went undelivered: {}.", self.failed.len(), self.attempted, what, failures.join(", "));
+        }
+    }
+}
+
+fn send_message<T: IntoIterator<Item=U>, U: AsRef<Session>>(body: &JsonValue, sessions: T) -> DeliveryReport {
     let mut msg = serde_to_jansson(body);
     let push_event = gateway_callbacks().push_event;
+    let mut report = DeliveryReport::default();
     for session in sessions {
         let handle = session.as_ref().handle;
-        janus_huge!("Signalling message going to {:p}: {}.", handle, body);
+        janus_huge!("Signalling message going to {}: {}.", session.as_ref().log_name(), body);
+        report.attempted += 1;
         let result = JanusError::from(push_event(handle, &mut PLUGIN, ptr::null(), msg.as_mut_ref(), ptr::null_mut()));
         match result {
             Ok(_) => (),
             Err(JanusError { code: 458 }) => {
                 // session not found -- should be unusual but not problematic
-                janus_warn!("Attempted to send signalling message to missing session {:p}: {}", handle, body);
+                janus_warn!("Attempted to send signalling message to missing session {}: {}", session.as_ref().log_name(), body);
+                report.failed.push((format!("{:p}", handle), String::from("session not found")));
+            }
+            Err(e) => {
+                janus_err!("Error sending signalling message to {}: {}", session.as_ref().log_name(), e);
+                report.failed.push((format!("{:p}", handle), e.to_string()));
             }
-            Err(e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e)
         }
     }
+    report
 }
 
-fn send_offer<T: IntoIterator<Item=U>, U: AsRef<Session>>(offer: &JsonValue, sessions: T) {
+fn push_offer(session: &Session, offer: &JsonValue) -> JanusResult {
     let mut msg = serde_to_jansson(&json!({}));
     let mut jsep = serde_to_jansson(offer);
     let push_event = gateway_callbacks().push_event;
+    janus_huge!("Offer going to {}: {}.", session.log_name(), offer);
+    JanusError::from(push_event(session.handle, &mut PLUGIN, ptr::null(), msg.as_mut_ref(), jsep.as_mut_ref()))
+}
+
+fn send_offer<'a, T: IntoIterator<Item=&'a Arc<Session>>>(offer: &JsonValue, sessions: T) {
+    let config = STATE.config.get().unwrap();
     for session in sessions {
-        let handle = session.as_ref().handle;
-        janus_huge!("Offer going to {:p}: {}.", handle, offer);
-        let result = JanusError::from(push_event(handle, &mut PLUGIN, ptr::null(), msg.as_mut_ref(), jsep.as_mut_ref()));
-        match result {
+        // any retry of an older offer to this session sees that it's been overtaken and gives up
+        let mut offers_sent = session.offers_sent.lock().unwrap();
+        *offers_sent += 1;
+        let sequence = *offers_sent;
+        match push_offer(session, offer) {
             Ok(_) => (),
             Err(JanusError { code: 458 }) => {
                 // session not found -- should be unusual but not problematic
-                janus_warn!("Attempted to send signalling message to missing session {:p}: {}", handle, offer);
+                janus_warn!("Attempted to send signalling message to missing session {}: {}", session.log_name(), offer);
+            }
+            Err(e) => {
+                janus_err!("Error sending signalling message to {}: {}", session.log_name(), e);
+                if config.offer_retry_attempts > 0 {
+                    let due = Instant::now() + offer_retry_delay(0);
+                    let retry = OfferRetry { subscriber: Arc::clone(session), offer: offer.clone(), sequence, attempts: 0, due };
+                    STATE.offer_retries.lock().unwrap().push(retry);
+                }
+            }
+        }
+    }
+}
+
+/// How long to wait before retrying an offer which has already been retried the given number of times. The wait doubles
+/// with each attempt.
+fn offer_retry_delay(attempts: usize) -> Duration {
+    let interval = STATE.config.get().unwrap().offer_retry_interval_ms as u64;
+    Duration::from_millis(interval.saturating_mul(1 << attempts.min(16)))
+}
+
+/// Sends again each undelivered offer whose time has come. Once an offer has been retried as many times as we're
+/// configured to without getting through, we give up and end its subscriber's media subscription, since the subscriber
+/// can't receive the media without it. The retries are sent without holding on to the list of them, so that offers
+/// failing meanwhile can join it.
+fn retry_offers() {
+    let config = STATE.config.get().unwrap();
+    let now = Instant::now();
+    let due: Vec<OfferRetry> = {
+        let mut retries = STATE.offer_retries.lock().unwrap();
+        let (due, waiting) = retries.drain(..).partition(|retry| retry.due <= now);
+        *retries = waiting;
+        due
+    };
+    let mut pending = Vec::new();
+    let mut exhausted = Vec::new();
+    for mut retry in due {
+        if retry.subscriber.destroyed.load(Ordering::Relaxed) {
+            continue;
+        }
+        let offers_sent = retry.subscriber.offers_sent.lock().unwrap();
+        if *offers_sent != retry.sequence {
+            janus_info!("Dropping retry of an offer to {}, which has been sent a newer one since.", retry.subscriber.log_name());
+            continue;
+        }
+        retry.attempts += 1;
+        match push_offer(&retry.subscriber, &retry.offer) {
+            Ok(_) => {
+                janus_info!("Delivered offer to {} on retry {}.", retry.subscriber.log_name(), retry.attempts);
+            }
+            Err(e) if retry.attempts < config.offer_retry_attempts => {
+                janus_warn!("Error retrying offer to {} (attempt {}): {}", retry.subscriber.log_name(), retry.attempts, e);
+                retry.due = now + offer_retry_delay(retry.attempts);
+                drop(offers_sent);
+                pending.push(retry);
             }
-            Err(e) => janus_err!("Error sending signalling message to {:p}: {}", handle, e)
+            Err(e) => {
+                janus_err!("Giving up on offer to {} after {} retries: {}", retry.subscriber.log_name(), retry.attempts, e);
+                exhausted.push(Arc::clone(&retry.subscriber));
+            }
+        }
+    }
+    STATE.offer_retries.lock().unwrap().extend(pending);
+    if !exhausted.is_empty() {
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+        for subscriber in exhausted {
+            end_media_subscriptions(&mut switchboard, &subscriber, "offer_undelivered");
+        }
+    }
+}
+
+/// Stops forwarding media from anyone to the given subscriber, other than its own looped-back media, telling it which
+/// users it was unsubscribed from and why.
+fn end_media_subscriptions(switchboard: &mut Switchboard, subscriber: &Arc<Session>, reason: &str) {
+    let publishers: Vec<Arc<Session>> = switchboard.publishers_to(subscriber).iter()
+        .filter(|publisher| !Arc::ptr_eq(publisher, subscriber))
+        .cloned()
+        .collect();
+    for publisher in publishers {
+        janus_info!("Ending subscription from {} to {} ({}).", subscriber.log_name(), publisher.log_name(), reason);
+        switchboard.unsubscribe_from_user(subscriber, &publisher);
+        if let Some(publisher_state) = publisher.join_state.get() {
+            let notification = json!({ "event": "unsubscribed", "user_id": &publisher_state.user_id, "reason": reason });
+            send_message(&notification, [subscriber]).warn_if_undelivered("unsubscription");
         }
     }
+    if let Some(sub) = subscriber.subscription.lock().unwrap().as_mut() {
+        sub.media = None;
+        sub.media_handle = None;
+    }
+}
+
+/// Pushes a fresh copy of the publisher's subscriber offer to a single one of its subscribers, so that the subscriber
+/// can renegotiate without disturbing anyone else subscribing to the same publisher.
+fn send_subscriber_offer(subscriber: &Arc<Session>, publisher: &Session) -> Result<(), Box<Error>> {
+    let subscriber_offer = publisher.subscriber_offer.lock().unwrap();
+    let sdp = subscriber_offer.as_ref().ok_or("That user isn't publishing anything.")?;
+    send_offer(&json!({ "type": "offer", "sdp": sdp }), [subscriber]);
+    Ok(())
 }
 
 fn send_pli<T: IntoIterator<Item=U>, U: AsRef<Session>>(publishers: T) {
@@ -251,23 +489,94 @@ fn send_fir<T: IntoIterator<Item=U>, U: AsRef<Session>>(publishers: T) {
     }
 }
 
-fn get_config(config_root: *const c_char) -> Result<Config, Box<Error>> {
+fn send_remb(publisher: &Session, bitrate: u32) {
+    let relay_rtcp = gateway_callbacks().relay_rtcp;
+    let mut remb = janus::rtcp::gen_remb(bitrate);
+    relay_rtcp(publisher.as_ptr(), 1, remb.as_mut_ptr(), remb.len() as i32);
+}
+
+/// Returns the publisher that the given session's media subscription is to, if it's still sending media to it. This
+/// excludes any other media a session is getting, e.g. its own, looped back for sendrecv.
+fn subscribed_publisher<'a>(switchboard: &'a Switchboard, subscriber: &'a Session) -> Option<&'a Arc<Session>> {
+    let (media, media_handle) = {
+        let subscription = subscriber.subscription.lock().unwrap();
+        let sub = subscription.as_ref()?;
+        (sub.media.clone(), sub.media_handle.clone())
+    };
+    switchboard.media_senders_to(subscriber).find(|p| match p.join_state.get() {
+        Some(joined) => media.as_ref() == Some(&joined.user_id),
+        None => media_handle.as_ref() == Some(&p.handle_id()),
+    })
+}
+
+fn get_config(config_root: *const c_char) -> Result<(Config, Diagnostics), Box<Error>> {
     let config_path = unsafe { Path::new(CStr::from_ptr(config_root).to_str()?) };
     let config_file = config_path.join("janus.plugin.sfu.cfg");
     Config::from_path(config_file)
 }
 
+/// Tells the operator which settings came from the config file, which were left at their defaults, and which were
+/// rejected.
+fn log_config_diagnostics(diagnostics: &Diagnostics) {
+    let with_outcome = |outcome| diagnostics.iter().filter(|d| d.1 == outcome).map(|d| d.0).collect::<Vec<_>>();
+    janus_info!("Settings loaded from the config file: {}.", with_outcome(SettingOutcome::Loaded).join(", "));
+    janus_info!("Settings left at their defaults: {}.", with_outcome(SettingOutcome::Defaulted).join(", "));
+    for (name, outcome) in diagnostics {
+        if let SettingOutcome::Rejected(reason) = outcome {
+            janus_warn!("Rejected the value of setting {}: {}. Using the default instead.", name, reason);
+        }
+    }
+}
+
 extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char) -> c_int {
     let config = match get_config(config_path) {
-        Ok(c) => {
+        Ok((c, diagnostics)) => {
             janus_info!("Loaded SFU plugin configuration: {:?}", c);
+            log_config_diagnostics(&diagnostics);
             c
         }
         Err(e) => {
-            janus_warn!("Error loading configuration for SFU plugin: {}", e);
+            janus_warn!("Error loading configuration for SFU plugin: {}. Using defaults for all settings.", e);
             Config::default()
         }
     };
+    if let Some(ref dir) = config.data_log_dir {
+        let logs = DataLogs::new(dir, config.data_log_max_bytes as u64);
+        STATE.data_logs.set_if_none(Box::new(Mutex::new(logs)));
+    }
+    if let Some(ref path) = config.audit_log_path {
+        match AuditLog::open(path) {
+            Ok(mut log) => {
+                let (entries_tx, entries_rx) = mpsc::channel::<AuditEntry>();
+                let writer = thread::spawn(move || {
+                    janus_verb!("Audit log thread is alive.");
+                    for entry in entries_rx {
+                        if let Err(e) = log.record(&entry) {
+                            janus_err!("Error recording {} in the audit log: {}", entry.action, e);
+                        }
+                    }
+                });
+                *STATE.audit_log.lock().unwrap() = Some((entries_tx, writer));
+            }
+            Err(e) => janus_err!("Error opening audit log at {}: {}", path, e),
+        }
+    }
+    {
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+        for (room, settings) in &config.rooms {
+            if settings.retain_state {
+                switchboard.retain_room_state(room.clone());
+            }
+        }
+    }
+    if let Some(ref path) = config.standby_state_path {
+        if Path::new(path).exists() {
+            match standby::take(path) {
+                Ok(snapshot) => restore_standby_snapshot(snapshot, Duration::from_secs(config.standby_window_secs as u64)),
+                Err(e) => janus_warn!("Error loading standby state from {}: {}", path, e),
+            }
+        }
+    }
     STATE.config.set_if_none(Box::new(config));
     match unsafe { callbacks.as_ref() } {
         Some(c) => {
@@ -277,13 +586,140 @@ extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char)
 
             thread::spawn(move || {
                 janus_verb!("Message processing thread is alive.");
-                for msg in messages_rx.iter() {
-                    if let Err(e) = handle_message_async(msg) {
-                        janus_err!("Error processing message: {}", e);
+                loop {
+                    *STATE.message_thread_heartbeat.lock().unwrap() = Instant::now();
+                    // wake up now and then even without messages, so that an idle thread doesn't look wedged
+                    match messages_rx.recv_timeout(MESSAGE_THREAD_HEARTBEAT_INTERVAL) {
+                        Ok(msg) => {
+                            if let Err(e) = handle_message_async(msg) {
+                                janus_err!("Error processing message: {}", e);
+                            }
+                        }
+                        Err(mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            });
+
+            if let Some(secs) = STATE.config.get().unwrap().publisher_idle_timeout_secs {
+                thread::spawn(move || {
+                    janus_verb!("Idle publisher demotion thread is alive.");
+                    loop {
+                        thread::sleep(Duration::from_secs(1));
+                        demote_idle_publishers(Duration::from_secs(secs));
+                    }
+                });
+            }
+
+            if let Some(ms) = STATE.config.get().unwrap().video_stall_timeout_ms {
+                thread::spawn(move || {
+                    janus_verb!("Video stall detection thread is alive.");
+                    loop {
+                        thread::sleep(VIDEO_STALL_CHECK_INTERVAL);
+                        detect_video_stalls(Duration::from_millis(ms));
+                    }
+                });
+            }
+
+            if STATE.config.get().unwrap().rtp_reorder_depth > 0 {
+                thread::spawn(move || {
+                    janus_verb!("Reorder flushing thread is alive.");
+                    loop {
+                        thread::sleep(REORDER_FLUSH_INTERVAL);
+                        flush_reorder_buffers();
+                    }
+                });
+            }
+
+            if STATE.config.get().unwrap().pacing_rate_kbps.is_some() {
+                thread::spawn(move || {
+                    janus_verb!("Pacing thread is alive.");
+                    loop {
+                        thread::sleep(PACING_INTERVAL);
+                        release_paced_packets();
+                    }
+                });
+            }
+
+            if let Some(secs) = STATE.config.get().unwrap().keepalive_interval_secs {
+                thread::spawn(move || {
+                    janus_verb!("Subscriber keepalive thread is alive.");
+                    loop {
+                        thread::sleep(Duration::from_secs(1));
+                        keep_idle_subscribers_alive(Duration::from_secs(secs));
                     }
+                });
+            }
+
+            let recovery_interval = Duration::from_secs(STATE.config.get().unwrap().video_recovery_interval_secs as u64);
+            thread::spawn(move || {
+                janus_verb!("Video recovery thread is alive.");
+                loop {
+                    thread::sleep(Duration::from_secs(1));
+                    recover_video(recovery_interval);
                 }
             });
 
+            if STATE.config.get().unwrap().rooms.values().any(|room| room.max_bitrate.is_some()) {
+                thread::spawn(move || {
+                    janus_verb!("Room bitrate thread is alive.");
+                    let mut last_estimate = Instant::now();
+                    loop {
+                        thread::sleep(Duration::from_secs(1));
+                        enforce_room_bitrates(last_estimate.elapsed());
+                        last_estimate = Instant::now();
+                    }
+                });
+            }
+
+            match STATE.config.get().unwrap().audio_only_load_threshold {
+                Some(_) if load_per_cpu().is_none() => {
+                    janus_warn!("Can't tell the system's load, so audio-only mode won't turn on by itself. This only works on Linux.");
+                }
+                Some(threshold) => {
+                    thread::spawn(move || {
+                        janus_verb!("Load monitoring thread is alive.");
+                        loop {
+                            thread::sleep(AUDIO_ONLY_LOAD_CHECK_INTERVAL);
+                            check_load(threshold);
+                        }
+                    });
+                }
+                None => {}
+            }
+
+            if STATE.config.get().unwrap().offer_retry_attempts > 0 {
+                thread::spawn(move || {
+                    janus_verb!("Offer retry thread is alive.");
+                    loop {
+                        thread::sleep(offer_retry_delay(0));
+                        retry_offers();
+                    }
+                });
+            }
+
+            if let Some(ms) = STATE.config.get().unwrap().notification_batch_window_ms {
+                thread::spawn(move || {
+                    janus_verb!("Notification batching thread is alive.");
+                    loop {
+                        thread::sleep(Duration::from_millis(ms));
+                        flush_notification_batches();
+                    }
+                });
+            }
+
+            if let Some(ref path) = STATE.config.get().unwrap().admin_socket_path {
+                match admin::listen(path) {
+                    Ok(listener) => {
+                        thread::spawn(move || {
+                            janus_verb!("Admin socket thread is alive.");
+                            admin::serve(listener, handle_admin_command);
+                        });
+                    }
+                    Err(e) => janus_err!("Error creating admin socket at {}: {}", path, e),
+                }
+            }
+
             janus_info!("Janus SFU plugin initialized!");
             0
         }
@@ -295,21 +731,186 @@ extern "C" fn init(callbacks: *mut PluginCallbacks, config_path: *const c_char)
 }
 
 extern "C" fn destroy() {
+    if let Some(ref path) = STATE.config.get().and_then(|c| c.standby_state_path.as_ref()) {
+        let snapshot = take_standby_snapshot(&STATE.switchboard.read().expect("Switchboard is poisoned :("));
+        match standby::save(path, &snapshot) {
+            Ok(()) => janus_info!("Saved standby state for {} users to {}.", snapshot.users.len(), path),
+            Err(e) => janus_err!("Error saving standby state to {}: {}", path, e),
+        }
+    }
+    // hanging up on the audit log thread lets it finish writing whatever's left, so wait for that
+    if let Some((entries_tx, writer)) = STATE.audit_log.lock().unwrap().take() {
+        drop(entries_tx);
+        if writer.join().is_err() {
+            janus_err!("The audit log thread panicked; the audit log may be missing entries.");
+        }
+    }
     janus_info!("Janus SFU plugin destroyed!");
 }
 
+/// Captures the state of every room, for restoring after the plugin is reloaded.
+fn take_standby_snapshot(switchboard: &Switchboard) -> Snapshot {
+    let mut users = Vec::new();
+    for room_id in switchboard.rooms() {
+        for joined in switchboard.occupants_of(room_id).iter().filter_map(|s| s.join_state.get()) {
+            users.push(SavedUser {
+                user_id: joined.user_id.clone(),
+                room_id: room_id.clone(),
+                moderator: joined.moderator.load(Ordering::Relaxed),
+                hand_raised: joined.hand_raised.load(Ordering::Relaxed),
+            });
+        }
+    }
+    let saved_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    Snapshot {
+        saved_at_ms: saved_at.as_secs() * 1000 + u64::from(saved_at.subsec_millis()),
+        users,
+        blocks: switchboard.blocks(),
+        mutes: switchboard.mutes(),
+        locked_rooms: switchboard.locked_rooms().cloned().collect(),
+        room_secrets: switchboard.room_secrets(),
+    }
+}
+
+/// Puts back the state saved before the plugin was reloaded. Blocks and mutes belong to users, and locks and secrets have
+/// to be in place before anyone can be let back into their rooms, so those come back right away; everything else comes
+/// back as each user rejoins their room, if they do within `window` of the snapshot.
+fn restore_standby_snapshot(snapshot: Snapshot, window: Duration) {
+    let saved_at = UNIX_EPOCH + Duration::from_millis(snapshot.saved_at_ms);
+    let age = SystemTime::now().duration_since(saved_at).unwrap_or_default();
+    if age >= window {
+        janus_info!("Ignoring standby state saved {} seconds ago.", age.as_secs());
+        return;
+    }
+    janus_info!("Restoring standby state for {} users, saved {} seconds ago.", snapshot.users.len(), age.as_secs());
+    let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+    for (from, target) in snapshot.blocks.iter().cloned() {
+        switchboard.establish_block(from, target);
+    }
+    for (from, target, mute) in snapshot.mutes.iter().cloned() {
+        switchboard.set_mute(from, target, mute);
+    }
+    // let everyone who was in a locked room back in
+    for room_id in &snapshot.locked_rooms {
+        let admitted = snapshot.users.iter().filter(|u| &u.room_id == room_id).map(|u| u.user_id.clone()).collect();
+        switchboard.restore_room(room_id.clone(), Some(admitted), None);
+    }
+    for (room_id, secret) in snapshot.room_secrets.iter().cloned() {
+        switchboard.restore_room(room_id, None, Some(secret));
+    }
+    *STATE.standby.lock().unwrap() = Some((snapshot, Instant::now() + (window - age)));
+}
+
+/// Forgets the standby state once its window has passed, along with the locks and secrets of rooms nobody came back to.
+fn expire_standby(switchboard: &mut Switchboard) {
+    let mut standby = STATE.standby.lock().unwrap();
+    if standby.as_ref().is_some_and(|(_, deadline)| Instant::now() >= *deadline) {
+        janus_info!("Standby window has passed; forgetting standby state.");
+        *standby = None;
+        switchboard.end_standby();
+    }
+}
+
+/// If the given user was in the given room before the plugin was reloaded, and came back in time, gives them back their
+/// raised hand, and their moderator role if their join's token shows that it's really them.
+fn restore_standby_user(switchboard: &mut Switchboard, joined: &JoinState, room_id: &RoomId, owns_user_id: bool) {
+    let mut standby = STATE.standby.lock().unwrap();
+    let snapshot = match *standby {
+        Some((ref mut snapshot, _)) => snapshot,
+        None => return
+    };
+    let saved = match snapshot.users.iter().position(|u| u.user_id == joined.user_id && &u.room_id == room_id) {
+        Some(i) => snapshot.users.remove(i),
+        None => return
+    };
+    janus_info!("Restoring standby state for {} in room {}.", saved.user_id, room_id);
+    if saved.hand_raised {
+        let config = STATE.config.get().unwrap();
+        joined.hand_raised.store(true, Ordering::Relaxed);
+        let notification = json!({ "event": "hand", "user_id": &saved.user_id, "room_id": room_id, "raised": true });
+        notify_roommates(switchboard, room_id, &saved.user_id, &notification);
+        switchboard.record_event(room_id, notification, config.room_event_buffer_size);
+    }
+    if saved.moderator && owns_user_id && !joined.moderator.load(Ordering::Relaxed) {
+        set_moderator(switchboard, room_id, &saved.user_id, true, None);
+    }
+}
+
 extern "C" fn create_session(handle: *mut PluginSession, error: *mut c_int) {
+    let config = STATE.config.get().unwrap();
+    let message_bucket = TokenBucket::new(config.message_rate_limit as f64, config.message_rate_burst as f64, Instant::now());
+    let packet_bucket = config.max_packet_rate.map(|rate| TokenBucket::new(rate as f64, rate as f64, Instant::now()));
     let initial_state = SessionState {
         destroyed: AtomicBool::new(false),
         join_state: AtomSetOnce::empty(),
+        correlation_id: Mutex::new(None),
+        observer_sends_data: AtomicBool::new(false),
+        offer_version: AtomicUsize::new(0),
+        offers_sent: Mutex::new(0),
+        published_media: Mutex::new(None),
         subscriber_offer: Arc::new(Mutex::new(None)),
-        subscription: AtomSetOnce::empty(),
+        subscription: Mutex::new(None),
         fir_seq: AtomicIsize::new(0),
+        max_temporal_layer: AtomicUsize::new(usize::MAX),
+        media_priority: AtomicIsize::new(0),
+        video_suspended: AtomicBool::new(false),
+        audio_label: Mutex::new(None),
+        audio_labeled: AtomicBool::new(false),
+        track_payload_type: AtomicUsize::new(0),
+        selected_audio_label: Mutex::new(None),
+        selects_audio_label: AtomicBool::new(false),
+        keyframes_only: AtomicBool::new(false),
+        video_constraints: Mutex::new(VideoConstraints::default()),
+        layer_selection: Mutex::new(LayerSelection::default()),
+        selects_layer: AtomicBool::new(false),
+        constrained_temporal_layer: AtomicUsize::new(usize::MAX),
+        keyframe_timestamp: Mutex::new(None),
+        message_bucket: Mutex::new(message_bucket),
+        packet_bucket: Mutex::new(packet_bucket),
+        pacer: Mutex::new(config.pacing_rate_kbps.map(|kbps| Pacer::new(kbps, Instant::now()))),
+        paced_video_dropped: AtomicBool::new(false),
+        candidate_types: Mutex::new(Vec::new()),
+        flooding: AtomicBool::new(false),
+        video_bytes_published: AtomicUsize::new(0),
+        last_video_at: Mutex::new(None),
+        video_stalled: AtomicBool::new(false),
+        audio_context: Mutex::new(SwitchingContext::default()),
+        video_context: Mutex::new(SwitchingContext::default()),
+        audio_level_extension: AtomicUsize::new(0),
+        audio_payload_type: AtomicUsize::new(0),
+        red_payload_type: AtomicUsize::new(0),
+        receives_red: AtomicBool::new(false),
+        dtmf_payload_type: AtomicUsize::new(0),
+        last_dtmf_timestamp: Mutex::new(None),
+        rid_extension: AtomicUsize::new(0),
+        audio_ssrc: AtomicUsize::new(usize::MAX),
+        said_bye: Mutex::new(Goodbyes::default()),
+        simulcast_rids: Mutex::new(Vec::new()),
+        last_spoke: Mutex::new(Instant::now()),
+        demoted: AtomicBool::new(false),
+        unpublished: AtomicBool::new(false),
+        held_until: Mutex::new(None),
+        audio_codec: Mutex::new(None),
+        offered_audio_codecs: Mutex::new(Vec::new()),
+        video_codec: Mutex::new(None),
+        offered_video_codecs: Mutex::new(Vec::new()),
+        codec_capabilities: Mutex::new(None),
+        publish_codecs: Mutex::new(PublishCodecs::default()),
+        paused_publishers: Mutex::new(HashSet::new()),
+        payload_type_rewrites: Mutex::new(Vec::new()),
+        extension_id_rewrites: Mutex::new(Vec::new()),
+        framerate_limiter: Mutex::new(FramerateLimiter::default()),
+        video_layers: Mutex::new(ActiveLayers::default()),
+        audio_reorder: Mutex::new(ReorderBuffer::new(config.rtp_reorder_depth)),
+        video_reorder: Mutex::new(ReorderBuffer::new(config.rtp_reorder_depth)),
+        recent_stats: Mutex::new(VecDeque::new()),
+        video_degraded_at: Mutex::new(None),
+        last_sent: Mutex::new(Instant::now()),
     };
 
     match unsafe { Session::associate(handle, initial_state) } {
         Ok(sess) => {
-            janus_info!("Initializing SFU session {:p}...", sess.handle);
+            janus_info!("Initializing SFU session {}...", sess.log_name());
             STATE.switchboard.write().expect("Switchboard is poisoned :(").connect(sess);
         }
         Err(e) => {
@@ -322,16 +923,22 @@ extern "C" fn create_session(handle: *mut PluginSession, error: *mut c_int) {
 extern "C" fn destroy_session(handle: *mut PluginSession, error: *mut c_int) {
     match unsafe { Session::from_ptr(handle) } {
         Ok(sess) => {
-            janus_info!("Destroying SFU session {:p}...", sess.handle);
+            janus_info!("Destroying SFU session {}...", sess.log_name());
+            let config = STATE.config.get().unwrap();
             let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
             switchboard.remove_session(&sess);
             if let Some(joined) = sess.join_state.get() {
                 // if they are entirely disconnected, notify their roommates
                 if !switchboard.is_connected(&joined.user_id) {
-                    let response = json!({ "event": "leave", "user_id": &joined.user_id, "room_id": &joined.room_id });
-                    let occupants = switchboard.occupants_of(&joined.room_id);
-                    notify_except(&response, &joined.user_id, occupants);
+                    let room_id = joined.room_id.read().unwrap();
+                    let response = json!({ "event": "leave", "user_id": &joined.user_id, "room_id": &*room_id });
+                    notify_roommates(&switchboard, &room_id, &joined.user_id, &response);
+                    switchboard.record_event(&room_id, response, config.room_event_buffer_size);
+                    if joined.moderator.load(Ordering::Relaxed) && config.auto_promote_moderator {
+                        promote_longest_present(&mut switchboard, &room_id);
+                    }
                 }
+                notify_if_room_available(&mut switchboard, &joined.room_id.read().unwrap());
             }
             sess.destroyed.store(true, Ordering::Relaxed);
         }
@@ -342,167 +949,1651 @@ extern "C" fn destroy_session(handle: *mut PluginSession, error: *mut c_int) {
     }
 }
 
-extern "C" fn query_session(_handle: *mut PluginSession) -> *mut RawJanssonValue {
-    let output = json!({});
-    serde_to_jansson(&output).into_raw()
+/// Makes whoever has been in the given room the longest its moderator, if it doesn't have one.
+fn promote_longest_present(switchboard: &mut Switchboard, room_id: &RoomId) {
+    if !switchboard.get_moderators(room_id).is_empty() {
+        return;
+    }
+    let oldest = switchboard.occupants_of(room_id).first().and_then(|s| s.join_state.get()).map(|j| j.user_id.clone());
+    if let Some(user_id) = oldest {
+        janus_info!("Promoting {} to moderator of room {}.", user_id, room_id);
+        set_moderator(switchboard, room_id, &user_id, true, None);
+    }
 }
 
-extern "C" fn setup_media(handle: *mut PluginSession) {
-    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+extern "C" fn query_session(handle: *mut PluginSession) -> *mut RawJanssonValue {
     let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
-    send_fir(switchboard.media_senders_to(&sess));
-    janus_info!("WebRTC media is now available on {:p}.", sess.handle);
+    let output = match unsafe { Session::from_ptr(handle) } {
+        Ok(sess) => session_info(&switchboard, &sess),
+        Err(_) => json!({}),
+    };
+    serde_to_jansson(&output).into_raw()
 }
 
-extern "C" fn incoming_rtp(handle: *mut PluginSession, video: c_int, buf: *mut c_char, len: c_int) {
-    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
-    let relay_rtp = gateway_callbacks().relay_rtp;
-    for other in switchboard.media_recipients_for(&sess) {
-        relay_rtp(other.as_ptr(), video, buf, len);
+/// Summarizes the given session for the admin API.
+fn session_info(switchboard: &Switchboard, sess: &Session) -> JsonValue {
+    let room_count = switchboard.room_count();
+    match sess.join_state.get() {
+        Some(joined) => json!({
+            "handle_id": format!("{:p}", sess.handle),
+            "correlation_id": &*sess.correlation_id.lock().unwrap(),
+            "user_id": &joined.user_id,
+            "room_id": &*joined.room_id.read().unwrap(),
+            "video_layers": sess.video_layers.lock().unwrap().ssrcs(),
+            "room_count": room_count,
+            "recent_stats": &*sess.recent_stats.lock().unwrap(),
+            "candidate_types": &*sess.candidate_types.lock().unwrap(),
+            "subscription_count": switchboard.subscription_count(&joined.user_id),
+            "room_bitrate": switchboard.room_bitrate(&joined.room_id.read().unwrap()),
+            "room_stats": room_stats(switchboard, &joined.room_id.read().unwrap())
+        }),
+        None => json!({
+            "handle_id": format!("{:p}", sess.handle),
+            "correlation_id": &*sess.correlation_id.lock().unwrap(),
+            "room_count": room_count,
+            "candidate_types": &*sess.candidate_types.lock().unwrap(),
+        }),
     }
 }
 
-extern "C" fn incoming_rtcp(handle: *mut PluginSession, video: c_int, buf: *mut c_char, len: c_int) {
-    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
-    let packet = unsafe { slice::from_raw_parts(buf, len as usize) };
-    match video {
-        1 if janus::rtcp::has_pli(packet) => {
-            send_pli(switchboard.media_senders_to(&sess));
-        }
-        1 if janus::rtcp::has_fir(packet) => {
-            send_fir(switchboard.media_senders_to(&sess));
-        }
-        _ => {
-            let relay_rtcp = gateway_callbacks().relay_rtcp;
-            for subscriber in switchboard.media_recipients_for(&sess) {
-                relay_rtcp(subscriber.as_ptr(), video, buf, len);
-            }
-        }
+/// Describes everything we know about the given session, for debugging. A superset of `session_info`.
+fn session_diagnostics(switchboard: &Switchboard, sess: &Session) -> JsonValue {
+    let describe = |s: &Arc<Session>| json!({ "handle_id": format!("{:p}", s.handle), "user_id": s.join_state.get().map(|j| &j.user_id) });
+    let mut result = session_info(switchboard, sess);
+    let details = json!({
+        "join": sess.join_state.get().map(|joined| json!({
+            "sendrecv": joined.sendrecv,
+            "hand_raised": joined.hand_raised.load(Ordering::Relaxed),
+            "moderator": joined.moderator.load(Ordering::Relaxed),
+            "metadata": &joined.metadata,
+            "blocks": switchboard.blocks_from(&joined.user_id),
+        })),
+        "subscription": sess.subscription.lock().unwrap().as_ref().map(|sub| json!({
+            "notifications": sub.notifications,
+            "data": sub.data,
+            "media": &sub.media,
+            "media_handle": &sub.media_handle,
+            "priority": sub.priority,
+        })),
+        "publishers_to": switchboard.publishers_to(sess).iter().map(&describe).collect::<Vec<_>>(),
+        "subscribers_to": switchboard.subscribers_to(sess).iter().map(&describe).collect::<Vec<_>>(),
+        "publishing": sess.is_publishing(),
+        "audio_codec": sess.audio_codec.lock().unwrap().map(|c| c.to_str()),
+        "offered_audio_codecs": sess.offered_audio_codecs.lock().unwrap().iter().map(AudioCodec::to_str).collect::<Vec<_>>(),
+        "video_codec": sess.video_codec.lock().unwrap().map(|c| c.to_str()),
+        "offered_video_codecs": sess.offered_video_codecs.lock().unwrap().iter().map(VideoCodec::to_str).collect::<Vec<_>>(),
+        "audio_level_extension": sess.audio_level_extension.load(Ordering::Relaxed),
+        "red_payload_type": sess.red_payload_type.load(Ordering::Relaxed),
+        "receives_red": sess.receives_red.load(Ordering::Relaxed),
+        "demoted": sess.demoted.load(Ordering::Relaxed),
+        "unpublished": sess.unpublished.load(Ordering::Relaxed),
+        "media_priority": sess.media_priority.load(Ordering::Relaxed),
+        "video_suspended": sess.video_suspended.load(Ordering::Relaxed),
+        "keyframes_only": sess.keyframes_only.load(Ordering::Relaxed),
+        "audio_label": *sess.audio_label.lock().unwrap(),
+        "selected_audio_label": *sess.selected_audio_label.lock().unwrap(),
+        "max_temporal_layer": sess.max_temporal_layer.load(Ordering::Relaxed),
+        "fir_seq": sess.fir_seq.load(Ordering::Relaxed),
+        "simulcast_rids": sess.simulcast_rids.lock().unwrap().iter()
+            .map(|(rid, ssrc)| json!({ "rid": rid, "ssrc": ssrc }))
+            .collect::<Vec<_>>(),
+        "destroyed": sess.destroyed.load(Ordering::Relaxed),
+    });
+    if let (Some(result), JsonValue::Object(details)) = (result.as_object_mut(), details) {
+        result.extend(details);
     }
+    result
 }
 
-extern "C" fn incoming_data(handle: *mut PluginSession, buf: *mut c_char, len: c_int) {
+extern "C" fn setup_media(handle: *mut PluginSession) {
     let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
-    let relay_data = gateway_callbacks().relay_data;
-    for other in switchboard.data_recipients_for(&sess) {
-        relay_data(other.as_ptr(), buf, len);
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    // only bother the publisher this connection subscribed to, rather than everything it's getting
+    match subscribed_publisher(&switchboard, &sess) {
+        Some(publisher) => send_fir([publisher]),
+        None => send_fir(switchboard.media_senders_to(&sess)),
     }
+    janus_info!("WebRTC media is now available on {}.", sess.log_name());
 }
 
-extern "C" fn slow_link(handle: *mut PluginSession, _uplink: c_int, _video: c_int) {
-    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    janus_info!("Slow link message received on {:p}.", sess.handle);
+/// Stops forwarding media from publishers who haven't been heard from in the given amount of time, tearing down
+/// their subscriptions and letting the room know. They can publish again by sending a new offer. Only publishers
+/// which negotiated the audio level header extension are considered, since we can't tell whether anyone else is
+/// speaking.
+fn demote_idle_publishers(timeout: Duration) {
+    let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+    let idle: Vec<Arc<Session>> = switchboard.sessions().iter()
+        .filter(|s| s.audio_level_extension.load(Ordering::Relaxed) != 0)
+        .filter(|s| s.is_publishing())
+        .filter(|s| !s.join_state.get().is_some_and(|joined| joined.pinned.load(Ordering::Relaxed)))
+        .filter(|s| s.last_spoke.lock().unwrap().elapsed() > timeout)
+        .map(|s| Arc::clone(s))
+        .collect();
+    for publisher in idle {
+        janus_info!("Demoting {} from publisher, since it's been idle for {:?}.", publisher.log_name(), timeout);
+        publisher.demoted.store(true, Ordering::Relaxed);
+        stop_publishing(&mut switchboard, &publisher);
+    }
 }
 
-extern "C" fn hangup_media(handle: *mut PluginSession) {
-    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
-    janus_info!("Hanging up WebRTC media on {:p}.", sess.handle);
+/// Tears down all media subscriptions to the given publisher, and lets it and the rest of its room know. Its subscriber
+/// offer is dropped along with them, so it has to offer again to publish again.
+fn stop_publishing(switchboard: &mut Switchboard, publisher: &Arc<Session>) {
+    let config = STATE.config.get().unwrap();
+    *publisher.last_video_at.lock().unwrap() = None;
+    publisher.video_stalled.store(false, Ordering::Relaxed);
+    switchboard.unpublish(publisher);
+    if let Some(joined) = publisher.join_state.get() {
+        let room_id = joined.room_id.read().unwrap().clone();
+        let notification = json!({ "event": "unpublished", "user_id": &joined.user_id, "room_id": &room_id });
+        send_message(&notification, [publisher]);
+        notify_roommates(switchboard, &room_id, &joined.user_id, &notification);
+        switchboard.record_event(&room_id, notification, config.room_event_buffer_size);
+    }
 }
 
-fn process_join(from: &Arc<Session>, room_id: RoomId, user_id: UserId, subscribe: Option<Subscription>, token: Option<String>) -> MessageResult {
-    // todo: holy shit clean this function up somehow
-    let config = STATE.config.get().unwrap();
-    match (&config.auth_key, token) {
-        (Some(ref key), Some(ref token)) => {
-            match ValidatedToken::from_str(token, key) {
-                Ok(tok) => {
-                    janus_info!("Processing validated join from {:p} to room ID {} with user ID {}. Join allowed: {}", from.handle, room_id, user_id, tok.join_hub);
-                }
-                Err(e) => {
-                    janus_warn!("Processing invalid join from {:p} to room ID {} with user ID {} ({})", from.handle, room_id, user_id, e);
-                }
-            }
-        },
-        _ => {
-            janus_info!("Processing anonymous join from {:p} to room ID {} with user ID {}.", from.handle, room_id, user_id);
+/// Starts holding back the media the given session is starting to publish, if its room is configured to delay new
+/// publications.
+fn hold_new_publication(publisher: &Session) {
+    if let Some(joined) = publisher.join_state.get() {
+        let delay = STATE.config.get().unwrap().room_settings(&joined.room_id.read().unwrap()).publish_delay_ms;
+        if delay > 0 {
+            *publisher.held_until.lock().unwrap() = Some(Instant::now() + Duration::from_millis(delay));
         }
     }
+}
 
-    let mut switchboard = STATE.switchboard.write()?;
-    let body = json!({ "users": { room_id.as_str(): switchboard.get_users(&room_id) }});
+/// Returns whether the given publisher's media is still being held back. Once the delay is up, asks the publisher for
+/// a single keyframe for all of the subscribers who piled up in the meantime.
+fn is_held(publisher: &Arc<Session>) -> bool {
+    let mut held_until = publisher.held_until.lock().unwrap();
+    match *held_until {
+        Some(until) if Instant::now() < until => true,
+        Some(_) => {
+            janus_verb!("Releasing held media from {}.", publisher.log_name());
+            *held_until = None;
+            send_fir([publisher]);
+            false
+        }
+        None => false,
+    }
+}
 
-    let already_joined = !from.join_state.is_none();
-    let already_subscribed = !from.subscription.is_none();
-    if already_joined {
-        return Err(From::from("Handles may only join once!"))
+/// Lets the rest of the given publisher's room know that it's publishing again.
+fn announce_publishing(switchboard: &Switchboard, publisher: &Session) {
+    if let Some(joined) = publisher.join_state.get() {
+        let room_id = joined.room_id.read().unwrap();
+        let layers = publisher.video_layers.lock().unwrap();
+        let notification = json!({
+            "event": "published",
+            "user_id": &joined.user_id,
+            "room_id": &*room_id,
+            "video_layers": layers.ssrcs(),
+            "layer_stats": layers.descriptors(),
+        });
+        notify_roommates(switchboard, &room_id, &joined.user_id, &notification);
     }
-    if already_subscribed && subscribe.is_some() {
-        return Err(From::from("Handles may only subscribe once!"))
+}
+
+/// Lets anyone who was turned away from the given room know if it has space for them now.
+fn notify_if_room_available(switchboard: &mut Switchboard, room_id: &RoomId) {
+    let config = STATE.config.get().unwrap();
+    if switchboard.occupants_of(room_id).len() < config.max_room_size {
+        let notification = json!({ "event": "room_available", "room_id": room_id });
+        send_message(&notification, switchboard.take_room_waiters(room_id));
     }
+}
 
-    let mut is_master_handle = false;
-    if let Some(subscription) = subscribe.as_ref() {
-        let room_is_full = switchboard.occupants_of(&room_id).len() > config.max_room_size;
-        let server_is_full = switchboard.sessions().len() > config.max_ccu;
-        is_master_handle = subscription.data; // hack -- assume there is only one "master" data connection per user
-        if is_master_handle && room_is_full {
-            return Err(From::from("Room is full."))
+/// If a default room is configured, places a session which never joined a room into it, under a made-up user ID.
+/// Lets single-room deployments skip joining entirely.
+fn join_default_room(sess: &Arc<Session>) {
+    let config = STATE.config.get().unwrap();
+    if let Some(ref room_id) = config.default_room {
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+        if !sess.join_state.is_none() {
+            return; // somebody beat us to it
         }
-        if is_master_handle && server_is_full {
-            return Err(From::from("Server is full."))
+        if switchboard.observed_room(sess).is_some() {
+            return; // observers stay out of rooms
+        }
+        if switchboard.occupants_of(room_id).len() >= config.max_room_size {
+            janus_warn!("Not joining {} to default room ID {} because it's full.", sess.log_name(), room_id);
+            return;
         }
+        let user_id = format!("anonymous-{}", ANONYMOUS_USER_COUNT.fetch_add(1, Ordering::Relaxed));
+        janus_info!("Implicitly joining {} to default room ID {} with user ID {}.", sess.log_name(), room_id, user_id);
+        sess.join_state.set_if_none(Box::new(JoinState::new(room_id.clone(), user_id.clone(), false, JsonValue::Null)));
+        sess.subscription.lock().unwrap().get_or_insert(Subscription { notifications: true, data: true, ..Subscription::default() });
+        switchboard.join_room(Arc::clone(sess), room_id.clone());
+        send_message(&json!({ "event": "joined", "user_id": &user_id, "room_id": room_id }), [sess]);
+        let notification = json!({ "event": "join", "user_id": &user_id, "room_id": room_id });
+        notify_roommates(&switchboard, room_id, &user_id, &notification);
+        switchboard.record_event(room_id, notification, config.room_event_buffer_size);
     }
+}
 
-    from.join_state.set_if_none(Box::new(JoinState::new(room_id.clone(), user_id.clone())));
-    if let Some(subscription) = subscribe {
-        janus_info!("Processing join-time subscription from {:p}: {:?}.", from.handle, subscription);
-        from.subscription.set_if_none(Box::new(subscription.clone()));
-        if is_master_handle {
-            let notification = json!({ "event": "join", "user_id": user_id, "room_id": room_id });
-            switchboard.join_room(Arc::clone(from), room_id.clone());
-            notify_except(&notification, &user_id, switchboard.occupants_of(&room_id));
+extern "C" fn incoming_rtp(handle: *mut PluginSession, video: c_int, buf: *mut c_char, len: c_int) {
+    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+    if sess.join_state.is_none() {
+        join_default_room(&sess);
+    }
+    if video == 1 {
+        sess.video_bytes_published.fetch_add(len as usize, Ordering::Relaxed);
+        if STATE.config.get().unwrap().video_stall_timeout_ms.is_some() {
+            *sess.last_video_at.lock().unwrap() = Some(Instant::now());
         }
-        if let Some(ref publisher_id) = subscription.media {
-            let publisher = switchboard.get_publisher(publisher_id).ok_or("Can't subscribe to a nonexistent publisher.")?.clone();
-            let jsep = json!({
-                "type": "offer",
-                "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
-            });
-            switchboard.subscribe_to_user(Arc::clone(from), publisher);
-            return Ok(MessageResponse::new(body, jsep));
+        if sess.video_stalled.swap(false, Ordering::Relaxed) {
+            janus_info!("Video from {} resumed.", sess.log_name());
+            notify_video_subscribers(&sess, "video_resumed");
         }
     }
-    Ok(MessageResponse::msg(body))
-}
-
-fn process_kick(from: &Arc<Session>, room_id: RoomId, user_id: UserId, token: String) -> MessageResult {
-    let config = STATE.config.get().unwrap();
-    if let Some(ref key) = config.auth_key {
-        match ValidatedToken::from_str(&token, key) {
-            Ok(tok) => {
-                if tok.kick_users {
-                    janus_info!("Processing kick from {:p} targeting user ID {} in room ID {}.", from.handle, user_id, room_id);
-                    let end_session = gateway_callbacks().end_session;
-                    let switchboard = STATE.switchboard.read()?;
-                    let sessions = switchboard.get_sessions(&room_id, &user_id);
-                    for sess in sessions {
-                        janus_info!("Kicking session {:p}.", from.handle);
-                        end_session(sess.as_ptr());
+    if over_packet_rate(&sess) || is_held(&sess) {
+        return;
+    }
+    if video == 1 && STATE.audio_only_enabled.load(Ordering::Relaxed) {
+        return;
+    }
+    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+    let packet = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    if video == 0 {
+        let extension_id = sess.audio_level_extension.load(Ordering::Relaxed);
+        if extension_id != 0 {
+            match rtp::audio_level(packet, extension_id as u8) {
+                Some(level) if level < SPEAKING_AUDIO_LEVEL => *sess.last_spoke.lock().unwrap() = Instant::now(),
+                _ => {}
+            }
+        }
+    }
+    let header = RtpHeader::parse(packet);
+    if let (0, Some(ref header)) = (video, header) {
+        sess.audio_ssrc.store(header.ssrc as usize, Ordering::Relaxed);
+        let dtmf_payload_type = sess.dtmf_payload_type.load(Ordering::Relaxed);
+        if dtmf_payload_type != 0 && usize::from(header.payload_type) == dtmf_payload_type {
+            // subscribers aren't offered telephone-event, so rather than forwarding these we tell the room about them
+            return surface_dtmf(&switchboard, &sess, header, packet);
+        }
+    }
+    if let (1, Some(ref header)) = (video, header) {
+        let rid_extension = sess.rid_extension.load(Ordering::Relaxed);
+        if let Some(rid) = rtp::rid(packet, rid_extension as u8).filter(|_| rid_extension != 0) {
+            let mut rids = sess.simulcast_rids.lock().unwrap();
+            if let Some(layer) = rids.iter_mut().find(|(other, _)| other == rid) {
+                layer.1 = Some(header.ssrc);
+            }
+        }
+        let codec = *sess.video_codec.lock().unwrap();
+        let resolution = codec.zip(rtp::payload(packet)).and_then(|(codec, payload)| rtp::keyframe_resolution(codec, payload));
+        let temporal_layer = codec.zip(rtp::payload(packet)).and_then(|(codec, payload)| rtp::temporal_layer(codec, payload));
+        let mut layers = sess.video_layers.lock().unwrap();
+        let changed = layers.update(header.ssrc, packet.len(), header.timestamp, resolution, Instant::now());
+        // subscribers capping the framerate need to know how many temporal layers there are to drop
+        let deeper = temporal_layer.is_some_and(|layer| layers.note_temporal_layer(header.ssrc, layer));
+        if changed {
+            if let Some(joined) = sess.join_state.get() {
+                let notification = json!({
+                    "event": "layers",
+                    "user_id": &joined.user_id,
+                    "video_layers": layers.ssrcs(),
+                    "layer_stats": layers.descriptors(),
+                });
+                send_message(&notification, switchboard.subscribers_to(&sess));
+            }
+        }
+        if changed || deeper {
+            for subscriber in switchboard.subscribers_to(&sess) {
+                select_video_layers(subscriber, &sess, &layers);
+            }
+        }
+    }
+    let config = STATE.config.get().unwrap();
+    if config.anonymize_payloads {
+        // only for testing, so we don't mind copying every packet
+        let mut anonymized = packet.to_vec();
+        rtp::blank_payload(&mut anonymized);
+        return relay_rtp_in_order(&switchboard, &sess, video, header, &mut anonymized);
+    }
+    match header {
+        Some(_) if config.rtp_reorder_depth > 0 => relay_rtp_in_order(&switchboard, &sess, video, header, &mut packet.to_vec()),
+        _ => relay_rtp_packet(&switchboard, &sess, video, buf, len)
+    }
+}
+
+/// Tells everyone else in the given publisher's room about a DTMF digit it sent in the given telephone-event packet,
+/// once per key press. Every packet for one key press has the same timestamp, so we go by that. Each key press counts
+/// against the publisher's signalling rate limit, since it's broadcast like a message.
+fn surface_dtmf(switchboard: &Switchboard, sess: &Session, header: &RtpHeader, packet: &[u8]) {
+    match rtp::payload(packet).and_then(rtp::dtmf_event) {
+        Some(event) if event.end => {
+            let mut last_timestamp = sess.last_dtmf_timestamp.lock().unwrap();
+            if *last_timestamp == Some(header.timestamp) {
+                return;
+            }
+            *last_timestamp = Some(header.timestamp);
+            if !sess.message_bucket.lock().unwrap().try_take(Instant::now()) {
+                janus_warn!("Dropping DTMF digit from {} due to rate limiting.", sess.log_name());
+                return;
+            }
+            if let Some(joined) = sess.join_state.get() {
+                let room_id = joined.room_id.read().unwrap();
+                let notification = json!({
+                    "event": "dtmf",
+                    "user_id": &joined.user_id,
+                    "room_id": &*room_id,
+                    "digit": event.digit.to_string(),
+                });
+                notify_except(&notification, &joined.user_id, switchboard.occupants_of(&room_id));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns whether the given session is publishing RTP faster than the configured maximum packet rate, in which case
+/// the packet should be dropped. Logs, and optionally tells the room's moderators, when a flood starts.
+fn over_packet_rate(sess: &Session) -> bool {
+    let allowed = match *sess.packet_bucket.lock().unwrap() {
+        Some(ref mut bucket) => bucket.try_take(Instant::now()),
+        None => return false,
+    };
+    let was_flooding = sess.flooding.swap(!allowed, Ordering::Relaxed);
+    if !allowed && !was_flooding {
+        janus_warn!("Dropping RTP from {} because it's exceeding the maximum packet rate.", sess.log_name());
+        if let Some(joined) = sess.join_state.get() {
+            if STATE.config.get().unwrap().notify_moderators_of_packet_floods {
+                let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+                let room_id = joined.room_id.read().unwrap();
+                let notification = json!({ "event": "packet_flood", "user_id": &joined.user_id, "room_id": &*room_id });
+                for moderator in switchboard.get_moderators(&room_id) {
+                    notify_user(&notification, moderator, switchboard.occupants_of(&room_id)).warn_if_undelivered("packet flood");
+                }
+            }
+        }
+    }
+    !allowed
+}
+
+/// Forwards an RTP packet from the given publisher, first putting it in order if reordering is enabled.
+fn relay_rtp_in_order(switchboard: &Switchboard, sess: &Arc<Session>, video: c_int, header: Option<RtpHeader>, packet: &mut Vec<u8>) {
+    match header {
+        Some(ref header) if STATE.config.get().unwrap().rtp_reorder_depth > 0 => {
+            let buffer = if video == 1 { &sess.video_reorder } else { &sess.audio_reorder };
+            // hold on to the buffer while relaying, so that packets it flushes in the meantime can't jump ahead
+            let mut buffer = buffer.lock().unwrap();
+            for mut packet in buffer.push(header, mem::take(packet), Instant::now()) {
+                let len = packet.len() as c_int;
+                relay_rtp_packet(switchboard, sess, video, packet.as_mut_ptr() as *mut c_char, len);
+            }
+        }
+        _ => relay_rtp_packet(switchboard, sess, video, packet.as_mut_ptr() as *mut c_char, packet.len() as c_int)
+    }
+}
+
+/// How often the reorder flushing thread checks for packets which have waited long enough to be put in order.
+const REORDER_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Forwards the packets that each publisher's reorder buffers gave up waiting to put in order, which would otherwise sit
+/// there until the publisher's next packet arrives.
+fn flush_reorder_buffers() {
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    let now = Instant::now();
+    for sess in switchboard.sessions() {
+        for &(video, buffer) in &[(0, &sess.audio_reorder), (1, &sess.video_reorder)] {
+            let mut buffer = buffer.lock().unwrap();
+            for mut packet in buffer.flush(now) {
+                let len = packet.len() as c_int;
+                relay_rtp_packet(&switchboard, sess, video, packet.as_mut_ptr() as *mut c_char, len);
+            }
+        }
+    }
+}
+
+/// Forwards an RTP packet from the given publisher to everyone who should get it.
+fn relay_rtp_packet(switchboard: &Switchboard, sess: &Arc<Session>, video: c_int, buf: *mut c_char, len: c_int) {
+    // if everyone subscribed to this publisher muted this kind of media from it, don't bother with the packet any further
+    let mut recipients = switchboard.media_recipients_of_kind(sess, video == 1).peekable();
+    if recipients.peek().is_none() {
+        return;
+    }
+    let track_sends = STATE.config.get().unwrap().keepalive_interval_secs.is_some();
+    let packet = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    let header = RtpHeader::parse(packet);
+    // if this publisher's subscribers were offered a different payload type than it sends with, everyone needs a copy
+    let track_payload_type = sess.track_payload_type.load(Ordering::Relaxed);
+    let rewrite = header.as_ref().and_then(|h| {
+        match video {
+            // a labeled audio track's subscribers were offered its user's main audio, so it has to look like that
+            0 if track_payload_type != 0 && usize::from(h.payload_type) == sess.audio_payload_type.load(Ordering::Relaxed) => {
+                Some(track_payload_type as u8).filter(|&pt| pt != h.payload_type)
+            }
+            _ => sess.payload_type_rewrites.lock().unwrap().iter().find(|&&(from, _)| from == h.payload_type).map(|&(_, to)| to)
+        }
+    });
+    // likewise if its subscribers were offered different IDs for the header extensions we forward
+    let remapped_extensions = {
+        let ids = sess.extension_id_rewrites.lock().unwrap();
+        if ids.iter().any(|&(from, to)| from != to) { rtp::remap_extension_ids(packet, &ids) } else { None }
+    };
+    let mut remapped;
+    let (buf, len, packet) = match (rewrite, remapped_extensions) {
+        (None, None) => (buf, len, packet),
+        (payload_type, extensions) => {
+            remapped = extensions.unwrap_or_else(|| packet.to_vec());
+            if let Some(payload_type) = payload_type {
+                rtp::set_payload_type(&mut remapped, payload_type);
+            }
+            (remapped.as_mut_ptr() as *mut c_char, remapped.len() as c_int, &remapped[..])
+        }
+    };
+    let video_codec = match video {
+        1 => *sess.video_codec.lock().unwrap(),
+        _ => None
+    };
+    let temporal_layer = video_codec.and_then(|codec| rtp::payload(packet).and_then(|p| rtp::temporal_layer(codec, p)));
+    let starts_keyframe = video_codec.is_some_and(|codec| rtp::payload(packet).is_some_and(|p| rtp::is_keyframe(codec, p)));
+    // every packet of a key frame shares its timestamp, but only the first few can be recognized by their payload. only
+    // subscribers who get nothing but key frames care, so don't bother keeping track without any
+    let in_keyframe = match (&header, video_codec) {
+        (Some(header), Some(_)) if switchboard.subscribers_to(sess).iter().any(|s| s.keyframes_only.load(Ordering::Relaxed)) => {
+            let mut keyframe_timestamp = sess.keyframe_timestamp.lock().unwrap();
+            if starts_keyframe {
+                *keyframe_timestamp = Some(header.timestamp);
+            }
+            *keyframe_timestamp == Some(header.timestamp)
+        }
+        _ => false
+    };
+    // drop frames over the room's maximum framerate, which we can only do with video that has temporal layers
+    let over_framerate = match (&header, temporal_layer, sess.join_state.get()) {
+        (Some(header), Some(_), Some(joined)) => {
+            match STATE.config.get().unwrap().room_settings(&joined.room_id.read().unwrap()).max_framerate {
+                Some(fps) => !sess.framerate_limiter.lock().unwrap().forward(header.timestamp, temporal_layer, fps),
+                None => false
+            }
+        }
+        _ => false
+    };
+    // subscribers who didn't accept redundant audio only get the primary audio out of it
+    let red_payload_type = sess.red_payload_type.load(Ordering::Relaxed);
+    let mut red_primary = match header {
+        Some(ref h) if video == 0 && red_payload_type != 0 && usize::from(h.payload_type) == red_payload_type => rtp::red_primary(packet),
+        _ => None
+    };
+    let publisher_user = sess.join_state.get().map(|joined| &joined.user_id);
+    // a labeled connection is just an alternative audio track for its user, which only goes to those who picked it
+    let labeled = sess.audio_labeled.load(Ordering::Relaxed);
+    let audio_label = if video == 0 && labeled { Some(sess.audio_label.lock().unwrap()) } else { None };
+    for other in recipients {
+        let wanted = match (video, &audio_label) {
+            _ if other.handle == sess.handle => true,
+            (1, _) => !labeled,
+            (_, None) => !other.selects_audio_label.load(Ordering::Relaxed),
+            (_, Some(label)) => {
+                other.selects_audio_label.load(Ordering::Relaxed) && *other.selected_audio_label.lock().unwrap() == **label
+            }
+        };
+        if !wanted {
+            // this subscriber is getting its audio from another track, so leave its continuity to that one
+            continue;
+        }
+        let (buf, len, packet) = match red_primary {
+            Some(ref mut primary) if !other.receives_red.load(Ordering::Relaxed) => {
+                (primary.as_mut_ptr() as *mut c_char, primary.len() as c_int, &primary[..])
+            }
+            _ => (buf, len, packet)
+        };
+        let (context, step) = match video {
+            1 => (&other.video_context, rtp::VIDEO_TIMESTAMP_STEP),
+            _ => (&other.audio_context, rtp::AUDIO_TIMESTAMP_STEP),
+        };
+        // packets from simulcast layers this subscriber isn't getting don't leave any gap in what it is getting
+        let unselected_layer = match header {
+            Some(ref header) if video == 1 && other.selects_layer.load(Ordering::Relaxed) => {
+                !other.layer_selection.lock().unwrap().forward(header.ssrc, starts_keyframe)
+            }
+            _ => false
+        };
+        if unselected_layer {
+            continue;
+        }
+        let paused = publisher_user.is_some_and(|user| other.paused_publishers.lock().unwrap().contains(user));
+        let keyframes_only = other.keyframes_only.load(Ordering::Relaxed);
+        let dropped_video = over_framerate || other.video_suspended.load(Ordering::Relaxed) || (keyframes_only && !in_keyframe);
+        if paused || (video == 1 && dropped_video) {
+            if let Some(ref header) = header {
+                context.lock().unwrap().skip(header, step);
+            }
+            continue;
+        }
+        if track_sends {
+            *other.last_sent.lock().unwrap() = Instant::now();
+        }
+        match header {
+            Some(ref header) => {
+                let mut context = context.lock().unwrap();
+                if let Some(layer) = temporal_layer {
+                    let max_layer = other.max_temporal_layer.load(Ordering::Relaxed).min(other.constrained_temporal_layer.load(Ordering::Relaxed));
+                    if layer as usize > max_layer {
+                        context.skip(header, step);
+                        continue;
+                    }
+                }
+                let (seq, timestamp) = context.update(header, step);
+                if seq == header.seq && timestamp == header.timestamp {
+                    send_rtp(other, sess, video, buf, len);
+                } else {
+                    // this subscriber's stream was switched over from some other source, so it needs its own copy
+                    let mut rewritten = packet.to_vec();
+                    rtp::set_seq_and_timestamp(&mut rewritten, seq, timestamp);
+                    send_rtp(other, sess, video, rewritten.as_mut_ptr() as *mut c_char, len);
+                }
+            }
+            None => send_rtp(other, sess, video, buf, len)
+        }
+    }
+}
+
+/// Decides which of the given publisher's video layers to send the given subscriber, going by the subscriber's video
+/// constraints and the layers the publisher is currently sending, and asks the publisher for a key frame if the
+/// subscriber needs one to switch layers.
+fn select_video_layers(subscriber: &Session, publisher: &Arc<Session>, layers: &ActiveLayers) {
+    let constraints = *subscriber.video_constraints.lock().unwrap();
+    let descriptors = layers.descriptors();
+    let target = rtp::choose_layer(&descriptors, &constraints);
+    let targeted: Vec<_> = descriptors.iter().filter(|layer| target.is_none_or(|ssrc| layer.ssrc == ssrc)).collect();
+    let framerate = targeted.iter().filter_map(|layer| layer.framerate).max();
+    let top_layer = targeted.iter().filter_map(|layer| layers.top_temporal_layer(layer.ssrc)).max();
+    let temporal_layer = match (constraints.max_fps, framerate, top_layer) {
+        (Some(max_fps), Some(framerate), Some(top_layer)) => rtp::temporal_layer_within(framerate, max_fps, top_layer as usize),
+        _ => usize::MAX,
+    };
+    subscriber.constrained_temporal_layer.store(temporal_layer, Ordering::Relaxed);
+    let ssrcs: Vec<_> = descriptors.iter().map(|layer| layer.ssrc).collect();
+    let switching = {
+        let mut selection = subscriber.layer_selection.lock().unwrap();
+        subscriber.selects_layer.store(target.is_some(), Ordering::Relaxed);
+        selection.retarget(target, &ssrcs)
+    };
+    if switching {
+        janus_verb!("Switching {} to video layer {:?} from {}.", subscriber.log_name(), target, publisher.log_name());
+        send_pli(Some(publisher));
+    }
+}
+
+/// Applies the given subscriber's video constraints to the video of everyone it's subscribed to.
+fn reselect_video_layers(switchboard: &Switchboard, subscriber: &Session) {
+    for publisher in switchboard.publishers_to(subscriber) {
+        select_video_layers(subscriber, publisher, &publisher.video_layers.lock().unwrap());
+    }
+}
+
+/// How often the pacing thread sends whatever queued packets subscribers' pacers have room for.
+const PACING_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Sends an RTP packet from the given publisher to the given subscriber, or queues it to go out later if the
+/// subscriber's pacer doesn't have room for it yet. Paced packets are sent while holding the pacer's lock, so that the
+/// pacing thread can't send any out of order.
+fn send_rtp(subscriber: &Session, publisher: &Arc<Session>, video: c_int, buf: *mut c_char, len: c_int) {
+    let relay_rtp = gateway_callbacks().relay_rtp;
+    let mut guard = subscriber.pacer.lock().unwrap();
+    let pacer = match *guard {
+        Some(ref mut pacer) => pacer,
+        None => return relay_rtp(subscriber.as_ptr(), video, buf, len),
+    };
+    let now = Instant::now();
+    let packet = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    let queued = pacer.push(video, packet.to_vec(), now);
+    if !queued {
+        janus_huge!("Dropping packet for {}, whose pacing queue is full.", subscriber.log_name());
+    }
+    if video == 1 {
+        // the subscriber can't decode the video after what we dropped, so ask for a key frame when we start dropping
+        let was_dropping = subscriber.paced_video_dropped.swap(!queued, Ordering::Relaxed);
+        if !queued && !was_dropping {
+            send_pli(Some(publisher));
+        }
+    }
+    for (video, mut packet) in pacer.release(now) {
+        relay_rtp(subscriber.as_ptr(), video, packet.as_mut_ptr() as *mut c_char, packet.len() as c_int);
+    }
+}
+
+/// Sends every subscriber whatever packets its pacer has queued up which it now has room for.
+fn release_paced_packets() {
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    let relay_rtp = gateway_callbacks().relay_rtp;
+    let now = Instant::now();
+    for subscriber in switchboard.sessions() {
+        if let Some(ref mut pacer) = *subscriber.pacer.lock().unwrap() {
+            for (video, mut packet) in pacer.release(now) {
+                relay_rtp(subscriber.as_ptr(), video, packet.as_mut_ptr() as *mut c_char, packet.len() as c_int);
+            }
+        }
+    }
+}
+
+/// Sends an empty RTCP receiver report to every subscriber which we haven't sent anything in the given amount of time,
+/// e.g. because the publisher they're subscribed to is muted, so that their NAT bindings don't time out.
+fn keep_idle_subscribers_alive(interval: Duration) {
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    let relay_rtcp = gateway_callbacks().relay_rtcp;
+    let now = Instant::now();
+    for subscriber in switchboard.sessions() {
+        if switchboard.publishers_to(subscriber).is_empty() {
+            continue;
+        }
+        let mut last_sent = subscriber.last_sent.lock().unwrap();
+        if now.duration_since(*last_sent) >= interval {
+            janus_huge!("Sending keepalive to idle subscriber {}.", subscriber.log_name());
+            let mut report = rtcp::empty_receiver_report();
+            relay_rtcp(subscriber.as_ptr(), 1, report.as_mut_ptr() as *mut c_char, report.len() as c_int);
+            *last_sent = now;
+        }
+    }
+}
+
+/// How often we check whether publishers' video has stalled, if we're configured to.
+const VIDEO_STALL_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Lets the subscribers of every publisher whose video has stopped arriving for the given amount of time know that it
+/// stalled, so that they can explain the frozen frame. `incoming_rtp` lets them know when it resumes.
+fn detect_video_stalls(timeout: Duration) {
+    let stalled: Vec<Arc<Session>> = {
+        let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+        switchboard.sessions().iter()
+            .filter(|s| s.is_publishing())
+            .filter(|s| s.last_video_at.lock().unwrap().is_some_and(|at| at.elapsed() > timeout))
+            .filter(|s| !s.video_stalled.swap(true, Ordering::Relaxed))
+            .map(|s| Arc::clone(s))
+            .collect()
+    };
+    for publisher in stalled {
+        janus_info!("Video from {} stalled.", publisher.log_name());
+        notify_video_subscribers(&publisher, "video_stalled");
+    }
+}
+
+/// Sends the given event about the given publisher's video to everyone subscribed to it.
+fn notify_video_subscribers(publisher: &Arc<Session>, event: &str) {
+    if let Some(joined) = publisher.join_state.get() {
+        let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+        let notification = json!({ "event": event, "user_id": &joined.user_id });
+        send_message(&notification, switchboard.subscribers_to(publisher)).warn_if_undelivered(event);
+    }
+}
+
+/// Makes up a CNAME to stand in for the publisher's real one in RTCP going to the given subscriber. It's the same for
+/// all of the publisher's streams, so the subscriber can still correlate them, but different for every subscription.
+fn opaque_cname(publisher: &Session, subscriber: &Session) -> String {
+    let mut hasher = STATE.cname_keys.build_hasher();
+    hasher.write_usize(publisher.handle as usize);
+    hasher.write_usize(subscriber.handle as usize);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Records that the given publisher sent a BYE for the given SSRCs, and returns whether it's now said goodbye for all
+/// of the media it's been sending. One that hasn't sent any media yet hasn't said goodbye to anything.
+fn said_goodbye(publisher: &Session, ssrcs: &[u32]) -> bool {
+    let mut sending = publisher.video_layers.lock().unwrap().ssrcs();
+    let audio_ssrc = publisher.audio_ssrc.load(Ordering::Relaxed);
+    if audio_ssrc != usize::MAX {
+        sending.push(audio_ssrc as u32);
+    }
+    publisher.said_bye.lock().unwrap().record(&sending, ssrcs)
+}
+
+extern "C" fn incoming_rtcp(handle: *mut PluginSession, video: c_int, buf: *mut c_char, len: c_int) {
+    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+    // a publisher saying goodbye for everything it sends is done sending, so stop treating it as a publisher instead of
+    // waiting for it to go away
+    let byes = rtcp::bye_ssrcs(unsafe { slice::from_raw_parts(buf as *const u8, len as usize) });
+    if !byes.is_empty() && sess.is_publishing() && said_goodbye(&sess, &byes) {
+        janus_info!("Unpublishing {}, since it sent an RTCP BYE for all of its media.", sess.log_name());
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard lock poisoned; can't continue.");
+        if sess.is_publishing() {
+            sess.unpublished.store(true, Ordering::Relaxed);
+            stop_publishing(&mut switchboard, &sess);
+        }
+        return;
+    }
+    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+    let packet = unsafe { slice::from_raw_parts(buf, len as usize) };
+    match video {
+        // keyframe requests to publishers we're holding back are moot, since they get one when they're released
+        1 if janus::rtcp::has_pli(packet) => {
+            send_pli(switchboard.media_senders_to(&sess).filter(|p| p.held_until.lock().unwrap().is_none()));
+        }
+        1 if janus::rtcp::has_fir(packet) => {
+            send_fir(switchboard.media_senders_to(&sess).filter(|p| p.held_until.lock().unwrap().is_none()));
+        }
+        _ => {
+            let relay_rtcp = gateway_callbacks().relay_rtcp;
+            let rewrite_cnames = STATE.config.get().unwrap().rewrite_rtcp_cnames;
+            let bytes = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+            for subscriber in switchboard.media_recipients_of_kind(&sess, video == 1) {
+                if rewrite_cnames {
+                    let cname = opaque_cname(&sess, subscriber);
+                    match rtcp::rewrite_cnames(bytes, cname.as_bytes()) {
+                        Some(mut rewritten) => {
+                            let ptr = rewritten.as_mut_ptr() as *mut c_char;
+                            relay_rtcp(subscriber.as_ptr(), video, ptr, rewritten.len() as c_int);
+                        }
+                        None => janus_huge!("Dropping malformed RTCP packet from {}.", sess.log_name()),
                     }
                 } else {
-                    janus_warn!("Ignoring kick from {:p} because they didn't have kick permissions.", from.handle);
+                    relay_rtcp(subscriber.as_ptr(), video, buf, len);
+                }
+            }
+        }
+    }
+}
+
+extern "C" fn incoming_data(handle: *mut PluginSession, buf: *mut c_char, len: c_int) {
+    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+    let config = STATE.config.get().unwrap();
+    let room_max = sess.join_state.get().and_then(|joined| config.room_settings(&joined.room_id.read().unwrap()).max_data_message_bytes);
+    let max_size = room_max.unwrap_or(config.max_data_message_bytes);
+    if len as usize > max_size {
+        janus_warn!("Dropping {} byte data message from {} (limit is {} bytes).", len, sess.log_name(), max_size);
+        let notification = json!({ "event": "data_dropped", "size": len, "max_size": max_size });
+        send_message(&notification, [&sess]);
+        return;
+    }
+    if sess.join_state.is_none() {
+        join_default_room(&sess);
+    }
+    let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+    if sess.join_state.is_none() && !sess.observer_sends_data.load(Ordering::Relaxed) {
+        return; // this is an observer which isn't allowed to send, or isn't in a room to send to anyway
+    }
+    let relay_data = gateway_callbacks().relay_data;
+    let mut anonymized;
+    let buf = if config.anonymize_payloads {
+        anonymized = vec![0u8; len as usize];
+        anonymized.as_mut_ptr() as *mut c_char
+    } else {
+        buf
+    };
+    for other in switchboard.data_recipients_for(&sess) {
+        relay_data(other.as_ptr(), buf, len);
+    }
+    if let (Some(logs), Some(joined)) = (STATE.data_logs.get(), sess.join_state.get()) {
+        let room_id = joined.room_id.read().unwrap();
+        if config.room_settings(&room_id).record_data {
+            let body = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+            log_data(logs, &room_id, &joined.user_id, body);
+        }
+    }
+}
+
+/// Records a data message that was sent in the given room in the room's data log.
+fn log_data(logs: &Mutex<DataLogs>, room_id: &RoomId, user_id: &UserId, body: &[u8]) {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let entry = json!({
+        "timestamp_ms": timestamp.as_millis() as u64,
+        "user_id": user_id,
+        "body": String::from_utf8_lossy(body),
+    });
+    if let Err(e) = logs.lock().unwrap().append(room_id, &entry) {
+        janus_err!("Error logging data message in room {}: {}", room_id, e);
+    }
+}
+
+/// Records a moderation action in the audit log, if there is one. The actor is none for actions taken through the
+/// admin socket. The entry is written out by the audit log thread, since most callers hold the switchboard lock, and
+/// shouldn't hold up media while the entry is synced to disk.
+fn audit(action: &str, actor: Option<&UserId>, target: Option<&str>, room_id: Option<&RoomId>) {
+    if let Some((ref log, _)) = *STATE.audit_log.lock().unwrap() {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let entry = AuditEntry {
+            timestamp_ms: timestamp.as_millis() as u64,
+            action: action.to_owned(),
+            actor: actor.cloned(),
+            target: target.map(str::to_owned),
+            room_id: room_id.cloned(),
+        };
+        if log.send(entry).is_err() {
+            janus_err!("Error recording {} in the audit log: the audit log thread is gone.", action);
+        }
+    }
+}
+
+/// How many VP8/VP9 temporal layers we expect publishers to send; the top layer is this minus one.
+const TEMPORAL_LAYER_COUNT: usize = 3;
+
+extern "C" fn slow_link(handle: *mut PluginSession, uplink: c_int, video: c_int) {
+    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+    janus_info!("Slow link message received on {}.", sess.log_name());
+    if uplink == 0 && video == 1 {
+        let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+        degrade_video_to_user(&switchboard, &sess);
+    }
+}
+
+/// The link from us to this session's user is struggling, so degrade the least important video they get.
+fn degrade_video_to_user(switchboard: &Switchboard, sess: &Arc<Session>) {
+    let mut subscriptions = media_subscriptions_of(switchboard, sess);
+    degrade_video(switchboard, &mut subscriptions);
+    *sess.video_degraded_at.lock().unwrap() = Some(Instant::now());
+}
+
+/// Returns the media subscriptions belonging to this session's user, or just the session itself if there are none.
+fn media_subscriptions_of(switchboard: &Switchboard, sess: &Arc<Session>) -> Vec<Arc<Session>> {
+    let mut subscriptions: Vec<Arc<Session>> = match sess.join_state.get() {
+        Some(joined) => switchboard.get_sessions(&joined.room_id.read().unwrap(), &joined.user_id).into_iter()
+            .filter(|s| !switchboard.publishers_to(s).is_empty())
+            .collect(),
+        None => Vec::new()
+    };
+    if subscriptions.is_empty() {
+        subscriptions.push(Arc::clone(sess));
+    }
+    subscriptions
+}
+
+/// Steps the video going to each user whose link was congested back up, one subscription at a time, once the link has
+/// stayed healthy for the given interval since the last step. If the link gets congested again, the video is degraded
+/// and the interval starts over, so each step probes whether the link can handle it before the next one is taken.
+fn recover_video(interval: Duration) {
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    for sess in switchboard.sessions() {
+        let mut degraded_at = sess.video_degraded_at.lock().unwrap();
+        if degraded_at.is_some_and(|at| at.elapsed() >= interval) {
+            let mut subscriptions = media_subscriptions_of(&switchboard, sess);
+            *degraded_at = if upgrade_video(&switchboard, &mut subscriptions) { Some(Instant::now()) } else { None };
+        }
+    }
+}
+
+/// Estimates how much video each room's publishers sent between them over the given time since the last estimate.
+/// In rooms with a maximum bitrate, each publisher is then sent a REMB telling it how much of the room's budget it may
+/// use; see `allocate_bitrate`.
+fn enforce_room_bitrates(elapsed: Duration) {
+    let config = STATE.config.get().unwrap();
+    let secs = elapsed.as_secs_f64().max(0.001);
+    let mut room_bitrates = Vec::new();
+    let mut rembs = Vec::new();
+    {
+        let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+        for room in switchboard.rooms() {
+            let publishers: Vec<Arc<Session>> = switchboard.occupants_of(room).iter().filter(|s| s.is_publishing()).cloned().collect();
+            let rates: Vec<u32> = publishers.iter()
+                .map(|p| (p.video_bytes_published.swap(0, Ordering::Relaxed) as f64 * 8.0 / secs) as u32)
+                .collect();
+            room_bitrates.push((room.clone(), rates.iter().fold(0u32, |total, &rate| total.saturating_add(rate))));
+            if let Some(budget) = config.room_settings(room).max_bitrate {
+                rembs.extend(publishers.into_iter().zip(ratelimit::allocate_bitrate(&rates, budget)));
+            }
+        }
+    }
+    {
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard is poisoned :(");
+        for (room, bitrate) in &room_bitrates {
+            switchboard.set_room_bitrate(room, *bitrate);
+        }
+    }
+    for (publisher, bitrate) in rembs {
+        send_remb(&publisher, bitrate);
+    }
+}
+
+/// Whether the video the given media subscription gets is in a codec with temporal layers which we can drop.
+fn receives_temporal_layers(switchboard: &Switchboard, subscription: &Session) -> bool {
+    match subscribed_publisher(switchboard, subscription).and_then(|p| *p.video_codec.lock().unwrap()) {
+        Some(codec) => codec != VideoCodec::H264,
+        None => false
+    }
+}
+
+/// Steps up the video going to the most important of the given media subscriptions that has been stepped down; first
+/// by resuming suspended video, and then by adding back temporal layers, in the reverse order that `degrade_video`
+/// takes them away. Returns whether anything was stepped up.
+fn upgrade_video(switchboard: &Switchboard, subscriptions: &mut [Arc<Session>]) -> bool {
+    subscriptions.sort_by_key(|s| Reverse(s.media_priority.load(Ordering::Relaxed)));
+    for sess in subscriptions.iter() {
+        if sess.video_suspended.swap(false, Ordering::Relaxed) {
+            janus_info!("Resuming video to {} now that congestion has cleared.", sess.log_name());
+            send_pli(subscribed_publisher(switchboard, sess));
+            return true;
+        }
+    }
+    for sess in subscriptions.iter().filter(|s| receives_temporal_layers(switchboard, s)) {
+        let current = sess.max_temporal_layer.load(Ordering::Relaxed);
+        if current < TEMPORAL_LAYER_COUNT - 1 {
+            janus_info!("Raising maximum temporal layer for {} to {}.", sess.log_name(), current + 1);
+            let raised = if current + 1 == TEMPORAL_LAYER_COUNT - 1 { usize::MAX } else { current + 1 };
+            sess.max_temporal_layer.store(raised, Ordering::Relaxed);
+            return true;
+        }
+    }
+    false
+}
+
+/// Whether the given media subscription is to a pinned user, whose video is never degraded.
+fn subscribes_to_pinned(switchboard: &Switchboard, subscription: &Session) -> bool {
+    subscribed_publisher(switchboard, subscription)
+        .and_then(|publisher| publisher.join_state.get())
+        .is_some_and(|joined| joined.pinned.load(Ordering::Relaxed))
+}
+
+/// Steps down the video going to the least important of the given media subscriptions that can still be stepped down;
+/// first by dropping temporal layers, if the codec has them, and then by suspending the video entirely. Video from the
+/// most important subscription, and from pinned users, is never suspended.
+fn degrade_video(switchboard: &Switchboard, subscriptions: &mut [Arc<Session>]) {
+    subscriptions.sort_by_key(|s| s.media_priority.load(Ordering::Relaxed));
+    let degradable = |s: &&Arc<Session>| !subscribes_to_pinned(switchboard, s);
+    for sess in subscriptions.iter().filter(degradable).filter(|s| receives_temporal_layers(switchboard, s)) {
+        let current = sess.max_temporal_layer.load(Ordering::Relaxed).min(TEMPORAL_LAYER_COUNT - 1);
+        if current > 0 {
+            janus_info!("Lowering maximum temporal layer for {} to {}.", sess.log_name(), current - 1);
+            sess.max_temporal_layer.store(current - 1, Ordering::Relaxed);
+            return;
+        }
+    }
+    let least_important = &subscriptions[..subscriptions.len().saturating_sub(1)];
+    for sess in least_important.iter().filter(degradable) {
+        if !sess.video_suspended.swap(true, Ordering::Relaxed) {
+            janus_info!("Suspending video to {} due to congestion.", sess.log_name());
+            return;
+        }
+    }
+}
+
+extern "C" fn hangup_media(handle: *mut PluginSession) {
+    let sess = unsafe { Session::from_ptr(handle).expect("Session can't be null!") };
+    janus_info!("Hanging up WebRTC media on {}.", sess.log_name());
+    // the media it was publishing is gone for good, so unpublish it the same as if it had said goodbye
+    if sess.is_publishing() {
+        let mut switchboard = STATE.switchboard.write().expect("Switchboard lock poisoned; can't continue.");
+        if sess.is_publishing() {
+            sess.unpublished.store(true, Ordering::Relaxed);
+            stop_publishing(&mut switchboard, &sess);
+        }
+    }
+    sess.forget_offer();
+}
+
+/// The longest correlation ID a client can attach to its join.
+const MAX_CORRELATION_ID_BYTES: usize = 256;
+
+#[allow(clippy::too_many_arguments)]
+fn process_join(from: &Arc<Session>, room_id: RoomId, user_id: UserId, subscribe: Option<Subscription>, token: Option<String>,
+                sendrecv: bool, metadata: JsonValue, notify_when_available: bool, codec_capabilities: Option<Vec<String>>,
+                secret: Option<String>, correlation_id: Option<String>) -> MessageResult {
+    // todo: holy shit clean this function up somehow
+    let config = STATE.config.get().unwrap();
+    let mut is_moderator = false;
+    // whether the join's token was issued for this user ID, rather than the client just claiming it
+    let mut owns_user_id = false;
+    let mut publish_codecs = PublishCodecs::default();
+    let join_token = token.clone();
+    match (&config.auth_key, token) {
+        (Some(ref key), Some(ref token)) => {
+            match ValidatedToken::from_str(token, key) {
+                Ok(tok) => {
+                    is_moderator = tok.kick_users;
+                    owns_user_id = tok.user_id.as_ref() == Some(&user_id);
+                    if let Some(ref names) = tok.publish_codecs {
+                        publish_codecs = PublishCodecs::from_names(names);
+                    }
+                    janus_info!("Processing validated join from {} to room ID {} with user ID {}. Join allowed: {}", from.log_name(), room_id, user_id, tok.join_hub);
+                }
+                Err(e) => {
+                    janus_warn!("Processing invalid join from {} to room ID {} with user ID {} ({})", from.log_name(), room_id, user_id, e);
+                }
+            }
+        },
+        _ => {
+            janus_info!("Processing anonymous join from {} to room ID {} with user ID {}.", from.log_name(), room_id, user_id);
+        }
+    }
+
+    if serde_json::to_string(&metadata)?.len() > config.max_join_metadata_bytes {
+        return Err(From::from("Join metadata is too large."));
+    }
+    if correlation_id.as_ref().is_some_and(|id| id.len() > MAX_CORRELATION_ID_BYTES) {
+        return Err(From::from(format!("Correlation ID is too long (limit is {} bytes).", MAX_CORRELATION_ID_BYTES)));
+    }
+
+    let mut switchboard = STATE.switchboard.write()?;
+    expire_standby(&mut switchboard);
+    // while draining, users already in a room can still add connections to it, e.g. to subscribe to someone new, but
+    // nobody new comes in and no new rooms start. since user IDs are no secret, only a token can show it's really them
+    let already_in_room = owns_user_id && !switchboard.get_sessions(&room_id, &user_id).is_empty();
+    if STATE.draining.load(Ordering::Relaxed) && !already_in_room {
+        return Err(Box::new(CodedError::new("server_draining", "Server draining.")));
+    }
+    let mut body = json!({
+        "users": { room_id.as_str(): switchboard.get_users(&room_id) },
+        "metadata": { room_id.as_str(): switchboard.get_user_metadata(&room_id) },
+        "raised_hands": { room_id.as_str(): switchboard.get_raised_hands(&room_id) },
+        "moderators": { room_id.as_str(): switchboard.get_moderators(&room_id) },
+        "pinned": { room_id.as_str(): switchboard.get_pinned(&room_id) },
+        "recent_events": switchboard.recent_events(&room_id),
+        "blocks": switchboard.blocks_from(&user_id)
+    });
+
+    if switchboard.observed_room(from).is_some() {
+        return Err(From::from("Observers can't join rooms."))
+    }
+    let already_joined = !from.join_state.is_none();
+    let already_subscribed = from.subscription.lock().unwrap().is_some();
+    if already_joined {
+        return Err(From::from("Handles may only join once!"))
+    }
+    if already_subscribed && subscribe.is_some() {
+        return Err(From::from("Handles which already subscribed must change their subscription with a subscribe message."))
+    }
+
+    let mut is_master_handle = false;
+    let mut replaces_sessions = false;
+    if let Some(subscription) = subscribe.as_ref() {
+        // like a room, the server is full once it has the maximum, not counting this connection, which is already in it
+        let server_is_full = switchboard.sessions().len().saturating_sub(1) >= config.max_ccu;
+        is_master_handle = subscription.data; // hack -- assume there is only one "master" data connection per user
+        if is_master_handle && server_is_full {
+            return Err(From::from("Server is full."))
+        }
+        // only a join whose token proves it's this user may take over for the user's other connections, since user IDs
+        // are no secret. the connections being replaced don't hold this one's place in the room
+        replaces_sessions = is_master_handle && config.single_session_per_user && owns_user_id;
+        let replaced = if replaces_sessions {
+            switchboard.occupants_of(&room_id).iter().filter(|s| s.join_state.get().is_some_and(|j| j.user_id == user_id)).count()
+        } else {
+            0
+        };
+        // we hold the switchboard lock from here until we've joined the room, so nobody can sneak in ahead of us
+        match switchboard.check_capacity(&room_id, config.max_room_size.saturating_add(replaced), config.max_rooms) {
+            Err(RoomRefusal::Full) if is_master_handle => {
+                if notify_when_available {
+                    switchboard.wait_for_room(Arc::clone(from), room_id.clone());
+                }
+                return Err(Box::new(CodedError::new("room_full", "Room is full.")))
+            }
+            Err(RoomRefusal::TooManyRooms) if is_master_handle => return Err(From::from("Room limit reached.")),
+            _ => {}
+        }
+        if is_master_handle {
+            check_admission(&switchboard, config, &room_id, &user_id, is_moderator, owns_user_id, secret.as_deref())?;
+        }
+    }
+    // look up everything a join-time media subscription needs before changing any state, so that a join refused here
+    // leaves nothing behind
+    let publisher = match subscribe {
+        Some(ref subscription) => find_publisher(&switchboard, subscription)?,
+        None => None
+    };
+    let media = match publisher {
+        Some(publisher) => {
+            if replaces_sessions && publisher.join_state.get().is_some_and(|joined| joined.user_id == user_id) {
+                return Err(From::from("Can't subscribe to a nonexistent publisher."));
+            }
+            check_codecs(&switchboard, Some(&user_id), &publisher)?;
+            let label = subscribe.as_ref().and_then(|subscription| subscription.audio_label.as_ref());
+            let track = find_audio_track(&switchboard, &publisher, label)?;
+            check_subscription_limit(&switchboard, from, 0, 1 + track.is_some() as usize)?;
+            Some((publisher, track))
+        }
+        None => None
+    };
+    if replaces_sessions {
+        replace_sessions(&mut switchboard, &user_id, from);
+    }
+
+    from.join_state.set_if_none(Box::new(JoinState::new(room_id.clone(), user_id.clone(), sendrecv, metadata)));
+    // remembered so that moving rooms later admits this session the same way this join did
+    from.join_state.get().unwrap().moderator_token.store(is_moderator, Ordering::Relaxed);
+    from.join_state.get().unwrap().owns_user_id.store(owns_user_id, Ordering::Relaxed);
+    // a pin is on the user, so it covers connections they make after being pinned too
+    let pinned = switchboard.get_sessions(&room_id, &user_id).iter()
+        .any(|s| s.join_state.get().is_some_and(|joined| joined.pinned.load(Ordering::Relaxed)));
+    from.join_state.get().unwrap().pinned.store(pinned, Ordering::Relaxed);
+    if let Some(ref id) = correlation_id {
+        // from here on, our logging names this session by its correlation ID as well as its handle
+        janus_info!("Session {:p} has correlation ID {:?}.", from.handle, id);
+        *from.correlation_id.lock().unwrap() = Some(id.clone());
+    }
+    // codecs we don't know of can't be negotiated anyway, so there's no harm in ignoring them
+    *from.codec_capabilities.lock().unwrap() = codec_capabilities.map(|names| {
+        names.iter().filter_map(|name| config::parse_video_codec(name)).collect()
+    });
+    *from.publish_codecs.lock().unwrap() = publish_codecs;
+    if let Some(ttl) = config.resume_token_ttl_secs {
+        let state = ResumeState {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            subscription: subscribe.clone(),
+            token: join_token,
+            sendrecv,
+            metadata: from.join_state.get().unwrap().metadata.clone(),
+            codec_capabilities: from.codec_capabilities.lock().unwrap().as_ref().map(|codecs| {
+                codecs.iter().map(|codec| codec.to_str().to_owned()).collect()
+            }),
+            secret,
+            correlation_id,
+        };
+        let resume_token = STATE.resume_tokens.lock()?.issue(state, Instant::now(), Duration::from_secs(ttl));
+        body["resume_token"] = json!(resume_token);
+    }
+    if sendrecv {
+        janus_info!("Looping back media for sendrecv session {}.", from.log_name());
+        switchboard.subscribe_to_user(Arc::clone(from), Arc::clone(from))?;
+    }
+    if let Some(subscription) = subscribe {
+        janus_info!("Processing join-time subscription from {}: {:?}.", from.log_name(), subscription);
+        *from.subscription.lock().unwrap() = Some(subscription.clone());
+        from.media_priority.store(subscription.priority as isize, Ordering::Relaxed);
+        from.keyframes_only.store(subscription.keyframes_only, Ordering::Relaxed);
+        *from.video_constraints.lock().unwrap() = subscription.video_constraints();
+        select_audio_label(from, subscription.audio_label.clone());
+        if is_master_handle {
+            from.join_state.get().unwrap().moderator.store(is_moderator, Ordering::Relaxed);
+            let metadata = &from.join_state.get().unwrap().metadata;
+            let notification = json!({ "event": "join", "user_id": user_id, "room_id": room_id, "metadata": metadata });
+            switchboard.join_room(Arc::clone(from), room_id.clone());
+            notify_roommates(&switchboard, &room_id, &user_id, &notification);
+            switchboard.record_event(&room_id, notification, config.room_event_buffer_size);
+            restore_standby_user(&mut switchboard, from.join_state.get().unwrap(), &room_id, owns_user_id);
+            // blocks outlive sessions, so remind anyone this user is still blocking, in case they've reconnected
+            // since the block was established and don't know about it
+            let event = json!({ "event": "blocked", "by": &user_id });
+            for whom in switchboard.blocks_from(&user_id) {
+                notify_user(&event, whom, switchboard.occupants_of(&room_id));
+            }
+        }
+        if let Some((publisher, track)) = media {
+            let jsep = json!({
+                "type": "offer",
+                "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
+            });
+            switchboard.subscribe_to_user(Arc::clone(from), publisher)?;
+            if let Some(track) = track {
+                switchboard.subscribe_to_user(Arc::clone(from), track)?;
+            }
+            reselect_video_layers(&switchboard, from);
+            return Ok(MessageResponse::new(body, jsep));
+        }
+    }
+    Ok(MessageResponse::msg(body))
+}
+
+/// Checks that a join which subscribes to data may enter the given room, going by whether the room is locked and whether
+/// it has a secret. Moderators may always enter.
+#[allow(clippy::too_many_arguments)]
+fn check_admission<T: Connection>(switchboard: &Switchboard<T>, config: &Config, room_id: &RoomId, user_id: &UserId,
+                                  is_moderator: bool, owns_user_id: bool, secret: Option<&str>) -> Result<(), Box<Error>> {
+    if is_moderator {
+        return Ok(());
+    }
+    if switchboard.is_room_locked(room_id) {
+        let may_reconnect = config.locked_rooms_allow_reconnect && switchboard.was_admitted(room_id, user_id);
+        if !may_reconnect {
+            return Err(Box::new(CodedError::new("room_locked", "Room is locked.")));
+        }
+    }
+    if let Some(expected) = switchboard.room_secret(room_id) {
+        // users already in the room were let in before, e.g. with an older secret, so their other connections may join,
+        // but only if their token shows that it's really them, since everyone in the room knows their user ID
+        let already_admitted = owns_user_id && switchboard.get_users(room_id).contains(user_id);
+        if secret != Some(expected) && !already_admitted {
+            return Err(Box::new(CodedError::new("wrong_secret", "Wrong room secret.")));
+        }
+    }
+    Ok(())
+}
+
+/// Disconnects every other connection of the given user, e.g. from an earlier login on another device, on behalf of a
+/// new connection which is taking over for them.
+fn replace_sessions(switchboard: &mut Switchboard, user_id: &UserId, replacement: &Session) {
+    let end_session = gateway_callbacks().end_session;
+    for old in switchboard.sessions_for_user(user_id).into_iter().filter(|s| s.handle != replacement.handle) {
+        janus_info!("Replacing session {} of user {} with {}.", old.log_name(), user_id, replacement.log_name());
+        let notification = json!({ "event": "unsubscribed", "user_id": user_id, "reason": "session_replaced" });
+        send_message(&notification, switchboard.subscribers_to(&old)).warn_if_undelivered("session replacement");
+        switchboard.unpublish(&old);
+        // stop counting it as an occupant right away, so that it doesn't hold the new connection's place in the room;
+        // janus will call destroy_session once it's torn down the handle, which will clean up the rest
+        if let Some(joined) = old.join_state.get() {
+            let room_id = joined.room_id.read().unwrap().clone();
+            switchboard.leave_room(&old, room_id);
+        }
+        send_message(&json!({ "event": "session_replaced" }), [&old]);
+        end_session(old.as_ptr());
+    }
+}
+
+fn process_resume(from: &Arc<Session>, token: String) -> MessageResult {
+    let state = STATE.resume_tokens.lock()?.redeem(&token, Instant::now()).ok_or("Invalid or expired resume token.")?;
+    janus_info!("Resuming {} as user ID {} in room ID {}.", from.log_name(), state.user_id, state.room_id);
+    process_join(from, state.room_id, state.user_id, state.subscription, state.token, state.sendrecv, state.metadata, false,
+                 state.codec_capabilities, state.secret, state.correlation_id)
+}
+
+fn process_observe(from: &Arc<Session>, room_id: RoomId, token: &str, send_data: bool) -> MessageResult {
+    janus_info!("Processing observe from {} of room ID {} (send data: {}).", from.log_name(), room_id, send_data);
+    let config = STATE.config.get().unwrap();
+    let key = config.auth_key.as_ref().ok_or("Can't observe rooms because no secret was configured.")?;
+    if !ValidatedToken::from_str(token, key)?.admin {
+        return Err(From::from("Observing rooms requires admin permissions."));
+    }
+    let mut switchboard = STATE.switchboard.write()?;
+    if !from.join_state.is_none() || switchboard.observed_room(from).is_some() {
+        return Err(From::from("Only connections which haven't joined or observed a room may observe one."));
+    }
+    from.observer_sends_data.store(send_data, Ordering::Relaxed);
+    switchboard.observe_room(Arc::clone(from), room_id);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_kick(from: &Arc<Session>, room_id: RoomId, user_id: UserId, token: String) -> MessageResult {
+    let config = STATE.config.get().unwrap();
+    if let Some(ref key) = config.auth_key {
+        match ValidatedToken::from_str(&token, key) {
+            Ok(tok) => {
+                if tok.kick_users {
+                    janus_info!("Processing kick from {} targeting user ID {} in room ID {}.", from.log_name(), user_id, room_id);
+                    let switchboard = STATE.switchboard.read()?;
+                    kick_user(&switchboard, &room_id, &user_id);
+                    let actor = from.join_state.get().map(|joined| &joined.user_id);
+                    audit("kick", actor, Some(&user_id), Some(&room_id));
+                } else {
+                    janus_warn!("Ignoring kick from {} because they didn't have kick permissions.", from.log_name());
                 }
             }
             Err(e) => {
-                janus_warn!("Ignoring kick from {:p} due to invalid token: {}.", from.handle, e);
+                janus_warn!("Ignoring kick from {} due to invalid token: {}.", from.log_name(), e);
+            }
+        }
+    } else {
+        janus_warn!("Ignoring kick from {} because no secret was configured.", from.log_name());
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// Disconnects every connection belonging to the given user in the given room.
+fn kick_user(switchboard: &Switchboard, room_id: &RoomId, user_id: &UserId) {
+    let end_session = gateway_callbacks().end_session;
+    for sess in switchboard.get_sessions(room_id, user_id) {
+        janus_info!("Kicking session {}.", sess.log_name());
+        end_session(sess.as_ptr());
+    }
+}
+
+fn process_kill_session(from: &Arc<Session>, handle_id: String, token: String) -> MessageResult {
+    let config = STATE.config.get().unwrap();
+    let key = config.auth_key.as_ref().ok_or("Can't kill sessions because no secret was configured.")?;
+    let tok = ValidatedToken::from_str(&token, key)?;
+    if !tok.admin {
+        return Err(From::from("Killing sessions requires admin permissions."));
+    }
+    janus_info!("Processing kill from {} targeting session {}.", from.log_name(), handle_id);
+    let switchboard = STATE.switchboard.read()?;
+    kill_session(&switchboard, &handle_id)?;
+    audit("kill_session", from.join_state.get().map(|joined| &joined.user_id), Some(&handle_id), None);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// Disconnects the connection with the given handle ID.
+fn kill_session(switchboard: &Switchboard, handle_id: &str) -> Result<(), Box<Error>> {
+    let target = switchboard.get_session_by_handle(handle_id).ok_or("No session exists with that handle ID.")?;
+    // janus will call destroy_session once it's torn down the handle, which will clean up and notify roommates
+    (gateway_callbacks().end_session)(target.as_ptr());
+    Ok(())
+}
+
+/// How often the message processing thread updates its heartbeat while it's waiting for messages.
+const MESSAGE_THREAD_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reports whether the message processing thread has updated its heartbeat recently enough, i.e. whether it's still
+/// alive and not stuck on a message. Doesn't take any lock the message thread could be stuck holding.
+fn describe_health() -> JsonValue {
+    let age = STATE.message_thread_heartbeat.lock().unwrap().elapsed();
+    let stale_after = Duration::from_secs(STATE.config.get().unwrap().message_thread_stale_secs as u64);
+    let healthy = age <= stale_after;
+    if !healthy {
+        janus_warn!("Message processing thread hasn't been heard from in {:?}.", age);
+    }
+    json!({
+        "healthy": healthy,
+        "message_thread_heartbeat_age_ms": age.as_millis() as u64,
+        "draining": STATE.draining.load(Ordering::Relaxed),
+    })
+}
+
+/// Answers a command from the admin socket, with a response in the same format as responses to messages.
+fn handle_admin_command(command: AdminCommand) -> JsonValue {
+    // orchestrators poll this, so don't fill the log with it
+    if command != AdminCommand::Health {
+        janus_info!("Processing admin socket command: {:?}", command);
+    }
+    match carry_out_admin_command(command) {
+        Ok(body) => json!({ "success": true, "response": body }),
+        Err(e) => error_response(e.as_ref()),
+    }
+}
+
+/// Carries out a command from the admin socket, only taking the switchboard lock for the commands that need it.
+fn carry_out_admin_command(command: AdminCommand) -> Result<JsonValue, Box<Error>> {
+    match command {
+        AdminCommand::Health => Ok(describe_health()),
+        AdminCommand::Verify { prune } => verify_switchboard(prune),
+        AdminCommand::GetConfig => Ok(json!(STATE.config.get().unwrap())),
+        AdminCommand::AudioOnly { enabled } => {
+            update_audio_only(|mode| mode.forced = enabled);
+            audit(if enabled { "enable_audio_only" } else { "disable_audio_only" }, None, None, None);
+            Ok(json!({}))
+        }
+        AdminCommand::SetDraining { draining } => {
+            janus_info!("Server is {} draining.", if draining { "now" } else { "no longer" });
+            STATE.draining.store(draining, Ordering::Relaxed);
+            Ok(json!({}))
+        }
+        AdminCommand::List => {
+            let switchboard = STATE.switchboard.read()?;
+            let rooms: serde_json::Map<String, JsonValue> = switchboard.rooms()
+                .map(|room| (room.clone(), json!(switchboard.get_users(room))))
+                .collect();
+            let peaks: serde_json::Map<String, JsonValue> = switchboard.rooms()
+                .map(|room| (room.clone(), json!(switchboard.room_peaks(room))))
+                .collect();
+            Ok(json!({ "rooms": rooms, "peaks": peaks, "draining": STATE.draining.load(Ordering::Relaxed) }))
+        }
+        AdminCommand::Kick { room_id, user_id } => {
+            let switchboard = STATE.switchboard.read()?;
+            kick_user(&switchboard, &room_id, &user_id);
+            audit("kick", None, Some(&user_id), Some(&room_id));
+            Ok(json!({}))
+        }
+        AdminCommand::KillSession { handle_id } => {
+            let switchboard = STATE.switchboard.read()?;
+            kill_session(&switchboard, &handle_id)?;
+            audit("kill_session", None, Some(&handle_id), None);
+            Ok(json!({}))
+        }
+        AdminCommand::Diagnostics { handle_id } => {
+            let switchboard = STATE.switchboard.read()?;
+            let sess = switchboard.get_session_by_handle(&handle_id).ok_or("No session exists with that handle ID.")?;
+            Ok(session_diagnostics(&switchboard, sess))
+        }
+    }
+}
+
+/// Whether the given user is allowed to moderate their room, either because they hold the moderator role or because
+/// they presented a token with kick permissions.
+fn may_moderate(joined: &JoinState, token: Option<&str>) -> bool {
+    let config = STATE.config.get().unwrap();
+    let has_permission = match (&config.auth_key, token) {
+        (Some(ref key), Some(token)) => ValidatedToken::from_str(token, key).map(|tok| tok.kick_users).unwrap_or(false),
+        _ => false,
+    };
+    has_permission || joined.moderator.load(Ordering::Relaxed)
+}
+
+/// Makes every session belonging to the given user in the given room a moderator, or not, and lets the room know
+/// about any new moderator.
+fn set_moderator(switchboard: &mut Switchboard, room_id: &RoomId, user_id: &UserId, moderator: bool, by: Option<&UserId>) {
+    for occupant in switchboard.occupants_of(room_id) {
+        if let Some(joined) = occupant.join_state.get().filter(|j| &j.user_id == user_id) {
+            joined.moderator.store(moderator, Ordering::Relaxed);
+        }
+    }
+    if moderator {
+        let config = STATE.config.get().unwrap();
+        let notification = json!({ "event": "moderator", "user_id": user_id, "room_id": room_id, "by": by });
+        send_message(&notification, switchboard.occupants_of(room_id)).warn_if_undelivered("moderator");
+        switchboard.record_event(room_id, notification, config.room_event_buffer_size);
+    }
+}
+
+fn process_transfer_moderator(from: &Arc<Session>, to: UserId) -> MessageResult {
+    janus_info!("Processing moderator transfer from {} to {}.", from.log_name(), to);
+    let joined = from.join_state.get().ok_or("Cannot transfer moderation when not in a room.")?;
+    if !joined.moderator.load(Ordering::Relaxed) {
+        return Err(From::from("Only moderators can transfer moderation."));
+    }
+    let mut switchboard = STATE.switchboard.write()?;
+    let room_id = joined.room_id.read().unwrap().clone();
+    if !switchboard.get_users(&room_id).contains(&to) {
+        return Err(From::from("That user isn't in your room."));
+    }
+    set_moderator(&mut switchboard, &room_id, &joined.user_id, false, None);
+    set_moderator(&mut switchboard, &room_id, &to, true, Some(&joined.user_id));
+    audit("transfer_moderator", Some(&joined.user_id), Some(&to), Some(&room_id));
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_pin(from: &Arc<Session>, user_id: UserId, pinned: bool) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot pin users when not in a room.")?;
+    if !may_moderate(joined, None) {
+        return Err(From::from("Pinning users requires being a moderator."));
+    }
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_info!("Processing {} of {} in room {} from {}.", if pinned { "pin" } else { "unpin" }, user_id, room_id, from.log_name());
+    let config = STATE.config.get().unwrap();
+    let mut switchboard = STATE.switchboard.write()?;
+    let sessions = switchboard.get_sessions(&room_id, &user_id);
+    if sessions.is_empty() {
+        return Err(From::from("That user isn't in your room."));
+    }
+    for sess in &sessions {
+        sess.join_state.get().unwrap().pinned.store(pinned, Ordering::Relaxed);
+        if pinned {
+            // resume any of this user's video which was suspended due to congestion
+            let mut resumed = false;
+            for subscription in switchboard.subscribers_to(sess) {
+                resumed |= subscription.video_suspended.swap(false, Ordering::Relaxed);
+            }
+            if resumed {
+                send_pli(Some(sess));
             }
         }
+    }
+    audit(if pinned { "pin" } else { "unpin" }, Some(&joined.user_id), Some(&user_id), Some(&room_id));
+    let notification = json!({ "event": if pinned { "pinned" } else { "unpinned" }, "user_id": &user_id, "room_id": &room_id, "by": &joined.user_id });
+    send_message(&notification, switchboard.occupants_of(&room_id)).warn_if_undelivered("pin");
+    switchboard.record_event(&room_id, notification, config.room_event_buffer_size);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_lock_room(from: &Arc<Session>, locked: bool, token: Option<String>) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot lock a room when not in one.")?;
+    if !may_moderate(joined, token.as_deref()) {
+        return Err(From::from("Locking rooms requires being a moderator."));
+    }
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_info!("Processing {} of room {} from {}.", if locked { "lock" } else { "unlock" }, room_id, from.log_name());
+    let mut switchboard = STATE.switchboard.write()?;
+    if locked {
+        switchboard.lock_room(&room_id);
+    } else {
+        switchboard.unlock_room(&room_id);
+    }
+    audit(if locked { "lock_room" } else { "unlock_room" }, Some(&joined.user_id), None, Some(&room_id));
+    let notification = json!({ "event": if locked { "room_locked" } else { "room_unlocked" }, "by": &joined.user_id });
+    notify_except(&notification, &joined.user_id, switchboard.occupants_of(&room_id)).warn_if_undelivered("room lock");
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// The longest announcement text a moderator can send, in bytes.
+const MAX_ANNOUNCEMENT_BYTES: usize = 1024;
+
+fn process_announce(from: &Arc<Session>, text: String) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot make an announcement when not in a room.")?;
+    if !may_moderate(joined, None) {
+        return Err(From::from("Making announcements requires being a moderator."));
+    }
+    if text.len() > MAX_ANNOUNCEMENT_BYTES {
+        return Err(From::from(format!("Announcement is too long ({} bytes, limit is {}).", text.len(), MAX_ANNOUNCEMENT_BYTES)));
+    }
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_info!("Processing announcement to room {} from {}.", room_id, from.log_name());
+    let switchboard = STATE.switchboard.read()?;
+    audit("announce", Some(&joined.user_id), None, Some(&room_id));
+    let notification = json!({ "event": "announcement", "by": &joined.user_id, "room_id": &room_id, "text": text });
+    send_message(&notification, switchboard.notification_recipients(&room_id, true)).warn_if_undelivered("announcement");
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_rotate_room_secret(from: &Arc<Session>, new_secret: String) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot set a room's secret when not in a room.")?;
+    if !may_moderate(joined, None) {
+        return Err(From::from("Setting a room's secret requires being a moderator."));
+    }
+    if new_secret.is_empty() {
+        return Err(From::from("Room secrets can't be empty."));
+    }
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_info!("Processing secret rotation for room {} from {}.", room_id, from.log_name());
+    let mut switchboard = STATE.switchboard.write()?;
+    switchboard.set_room_secret(&room_id, new_secret);
+    audit("rotate_room_secret", Some(&joined.user_id), None, Some(&room_id));
+    let notification = json!({ "event": "room_secret_rotated", "by": &joined.user_id, "room_id": &room_id });
+    for moderator in switchboard.get_moderators(&room_id) {
+        notify_user(&notification, moderator, switchboard.occupants_of(&room_id)).warn_if_undelivered("room secret rotation");
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_diagnostics(from: &Arc<Session>, handle_id: Option<String>, token: Option<String>) -> MessageResult {
+    janus_info!("Processing diagnostics request from {} for {:?}.", from.log_name(), handle_id);
+    let switchboard = STATE.switchboard.read()?;
+    let target = match handle_id {
+        None => from,
+        Some(ref handle_id) => {
+            let config = STATE.config.get().unwrap();
+            let key = config.auth_key.as_ref().ok_or("Can't diagnose other sessions because no secret was configured.")?;
+            let tok = ValidatedToken::from_str(token.as_ref().ok_or("Diagnosing other sessions requires a token.")?, key)?;
+            if !tok.admin {
+                return Err(From::from("Diagnosing other sessions requires admin permissions."));
+            }
+            switchboard.get_session_by_handle(handle_id).ok_or("No session exists with that handle ID.")?
+        }
+    };
+    Ok(MessageResponse::msg(session_diagnostics(&switchboard, target)))
+}
+
+fn process_verify(from: &Arc<Session>, token: &str, prune: bool) -> MessageResult {
+    janus_info!("Processing consistency check from {} (prune: {}).", from.log_name(), prune);
+    let config = STATE.config.get().unwrap();
+    let key = config.auth_key.as_ref().ok_or("Can't verify the server's state because no secret was configured.")?;
+    if !ValidatedToken::from_str(token, key)?.admin {
+        return Err(From::from("Verifying the server's state requires admin permissions."));
+    }
+    Ok(MessageResponse::msg(verify_switchboard(prune)?))
+}
+
+/// Checks the switchboard for inconsistencies, and, if asked to and it finds any, takes the write lock just long enough
+/// to clean them up.
+fn verify_switchboard(prune: bool) -> Result<JsonValue, Box<Error>> {
+    let report = STATE.switchboard.read()?.verify();
+    if report != ConsistencyReport::default() {
+        janus_warn!("Switchboard consistency check found problems: {:?}", report);
+    }
+    if !prune || report == ConsistencyReport::default() {
+        return Ok(json!({ "report": report, "pruned": false }));
+    }
+    // things could have changed between the locks, so report what was actually pruned
+    let pruned = STATE.switchboard.write()?.prune();
+    Ok(json!({ "report": pruned, "pruned": true }))
+}
+
+fn process_get_config(from: &Arc<Session>, token: &str) -> MessageResult {
+    janus_info!("Processing config request from {}.", from.log_name());
+    let config = STATE.config.get().unwrap();
+    let key = config.auth_key.as_ref().ok_or("Can't describe the config because no secret was configured.")?;
+    if !ValidatedToken::from_str(token, key)?.admin {
+        return Err(From::from("Describing the config requires admin permissions."));
+    }
+    Ok(MessageResponse::msg(serde_json::to_value(config)?))
+}
+
+fn process_audio_only(from: &Arc<Session>, token: &str, enabled: bool) -> MessageResult {
+    janus_info!("Processing audio-only mode change from {} (enabled: {}).", from.log_name(), enabled);
+    let config = STATE.config.get().unwrap();
+    let key = config.auth_key.as_ref().ok_or("Can't change audio-only mode because no secret was configured.")?;
+    if !ValidatedToken::from_str(token, key)?.admin {
+        return Err(From::from("Changing audio-only mode requires admin permissions."));
+    }
+    update_audio_only(|mode| mode.forced = enabled);
+    let actor = from.join_state.get().map(|joined| &joined.user_id);
+    audit(if enabled { "enable_audio_only" } else { "disable_audio_only" }, actor, None, None);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// How often we check the system's load, if audio-only mode is configured to kick in under load.
+const AUDIO_ONLY_LOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How far below the configured threshold load has to drop before audio-only mode is turned off again.
+const AUDIO_ONLY_EXIT_FRACTION: f64 = 0.75;
+
+/// Returns the system's one-minute load average divided by how many CPUs it has, if we can tell, which we can only do
+/// on Linux.
+fn load_per_cpu() -> Option<f64> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = thread::available_parallelism().ok()?.get();
+    Some(load / cpus as f64)
+}
+
+/// Enters audio-only mode if the system's load has reached the given threshold, or leaves it if load has dropped well
+/// below it, so that we don't flap between the two while load hovers around the threshold.
+fn check_load(threshold: f64) {
+    if let Some(load) = load_per_cpu() {
+        if load >= threshold {
+            update_audio_only(|mode| mode.overloaded = true);
+        } else if load < threshold * AUDIO_ONLY_EXIT_FRACTION {
+            update_audio_only(|mode| mode.overloaded = false);
+        }
+    }
+}
+
+/// Applies the given change to the server's audio-only mode. If that turns it on or off, everyone subscribed to
+/// notifications is told, and when it's turned off, every publisher is asked for a keyframe so video resumes quickly.
+fn update_audio_only<F: FnOnce(&mut AudioOnly)>(change: F) {
+    let (was_enabled, enabled) = {
+        let mut mode = STATE.audio_only.lock().unwrap();
+        let was_enabled = mode.enabled();
+        change(&mut mode);
+        STATE.audio_only_enabled.store(mode.enabled(), Ordering::Relaxed);
+        (was_enabled, mode.enabled())
+    };
+    if was_enabled == enabled {
+        return;
+    }
+    if enabled {
+        janus_warn!("Entering audio-only mode; no video will be forwarded.");
     } else {
-        janus_warn!("Ignoring kick from {:p} because no secret was configured.", from.handle);
+        janus_info!("Leaving audio-only mode.");
+    }
+    let switchboard = STATE.switchboard.read().expect("Switchboard is poisoned :(");
+    let notification = json!({ "event": "audio_only", "enabled": enabled });
+    for room in switchboard.rooms() {
+        send_message(&notification, switchboard.notification_recipients(room, true));
+    }
+    if !enabled {
+        send_pli(switchboard.sessions().iter().map(|s| s.as_ref()).filter(|s| s.is_publishing()));
     }
-    Ok(MessageResponse::msg(json!({})))
 }
 
 fn process_block(from: &Arc<Session>, whom: UserId) -> MessageResult {
-    janus_info!("Processing block from {:p} to {}", from.handle, whom);
+    janus_info!("Processing block from {} to {}", from.log_name(), whom);
     if let Some(joined) = from.join_state.get() {
         let mut switchboard = STATE.switchboard.write()?;
         let event = json!({ "event": "blocked", "by": &joined.user_id });
-        notify_user(&event, &whom, switchboard.occupants_of(&joined.room_id));
-        switchboard.establish_block(joined.user_id.clone(), whom);
+        notify_user(&event, &whom, switchboard.occupants_of(&joined.room_id.read().unwrap())).warn_if_undelivered("block");
+        switchboard.establish_block(joined.user_id.clone(), whom.clone());
+        audit("block", Some(&joined.user_id), Some(&whom), Some(&joined.room_id.read().unwrap()));
+        if STATE.config.get().unwrap().blocks_end_subscriptions {
+            for (publisher, subscriber) in switchboard.end_subscriptions_between(&joined.user_id, &whom) {
+                janus_info!("Ending subscription from {} to {} due to block.", subscriber.log_name(), publisher.log_name());
+                if let Some(sub) = subscriber.subscription.lock().unwrap().as_mut() {
+                    sub.media = None;
+                }
+                if let Some(publisher_state) = publisher.join_state.get() {
+                    let notification = json!({ "event": "unsubscribed", "user_id": &publisher_state.user_id, "reason": "blocked" });
+                    send_message(&notification, [&subscriber]).warn_if_undelivered("block");
+                }
+            }
+        }
         Ok(MessageResponse::msg(json!({})))
     } else {
         Err(From::from("Cannot block when not in a room."))
@@ -510,47 +2601,236 @@ fn process_block(from: &Arc<Session>, whom: UserId) -> MessageResult {
 }
 
 fn process_unblock(from: &Arc<Session>, whom: UserId) -> MessageResult {
-    janus_info!("Processing unblock from {:p} to {}", from.handle, whom);
+    janus_info!("Processing unblock from {} to {}", from.log_name(), whom);
     if let Some(joined) = from.join_state.get() {
         let mut switchboard = STATE.switchboard.write()?;
         switchboard.lift_block(&joined.user_id, &whom);
+        audit("unblock", Some(&joined.user_id), Some(&whom), Some(&joined.room_id.read().unwrap()));
         if let Some(publisher) = switchboard.get_publisher(&whom) {
             send_fir(&[publisher]);
         }
         let event = json!({ "event": "unblocked", "by": &joined.user_id });
-        notify_user(&event, &whom, switchboard.occupants_of(&joined.room_id));
+        notify_user(&event, &whom, switchboard.occupants_of(&joined.room_id.read().unwrap()));
         Ok(MessageResponse::msg(json!({})))
     } else {
         Err(From::from("Cannot unblock when not in a room."))
     }
 }
 
-fn process_subscribe(from: &Arc<Session>, what: &Subscription) -> MessageResult {
-    janus_info!("Processing subscription from {:p}: {:?}", from.handle, what);
-    let subscription_state = Box::new(what.clone());
-    if from.subscription.set_if_none(subscription_state).is_some() {
-        return Err(From::from("Users may only subscribe once!"))
+fn process_mute_user(from: &Arc<Session>, whom: UserId, mute: Mute) -> MessageResult {
+    janus_info!("Processing mute from {} to {}: {:?}", from.log_name(), whom, mute);
+    if let Some(joined) = from.join_state.get() {
+        let mut switchboard = STATE.switchboard.write()?;
+        let was_video_muted = switchboard.mute_between(&joined.user_id, &whom).video;
+        switchboard.set_mute(joined.user_id.clone(), whom.clone(), mute);
+        if was_video_muted && !mute.video {
+            // the subscriber can't decode anything until the next keyframe
+            if let Some(publisher) = switchboard.get_publisher(&whom) {
+                send_fir([publisher]);
+            }
+        }
+        Ok(MessageResponse::msg(json!({})))
+    } else {
+        Err(From::from("Cannot mute when not in a room."))
     }
+}
 
-    let mut switchboard = STATE.switchboard.write()?;
-    if let Some(ref publisher_id) = what.media {
-        let publisher = switchboard.get_publisher(publisher_id).ok_or("Can't subscribe to a nonexistent publisher.")?.clone();
-        let jsep = json!({
-            "type": "offer",
-            "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
-        });
-        switchboard.subscribe_to_user(from.clone(), publisher);
-        return Ok(MessageResponse::new(json!({}), jsep));
+fn process_pause_subscription(from: &Arc<Session>, whom: UserId, paused: bool) -> MessageResult {
+    janus_info!("Processing subscription {} from {} to {}.", if paused { "pause" } else { "resume" }, from.log_name(), whom);
+    let joined = from.join_state.get().ok_or("Cannot pause subscriptions when not in a room.")?;
+    let switchboard = STATE.switchboard.read()?;
+    let publisher = switchboard.get_publisher(&whom).ok_or("That user isn't publishing.")?;
+    // the message can come in on the subscribing connection itself, or on any other connection of the same user
+    let subscribers: Vec<_> = switchboard.sessions_for_user(&joined.user_id).into_iter()
+        .filter(|s| switchboard.publishers_to(s).contains(publisher))
+        .collect();
+    if subscribers.is_empty() {
+        return Err(From::from("You aren't subscribed to that user."));
+    }
+    for subscriber in &subscribers {
+        let mut paused_publishers = subscriber.paused_publishers.lock().unwrap();
+        if paused {
+            paused_publishers.insert(whom.clone());
+        } else {
+            paused_publishers.remove(&whom);
+        }
+    }
+    if !paused {
+        // the subscribers can't decode anything until the next keyframe
+        send_fir([publisher]);
     }
     Ok(MessageResponse::msg(json!({})))
 }
 
+/// Makes sure that a subscriber will be able to play the publisher's audio and video, judging by the codecs that the
+/// subscriber's user offered when publishing their own media. We don't transcode, so there's no point subscribing
+/// otherwise.
+fn check_codecs(switchboard: &Switchboard, subscriber: Option<&UserId>, publisher: &Session) -> Result<(), Box<Error>> {
+    let own_publisher = match subscriber.and_then(|user_id| switchboard.get_publisher(user_id)) {
+        Some(own_publisher) => own_publisher,
+        None => return Ok(())
+    };
+    if let Some(codec) = *publisher.audio_codec.lock().unwrap() {
+        if !own_publisher.offered_audio_codecs.lock().unwrap().contains(&codec) {
+            let msg = format!("Can't subscribe to a publisher sending {} audio, which you don't support.", codec.to_str());
+            return Err(From::from(msg));
+        }
+    }
+    if let Some(codec) = *publisher.video_codec.lock().unwrap() {
+        if !own_publisher.offered_video_codecs.lock().unwrap().contains(&codec) {
+            let msg = format!("Can't subscribe to a publisher sending {} video, which you don't support.", codec.to_str());
+            return Err(From::from(msg));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the given session can subscribe to the given number of publishers' media, once the given number of its
+/// media subscriptions are dropped, without going over the configured maximum. Media it loops back doesn't count.
+fn check_subscription_limit(switchboard: &Switchboard, subscriber: &Session, replacing: usize, adding: usize) -> Result<(), Box<Error>> {
+    let max = STATE.config.get().unwrap().max_subscriptions_per_session;
+    let current = switchboard.publishers_to(subscriber).iter().filter(|p| p.handle != subscriber.handle).count();
+    if current.saturating_sub(replacing).saturating_add(adding) > max {
+        return Err(From::from(format!("Can't have more than {} media subscriptions at once.", max)));
+    }
+    Ok(())
+}
+
+/// Finds the publisher that the given subscription asks for media from, if any: the user with its `media` user ID,
+/// preferring a connection publishing the user's main audio over ones publishing labeled audio tracks, or, if we don't
+/// require joining for media, the unjoined publishing connection with its `media_handle` handle ID.
+fn find_publisher(switchboard: &Switchboard, what: &Subscription) -> Result<Option<Arc<Session>>, Box<Error>> {
+    let publisher = match (&what.media, &what.media_handle) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => return Err(From::from("Can't subscribe to media by both user ID and handle ID.")),
+        (Some(publisher_id), None) => {
+            switchboard.sessions_for_user(publisher_id).into_iter()
+                .filter(|s| s.is_publishing())
+                .min_by_key(|s| s.audio_label.lock().unwrap().is_some())
+        }
+        (None, Some(handle_id)) => {
+            if STATE.config.get().unwrap().require_join_for_media {
+                return Err(From::from("This server doesn't allow subscribing to media by handle ID."));
+            }
+            switchboard.get_session_by_handle(handle_id)
+                .filter(|s| s.join_state.is_none() && s.is_publishing())
+                .cloned()
+        }
+    };
+    Ok(Some(publisher.ok_or("Can't subscribe to a nonexistent publisher.")?))
+}
+
+/// Finds the connection publishing the given publisher's user's audio track with the given label, if a label is given.
+fn find_audio_track(switchboard: &Switchboard, publisher: &Session, label: Option<&String>) -> Result<Option<Arc<Session>>, Box<Error>> {
+    let label = match label {
+        Some(label) => label,
+        None => return Ok(None)
+    };
+    let joined = publisher.join_state.get().ok_or("Only joined users can have labeled audio tracks.")?;
+    let track = switchboard.get_sessions(&joined.room_id.read().unwrap(), &joined.user_id).into_iter()
+        .filter(|s| s.is_publishing())
+        .find(|s| s.audio_label.lock().unwrap().as_ref() == Some(label))
+        .ok_or("That user isn't publishing an audio track with that label.")?;
+    if *track.audio_codec.lock().unwrap() != *publisher.audio_codec.lock().unwrap() {
+        return Err(From::from("That audio track uses a different codec than the user's main audio."));
+    }
+    track.track_payload_type.store(subscriber_audio_payload_type(publisher).unwrap_or(0) as usize, Ordering::Relaxed);
+    Ok(Some(track))
+}
+
+/// Returns the audio payload type that the given publisher offered its subscribers, if it publishes audio.
+fn subscriber_audio_payload_type(publisher: &Session) -> Option<i32> {
+    let codec = (*publisher.audio_codec.lock().unwrap())?;
+    publisher.subscriber_offer.lock().unwrap().as_ref()?.get_payload_type(codec.to_cstr())
+}
+
+/// Chooses which of its publisher's audio tracks the given subscriber receives, by label, or its main audio if none.
+fn select_audio_label(subscriber: &Session, label: Option<String>) {
+    let mut selected = subscriber.selected_audio_label.lock().unwrap();
+    subscriber.selects_audio_label.store(label.is_some(), Ordering::Relaxed);
+    *selected = label;
+}
+
+fn process_subscribe(from: &Arc<Session>, what: &Subscription) -> MessageResult {
+    janus_info!("Processing subscription from {}: {:?}", from.log_name(), what);
+    let mut switchboard = STATE.switchboard.write()?;
+    let mut subscription = from.subscription.lock().unwrap();
+    let (previous_media, previous_handle) = subscription.as_ref().map_or((None, None), |sub| (sub.media.clone(), sub.media_handle.clone()));
+    let mut jsep = None;
+    if what.media != previous_media || what.media_handle != previous_handle {
+        let publisher = match find_publisher(&switchboard, what)? {
+            Some(publisher) => {
+                check_codecs(&switchboard, from.join_state.get().map(|joined| &joined.user_id), &publisher)?;
+                let track = find_audio_track(&switchboard, &publisher, what.audio_label.as_ref())?;
+                let replacing = switchboard.publishers_to(from).iter().filter(|p| p.handle != from.handle).count();
+                check_subscription_limit(&switchboard, from, replacing, 1 + track.is_some() as usize)?;
+                Some((publisher, track))
+            }
+            None => None
+        };
+        // drop the media subscription we're replacing, if any, keeping anything looped back for sendrecv
+        let replaced: Vec<_> = switchboard.publishers_to(from).iter().filter(|p| p.handle != from.handle).cloned().collect();
+        for old_publisher in &replaced {
+            switchboard.unsubscribe_from_user(from, old_publisher);
+        }
+        if let Some((publisher, track)) = publisher {
+            let offer = json!({
+                "type": "offer",
+                "sdp": publisher.subscriber_offer.lock().unwrap().as_ref().unwrap()
+            });
+            let subscribed = switchboard.subscribe_to_user(Arc::clone(from), publisher).and_then(|()| {
+                match track {
+                    Some(track) => switchboard.subscribe_to_user(Arc::clone(from), track),
+                    None => Ok(())
+                }
+            });
+            if let Err(e) = subscribed {
+                let partial: Vec<_> = switchboard.publishers_to(from).iter().filter(|p| p.handle != from.handle).cloned().collect();
+                for new_publisher in &partial {
+                    switchboard.unsubscribe_from_user(from, new_publisher);
+                }
+                for old_publisher in replaced {
+                    switchboard.subscribe_to_user(Arc::clone(from), old_publisher)?;
+                }
+                return Err(e);
+            }
+            jsep = Some(offer);
+        }
+    } else if what.audio_label != subscription.as_ref().and_then(|sub| sub.audio_label.clone()) {
+        // same user, different audio track, which doesn't need renegotiating
+        let (tracks, main): (Vec<_>, Vec<_>) = switchboard.publishers_to(from).iter()
+            .filter(|p| p.handle != from.handle)
+            .cloned()
+            .partition(|p| p.audio_label.lock().unwrap().is_some());
+        if let Some(publisher) = main.first() {
+            let track = find_audio_track(&switchboard, publisher, what.audio_label.as_ref())?;
+            check_subscription_limit(&switchboard, from, tracks.len(), track.is_some() as usize)?;
+            for old_track in &tracks {
+                switchboard.unsubscribe_from_user(from, old_track);
+            }
+            if let Some(track) = track {
+                switchboard.subscribe_to_user(Arc::clone(from), track)?;
+            }
+        }
+    }
+    *subscription = Some(what.clone());
+    from.media_priority.store(what.priority as isize, Ordering::Relaxed);
+    from.keyframes_only.store(what.keyframes_only, Ordering::Relaxed);
+    *from.video_constraints.lock().unwrap() = what.video_constraints();
+    select_audio_label(from, what.audio_label.clone());
+    reselect_video_layers(&switchboard, from);
+    match jsep {
+        Some(jsep) => Ok(MessageResponse::new(json!({}), jsep)),
+        None => Ok(MessageResponse::msg(json!({}))),
+    }
+}
+
 fn process_data(from: &Arc<Session>, whom: Option<UserId>, body: &str) -> MessageResult {
-    janus_huge!("Processing data message from {:p}: {:?}", from.handle, body);
+    janus_huge!("Processing data message from {}: {:?}", from.log_name(), body);
     let payload = json!({ "event": "data", "body": body });
     let switchboard = STATE.switchboard.write()?;
     if let Some(joined) = from.join_state.get() {
-        let occupants = switchboard.occupants_of(&joined.room_id);
+        let occupants = switchboard.occupants_of(&joined.room_id.read().unwrap());
         if let Some(user_id) = whom {
             send_data_user(&payload, &user_id, occupants);
         } else {
@@ -562,110 +2842,734 @@ fn process_data(from: &Arc<Session>, whom: Option<UserId>, body: &str) -> Messag
     }
 }
 
+fn process_get_room_peaks(from: &Arc<Session>) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot get room peaks when not in a room.")?;
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_verb!("Processing room peaks query from {} for room {}.", from.log_name(), room_id);
+    let switchboard = STATE.switchboard.read()?;
+    Ok(MessageResponse::msg(json!({ "room_id": &room_id, "peaks": switchboard.room_peaks(&room_id) })))
+}
+
+fn process_get_user(from: &Arc<Session>, user_id: UserId) -> MessageResult {
+    let joined = from.join_state.get().ok_or("Cannot get a user when not in a room.")?;
+    let room_id = joined.room_id.read().unwrap().clone();
+    janus_verb!("Processing user query from {} for {} in room {}.", from.log_name(), user_id, room_id);
+    let switchboard = STATE.switchboard.read()?;
+    let sessions = switchboard.get_sessions(&room_id, &user_id);
+    if sessions.is_empty() {
+        return Err(From::from("No such user is in your room."));
+    }
+    let states: Vec<&JoinState> = sessions.iter().filter_map(|s| s.join_state.get()).collect();
+    let metadata = states.iter().map(|j| &j.metadata).find(|m| !m.is_null()).cloned().unwrap_or(JsonValue::Null);
+    Ok(MessageResponse::msg(json!({
+        "user_id": &user_id,
+        "room_id": &room_id,
+        "metadata": metadata,
+        "publishing": sessions.iter().any(|s| s.is_publishing()),
+        "hand_raised": states.iter().any(|j| j.hand_raised.load(Ordering::Relaxed)),
+        "moderator": states.iter().any(|j| j.moderator.load(Ordering::Relaxed)),
+        "pinned": states.iter().any(|j| j.pinned.load(Ordering::Relaxed)),
+    })))
+}
+
+fn process_get_capabilities(from: &Arc<Session>) -> MessageResult {
+    janus_verb!("Processing capabilities query from {}.", from.log_name());
+    let config = STATE.config.get().unwrap();
+    Ok(MessageResponse::msg(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "audio_codecs": config.audio_codecs.iter().map(AudioCodec::to_str).collect::<Vec<_>>(),
+        "video_codecs": config.video_codecs.iter().map(VideoCodec::to_str).collect::<Vec<_>>(),
+        "egress_audio_codecs": config.egress_audio_codecs.iter().map(AudioCodec::to_str).collect::<Vec<_>>(),
+        "egress_video_codecs": config.egress_video_codecs.iter().map(VideoCodec::to_str).collect::<Vec<_>>(),
+        "audio_only": STATE.audio_only_enabled.load(Ordering::Relaxed),
+        "draining": STATE.draining.load(Ordering::Relaxed),
+        "features": {
+            "simulcast": false,
+            "recording": false,
+            "sendrecv": true,
+            "move_room": true,
+            "raise_hand": true,
+            "lock_room": true,
+            "unpublish": true,
+        }
+    })))
+}
+
+fn process_move_room(from: &Arc<Session>, to: RoomId, secret: Option<String>) -> MessageResult {
+    janus_info!("Processing move from {} to room ID {}.", from.log_name(), to);
+    let config = STATE.config.get().unwrap();
+    let joined = from.join_state.get().ok_or("Cannot move rooms when not in a room.")?;
+    let mut switchboard = STATE.switchboard.write()?;
+    let old_room = joined.room_id.read().unwrap().clone();
+    if old_room == to {
+        return Err(From::from("Already in that room."));
+    }
+    let is_master_handle = switchboard.occupants_of(&old_room).iter().any(|s| s.handle == from.handle);
+    if is_master_handle {
+        if STATE.draining.load(Ordering::Relaxed) && switchboard.occupants_of(&to).is_empty() {
+            return Err(Box::new(CodedError::new("server_draining", "Server draining.")));
+        }
+        // a move which leaves the old room empty frees up its place, so it can start a new room even at the limit
+        let empties_room = switchboard.occupants_of(&old_room).len() == 1;
+        let max_rooms = if empties_room { config.max_rooms.saturating_add(1) } else { config.max_rooms };
+        match switchboard.check_capacity(&to, config.max_room_size, max_rooms) {
+            Err(RoomRefusal::Full) => return Err(Box::new(CodedError::new("room_full", "Room is full."))),
+            Err(RoomRefusal::TooManyRooms) => return Err(From::from("Room limit reached.")),
+            Ok(()) => {}
+        }
+    }
+    if is_master_handle {
+        let is_moderator = joined.moderator_token.load(Ordering::Relaxed);
+        let owns_user_id = joined.owns_user_id.load(Ordering::Relaxed);
+        check_admission(&switchboard, config, &to, &joined.user_id, is_moderator, owns_user_id, secret.as_deref())?;
+    }
+
+    // bring along all of this user's other connections in the old room too, so nothing is left behind in a room
+    // that the user isn't in anymore
+    let sessions = switchboard.get_sessions(&old_room, &joined.user_id);
+    for session in sessions {
+        switchboard.move_session(&session, to.clone());
+    }
+    let body = json!({
+        "users": { to.as_str(): switchboard.get_users(&to) },
+        "metadata": { to.as_str(): switchboard.get_user_metadata(&to) },
+        "raised_hands": { to.as_str(): switchboard.get_raised_hands(&to) },
+        "moderators": { to.as_str(): switchboard.get_moderators(&to) },
+        "recent_events": switchboard.recent_events(&to)
+    });
+    if is_master_handle {
+        let leave = json!({ "event": "leave", "user_id": &joined.user_id, "room_id": &old_room });
+        notify_roommates(&switchboard, &old_room, &joined.user_id, &leave);
+        switchboard.record_event(&old_room, leave, config.room_event_buffer_size);
+        let join = json!({ "event": "join", "user_id": &joined.user_id, "room_id": &to, "metadata": &joined.metadata });
+        notify_roommates(&switchboard, &to, &joined.user_id, &join);
+        switchboard.record_event(&to, join, config.room_event_buffer_size);
+        notify_if_room_available(&mut switchboard, &old_room);
+    }
+    Ok(MessageResponse::msg(body))
+}
+
+fn process_select_temporal_layer(from: &Arc<Session>, layer: Option<usize>) -> MessageResult {
+    janus_info!("Processing temporal layer selection from {}: {:?}", from.log_name(), layer);
+    from.max_temporal_layer.store(layer.unwrap_or(usize::MAX), Ordering::Relaxed);
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_set_priority(from: &Arc<Session>, priority: i32) -> MessageResult {
+    janus_info!("Processing priority change from {} to {}.", from.log_name(), priority);
+    let switchboard = STATE.switchboard.read()?;
+    if switchboard.publishers_to(from).is_empty() {
+        return Err(From::from("Only media subscriptions have a priority."));
+    }
+    let previous = from.media_priority.swap(priority as isize, Ordering::Relaxed);
+    // a subscription that just got more important deserves another chance at getting video
+    if priority as isize > previous && from.video_suspended.swap(false, Ordering::Relaxed) {
+        janus_info!("Resuming video to {}.", from.log_name());
+        send_pli(subscribed_publisher(&switchboard, from));
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_publish(from: &Arc<Session>) -> MessageResult {
+    janus_info!("Processing publish from {}.", from.log_name());
+    let mut switchboard = STATE.switchboard.write()?;
+    if from.subscriber_offer.lock().unwrap().is_none() {
+        return Err(From::from("Send an offer to negotiate media before publishing."));
+    }
+    if from.unpublished.swap(false, Ordering::Relaxed) {
+        hold_new_publication(from);
+        announce_publishing(&switchboard, from);
+        if let Some(joined) = from.join_state.get() {
+            switchboard.update_room_peaks(&joined.room_id.read().unwrap());
+        }
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_unpublish(from: &Arc<Session>) -> MessageResult {
+    janus_info!("Processing unpublish from {}.", from.log_name());
+    let mut switchboard = STATE.switchboard.write()?;
+    if from.is_publishing() {
+        from.unpublished.store(true, Ordering::Relaxed);
+        stop_publishing(&mut switchboard, from);
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_label_audio(from: &Arc<Session>, label: Option<String>) -> MessageResult {
+    janus_info!("Processing audio label {:?} from {}.", label, from.log_name());
+    from.join_state.get().ok_or("Cannot label audio when not in a room.")?;
+    if label.as_ref().is_some_and(|l| l.is_empty()) {
+        return Err(From::from("Audio labels can't be empty."));
+    }
+    let mut audio_label = from.audio_label.lock().unwrap();
+    from.audio_labeled.store(label.is_some(), Ordering::Relaxed);
+    if label.is_none() {
+        from.track_payload_type.store(0, Ordering::Relaxed);
+    }
+    *audio_label = label;
+    Ok(MessageResponse::msg(json!({})))
+}
+
+fn process_request_offer(from: &Arc<Session>) -> MessageResult {
+    janus_info!("Processing offer request from {}.", from.log_name());
+    let switchboard = STATE.switchboard.read()?;
+    let publisher = switchboard.publishers_to(from).iter().find(|p| p.handle != from.handle)
+        .ok_or("Only media subscriptions can request an offer.")?;
+    send_subscriber_offer(from, publisher)?;
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// How many of each session's most recent stats reports we keep.
+const STATS_HISTORY: usize = 10;
+
+/// The reported packet loss above which we treat a subscriber's connection as congested.
+const CONGESTED_PACKET_LOSS: f64 = 0.1;
+
+fn process_report_stats(from: &Arc<Session>, stats: ClientStats) -> MessageResult {
+    janus_huge!("Processing stats report from {}: {:?}", from.log_name(), stats);
+    stats.validate()?;
+    let congested = stats.packet_loss.is_some_and(|loss| loss > CONGESTED_PACKET_LOSS);
+    {
+        let mut recent = from.recent_stats.lock().unwrap();
+        if recent.len() >= STATS_HISTORY {
+            recent.pop_front();
+        }
+        recent.push_back(stats);
+    }
+    if congested {
+        let switchboard = STATE.switchboard.read()?;
+        if !switchboard.publishers_to(from).is_empty() {
+            janus_info!("Client of {} reports heavy packet loss; degrading its video.", from.log_name());
+            degrade_video_to_user(&switchboard, from);
+        }
+    }
+    Ok(MessageResponse::msg(json!({})))
+}
+
+/// Averages the most recent stats reported by every session in the given room, for operators to look at.
+fn room_stats(switchboard: &Switchboard, room_id: &RoomId) -> JsonValue {
+    let latest: Vec<ClientStats> = switchboard.sessions().iter()
+        .filter(|s| s.join_state.get().is_some_and(|j| &*j.room_id.read().unwrap() == room_id))
+        .filter_map(|s| s.recent_stats.lock().unwrap().back().cloned())
+        .collect();
+    let mean = |field: fn(&ClientStats) -> Option<f64>| {
+        let values: Vec<f64> = latest.iter().filter_map(field).collect();
+        if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) }
+    };
+    json!({
+        "reports": latest.len(),
+        "mean_jitter_ms": mean(|s| s.jitter_ms),
+        "mean_packet_loss": mean(|s| s.packet_loss),
+        "mean_framerate": mean(|s| s.framerate),
+    })
+}
+
+fn process_raise_hand(from: &Arc<Session>, raised: bool) -> MessageResult {
+    janus_info!("Processing hand {} from {}.", if raised { "raise" } else { "lower" }, from.log_name());
+    if let Some(joined) = from.join_state.get() {
+        let config = STATE.config.get().unwrap();
+        let mut switchboard = STATE.switchboard.write()?;
+        let room_id = joined.room_id.read().unwrap();
+        if !switchboard.occupants_of(&room_id).iter().any(|s| s.handle == from.handle) {
+            return Err(From::from("Only the session subscribed to room data may raise a hand."));
+        }
+        joined.hand_raised.store(raised, Ordering::Relaxed);
+        let notification = json!({ "event": "hand", "user_id": &joined.user_id, "room_id": &*room_id, "raised": raised });
+        notify_roommates(&switchboard, &room_id, &joined.user_id, &notification);
+        switchboard.record_event(&room_id, notification, config.room_event_buffer_size);
+        Ok(MessageResponse::msg(json!({})))
+    } else {
+        Err(From::from("Cannot raise hand when not in a room."))
+    }
+}
+
 fn process_message(from: &Arc<Session>, msg: MessageKind) -> MessageResult {
     match msg {
-        MessageKind::Join { room_id, user_id, subscribe, token } => process_join(from, room_id, user_id, subscribe, token),
+        MessageKind::Join { room_id, user_id, subscribe, token, sendrecv, metadata, notify_when_available, codec_capabilities, secret,
+                            correlation_id } => {
+            process_join(from, room_id, user_id, subscribe, token, sendrecv, metadata, notify_when_available, codec_capabilities, secret,
+                         correlation_id)
+        }
+        MessageKind::Resume { token } => process_resume(from, token),
+        MessageKind::Publish => process_publish(from),
+        MessageKind::Unpublish => process_unpublish(from),
         MessageKind::Kick { room_id, user_id, token } => process_kick(from, room_id, user_id, token),
+        MessageKind::KillSession { handle_id, token } => process_kill_session(from, handle_id, token),
+        MessageKind::LockRoom { locked, token } => process_lock_room(from, locked, token),
+        MessageKind::Announce { text } => process_announce(from, text),
+        MessageKind::RotateRoomSecret { new_secret } => process_rotate_room_secret(from, new_secret),
+        MessageKind::TransferModerator { to } => process_transfer_moderator(from, to),
+        MessageKind::Pin { user_id } => process_pin(from, user_id, true),
+        MessageKind::Unpin { user_id } => process_pin(from, user_id, false),
+        MessageKind::ReportStats { stats } => process_report_stats(from, stats),
+        MessageKind::Diagnostics { handle_id, token } => process_diagnostics(from, handle_id, token),
+        MessageKind::Observe { room_id, token, send_data } => process_observe(from, room_id, &token, send_data),
+        MessageKind::Verify { token, prune } => process_verify(from, &token, prune),
+        MessageKind::GetConfig { token } => process_get_config(from, &token),
+        MessageKind::AudioOnly { token, enabled } => process_audio_only(from, &token, enabled),
         MessageKind::Subscribe { what } => process_subscribe(from, &what),
         MessageKind::Block { whom } => process_block(from, whom),
         MessageKind::Unblock { whom } => process_unblock(from, whom),
+        MessageKind::PauseSubscription { whom } => process_pause_subscription(from, whom, true),
+        MessageKind::ResumeSubscription { whom } => process_pause_subscription(from, whom, false),
+        MessageKind::MuteUser { whom, audio, video } => process_mute_user(from, whom, Mute { audio, video }),
         MessageKind::Data { whom, body } => process_data(from, whom, &body),
+        MessageKind::GetCapabilities => process_get_capabilities(from),
+        MessageKind::GetRoomPeaks => process_get_room_peaks(from),
+        MessageKind::GetUser { user_id } => process_get_user(from, user_id),
+        MessageKind::MoveRoom { to, secret } => process_move_room(from, to, secret),
+        MessageKind::SelectTemporalLayer { layer } => process_select_temporal_layer(from, layer),
+        MessageKind::RaiseHand { raised } => process_raise_hand(from, raised),
+        MessageKind::SetPriority { priority } => process_set_priority(from, priority),
+        MessageKind::LabelAudio { label } => process_label_audio(from, label),
+        MessageKind::RequestOffer => process_request_offer(from),
+    }
+}
+
+/// Fails if the session has sent an offer newer than the given one since, and we're configured to skip stale offers.
+fn check_offer_current(from: &Session, version: Option<usize>) -> Result<(), Box<Error>> {
+    let latest = from.offer_version.load(Ordering::SeqCst);
+    match version {
+        Some(v) if v != latest && STATE.config.get().unwrap().supersede_stale_offers => {
+            janus_info!("Skipping offer {} from {}, which was superseded by offer {}.", v, from.log_name(), latest);
+            Err(Box::new(CodedError::new("offer_superseded", "Superseded by a newer offer.")))
+        }
+        _ => Ok(())
+    }
+}
+
+/// Returns the given SDP as rewritten by the given function. Janus can't make some changes to an SDP for us, like adding
+/// payload types or touching the data channel's media section, so for those we go through the SDP's text.
+fn rewrite_sdp<F: FnOnce(&str) -> String>(sdp: &Sdp, rewrite: F) -> Result<Sdp, Box<Error>> {
+    let text = rewrite(&sdp.to_glibstring().to_string_lossy());
+    Ok(Sdp::parse(&CString::new(text)?)?)
+}
+
+/// Combines the codec restrictions from the tokens all of the given session's user's connections joined with, so that
+/// a user can't shed theirs by connecting again without a token.
+fn user_publish_codecs(from: &Session) -> PublishCodecs {
+    let mut publish_codecs = from.publish_codecs.lock().unwrap().clone();
+    if let Some(joined) = from.join_state.get() {
+        let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+        for other in switchboard.sessions_for_user(&joined.user_id) {
+            publish_codecs.restrict(&other.publish_codecs.lock().unwrap());
+        }
     }
+    publish_codecs
 }
 
-fn process_offer(from: &Session, offer: &Sdp) -> JsepResult {
+fn process_offer(from: &Arc<Session>, offer: &Sdp, version: Option<usize>) -> JsepResult {
     // enforce publication of the codecs that we know our client base will be compatible with
-    janus_info!("Processing JSEP offer from {:p}: {:?}", from.handle, offer);
+    janus_info!("Processing JSEP offer from {}: {:?}", from.log_name(), offer);
+    check_offer_current(from, version)?;
+    if from.join_state.is_none() {
+        join_default_room(from);
+    }
+    let direction = match from.join_state.get() {
+        Some(joined) if joined.sendrecv => MediaDirection::JANUS_SDP_SENDRECV,
+        _ => MediaDirection::JANUS_SDP_RECVONLY,
+    };
+    let config = STATE.config.get().unwrap();
+    // the room we negotiate for, which we check is still the session's room before telling its subscribers
+    let offered_room = from.join_state.get().map(|joined| joined.room_id.read().unwrap().clone());
+    let room = offered_room.as_ref().map(|r| config.room_settings(r)).unwrap_or_default();
+    let offer_text = offer.to_glibstring().to_string_lossy().into_owned();
+    if !room.audio_enabled && rtp::sends_media(&offer_text, "audio") {
+        return Err(From::from("Audio is disabled in this room."));
+    }
+    if !room.video_enabled && rtp::sends_media(&offer_text, "video") {
+        return Err(From::from("Video is disabled in this room."));
+    }
+    // in rooms which lock down the media after it's first published, only let through offers which don't change it,
+    // e.g. ICE restarts
+    let media_lines = if room.allow_renegotiation { None } else { Some(rtp::media_lines(&offer_text)) };
+    if let (Some(published), Some(lines)) = (&*from.published_media.lock().unwrap(), &media_lines) {
+        if published != lines {
+            janus_warn!("Rejecting offer from {}, which would renegotiate its media: {:?}", from.log_name(), lines);
+            return Err(Box::new(CodedError::new("renegotiation_denied", "This room doesn't allow changing your media once it's published.")));
+        }
+    }
+    // pick the most preferred audio codec that the client can do, its token allows, and we can send on to subscribers;
+    // if it can't do any, the audio just gets rejected, unless it's trying to send audio that we'd have to turn away
+    let publish_codecs = user_publish_codecs(from);
+    let offered_audio_codecs: Vec<_> = KNOWN_AUDIO_CODECS.iter().cloned()
+        .filter(|codec| offer.get_payload_type(codec.to_cstr()).is_some())
+        .collect();
+    let ingest_audio_codecs: Vec<_> = config.audio_codecs.iter().cloned()
+        .filter(|&codec| publish_codecs.allows_audio(codec))
+        .filter(|codec| offered_audio_codecs.contains(codec))
+        .collect();
+    let allowed_audio_codec = ingest_audio_codecs.iter().cloned().find(|codec| config.egress_audio_codecs.contains(codec));
+    if allowed_audio_codec.is_none() && room.audio_enabled && rtp::sends_media(&offer_text, "audio") {
+        if !ingest_audio_codecs.is_empty() {
+            return Err(Box::new(CodedError::new("transcoding_required", "None of the audio codecs offered can be sent on to subscribers.")));
+        }
+        if publish_codecs.audio.is_some() {
+            return Err(Box::new(CodedError::new("codec_not_allowed", "None of the audio codecs offered are ones you're allowed to publish.")));
+        }
+    }
+    let audio_codec = allowed_audio_codec.unwrap_or(config.audio_codecs[0]);
+    // likewise for video, except that the codecs the client said it handles well come first, if we allow any of them
+    let offered_video_codecs: Vec<_> = KNOWN_VIDEO_CODECS.iter().cloned()
+        .filter(|codec| offer.get_payload_type(codec.to_cstr()).is_some())
+        .collect();
+    let capabilities = from.codec_capabilities.lock().unwrap().clone().unwrap_or_default();
+    // H.264 can be restricted to particular profiles, by the config or the token, or both
+    let h264_profiles = match (&config.h264_profile_level_id, &publish_codecs.h264_profiles) {
+        (Some(id), _) if publish_codecs.allows_h264_profile(id) => Some(vec![id.as_str()]),
+        (Some(_), _) => Some(Vec::new()),
+        (None, Some(ids)) => Some(ids.iter().map(|id| id.as_str()).collect()),
+        (None, None) => None,
+    };
+    let offered_h264_profile = h264_profiles.map(|ids| {
+        ids.into_iter().find(|&id| rtp::h264_payload_type(&offer_text, Some(id), config.h264_packetization_mode).is_some())
+    });
+    let ingest_video_codecs: Vec<_> = capabilities.into_iter()
+        .filter(|codec| config.video_codecs.contains(codec))
+        .chain(config.video_codecs.iter().cloned())
+        .filter(|&codec| publish_codecs.allows_video(codec))
+        .filter(|&codec| codec != VideoCodec::H264 || publish_codecs.h264_profiles.is_none() || offered_h264_profile.is_some_and(|id| id.is_some()))
+        .filter(|codec| offered_video_codecs.contains(codec))
+        .collect();
+    let allowed_video_codec = ingest_video_codecs.iter().cloned().find(|codec| config.egress_video_codecs.contains(codec));
+    if allowed_video_codec.is_none() && room.video_enabled && rtp::sends_media(&offer_text, "video") {
+        if !ingest_video_codecs.is_empty() {
+            return Err(Box::new(CodedError::new("transcoding_required", "None of the video codecs offered can be sent on to subscribers.")));
+        }
+        if publish_codecs.video.is_some() {
+            return Err(Box::new(CodedError::new("codec_not_allowed", "None of the video codecs offered are ones you're allowed to publish.")));
+        }
+    }
+    let video_codec = allowed_video_codec.unwrap_or(config.video_codecs[0]);
     let mut answer = answer_sdp!(
         offer,
-        OfferAnswerParameters::AudioCodec, AUDIO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::AudioDirection, MediaDirection::JANUS_SDP_RECVONLY,
-        OfferAnswerParameters::VideoCodec, VIDEO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::VideoDirection, MediaDirection::JANUS_SDP_RECVONLY,
+        OfferAnswerParameters::Audio, room.audio_enabled as i32,
+        OfferAnswerParameters::AudioCodec, audio_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::AudioDirection, direction,
+        OfferAnswerParameters::AudioDtmf, 1,
+        OfferAnswerParameters::Video, room.video_enabled as i32,
+        OfferAnswerParameters::VideoCodec, video_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::VideoDirection, direction,
     );
-    let audio_payload_type = answer.get_payload_type(AUDIO_CODEC.to_cstr());
-    let video_payload_type = answer.get_payload_type(VIDEO_CODEC.to_cstr());
+    let audio_payload_type = answer.get_payload_type(audio_codec.to_cstr()).filter(|_| room.audio_enabled);
+    let mut video_payload_type = answer.get_payload_type(video_codec.to_cstr()).filter(|_| room.video_enabled);
+    // accept DTMF digits alongside the audio, if offered, e.g. by a SIP gateway relaying keypad input
+    let dtmf_payload_type = answer.get_payload_type(c_str!("telephone-event")).filter(|_| audio_payload_type.is_some());
+    // if we're configured to insist on a particular H.264 profile, answer with the payload type that has it, and
+    // pass the same profile on to subscribers
+    let h264_fmtp = match (offered_h264_profile, config.h264_packetization_mode) {
+        (None, None) => None,
+        // an offer that isn't sending video doesn't need any particular profile of it
+        _ if video_codec != VideoCodec::H264 || !room.video_enabled || !rtp::sends_media(&offer_text, "video") => None,
+        (Some(None), _) => return Err(From::from("Offer doesn't include an allowed H.264 profile.")),
+        (profile_level_id, packetization_mode) => {
+            let profile_level_id = profile_level_id.unwrap_or(None);
+            let pt = rtp::h264_payload_type(&offer_text, profile_level_id, packetization_mode)
+                .ok_or("Offer doesn't include the configured H.264 packetization mode.")?;
+            if let Some(chosen) = video_payload_type.filter(|&chosen| chosen != pt) {
+                answer.rewrite_payload_type(chosen, pt);
+            }
+            video_payload_type = Some(pt);
+            let mut params = Vec::new();
+            if let Some(id) = profile_level_id {
+                params.push(format!("profile-level-id={}", id));
+            }
+            if let Some(mode) = packetization_mode {
+                params.push(format!("packetization-mode={}", mode));
+            }
+            Some(params.join(";"))
+        }
+    };
+    if let (Some(pt), Some(ref params)) = (video_payload_type, &h264_fmtp) {
+        answer = rewrite_sdp(&answer, |sdp| rtp::with_fmtp(sdp, pt, params))?;
+    }
+    let mut audio_level_extension = None;
     if let Some(pt) = audio_payload_type {
-        // todo: figure out some more principled way to keep track of this stuff per room
-        let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
-        answer.add_attribute(pt, c_str!("fmtp"), &settings);
+        if audio_codec == AudioCodec::Opus {
+            // todo: figure out some more principled way to keep track of this stuff per room
+            let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
+            answer.add_attribute(pt, c_str!("fmtp"), &settings);
+        }
+        // accept the audio level extension, if offered, so that we can tell when this publisher is speaking
+        if let Some(id) = rtp::extmap_id(&offer_text, rtp::AUDIO_LEVEL_URI) {
+            let extmap = CString::new(format!("{} {}", id, rtp::AUDIO_LEVEL_URI))?;
+            answer.add_attribute(pt, c_str!("extmap"), &extmap);
+            audio_level_extension = Some(id);
+        }
+    }
+    // accept the video orientation extension, if offered, so that subscribers can tell which way up the video goes.
+    // we forward the extension as-is, so subscribers get offered it with the same ID the publisher picked
+    let video_orientation = match video_payload_type {
+        Some(pt) if config.negotiate_video_orientation => {
+            match rtp::extmap_id(&offer_text, rtp::VIDEO_ORIENTATION_URI) {
+                Some(id) => Some((pt, CString::new(format!("{} {}", id, rtp::VIDEO_ORIENTATION_URI))?)),
+                None => None
+            }
+        }
+        _ => None
+    };
+    if let Some((pt, ref extmap)) = video_orientation {
+        answer.add_attribute(pt, c_str!("extmap"), extmap);
+    }
+    // accept the other header extensions we're configured to forward, if offered. subscribers are offered each one under
+    // an ID that doesn't depend on the publisher, and we rewrite the publisher's IDs to match as we forward its packets
+    let orientation_id = video_orientation.as_ref().and_then(|_| rtp::extmap_id(&offer_text, rtp::VIDEO_ORIENTATION_URI));
+    let mut subscriber_extension_ids = (1..=14).rev().filter(|&id| Some(id) != orientation_id);
+    let mut forwarded_extensions = Vec::new();
+    for uri in &config.forwarded_header_extensions {
+        let subscriber_id = match subscriber_extension_ids.next() {
+            Some(id) => id,
+            None => break
+        };
+        for &(video, pt) in &[(false, audio_payload_type), (true, video_payload_type)] {
+            let kind = if video { "video" } else { "audio" };
+            if let (Some(pt), Some(id)) = (pt, rtp::media_extmap_id(&offer_text, kind, uri)) {
+                answer.add_attribute(pt, c_str!("extmap"), &CString::new(format!("{} {}", id, uri))?);
+                forwarded_extensions.push((video, uri, id, subscriber_id));
+            }
+        }
     }
+    // if the publisher offered simulcast, agree to receive every layer it offered, and accept the RTP stream ID
+    // extension so that we can tell which layer is which
+    let rids = video_payload_type.map(|_| rtp::simulcast_rids(&offer_text)).unwrap_or_default();
+    let mut rid_extension = 0;
+    if let (Some(pt), false) = (video_payload_type, rids.is_empty()) {
+        for rid in &rids {
+            answer.add_attribute(pt, c_str!("rid"), &CString::new(format!("{} recv", rid))?);
+        }
+        answer.add_attribute(pt, c_str!("simulcast"), &CString::new(format!("recv {}", rids.join(";")))?);
+        if let Some(id) = rtp::extmap_id(&offer_text, rtp::RTP_STREAM_ID_URI) {
+            answer.add_attribute(pt, c_str!("extmap"), &CString::new(format!("{} {}", id, rtp::RTP_STREAM_ID_URI))?);
+            rid_extension = id as usize;
+        }
+    }
+
+    // tag our SDP with the server build that produced it, so that captured traffic can be traced back to it. there's no
+    // way to add a session-level attribute here, so it goes on the first media section
+    let identity = match audio_payload_type.or(video_payload_type) {
+        Some(pt) if config.advertise_sfu_identity => {
+            let identity = CString::new(format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")))?;
+            answer.add_attribute(pt, c_str!("x-sfu"), &identity);
+            Some(identity)
+        }
+        _ => None
+    };
 
-    janus_verb!("Providing answer to {:p}: {:?}", from.handle, answer);
+    if let Some(bytes) = room.max_data_message_bytes {
+        answer = rewrite_sdp(&answer, |sdp| rtp::with_max_message_size(sdp, bytes))?;
+    }
+    // Janus only answers with one audio codec, so if the room wants redundant audio and the publisher offered it, we add
+    // it ourselves. it refers to the payload type of the audio inside it, so it can't be combined with rewriting that
+    let red_payload_type = match (audio_payload_type, rtp::red_payload_type(&offer_text)) {
+        (Some(pt), Some(red)) if room.audio_red && audio_codec == AudioCodec::Opus && room.audio_payload_type.is_none() => {
+            answer = rewrite_sdp(&answer, |sdp| rtp::with_red(sdp, red, pt))?;
+            Some(red)
+        }
+        _ => None
+    };
+    janus_verb!("Providing answer to {}: {:?}", from.log_name(), answer);
+
+    // offer subscribers the payload types configured for the room, if any, and rewrite packets to match
+    let subscriber_audio_pt = audio_payload_type.and(room.audio_payload_type.map(i32::from)).or(audio_payload_type);
+    let subscriber_video_pt = video_payload_type.and(room.video_payload_type.map(i32::from)).or(video_payload_type);
+    let payload_type_rewrites: Vec<_> = [(audio_payload_type, subscriber_audio_pt), (video_payload_type, subscriber_video_pt)]
+        .iter()
+        .filter_map(|&(pt, subscriber_pt)| match (pt, subscriber_pt) {
+            (Some(pt), Some(subscriber_pt)) if pt != subscriber_pt => Some((pt as u8, subscriber_pt as u8)),
+            _ => None
+        })
+        .collect();
 
     // it's fishy, but we provide audio and video streams to subscribers regardless of whether the client is sending
     // audio and video right now or not -- this is basically working around pains in renegotiation to do with
     // reordering/removing media streams on an existing connection. to improve this, we'll want to keep the same offer
-    // around and mutate it, instead of generating a new one every time the publisher changes something.
+    // around and mutate it, instead of generating a new one every time the publisher changes something. the exception
+    // is rooms configured without audio or video, where nobody can ever publish that kind of media.
 
     let mut subscriber_offer = offer_sdp!(
         ptr::null(),
         answer.c_addr as *const _,
         OfferAnswerParameters::Data, 1,
-        OfferAnswerParameters::Audio, 1,
-        OfferAnswerParameters::AudioCodec, AUDIO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::AudioPayloadType, audio_payload_type.unwrap_or(100),
+        OfferAnswerParameters::Audio, room.audio_enabled as i32,
+        OfferAnswerParameters::AudioCodec, audio_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::AudioPayloadType, subscriber_audio_pt.unwrap_or(100),
         OfferAnswerParameters::AudioDirection, MediaDirection::JANUS_SDP_SENDONLY,
-        OfferAnswerParameters::Video, 1,
-        OfferAnswerParameters::VideoCodec, VIDEO_CODEC.to_cstr().as_ptr(),
-        OfferAnswerParameters::VideoPayloadType, video_payload_type.unwrap_or(100),
+        OfferAnswerParameters::Video, room.video_enabled as i32,
+        OfferAnswerParameters::VideoCodec, video_codec.to_cstr().as_ptr(),
+        OfferAnswerParameters::VideoPayloadType, subscriber_video_pt.unwrap_or(100),
         OfferAnswerParameters::VideoDirection, MediaDirection::JANUS_SDP_SENDONLY,
     );
-    if let Some(pt) = audio_payload_type {
-        // todo: figure out some more principled way to keep track of this stuff per room
-        let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
-        subscriber_offer.add_attribute(pt, c_str!("fmtp"), &settings);
+    if let Some(pt) = subscriber_audio_pt {
+        if audio_codec == AudioCodec::Opus {
+            // todo: figure out some more principled way to keep track of this stuff per room
+            let settings = CString::new(format!("{} stereo=0; sprop-stereo=0; usedtx=1;", pt))?;
+            subscriber_offer.add_attribute(pt, c_str!("fmtp"), &settings);
+        }
+    }
+    if let (Some(pt), Some((_, ref extmap))) = (subscriber_video_pt, &video_orientation) {
+        subscriber_offer.add_attribute(pt, c_str!("extmap"), extmap);
+    }
+    for &(video, uri, _, subscriber_id) in &forwarded_extensions {
+        if let Some(pt) = if video { subscriber_video_pt } else { subscriber_audio_pt } {
+            subscriber_offer.add_attribute(pt, c_str!("extmap"), &CString::new(format!("{} {}", subscriber_id, uri))?);
+        }
+    }
+    if let (Some(pt), Some(ref params)) = (subscriber_video_pt, &h264_fmtp) {
+        subscriber_offer = rewrite_sdp(&subscriber_offer, |sdp| rtp::with_fmtp(sdp, pt, params))?;
+    }
+    if let (Some(pt), Some(ref identity)) = (subscriber_audio_pt.or(subscriber_video_pt), &identity) {
+        subscriber_offer.add_attribute(pt, c_str!("x-sfu"), identity);
     }
-    janus_verb!("Storing subscriber offer for {:p}: {:?}", from.handle, subscriber_offer);
+    if let Some(bytes) = room.max_data_message_bytes {
+        subscriber_offer = rewrite_sdp(&subscriber_offer, |sdp| rtp::with_max_message_size(sdp, bytes))?;
+    }
+    if let (Some(red), Some(pt)) = (red_payload_type, subscriber_audio_pt) {
+        subscriber_offer = rewrite_sdp(&subscriber_offer, |sdp| rtp::with_red(sdp, red, pt))?;
+    }
+    janus_verb!("Storing subscriber offer for {}: {:?}", from.log_name(), subscriber_offer);
 
     let switchboard = STATE.switchboard.read().expect("Switchboard lock poisoned; can't continue.");
+    // a room move could have slipped in since we started, in which case this negotiation went by the wrong room's
+    // settings, and our subscribers have already been dropped; make the client offer again for the room it's in now
+    let current_room = from.join_state.get().map(|joined| joined.room_id.read().unwrap().clone());
+    if current_room != offered_room {
+        janus_warn!("Rejecting offer from {}, which moved from room {:?} to {:?} while it was processed.", from.log_name(), offered_room, current_room);
+        return Err(From::from("Room changed while processing offer; please offer again."));
+    }
+    // likewise if the client offered again since, its subscribers only need the offer for the newest one. nothing
+    // about the session changes until it passes both checks, so a rejected offer can't clobber a newer one's setup
+    check_offer_current(from, version)?;
+    from.dtmf_payload_type.store(dtmf_payload_type.unwrap_or(0) as usize, Ordering::Relaxed);
+    if let Some(id) = audio_level_extension {
+        from.audio_level_extension.store(id as usize, Ordering::Relaxed);
+    }
+    *from.extension_id_rewrites.lock().unwrap() = forwarded_extensions.iter().map(|&(_, _, id, subscriber_id)| (id, subscriber_id)).collect();
+    from.rid_extension.store(rid_extension, Ordering::Relaxed);
+    *from.simulcast_rids.lock().unwrap() = rids.into_iter().map(|rid| (rid, None)).collect();
+    // a renegotiation can bring back media the publisher said goodbye to, or drop its audio
+    from.said_bye.lock().unwrap().clear();
+    from.audio_ssrc.store(usize::MAX, Ordering::Relaxed);
+    *from.last_spoke.lock().unwrap() = Instant::now();
+    *from.audio_codec.lock().unwrap() = audio_payload_type.map(|_| audio_codec);
+    *from.offered_audio_codecs.lock().unwrap() = offered_audio_codecs;
+    *from.video_codec.lock().unwrap() = video_payload_type.map(|_| video_codec);
+    *from.offered_video_codecs.lock().unwrap() = offered_video_codecs;
+    from.red_payload_type.store(red_payload_type.unwrap_or(0) as usize, Ordering::Relaxed);
+    from.audio_payload_type.store(audio_payload_type.unwrap_or(0) as usize, Ordering::Relaxed);
+    *from.payload_type_rewrites.lock().unwrap() = payload_type_rewrites;
     let jsep = json!({ "type": "offer", "sdp": subscriber_offer });
     send_offer(&jsep, switchboard.subscribers_to(from));
-    *from.subscriber_offer.lock().unwrap() = Some(subscriber_offer);
+    if let Some(lines) = media_lines {
+        from.published_media.lock().unwrap().get_or_insert(lines);
+    }
+    if from.subscriber_offer.lock().unwrap().replace(subscriber_offer).is_none() {
+        hold_new_publication(from);
+    }
+    // the user's labeled audio tracks go out under whatever payload type this offered for its audio
+    if let (Some(joined), false) = (from.join_state.get(), from.audio_labeled.load(Ordering::Relaxed)) {
+        let payload_type = subscriber_audio_pt.unwrap_or(0) as usize;
+        for track in switchboard.sessions_for_user(&joined.user_id) {
+            if track.audio_labeled.load(Ordering::Relaxed) && track.track_payload_type.load(Ordering::Relaxed) != 0 {
+                track.track_payload_type.store(payload_type, Ordering::Relaxed);
+            }
+        }
+    }
+    if from.demoted.swap(false, Ordering::Relaxed) && !from.unpublished.load(Ordering::Relaxed) {
+        announce_publishing(&switchboard, from);
+    }
+    drop(switchboard);
+    if let (Some(room), true) = (current_room, from.is_publishing()) {
+        STATE.switchboard.write()?.update_room_peaks(&room);
+    }
     Ok(json!({ "type": "answer", "sdp": answer }))
 }
 
 fn process_answer(from: &Session, answer: &Sdp) -> JsepResult {
-    janus_info!("Processing JSEP answer from {:p}: {:?}", from.handle, answer);
+    janus_info!("Processing JSEP answer from {}: {:?}", from.log_name(), answer);
+    let receives_red = rtp::red_payload_type(&answer.to_glibstring().to_string_lossy()).is_some();
+    from.receives_red.store(receives_red, Ordering::Relaxed);
     Ok(json!({})) // todo: check that this guy should actually be sending us an answer?
 }
 
-fn process_jsep(from: &Session, jsep: JsepKind) -> JsepResult {
+/// Remembers which types of ICE candidates the given SDP from the given session has, if any, so that we can tell whether
+/// its connection is likely to be relayed.
+fn record_candidate_types(from: &Session, sdp: &Sdp) {
+    let types = rtp::candidate_types(&sdp.to_glibstring().to_string_lossy());
+    if types.is_empty() {
+        return;
+    }
+    if types.iter().all(|t| t == "relay") {
+        janus_warn!("Session {} only offered relay ICE candidates; its connection is probably going through TURN.", from.log_name());
+    }
+    *from.candidate_types.lock().unwrap() = types;
+}
+
+fn process_jsep(from: &Arc<Session>, jsep: JsepKind, offer_version: Option<usize>) -> JsepResult {
     match jsep {
-        JsepKind::Offer { sdp } => process_offer(from, &sdp),
-        JsepKind::Answer { sdp } => process_answer(from, &sdp),
+        JsepKind::Offer { sdp } => {
+            record_candidate_types(from, &sdp);
+            process_offer(from, &sdp, offer_version)
+        }
+        JsepKind::Answer { sdp } => {
+            record_candidate_types(from, &sdp);
+            process_answer(from, &sdp)
+        }
     }
 }
 
 fn push_response(from: &Session, txn: &TransactionId, body: &JsonValue, jsep: Option<JsonValue>) -> JanusResult {
     let push_event = gateway_callbacks().push_event;
     let jsep = jsep.unwrap_or_else(|| json!({}));
-    janus_huge!("Responding to {:p} for txid {}: body={}, jsep={}", from.handle, txn, body, jsep);
+    janus_huge!("Responding to {} for txid {}: body={}, jsep={}", from.log_name(), txn, body, jsep);
     JanusError::from(push_event(from.as_ptr(), &mut PLUGIN, txn.0, serde_to_jansson(body).as_mut_ref(), serde_to_jansson(&jsep).as_mut_ref()))
 }
 
+/// Formats an error processing a message into a response, including the error's code if it has one.
+fn error_response(err: &(Error + 'static)) -> JsonValue {
+    match err.downcast_ref::<CodedError>() {
+        Some(coded) => json!({ "success": false, "error": { "msg": &coded.msg, "code": coded.code }}),
+        None => json!({ "success": false, "error": { "msg": format!("{}", err) }})
+    }
+}
+
 fn try_parse_jansson<T: DeserializeOwned>(json: &JanssonValue) -> Result<Option<T>, Box<Error>> {
     jansson_to_str(json).and_then(|x| OptionalField::try_parse(x.to_string_lossy()))
 }
 
-fn handle_message_async(RawMessage { jsep, msg, txn, from }: RawMessage) -> JanusResult {
+fn try_parse_jsep(json: &JanssonValue) -> Result<Option<UnparsedJsep>, Box<Error>> {
+    let max_sdp_bytes = STATE.config.get().unwrap().max_sdp_bytes;
+    jansson_to_str(json).and_then(|x| UnparsedJsep::try_parse_limited(x.to_string_lossy(), max_sdp_bytes))
+}
+
+/// Parses the message and JSEP that came with a signalling message, either of which may be missing or empty, leaving
+/// the JSEP's SDP to be parsed when it's processed.
+fn parse_raw_message(msg: Option<JanssonValue>, jsep: Option<JanssonValue>) -> Result<(Option<MessageKind>, Option<UnparsedJsep>), Box<Error>> {
+    let msg = match msg {
+        Some(ref x) => try_parse_jansson(x)?,
+        None => None
+    };
+    let jsep = match jsep {
+        Some(ref x) => try_parse_jsep(x)?,
+        None => None
+    };
+    Ok((msg, jsep))
+}
+
+fn handle_message_async(RawMessage { jsep, msg, txn, from, offer_version }: RawMessage) -> JanusResult {
     if let Some(ref from) = from.upgrade() {
-        janus_huge!("Processing txid {} from {:p}: msg={:?}, jsep={:?}", txn, from.handle, msg, jsep);
+        janus_huge!("Processing txid {} from {}: msg={:?}, jsep={:?}", txn, from.log_name(), msg, jsep);
         if !from.destroyed.load(Ordering::Relaxed) {
             // process the message first, because processing a JSEP can cause us to want to send an RTCP
             // FIR to our subscribers, which may have been established in the message
-            let parsed_msg = msg.and_then(|x| transpose(try_parse_jansson(&x)));
-            let parsed_jsep = jsep.and_then(|x| transpose(try_parse_jansson(&x)));
-            let msg_result = parsed_msg.map(|x| x.and_then(|msg| process_message(from, msg)));
-            let jsep_result = parsed_jsep.map(|x| x.and_then(|jsep| process_jsep(from, jsep)));
+            let msg_result = msg.map(|msg| process_message(from, msg));
+            let jsep_result = jsep.map(|jsep| jsep.parse().and_then(|jsep| process_jsep(from, jsep, offer_version)));
             return match (msg_result, jsep_result) {
                 (Some(Err(msg_err)), _) => {
-                    let resp = json!({ "success": false, "error": { "msg": format!("{}", msg_err) }});
-                    push_response(from, &txn, &resp, None)
+                    push_response(from, &txn, &error_response(&*msg_err), None)
                 }
                 (_, Some(Err(jsep_err))) => {
-                    let resp = json!({ "success": false, "error": { "msg": format!("{}", jsep_err) }});
-                    push_response(from, &txn, &resp, None)
+                    push_response(from, &txn, &error_response(&*jsep_err), None)
                 }
                 (Some(Ok(msg_resp)), None) => {
                     let msg_body = msg_resp.body.map_or(json!({ "success": true }), |x| {
@@ -698,15 +3602,32 @@ fn handle_message_async(RawMessage { jsep, msg, txn, from }: RawMessage) -> Janu
 extern "C" fn handle_message(handle: *mut PluginSession, transaction: *mut c_char,
                              message: *mut RawJanssonValue, jsep: *mut RawJanssonValue) -> *mut RawPluginResult {
     let result = match unsafe { Session::from_ptr(handle) } {
+        Ok(ref sess) if !sess.message_bucket.lock().unwrap().try_take(Instant::now()) => {
+            janus_warn!("Rejecting signalling message on {} due to rate limiting.", sess.log_name());
+            PluginResult::error(c_str!("Rate limited."))
+        }
         Ok(sess) => {
-            let msg = RawMessage {
-                from: Arc::downgrade(&sess),
-                txn: TransactionId(transaction),
-                msg: unsafe { JanssonValue::from_raw(message) },
-                jsep: unsafe { JanssonValue::from_raw(jsep) }
-            };
-            janus_info!("Queueing signalling message on {:p}.", sess.handle);
-            STATE.message_channel.get().unwrap().send(msg).ok();
+            let txn = TransactionId(transaction);
+            // parse messages as they arrive, so that a malformed one is answered right away, rather than once it gets
+            // to the front of the queue. SDPs are slower to parse, so those wait for the message thread
+            let parsed = unsafe { parse_raw_message(JanssonValue::from_raw(message), JanssonValue::from_raw(jsep)) };
+            match parsed {
+                Ok((msg, jsep)) => {
+                    // number offers as they arrive, rather than as they're processed, so that a newer one waiting in
+                    // the queue can supersede an older one
+                    let offer_version = match jsep {
+                        Some(UnparsedJsep::Offer { .. }) => Some(sess.offer_version.fetch_add(1, Ordering::SeqCst) + 1),
+                        _ => None
+                    };
+                    let msg = RawMessage { from: Arc::downgrade(&sess), txn, msg, jsep, offer_version };
+                    janus_info!("Queueing signalling message on {}.", sess.log_name());
+                    STATE.message_channel.get().unwrap().send(msg).ok();
+                }
+                Err(e) => {
+                    janus_warn!("Rejecting malformed signalling message on {}: {}", sess.log_name(), e);
+                    push_response(&sess, &txn, &error_response(&*e), None).ok();
+                }
+            }
             PluginResult::ok_wait(Some(c_str!("Processing.")))
         },
         Err(_) => PluginResult::error(c_str!("No handle associated with message!"))
@@ -739,3 +3660,53 @@ const PLUGIN: Plugin = build_plugin!(
 );
 
 export_plugin!(&PLUGIN);
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use switchboard::tests::join;
+
+    fn refusal_code(result: Result<(), Box<Error>>) -> &'static str {
+        result.unwrap_err().downcast_ref::<CodedError>().unwrap().code
+    }
+
+    #[test]
+    fn joins_need_the_room_secret() {
+        let config = Config::default();
+        let mut switchboard = Switchboard::new();
+        join(&mut switchboard, 1, "room", "alice");
+        let room_id: RoomId = "room".into();
+        assert!(check_admission(&switchboard, &config, &room_id, &"bob".into(), false, false, None).is_ok());
+
+        switchboard.set_room_secret(&room_id, "hunter2".into());
+        assert_eq!(refusal_code(check_admission(&switchboard, &config, &room_id, &"bob".into(), false, false, None)), "wrong_secret");
+        assert_eq!(refusal_code(check_admission(&switchboard, &config, &room_id, &"bob".into(), false, false, Some("guess"))), "wrong_secret");
+        assert!(check_admission(&switchboard, &config, &room_id, &"bob".into(), false, false, Some("hunter2")).is_ok());
+        assert!(check_admission(&switchboard, &config, &room_id, &"bob".into(), true, false, None).is_ok());
+
+        // claiming to be someone who's already in the room isn't enough, but a token saying so is
+        assert_eq!(refusal_code(check_admission(&switchboard, &config, &room_id, &"alice".into(), false, false, None)), "wrong_secret");
+        assert!(check_admission(&switchboard, &config, &room_id, &"alice".into(), false, true, None).is_ok());
+        assert_eq!(refusal_code(check_admission(&switchboard, &config, &room_id, &"bob".into(), false, true, None)), "wrong_secret");
+    }
+
+    #[test]
+    fn goodbyes_for_all_media_unpublish_and_notify_subscribers() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+
+        // alice sends audio as 7 and video as 9, and says goodbye to each separately
+        let sending = [7, 9];
+        let mut goodbyes = Goodbyes::default();
+        assert!(!goodbyes.record(&sending, &rtcp::bye_ssrcs(&[0x81, 203, 0, 1, 0, 0, 0, 7])));
+        assert!(goodbyes.record(&sending, &rtcp::bye_ssrcs(&[0x81, 203, 0, 1, 0, 0, 0, 9])));
+
+        switchboard.unpublish(&alice);
+        assert!(switchboard.subscribers_to(&alice).is_empty());
+        let notified: Vec<_> = switchboard.roommates_to_notify(&"room".into(), &"alice".into()).map(|s| s.handle_id()).collect();
+        assert_eq!(notified, vec!["mock-2"]);
+    }
+}
@@ -1,9 +1,13 @@
 /// Types and code related to handling signalling messages.
 use super::Sdp;
+use rtp::VideoConstraints;
 use super::serde_json;
+use serde_json::Value as JsonValue;
 use serde::de::DeserializeOwned;
 use std::error::Error;
 use std::borrow::Borrow;
+use std::ffi::CString;
+use std::fmt;
 
 /// A room ID representing a Janus multicast room.
 pub type RoomId = String;
@@ -12,6 +16,28 @@ pub type RoomId = String;
 /// conceptual user for managing subscriptions.
 pub type UserId = String;
 
+/// An error processing a signalling message which carries a machine-readable code, so that clients can tell it apart
+/// from other failures.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodedError {
+    pub code: &'static str,
+    pub msg: String,
+}
+
+impl CodedError {
+    pub fn new(code: &'static str, msg: impl Into<String>) -> Self {
+        Self { code, msg: msg.into() }
+    }
+}
+
+impl Error for CodedError {}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.msg)
+    }
+}
+
 /// Useful to represent a JSON message field which may or may not be present.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(untagged)]
@@ -31,11 +57,27 @@ impl<T> Into<Option<T>> for OptionalField<T> {
 }
 
 impl<T> OptionalField<T> where T: DeserializeOwned {
+    /// Parses a field which may be an empty object. If it's malformed, the error says what's wrong and where, which
+    /// parsing it as an `OptionalField` can't, since untagged enums only say that nothing matched.
     pub fn try_parse(val: impl Borrow<str>) -> Result<Option<T>, Box<Error>> {
-        Ok(serde_json::from_str::<OptionalField<T>>(val.borrow()).map(|x| x.into())?)
+        let raw: JsonValue = serde_json::from_str(val.borrow()).map_err(malformed)?;
+        parse_present(val.borrow(), &raw)
     }
 }
 
+/// Parses the given text, which has already been parsed as the given JSON value, unless it's an empty object.
+fn parse_present<T: DeserializeOwned>(text: &str, raw: &JsonValue) -> Result<Option<T>, Box<Error>> {
+    if raw.as_object().is_some_and(|fields| fields.is_empty()) {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(text).map_err(malformed)?))
+}
+
+/// Turns an error parsing a message or JSEP into one which clients can tell apart from errors processing it.
+fn malformed(err: serde_json::Error) -> Box<Error> {
+    Box::new(CodedError::new("malformed_json", format!("Malformed JSON: {}.", err)))
+}
+
 /// A signalling message carrying a JSEP SDP offer or answer.
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "type")]
@@ -47,8 +89,52 @@ pub enum JsepKind {
     Answer { sdp: Sdp },
 }
 
-/// The enumeration of all (non-JSEP) signalling messages which can be received from a client.
+/// A JSEP as it arrives, with its SDP not yet parsed. Parsing an SDP is comparatively slow, so it waits until the JSEP
+/// is processed, rather than holding up whichever Janus thread handed it to us.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase", tag = "type")]
+pub enum UnparsedJsep {
+    Offer { sdp: String },
+    Answer { sdp: String },
+}
+
+impl UnparsedJsep {
+    /// Parses an optional JSEP, apart from its SDP, rejecting it if the SDP is obviously malformed or is longer than
+    /// `max_sdp_bytes`.
+    pub fn try_parse_limited(val: impl Borrow<str>, max_sdp_bytes: usize) -> Result<Option<Self>, Box<Error>> {
+        let raw: JsonValue = serde_json::from_str(val.borrow()).map_err(malformed)?;
+        check_sdp(&raw, max_sdp_bytes)?;
+        parse_present(val.borrow(), &raw)
+    }
+
+    /// Parses the SDP.
+    pub fn parse(self) -> Result<JsepKind, Box<Error>> {
+        fn parse_sdp(sdp: String) -> Result<Sdp, Box<Error>> {
+            Sdp::parse(&CString::new(sdp)?)
+                .map_err(|e| From::from(CodedError::new("malformed_sdp", format!("Malformed SDP: {}.", e))))
+        }
+        Ok(match self {
+            UnparsedJsep::Offer { sdp } => JsepKind::Offer { sdp: parse_sdp(sdp)? },
+            UnparsedJsep::Answer { sdp } => JsepKind::Answer { sdp: parse_sdp(sdp)? },
+        })
+    }
+}
+
+/// Checks that the SDP in an unparsed JSEP, if any, is no longer than `max_sdp_bytes` and at least starts out like an
+/// SDP, so that we don't bother handing anything hopeless to the SDP parser.
+pub fn check_sdp(jsep: &JsonValue, max_sdp_bytes: usize) -> Result<(), Box<Error>> {
+    match jsep.get("sdp").and_then(JsonValue::as_str) {
+        Some(sdp) if sdp.len() > max_sdp_bytes => {
+            Err(From::from(format!("SDP is too large ({} bytes, limit is {}).", sdp.len(), max_sdp_bytes)))
+        }
+        Some(sdp) if sdp.trim().is_empty() => Err(From::from("SDP is empty.")),
+        Some(sdp) if !sdp.trim_start().starts_with("v=") => Err(From::from("SDP doesn't start with a version line.")),
+        _ => Ok(())
+    }
+}
+
+/// The enumeration of all (non-JSEP) signalling messages which can be received from a client.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 #[serde(rename_all = "lowercase", tag = "kind")]
 pub enum MessageKind {
     /// Indicates that a client wishes to "join" a room on the server. Prior to this, no audio, video, or data
@@ -56,11 +142,46 @@ pub enum MessageKind {
     ///
     /// The "subscribe" field specifies which kind of traffic this client will receive. (Useful for saving a round
     /// trip if you wanted to both join and subscribe, as is typical.)
+    ///
+    /// The "sendrecv" field asks for this connection's media to be negotiated in both directions, with the client's
+    /// own audio and video looped back to it, instead of the usual publish-only connection.
+    ///
+    /// The "metadata" field is arbitrary JSON which is passed along to other users in the room, for the client to
+    /// interpret however it likes.
+    ///
+    /// The "notify_when_available" field asks that, if the room is full, the client be told when it has space again.
+    ///
+    /// The "codec_capabilities" field lists the video codecs the client handles well, e.g. in hardware, in order of
+    /// preference, so that offers it sends after joining negotiate one of them if the server allows it.
+    ///
+    /// The "secret" field is the room's secret, if a moderator has set one; see `RotateRoomSecret`.
+    ///
+    /// The "correlation_id" field is an opaque ID which is logged alongside this connection's handle and reported back
+    /// by the admin API, so that whoever created the connection can find its activity in our logs.
     Join {
         room_id: RoomId,
         user_id: UserId,
         subscribe: Option<Subscription>,
-        token: Option<String>
+        token: Option<String>,
+        #[serde(default)]
+        sendrecv: bool,
+        #[serde(default)]
+        metadata: JsonValue,
+        #[serde(default)]
+        notify_when_available: bool,
+        codec_capabilities: Option<Vec<String>>,
+        secret: Option<String>,
+        correlation_id: Option<String>,
+    },
+
+    /// Makes this connection an observer of the given room, which gets all of the data sent in the room without being in
+    /// it, e.g. for a monitoring bot. Observers can't send data unless "send_data" is set. Requires a token with admin
+    /// permissions.
+    Observe {
+        room_id: RoomId,
+        token: String,
+        #[serde(default)]
+        send_data: bool
     },
 
     /// Indicates that the given user should be disconnected from the given room. Requires a token bequeathing
@@ -71,6 +192,38 @@ pub enum MessageKind {
         token: String
     },
 
+    /// Indicates that the connection with the given handle ID should be disconnected, wherever it is. Requires a
+    /// token with admin permissions.
+    KillSession {
+        handle_id: String,
+        token: String
+    },
+
+    /// Locks or unlocks the room you're in against new joins. Requires being a moderator of the room, or a token with
+    /// kick permissions.
+    LockRoom {
+        locked: bool,
+        token: Option<String>
+    },
+
+    /// Sends an announcement to everyone in the room you're in, whether or not they subscribed to notifications. Requires
+    /// being a moderator of the room.
+    Announce { text: String },
+
+    /// Sets a new secret for the room you're in, replacing any old one. Everyone in the room stays, but from then on,
+    /// anyone else joining it has to give the new secret. Requires being a moderator of the room.
+    RotateRoomSecret { new_secret: String },
+
+    /// Hands this user's moderator role in the room they're in over to another user in the room.
+    TransferModerator { to: UserId },
+
+    /// Pins the given user in your room, so that their media is never demoted or degraded, e.g. because they're
+    /// presenting. Requires being a moderator.
+    Pin { user_id: UserId },
+
+    /// Undoes a pin of the given user.
+    Unpin { user_id: UserId },
+
     /// Indicates that a client wishes to subscribe to traffic described by the given subscription specification.
     Subscribe { what: Subscription },
 
@@ -81,11 +234,107 @@ pub enum MessageKind {
     /// Undoes a block targeting the given user.
     Unblock { whom: UserId },
 
+    /// Stops forwarding the media of the given user, which you're subscribed to, without tearing down the
+    /// subscription, e.g. while it's off-screen.
+    PauseSubscription { whom: UserId },
+
+    /// Starts forwarding the media of the given user again after a pause.
+    ResumeSubscription { whom: UserId },
+
+    /// Stops forwarding the given kinds of media from the given user to you, without blocking them entirely; they still
+    /// get your traffic, and you still get their data and any kind of media you didn't mute. Replaces any earlier mute
+    /// of the same user, so muting neither kind unmutes them.
+    MuteUser {
+        whom: UserId,
+        #[serde(default)]
+        audio: bool,
+        #[serde(default)]
+        video: bool,
+    },
+
     /// Sends arbitrary data to either all other clients in the room with you, or to a single other client.
     Data {
         whom: Option<UserId>,
         body: String
-    }
+    },
+
+    /// Asks what this server supports, so that clients can adapt to different deployments.
+    GetCapabilities,
+
+    /// Asks for the most users and publishers the room you're in has had at once since it was last empty.
+    GetRoomPeaks,
+
+    /// Asks for what's public about one user in the room you're in, e.g. their join metadata and whether they're
+    /// publishing, without listing everyone else.
+    GetUser { user_id: UserId },
+
+    /// Moves this user from the room they're in to another room, without leaving and rejoining. Any media
+    /// subscriptions to or from the user are dropped, since they belonged to the old room.
+    ///
+    /// The "secret" field is the new room's secret, if a moderator has set one, as for `Join`.
+    MoveRoom {
+        to: RoomId,
+        secret: Option<String>
+    },
+
+    /// Limits the video this connection receives to the given VP8/VP9 temporal layer and below, or lifts the limit
+    /// if no layer is given. Has no effect on H.264 video.
+    SelectTemporalLayer { layer: Option<usize> },
+
+    /// Changes the priority of this connection's media subscription. See `Subscription::priority`.
+    SetPriority { priority: i32 },
+
+    /// Starts forwarding the media this connection negotiated to anyone who subscribes to it, after an unpublish.
+    Publish,
+
+    /// Stops forwarding this connection's media, tearing down everyone's subscriptions to it, without leaving the room
+    /// or renegotiating this connection.
+    Unpublish,
+
+    /// Labels the audio this connection publishes as an alternative audio track for its user, e.g. an interpretation
+    /// into another language, or removes the label. Subscribers to the user only get a labeled track if they ask for it
+    /// by label, and this connection's video isn't forwarded while it's labeled.
+    LabelAudio { label: Option<String> },
+
+    /// Asks for a fresh offer for this connection's media subscription, e.g. to recover after a network change,
+    /// without renegotiating anyone else subscribed to the same user.
+    RequestOffer,
+
+    /// Asks for everything the server knows about a connection, for debugging. Without a handle ID, describes the
+    /// connection the message was sent on; describing any other connection requires a token with admin permissions.
+    Diagnostics {
+        handle_id: Option<String>,
+        token: Option<String>
+    },
+
+    /// Checks the server's records of rooms and subscriptions for anything referring to connections which are gone, or
+    /// which disagrees with itself, and optionally cleans it up. Requires a token with admin permissions.
+    Verify {
+        token: String,
+        #[serde(default)]
+        prune: bool
+    },
+
+    /// Asks for the settings the server is running with, without the values of any secrets. Requires a token with admin
+    /// permissions.
+    GetConfig { token: String },
+
+    /// Turns the server's audio-only mode, where no video is forwarded in any room, on or off. Requires a token with admin
+    /// permissions.
+    AudioOnly {
+        token: String,
+        enabled: bool
+    },
+
+    /// Joins this connection to the room it was in before, with the same user ID, subscription, and join settings, using
+    /// a resume token returned by an earlier join.
+    Resume { token: String },
+
+    /// Reports statistics that the client measured about the media it's receiving on this connection.
+    ReportStats { stats: ClientStats },
+
+    /// Raises or lowers this user's hand in the room they're in. Other occupants are notified of the change.
+    RaiseHand { raised: bool },
 }
 
 /// Information about which traffic a client will get pushed to them.
@@ -100,6 +349,71 @@ pub struct Subscription {
 
     /// Whether to subscribe to media (audio and video) from a particular user.
     pub media: Option<UserId>,
+
+    /// Whether to subscribe to media from a particular publishing connection which never joined a room, by its handle
+    /// ID. Only allowed if the server doesn't require joining for media, and not together with `media`.
+    pub media_handle: Option<String>,
+
+    /// How important the media subscription is relative to the user's other media subscriptions. When the user's
+    /// connection is congested, video from lower priority subscriptions is degraded first.
+    pub priority: i32,
+
+    /// Whether to forward only the key frames of the subscribed user's video, e.g. for a thumbnail which doesn't need
+    /// smooth motion. Audio is unaffected.
+    pub keyframes_only: bool,
+
+    /// Which of the subscribed user's labeled audio tracks to receive instead of their main audio, e.g. an
+    /// interpretation into another language. See `MessageKind::LabelAudio`.
+    pub audio_label: Option<String>,
+
+    /// The widest video the subscriber wants, e.g. because of the size it's shown at. If the subscribed user sends
+    /// simulcast, the biggest layer within this and `max_height` is forwarded, adjusting as the layers change.
+    pub max_width: Option<u16>,
+
+    /// The tallest video the subscriber wants. See `max_width`.
+    pub max_height: Option<u16>,
+
+    /// The most frames per second of video the subscriber wants, which is kept to by dropping temporal layers. Does
+    /// nothing for H.264, which we can't tell the temporal layers of.
+    pub max_fps: Option<u32>,
+}
+
+impl Subscription {
+    pub fn video_constraints(&self) -> VideoConstraints {
+        VideoConstraints { max_width: self.max_width, max_height: self.max_height, max_fps: self.max_fps }
+    }
+}
+
+/// Statistics a client measured about the media it's receiving, e.g. from `getStats()`. Every field is optional, since
+/// not every browser reports everything.
+#[derive(Debug, Clone, PartialEq, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientStats {
+    /// The interarrival jitter of the received audio and video, in milliseconds.
+    pub jitter_ms: Option<f64>,
+
+    /// The fraction of packets lost, from 0 to 1.
+    pub packet_loss: Option<f64>,
+
+    /// The framerate of the received video, in frames per second.
+    pub framerate: Option<f64>,
+}
+
+impl ClientStats {
+    /// Checks that every statistic is within a plausible range, so that nonsense can't skew the aggregates.
+    pub fn validate(&self) -> Result<(), Box<Error>> {
+        let in_range = |value: Option<f64>, max: f64| value.is_none_or(|x| x.is_finite() && (0.0..=max).contains(&x));
+        if !in_range(self.jitter_ms, 60_000.0) {
+            return Err(From::from("Jitter must be between 0 and 60000 milliseconds."));
+        }
+        if !in_range(self.packet_loss, 1.0) {
+            return Err(From::from("Packet loss must be a fraction between 0 and 1."));
+        }
+        if !in_range(self.framerate, 240.0) {
+            return Err(From::from("Framerate must be between 0 and 240 frames per second."));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -126,6 +440,28 @@ mod tests {
             assert!(result.is_err());
         }
 
+        fn malformed_msg<T: fmt::Debug>(result: Result<Option<T>, Box<Error>>) -> String {
+            let err = result.unwrap_err();
+            let coded = err.downcast_ref::<CodedError>().unwrap();
+            assert_eq!(coded.code, "malformed_json");
+            coded.msg.clone()
+        }
+
+        #[test]
+        fn describe_malformed_messages() {
+            assert_eq!(OptionalField::<MessageKind>::try_parse("{}").unwrap(), None);
+            // serde can't say where the problem is once it's buffered up a tagged message's fields
+            assert_eq!(malformed_msg(OptionalField::<MessageKind>::try_parse(r#"{"kind": "join", "room_id": "alpha"}"#)),
+                       "Malformed JSON: missing field `user_id`.");
+            assert_eq!(malformed_msg(OptionalField::<MessageKind>::try_parse(r#"{"kind": "kick", "room_id": 5}"#)),
+                       "Malformed JSON: invalid type: integer `5`, expected a string.");
+            let unknown = malformed_msg(OptionalField::<MessageKind>::try_parse(r#"{"kind": "fiddle"}"#));
+            assert!(unknown.starts_with("Malformed JSON: unknown variant `fiddle`, expected one of `join`"));
+            assert!(unknown.ends_with(" at line 1 column 17."));
+            assert_eq!(malformed_msg(OptionalField::<MessageKind>::try_parse("[1, 2")),
+                       "Malformed JSON: EOF while parsing a list at line 1 column 5.");
+        }
+
         #[test]
         fn parse_outer_error() {
             let json = r#"{"kind": "fiddle"}"#;
@@ -141,7 +477,13 @@ mod tests {
                 user_id: "10".into(),
                 room_id: "alpha".into(),
                 subscribe: None,
-                token: Some(String::from("foo"))
+                token: Some(String::from("foo")),
+                sendrecv: false,
+                metadata: JsonValue::Null,
+                notify_when_available: false,
+                codec_capabilities: None,
+                secret: None,
+                correlation_id: None
             });
         }
 
@@ -155,9 +497,94 @@ mod tests {
                 subscribe: Some(Subscription {
                     notifications: true,
                     data: false,
-                    media: None
+                    media: None,
+                    media_handle: None,
+                    priority: 0,
+                    keyframes_only: false,
+                    audio_label: None,
+                    max_width: None,
+                    max_height: None,
+                    max_fps: None
                 }),
-                token: None
+                token: None,
+                sendrecv: false,
+                metadata: JsonValue::Null,
+                notify_when_available: false,
+                codec_capabilities: None,
+                secret: None,
+                correlation_id: None
+            });
+        }
+
+        #[test]
+        fn parse_join_sendrecv() {
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "5", "sendrecv": true}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Join {
+                user_id: "10".into(),
+                room_id: "5".into(),
+                subscribe: None,
+                token: None,
+                sendrecv: true,
+                metadata: JsonValue::Null,
+                notify_when_available: false,
+                codec_capabilities: None,
+                secret: None,
+                correlation_id: None
+            });
+        }
+
+        #[test]
+        fn parse_join_metadata() {
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "5", "metadata": {"color": "red", "flags": [1, 2]}}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Join {
+                user_id: "10".into(),
+                room_id: "5".into(),
+                subscribe: None,
+                token: None,
+                sendrecv: false,
+                metadata: json!({ "color": "red", "flags": [1, 2] }),
+                notify_when_available: false,
+                codec_capabilities: None,
+                secret: None,
+                correlation_id: None
+            });
+        }
+
+        #[test]
+        fn parse_join_correlation_id() {
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "5", "correlation_id": "req-1234"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Join {
+                user_id: "10".into(),
+                room_id: "5".into(),
+                subscribe: None,
+                token: None,
+                sendrecv: false,
+                metadata: JsonValue::Null,
+                notify_when_available: false,
+                codec_capabilities: None,
+                secret: None,
+                correlation_id: Some("req-1234".into())
+            });
+        }
+
+        #[test]
+        fn parse_join_codec_capabilities() {
+            let json = r#"{"kind": "join", "user_id": "10", "room_id": "5", "codec_capabilities": ["vp8", "h264"]}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Join {
+                user_id: "10".into(),
+                room_id: "5".into(),
+                subscribe: None,
+                token: None,
+                sendrecv: false,
+                metadata: JsonValue::Null,
+                notify_when_available: false,
+                codec_capabilities: Some(vec!["vp8".into(), "h264".into()]),
+                secret: None,
+                correlation_id: None
             });
         }
 
@@ -169,9 +596,262 @@ mod tests {
                 what: Subscription {
                     notifications: false,
                     data: true,
-                    media: Some("steve".into())
+                    media: Some("steve".into()),
+                    media_handle: None,
+                    priority: 0,
+                    keyframes_only: false,
+                    audio_label: None,
+                    max_width: None,
+                    max_height: None,
+                    max_fps: None
                 }
             });
+            let json = r#"{"kind": "subscribe", "what": {"media": "steve", "keyframes_only": true}}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Subscribe {
+                what: Subscription { media: Some("steve".into()), keyframes_only: true, ..Subscription::default() }
+            });
+            let json = r#"{"kind": "subscribe", "what": {"media": "steve", "max_width": 640, "max_fps": 15}}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Subscribe {
+                what: Subscription { media: Some("steve".into()), max_width: Some(640), max_fps: Some(15), ..Subscription::default() }
+            });
+            let json = r#"{"kind": "subscribe", "what": {"media_handle": "0x7f00deadbeef"}}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Subscribe {
+                what: Subscription { media_handle: Some("0x7f00deadbeef".into()), ..Subscription::default() }
+            });
+        }
+
+        #[test]
+        fn parse_get_capabilities() {
+            let json = r#"{"kind": "getcapabilities"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::GetCapabilities);
+        }
+
+        #[test]
+        fn parse_get_room_peaks() {
+            let json = r#"{"kind": "getroompeaks"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::GetRoomPeaks);
+        }
+
+        #[test]
+        fn parse_get_user() {
+            let json = r#"{"kind": "getuser", "user_id": "alice"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::GetUser { user_id: "alice".into() });
+        }
+
+        #[test]
+        fn parse_move_room() {
+            let json = r#"{"kind": "moveroom", "to": "beta"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::MoveRoom { to: "beta".into(), secret: None });
+            let json = r#"{"kind": "moveroom", "to": "beta", "secret": "hunter2"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::MoveRoom { to: "beta".into(), secret: Some("hunter2".into()) });
+        }
+
+        #[test]
+        fn parse_select_temporal_layer() {
+            let json = r#"{"kind": "selecttemporallayer", "layer": 1}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::SelectTemporalLayer { layer: Some(1) });
+        }
+
+        #[test]
+        fn parse_raise_hand() {
+            let json = r#"{"kind": "raisehand", "raised": true}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::RaiseHand { raised: true });
+        }
+
+        #[test]
+        fn parse_set_priority() {
+            let json = r#"{"kind": "setpriority", "priority": -3}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::SetPriority { priority: -3 });
+        }
+
+        #[test]
+        fn parse_report_stats() {
+            let json = r#"{"kind": "reportstats", "stats": {"jitter_ms": 12.5, "packet_loss": 0.01}}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            let stats = ClientStats { jitter_ms: Some(12.5), packet_loss: Some(0.01), framerate: None };
+            assert_eq!(result, MessageKind::ReportStats { stats: stats.clone() });
+            assert!(stats.validate().is_ok());
+            let json = r#"{"kind": "reportstats", "stats": {"bogus": 1}}"#;
+            assert!(serde_json::from_str::<MessageKind>(json).is_err());
+        }
+
+        #[test]
+        fn validate_stats() {
+            assert!(ClientStats { packet_loss: Some(1.5), ..Default::default() }.validate().is_err());
+            assert!(ClientStats { jitter_ms: Some(-1.0), ..Default::default() }.validate().is_err());
+            assert!(ClientStats { framerate: Some(f64::NAN), ..Default::default() }.validate().is_err());
+        }
+
+        #[test]
+        fn parse_publish_and_unpublish() {
+            let result: MessageKind = serde_json::from_str(r#"{"kind": "publish"}"#).unwrap();
+            assert_eq!(result, MessageKind::Publish);
+            let result: MessageKind = serde_json::from_str(r#"{"kind": "unpublish"}"#).unwrap();
+            assert_eq!(result, MessageKind::Unpublish);
+        }
+
+        #[test]
+        fn parse_label_audio() {
+            let result: MessageKind = serde_json::from_str(r#"{"kind": "labelaudio", "label": "fr"}"#).unwrap();
+            assert_eq!(result, MessageKind::LabelAudio { label: Some("fr".into()) });
+            let result: MessageKind = serde_json::from_str(r#"{"kind": "labelaudio", "label": null}"#).unwrap();
+            assert_eq!(result, MessageKind::LabelAudio { label: None });
+        }
+
+        #[test]
+        fn parse_resume() {
+            let json = r#"{"kind": "resume", "token": "abc"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Resume { token: "abc".into() });
+        }
+
+        #[test]
+        fn parse_diagnostics() {
+            let json = r#"{"kind": "diagnostics"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Diagnostics { handle_id: None, token: None });
+        }
+
+        #[test]
+        fn parse_verify() {
+            let json = r#"{"kind": "verify", "token": "foo", "prune": true}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Verify { token: "foo".into(), prune: true });
+        }
+
+        #[test]
+        fn parse_get_config() {
+            let json = r#"{"kind": "getconfig", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::GetConfig { token: "foo".into() });
+        }
+
+        #[test]
+        fn parse_audio_only() {
+            let json = r#"{"kind": "audioonly", "token": "foo", "enabled": true}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::AudioOnly { token: "foo".into(), enabled: true });
+        }
+
+        #[test]
+        fn parse_request_offer() {
+            let json = r#"{"kind": "requestoffer"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::RequestOffer);
+        }
+
+        #[test]
+        fn parse_lock_room() {
+            let json = r#"{"kind": "lockroom", "locked": true, "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::LockRoom { locked: true, token: Some("foo".into()) });
+        }
+
+        #[test]
+        fn parse_observe() {
+            let json = r#"{"kind": "observe", "room_id": "alpha", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Observe { room_id: "alpha".into(), token: "foo".into(), send_data: false });
+        }
+
+        #[test]
+        fn parse_rotate_room_secret() {
+            let json = r#"{"kind": "rotateroomsecret", "new_secret": "hunter2"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::RotateRoomSecret { new_secret: "hunter2".into() });
+        }
+
+        #[test]
+        fn parse_announce() {
+            let json = r#"{"kind": "announce", "text": "Meeting ending in 5 minutes."}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Announce { text: "Meeting ending in 5 minutes.".into() });
+        }
+
+        #[test]
+        fn parse_transfer_moderator() {
+            let json = r#"{"kind": "transfermoderator", "to": "bob"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::TransferModerator { to: "bob".into() });
+        }
+
+        #[test]
+        fn parse_pin() {
+            let json = r#"{"kind": "pin", "user_id": "bob"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Pin { user_id: "bob".into() });
+            let json = r#"{"kind": "unpin", "user_id": "bob"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::Unpin { user_id: "bob".into() });
+        }
+
+        #[test]
+        fn parse_kill_session() {
+            let json = r#"{"kind": "killsession", "handle_id": "0x7f00deadbeef", "token": "foo"}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::KillSession { handle_id: "0x7f00deadbeef".into(), token: "foo".into() });
+        }
+
+        #[test]
+        fn parse_pause_and_resume_subscription() {
+            let pause: MessageKind = serde_json::from_str(r#"{"kind": "pausesubscription", "whom": "alice"}"#).unwrap();
+            assert_eq!(pause, MessageKind::PauseSubscription { whom: "alice".into() });
+            let resume: MessageKind = serde_json::from_str(r#"{"kind": "resumesubscription", "whom": "alice"}"#).unwrap();
+            assert_eq!(resume, MessageKind::ResumeSubscription { whom: "alice".into() });
+        }
+
+        #[test]
+        fn parse_mute_user() {
+            let json = r#"{"kind": "muteuser", "whom": "alice", "audio": true}"#;
+            let result: MessageKind = serde_json::from_str(json).unwrap();
+            assert_eq!(result, MessageKind::MuteUser { whom: "alice".into(), audio: true, video: false });
+        }
+    }
+
+    mod jsep_parsing {
+
+        use super::*;
+        use ::serde_json;
+
+        #[test]
+        fn parse_envelope() {
+            let json = r#"{"type": "offer", "sdp": "v=0\r\n"}"#;
+            let result = UnparsedJsep::try_parse_limited(json, 100).unwrap();
+            assert_eq!(result, Some(UnparsedJsep::Offer { sdp: "v=0\r\n".into() }));
+            assert_eq!(UnparsedJsep::try_parse_limited("{}", 100).unwrap(), None);
+            assert!(UnparsedJsep::try_parse_limited(r#"{"type": "pranswer", "sdp": "v=0"}"#, 100).is_err());
+        }
+
+        #[test]
+        fn accept_missing_sdp() {
+            assert!(check_sdp(&json!({}), 100).is_ok());
+        }
+
+        #[test]
+        fn reject_empty_sdp() {
+            assert!(check_sdp(&json!({ "type": "offer", "sdp": "" }), 100).is_err());
+            assert!(check_sdp(&json!({ "type": "offer", "sdp": " \r\n " }), 100).is_err());
+            assert!(check_sdp(&json!({ "type": "offer", "sdp": "o=- 0 0 IN IP4 127.0.0.1" }), 100).is_err());
+        }
+
+        #[test]
+        fn reject_oversized_sdp() {
+            let sdp = format!("v=0\r\n{}", "a=x\r\n".repeat(1000));
+            let jsep: JsonValue = serde_json::from_str(&format!(r#"{{"type": "offer", "sdp": {:?}}}"#, sdp)).unwrap();
+            assert!(check_sdp(&jsep, sdp.len()).is_ok());
+            let err = check_sdp(&jsep, 1000).unwrap_err();
+            assert!(format!("{}", err).starts_with("SDP is too large"));
         }
     }
 }
@@ -0,0 +1,71 @@
+//! Batching of room-wide notifications, so that a burst of them goes out as one message per recipient.
+
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::mem;
+
+/// The notifications waiting to be sent, and who each one is for, as decided when it was queued.
+#[derive(Debug)]
+pub struct NotificationBatches<T: Eq + Hash> {
+    notifications: Vec<JsonValue>,
+    /// The indices into `notifications` of what each recipient is waiting for, oldest first.
+    recipients: HashMap<T, Vec<usize>>,
+}
+
+impl<T: Eq + Hash> Default for NotificationBatches<T> {
+    fn default() -> Self {
+        Self { notifications: Vec::new(), recipients: HashMap::new() }
+    }
+}
+
+impl<T: Eq + Hash> NotificationBatches<T> {
+    /// Queues a notification for the given recipients.
+    pub fn push<I: IntoIterator<Item=T>>(&mut self, recipients: I, notification: JsonValue) {
+        let index = self.notifications.len();
+        self.notifications.push(notification);
+        for recipient in recipients {
+            self.recipients.entry(recipient).or_default().push(index);
+        }
+    }
+
+    /// Takes all of the queued notifications, returning the message each recipient should get for them: the
+    /// notification itself if there's only one for it, or an `events` notification listing them in order otherwise.
+    pub fn take(&mut self) -> Vec<(T, JsonValue)> {
+        let notifications = mem::take(&mut self.notifications);
+        mem::take(&mut self.recipients).into_iter().map(|(recipient, indices)| {
+            let message = match indices[..] {
+                [index] => notifications[index].clone(),
+                _ => json!({ "event": "events", "events": indices.iter().map(|&i| &notifications[i]).collect::<Vec<_>>() }),
+            };
+            (recipient, message)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn batches_per_recipient() {
+        let mut batches = NotificationBatches::default();
+        batches.push(vec!["bob", "carol"], json!({ "event": "join", "user_id": "alice" }));
+        batches.push(vec!["alice", "carol"], json!({ "event": "join", "user_id": "bob" }));
+        batches.push(vec!["dave"], json!({ "event": "leave", "user_id": "erin" }));
+        let mut messages = batches.take();
+        messages.sort_by_key(|&(recipient, _)| recipient);
+        assert!(batches.take().is_empty());
+
+        assert_eq!(messages, vec![
+            ("alice", json!({ "event": "join", "user_id": "bob" })),
+            ("bob", json!({ "event": "join", "user_id": "alice" })),
+            ("carol", json!({
+                "event": "events",
+                "events": [{ "event": "join", "user_id": "alice" }, { "event": "join", "user_id": "bob" }]
+            })),
+            ("dave", json!({ "event": "leave", "user_id": "erin" })),
+        ]);
+    }
+}
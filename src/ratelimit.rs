@@ -0,0 +1,238 @@
+//! A token bucket for limiting how often something can happen, a pacer for spreading out bursts of packets, and
+//! sharing out a bitrate budget.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Allows up to `burst` events at once, refilling at `rate` events per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// How many tokens are added per second.
+    rate: f64,
+    /// The most tokens the bucket can hold.
+    burst: f64,
+    /// How many tokens the bucket held as of `last_update`.
+    tokens: f64,
+    /// When `tokens` was last brought up to date.
+    last_update: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self { rate, burst, tokens: burst, last_update: now }
+    }
+
+    /// Takes a token if one is available at time `now`, returning whether one was.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        if now > self.last_update {
+            self.tokens = (self.tokens + secs(now.duration_since(self.last_update)) * self.rate).min(self.burst);
+            self.last_update = now;
+        }
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// How much sending a pacer lets build up while it's idle, so that it can send that much at once.
+const PACER_BURST: Duration = Duration::from_millis(10);
+
+/// The longest a pacer holds on to packets; packets which would have to wait longer than this to go out are dropped.
+const PACER_MAX_DELAY: Duration = Duration::from_millis(500);
+
+/// How often a pacer measures how fast it's being given packets, to size its rate to match.
+const PACER_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// How much faster than it's being given packets a pacer lets them out, so that it smooths bursts without falling
+/// behind.
+const PACER_HEADROOM: f64 = 2.5;
+
+/// A leaky bucket which spaces out packets so that they leave at no more than a target rate, queueing those which
+/// arrive faster than that. The rate follows how fast packets arrive, but never goes below the one it started with.
+#[derive(Debug)]
+pub struct Pacer<T> {
+    /// How many bytes may be sent per second.
+    bytes_per_sec: f64,
+    /// The least `bytes_per_sec` can be.
+    min_bytes_per_sec: f64,
+    /// When we started measuring how fast packets arrive, and how many bytes have arrived since.
+    window_start: Instant,
+    window_bytes: usize,
+    /// How many bytes may be sent right away, as of `last_update`. Negative when we sent a packet bigger than we had
+    /// room for, since packets can't be split up.
+    budget: f64,
+    /// When `budget` was last brought up to date.
+    last_update: Instant,
+    /// Packets waiting to be sent, oldest first, each with whatever the sender needs to know to send it.
+    queue: VecDeque<(T, Vec<u8>)>,
+    /// How many bytes of packets are in `queue`.
+    queued_bytes: usize,
+}
+
+impl<T> Pacer<T> {
+    /// Creates an idle pacer which sends at least the given number of kilobits per second.
+    pub fn new(kbps: u64, now: Instant) -> Self {
+        let bytes_per_sec = kbps as f64 * 1000.0 / 8.0;
+        Self {
+            bytes_per_sec,
+            min_bytes_per_sec: bytes_per_sec,
+            window_start: now,
+            window_bytes: 0,
+            budget: 0.0,
+            last_update: now,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    /// Queues a packet at time `now`, returning false and dropping it if the queue is already as long as we're
+    /// willing to make anything wait.
+    pub fn push(&mut self, info: T, packet: Vec<u8>, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed >= PACER_RATE_WINDOW {
+            let arriving = self.window_bytes as f64 / secs(elapsed);
+            self.bytes_per_sec = (arriving * PACER_HEADROOM).max(self.min_bytes_per_sec);
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
+        self.window_bytes += packet.len();
+        if self.queued_bytes as f64 >= self.bytes_per_sec * secs(PACER_MAX_DELAY) {
+            return false;
+        }
+        if self.queue.is_empty() {
+            self.refill(now);
+        }
+        self.queued_bytes += packet.len();
+        self.queue.push_back((info, packet));
+        true
+    }
+
+    /// Takes every packet which may go out as of time `now`, oldest first.
+    pub fn release(&mut self, now: Instant) -> Vec<(T, Vec<u8>)> {
+        self.refill(now);
+        let mut ready = Vec::new();
+        while self.budget > 0.0 {
+            match self.queue.pop_front() {
+                Some(entry) => {
+                    self.budget -= entry.1.len() as f64;
+                    self.queued_bytes -= entry.1.len();
+                    ready.push(entry);
+                }
+                None => break,
+            }
+        }
+        ready
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now > self.last_update {
+            let burst = self.bytes_per_sec * secs(PACER_BURST);
+            self.budget = (self.budget + secs(now.duration_since(self.last_update)) * self.bytes_per_sec).min(burst);
+            self.last_update = now;
+        }
+    }
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// Divides a bitrate budget among senders currently sending at the given rates, returning the most each may send.
+/// If they're over budget between them, each is cut back in proportion to what it's sending; otherwise, whatever's
+/// left of the budget is split evenly between them, so that any of them can grow into it.
+pub fn allocate_bitrate(rates: &[u32], budget: u32) -> Vec<u32> {
+    let total: u64 = rates.iter().map(|&r| u64::from(r)).sum();
+    if total > u64::from(budget) {
+        rates.iter().map(|&r| (u64::from(r) * u64::from(budget) / total) as u32).collect()
+    } else {
+        let headroom = (u64::from(budget) - total) / rates.len().max(1) as u64;
+        rates.iter().map(|&r| (u64::from(r) + headroom) as u32).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_burst_then_limits() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 3.0, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 2.0, start);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start + Duration::from_millis(250)));
+        assert!(bucket.try_take(start + Duration::from_millis(500)));
+        assert!(!bucket.try_take(start + Duration::from_millis(500)));
+        assert!(bucket.try_take(start + Duration::from_secs(10)));
+        assert!(bucket.try_take(start + Duration::from_secs(10)));
+        assert!(!bucket.try_take(start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn paces_bursts() {
+        let start = Instant::now();
+        // 800kbps is 100 bytes per millisecond
+        let mut pacer = Pacer::new(800, start);
+        for i in 0..5 {
+            assert!(pacer.push(i, vec![0; 500], start));
+        }
+        assert_eq!(pacer.release(start).len(), 0);
+        let released: Vec<_> = pacer.release(start + Duration::from_millis(1)).into_iter().map(|(i, _)| i).collect();
+        assert_eq!(released, vec![0]);
+        assert_eq!(pacer.release(start + Duration::from_millis(4)).len(), 0);
+        assert_eq!(pacer.release(start + Duration::from_millis(6)).len(), 1);
+        // idle time only builds up so much room for bursts
+        assert_eq!(pacer.release(start + Duration::from_secs(10)).len(), 2);
+        assert_eq!(pacer.release(start + Duration::from_secs(10) + Duration::from_millis(1)).len(), 1);
+    }
+
+    #[test]
+    fn drops_packets_which_would_wait_too_long() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(8, start);
+        for _ in 0..5 {
+            assert!(pacer.push((), vec![0; 100], start));
+        }
+        assert!(!pacer.push((), vec![0; 100], start));
+    }
+
+    #[test]
+    fn paces_at_a_multiple_of_what_it_is_sent() {
+        let start = Instant::now();
+        // starting out at 1000 bytes per second, which is too slow for the 4000 it's sent
+        let mut pacer = Pacer::new(8, start);
+        assert!(pacer.push((), vec![0; 1000], start));
+        for i in 1..4 {
+            assert!(!pacer.push((), vec![0; 1000], start + Duration::from_millis(i * 250)));
+        }
+        // so it makes room for 10000, and queues up to 500ms of that
+        let now = start + Duration::from_secs(1);
+        for _ in 0..4 {
+            assert!(pacer.push((), vec![0; 1000], now));
+        }
+        assert!(!pacer.push((), vec![0; 1000], now));
+    }
+
+    #[test]
+    fn allocates_bitrate_proportionally() {
+        assert_eq!(allocate_bitrate(&[3_000_000, 1_000_000], 2_000_000), vec![1_500_000, 500_000]);
+        assert_eq!(allocate_bitrate(&[500_000, 0], 2_000_000), vec![1_250_000, 750_000]);
+        assert_eq!(allocate_bitrate(&[], 2_000_000), Vec::<u32>::new());
+    }
+}
@@ -0,0 +1,105 @@
+//! Tokens which let a client put a new connection back into the state that an old one was in.
+
+use messages::{RoomId, Subscription, UserId};
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, Instant};
+
+/// Everything a connection said when it joined, which is what it takes to join again.
+#[derive(Debug, Clone)]
+pub struct ResumeState {
+    pub room_id: RoomId,
+    pub user_id: UserId,
+    pub subscription: Option<Subscription>,
+    pub token: Option<String>,
+    pub sendrecv: bool,
+    pub metadata: JsonValue,
+    pub codec_capabilities: Option<Vec<String>>,
+    pub secret: Option<String>,
+    pub correlation_id: Option<String>,
+}
+
+/// The resume tokens that have been handed out and not yet used, each good until it expires.
+#[derive(Debug)]
+pub struct ResumeTokens {
+    /// A secret used to make tokens unguessable.
+    keys: RandomState,
+    /// How many tokens have ever been issued, so that no two tokens are the same.
+    issued: u64,
+    /// The state each outstanding token restores, and when it expires.
+    tokens: HashMap<String, (ResumeState, Instant)>,
+}
+
+impl ResumeTokens {
+    pub fn new() -> Self {
+        Self { keys: RandomState::new(), issued: 0, tokens: HashMap::new() }
+    }
+
+    /// Remembers the given state and returns a new token which restores it until `ttl` after `now`. Forgets any tokens
+    /// which have expired in the meantime.
+    pub fn issue(&mut self, state: ResumeState, now: Instant, ttl: Duration) -> String {
+        self.tokens.retain(|_, &mut (_, expiry)| expiry > now);
+        self.issued += 1;
+        let mut token = String::new();
+        for half in 0..2u8 {
+            let mut hasher = self.keys.build_hasher();
+            hasher.write_u64(self.issued);
+            hasher.write_u8(half);
+            token.push_str(&format!("{:016x}", hasher.finish()));
+        }
+        self.tokens.insert(token.clone(), (state, now + ttl));
+        token
+    }
+
+    /// Returns the state that the given token restores, if it's outstanding and hasn't expired as of `now`. Each token
+    /// can only be redeemed once.
+    pub fn redeem(&mut self, token: &str, now: Instant) -> Option<ResumeState> {
+        match self.tokens.remove(token) {
+            Some((state, expiry)) if expiry > now => Some(state),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn state(user_id: &str) -> ResumeState {
+        ResumeState {
+            room_id: "room".into(),
+            user_id: user_id.into(),
+            subscription: Some(Subscription { data: true, ..Default::default() }),
+            token: None,
+            sendrecv: false,
+            metadata: JsonValue::Null,
+            codec_capabilities: None,
+            secret: None,
+            correlation_id: None,
+        }
+    }
+
+    #[test]
+    fn tokens_redeem_once() {
+        let now = Instant::now();
+        let mut tokens = ResumeTokens::new();
+        let alice = tokens.issue(state("alice"), now, Duration::from_secs(60));
+        let bob = tokens.issue(state("bob"), now, Duration::from_secs(60));
+        assert_ne!(alice, bob);
+        assert_eq!(tokens.redeem(&bob, now).map(|s| s.user_id), Some("bob".into()));
+        assert!(tokens.redeem(&bob, now).is_none());
+        assert!(tokens.redeem("bogus", now).is_none());
+        assert_eq!(tokens.redeem(&alice, now).map(|s| s.subscription), Some(state("alice").subscription));
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let now = Instant::now();
+        let mut tokens = ResumeTokens::new();
+        let alice = tokens.issue(state("alice"), now, Duration::from_secs(60));
+        assert!(tokens.redeem(&alice, now + Duration::from_secs(60)).is_none());
+    }
+}
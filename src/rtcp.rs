@@ -0,0 +1,227 @@
+//! Utilities for rewriting RTCP packets. For reference, see:
+//!
+//! <https://tools.ietf.org/html/rfc3550#section-6> (RTCP)
+
+/// The packet type of an RTCP receiver report packet.
+const RR: u8 = 201;
+
+/// The packet type of an RTCP source description packet.
+const SDES: u8 = 202;
+
+/// The packet type of an RTCP goodbye packet.
+const BYE: u8 = 203;
+
+/// The SDES item type for a canonical name.
+const CNAME: u8 = 1;
+
+/// Returns a copy of the given compound RTCP packet with the value of every SDES CNAME item replaced with the given
+/// CNAME, or None if the packet is malformed. Packets other than SDES are copied unchanged.
+pub fn rewrite_cnames(packet: &[u8], cname: &[u8]) -> Option<Vec<u8>> {
+    if cname.len() > 255 {
+        return None;
+    }
+    let mut result = Vec::with_capacity(packet.len() + cname.len());
+    let mut rest = packet;
+    while !rest.is_empty() {
+        if rest.len() < 4 || rest[0] >> 6 != 2 {
+            return None;
+        }
+        let len = 4 * (1 + ((rest[2] as usize) << 8 | rest[3] as usize));
+        if rest.len() < len {
+            return None;
+        }
+        let (current, next) = rest.split_at(len);
+        if current[1] == SDES {
+            rewrite_sdes(current, cname, &mut result)?;
+        } else {
+            result.extend_from_slice(current);
+        }
+        rest = next;
+    }
+    Some(result)
+}
+
+/// Returns the SSRCs which the BYEs in the given compound RTCP packet say are done sending, if it has any. Anything
+/// after a malformed part of the packet is ignored.
+pub fn bye_ssrcs(packet: &[u8]) -> Vec<u32> {
+    let mut result = Vec::new();
+    let mut rest = packet;
+    while rest.len() >= 4 && rest[0] >> 6 == 2 {
+        let len = 4 * (1 + ((rest[2] as usize) << 8 | rest[3] as usize));
+        let current = match rest.get(..len) {
+            Some(current) => current,
+            None => break,
+        };
+        if current[1] == BYE {
+            let source_count = (current[0] & 0x1f) as usize;
+            for source in current[4..].chunks_exact(4).take(source_count) {
+                result.push(u32::from(source[0]) << 24 | u32::from(source[1]) << 16 | u32::from(source[2]) << 8 | u32::from(source[3]));
+            }
+        }
+        rest = &rest[len..];
+    }
+    result
+}
+
+/// Keeps track of which of a publisher's sources have said goodbye. Audio and video can say goodbye in separate
+/// packets, so this has to be kept across them.
+#[derive(Debug, Default)]
+pub struct Goodbyes {
+    ssrcs: Vec<u32>,
+}
+
+impl Goodbyes {
+    /// Records BYEs for the given SSRCs, given the SSRCs the publisher is sending, and returns whether it's now said
+    /// goodbye for all of them. BYEs for sources it isn't sending are ignored, and a publisher that isn't sending anything
+    /// has nothing to say goodbye for.
+    pub fn record(&mut self, sending: &[u32], byes: &[u32]) -> bool {
+        self.ssrcs.retain(|ssrc| sending.contains(ssrc));
+        for ssrc in byes {
+            if sending.contains(ssrc) && !self.ssrcs.contains(ssrc) {
+                self.ssrcs.push(*ssrc);
+            }
+        }
+        !sending.is_empty() && sending.iter().all(|ssrc| self.ssrcs.contains(ssrc))
+    }
+
+    /// Forgets every goodbye so far.
+    pub fn clear(&mut self) {
+        self.ssrcs.clear();
+    }
+}
+
+/// Returns an RTCP receiver report with no report blocks, which is the smallest valid compound RTCP packet. The sender
+/// SSRC is left zero for Janus to fill in.
+pub fn empty_receiver_report() -> Vec<u8> {
+    vec![0x80, RR, 0, 1, 0, 0, 0, 0]
+}
+
+/// Writes a copy of the given SDES packet with its CNAMEs replaced to the end of `out`, dropping any padding.
+fn rewrite_sdes(packet: &[u8], cname: &[u8], out: &mut Vec<u8>) -> Option<()> {
+    let source_count = (packet[0] & 0x1f) as usize;
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(packet[end - 1] as usize)?;
+    }
+    let body = packet.get(4..end)?;
+    let start = out.len();
+    out.extend_from_slice(&[packet[0] & !0x20, packet[1], 0, 0]);
+    let mut offset = 0;
+    for _ in 0..source_count {
+        // each chunk is an SSRC and a list of items, terminated by a null item and padded to a 32-bit boundary
+        out.extend_from_slice(body.get(offset..offset + 4)?);
+        offset += 4;
+        loop {
+            let item_type = *body.get(offset)?;
+            if item_type == 0 {
+                break;
+            }
+            let item_len = *body.get(offset + 1)? as usize;
+            let value = body.get(offset + 2..offset + 2 + item_len)?;
+            if item_type == CNAME {
+                out.extend_from_slice(&[CNAME, cname.len() as u8]);
+                out.extend_from_slice(cname);
+            } else {
+                out.extend_from_slice(&[item_type, item_len as u8]);
+                out.extend_from_slice(value);
+            }
+            offset += 2 + item_len;
+        }
+        offset = (offset + 4) / 4 * 4;
+        let chunk_end = start + (out.len() - start + 4) / 4 * 4;
+        out.resize(chunk_end, 0);
+    }
+    let words = (out.len() - start) / 4 - 1;
+    out[start + 2] = (words >> 8) as u8;
+    out[start + 3] = words as u8;
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    /// An SSRC and its SDES items, as (type, value) pairs.
+    type Chunk<'a> = (u32, &'a [(u8, &'a [u8])]);
+
+    fn sdes(chunks: &[Chunk]) -> Vec<u8> {
+        let mut packet = vec![0x80 | chunks.len() as u8, SDES, 0, 0];
+        for &(ssrc, items) in chunks {
+            let chunk_start = packet.len();
+            packet.extend_from_slice(&[(ssrc >> 24) as u8, (ssrc >> 16) as u8, (ssrc >> 8) as u8, ssrc as u8]);
+            for &(item_type, value) in items {
+                packet.extend_from_slice(&[item_type, value.len() as u8]);
+                packet.extend_from_slice(value);
+            }
+            let chunk_end = chunk_start + (packet.len() - chunk_start + 4) / 4 * 4;
+            packet.resize(chunk_end, 0);
+        }
+        let words = packet.len() / 4 - 1;
+        packet[2] = (words >> 8) as u8;
+        packet[3] = words as u8;
+        packet
+    }
+
+    #[test]
+    fn rewrites_cnames_in_every_chunk() {
+        let tool: &[u8] = b"some browser";
+        let original = sdes(&[(1, &[(CNAME, b"user@host"), (6, tool)]), (2, &[(CNAME, b"abc")])]);
+        let expected = sdes(&[(1, &[(CNAME, b"opaque"), (6, tool)]), (2, &[(CNAME, b"opaque")])]);
+        assert_eq!(rewrite_cnames(&original, b"opaque"), Some(expected));
+    }
+
+    #[test]
+    fn leaves_other_packets_alone() {
+        // a receiver report with no report blocks, followed by an SDES
+        let mut original = empty_receiver_report();
+        original.extend(sdes(&[(7, &[(CNAME, b"x")])]));
+        let mut expected = empty_receiver_report();
+        expected.extend(sdes(&[(7, &[(CNAME, b"a longer name")])]));
+        assert_eq!(rewrite_cnames(&original, b"a longer name"), Some(expected));
+    }
+
+    #[test]
+    fn finds_byes_in_compound_packets() {
+        // a BYE for one source, with no reason given
+        let bye = vec![0x81, BYE, 0, 1, 0, 0, 0, 7];
+        let mut compound = empty_receiver_report();
+        compound.extend(sdes(&[(7, &[(CNAME, b"x")])]));
+        assert!(bye_ssrcs(&compound).is_empty());
+        compound.extend(&bye);
+        assert_eq!(bye_ssrcs(&compound), vec![7]);
+        assert_eq!(bye_ssrcs(&bye), vec![7]);
+        assert!(bye_ssrcs(&bye[..3]).is_empty());
+    }
+
+    #[test]
+    fn finds_every_source_saying_bye() {
+        // a BYE for two sources, with a reason, and then another for a third
+        let mut packet = vec![0x82, BYE, 0, 3, 0, 0, 0, 7, 0x01, 0x02, 0x03, 0x04, 3, b'b', b'y', b'e'];
+        packet.extend(&[0x81, BYE, 0, 1, 0, 0, 0, 9]);
+        assert_eq!(bye_ssrcs(&packet), vec![7, 0x01020304, 9]);
+        // a BYE claiming more sources than it has room for only counts the ones it has
+        assert_eq!(bye_ssrcs(&[0x83, BYE, 0, 1, 0, 0, 0, 7]), vec![7]);
+    }
+
+    #[test]
+    fn waits_for_every_source_to_say_bye() {
+        let mut goodbyes = Goodbyes::default();
+        assert!(!goodbyes.record(&[], &[7]));
+        assert!(!goodbyes.record(&[1, 2], &[1, 1, 9]));
+        assert_eq!(goodbyes.ssrcs, vec![1]);
+        assert!(goodbyes.record(&[1, 2], &[2]));
+        // sources the publisher has stopped sending are forgotten
+        assert!(!goodbyes.record(&[2, 3], &[]));
+        assert_eq!(goodbyes.ssrcs, vec![2]);
+        goodbyes.clear();
+        assert!(!goodbyes.record(&[2, 3], &[3]));
+    }
+
+    #[test]
+    fn rejects_truncated_packets() {
+        let original = sdes(&[(1, &[(CNAME, b"user@host")])]);
+        assert_eq!(rewrite_cnames(&original[..original.len() - 4], b"opaque"), None);
+        assert_eq!(rewrite_cnames(&original[..3], b"opaque"), None);
+    }
+}
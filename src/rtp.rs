@@ -0,0 +1,1517 @@
+//! Utilities for inspecting and rewriting RTP packet headers. For reference, see:
+//!
+//! <https://tools.ietf.org/html/rfc3550> (RTP)
+//! <https://tools.ietf.org/html/rfc5285> (RTP header extensions)
+
+use janus::sdp::VideoCodec;
+use std::ops::Range;
+use std::str;
+use std::time::{Duration, Instant};
+
+/// The size of the fixed part of an RTP header, before any CSRCs or extensions.
+pub const FIXED_HEADER_LEN: usize = 12;
+
+/// How far to advance the timestamp across a source switch for audio; one 20ms Opus frame at 48kHz.
+pub const AUDIO_TIMESTAMP_STEP: u32 = 960;
+
+/// How far to advance the timestamp across a source switch for video; one frame at 30fps on the 90kHz video clock.
+pub const VIDEO_TIMESTAMP_STEP: u32 = 3000;
+
+/// The clock rate of the RTP timestamps on video packets.
+pub const VIDEO_CLOCK_RATE: u32 = 90000;
+
+/// The URI identifying the client-to-mixer audio level header extension in SDP. See <https://tools.ietf.org/html/rfc6464>.
+pub const AUDIO_LEVEL_URI: &str = "urn:ietf:params:rtp-hdrext:ssrc-audio-level";
+
+/// The URI identifying the coordination of video orientation (CVO) header extension in SDP, which tells receivers how
+/// the sender's camera is rotated. See 3GPP TS 26.114.
+pub const VIDEO_ORIENTATION_URI: &str = "urn:3gpp:video-orientation";
+
+/// The URI identifying the header extension which says which simulcast layer, by RID, a packet belongs to. See
+/// <https://tools.ietf.org/html/rfc8852>.
+pub const RTP_STREAM_ID_URI: &str = "urn:ietf:params:rtp-hdrext:sdes:rtp-stream-id";
+
+/// How long a publisher's video stream can go without packets before we consider that layer to no longer be sent.
+pub const LAYER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often we measure the bitrate and framerate of each of a publisher's video layers.
+pub const LAYER_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much a layer's measured bitrate has to change, as a fraction of what we last told subscribers, before we tell them
+/// again.
+const LAYER_BITRATE_CHANGE: f64 = 0.25;
+
+/// How long a reorder buffer holds on to packets waiting for a gap in the sequence numbers to be filled.
+pub const REORDER_TIMEOUT: Duration = Duration::from_millis(30);
+
+/// The fields of an RTP header that we care about for forwarding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub payload_type: u8,
+    pub seq: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    /// Reads the header of the given RTP packet, or returns None if it's too short or isn't RTP version 2.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < FIXED_HEADER_LEN || packet[0] >> 6 != 2 {
+            return None;
+        }
+        Some(Self {
+            payload_type: packet[1] & 0x7f,
+            seq: u16::from(packet[2]) << 8 | u16::from(packet[3]),
+            timestamp: read_u32(&packet[4..8]),
+            ssrc: read_u32(&packet[8..12]),
+        })
+    }
+}
+
+/// Returns the payload of the given RTP packet, skipping past any CSRCs, header extension, and padding.
+pub fn payload(packet: &[u8]) -> Option<&[u8]> {
+    payload_range(packet).map(|range| &packet[range])
+}
+
+/// Zeroes out the payload of the given RTP packet, leaving the header and padding as they were.
+pub fn blank_payload(packet: &mut [u8]) {
+    if let Some(range) = payload_range(packet) {
+        for byte in &mut packet[range] {
+            *byte = 0;
+        }
+    }
+}
+
+fn payload_range(packet: &[u8]) -> Option<Range<usize>> {
+    if packet.len() < FIXED_HEADER_LEN {
+        return None;
+    }
+    let csrc_count = (packet[0] & 0x0f) as usize;
+    let mut start = FIXED_HEADER_LEN + 4 * csrc_count;
+    if packet[0] & 0x10 != 0 {
+        // header extension: 16 bits of profile, then 16 bits of length in 32-bit words
+        if packet.len() < start + 4 {
+            return None;
+        }
+        let words = (packet[start + 2] as usize) << 8 | packet[start + 3] as usize;
+        start += 4 + 4 * words;
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last().unwrap() as usize)?;
+    }
+    if start > end {
+        return None;
+    }
+    Some(start..end)
+}
+
+/// Returns the ID that the given SDP assigns to the RTP header extension with the given URI, if it offers it.
+pub fn extmap_id(sdp: &str, uri: &str) -> Option<u8> {
+    sdp.lines().filter_map(|line| line.trim().strip_prefix("a=extmap:")).find_map(|extmap| {
+        let mut parts = extmap.split_whitespace();
+        let id = parts.next()?.split('/').next()?.parse().ok()?;
+        if parts.next()? == uri { Some(id) } else { None }
+    })
+}
+
+/// Returns the ID that the given SDP assigns to the RTP header extension with the given URI in its media section of the
+/// given kind, e.g. "audio" or "video", if it offers it there.
+pub fn media_extmap_id(sdp: &str, kind: &str, uri: &str) -> Option<u8> {
+    sdp.split("m=").skip(1)
+        .filter(|section| section.split_whitespace().next() == Some(kind))
+        .find_map(|section| extmap_id(section, uri))
+}
+
+/// Returns the first H.264 payload type that the given SDP offers with the given profile-level-id and packetization
+/// mode, whichever of them are given. Packetization mode 0 is assumed if a payload type doesn't say otherwise.
+pub fn h264_payload_type(sdp: &str, profile_level_id: Option<&str>, packetization_mode: Option<u8>) -> Option<i32> {
+    attributes(sdp, "rtpmap").filter(|&(_, codec)| codec.to_ascii_uppercase().starts_with("H264/")).map(|(pt, _)| pt).find(|&pt| {
+        let fmtp = attributes(sdp, "fmtp").find(|&(other, _)| other == pt).map_or("", |(_, params)| params);
+        let param = |name| fmtp.split(';').filter_map(|p| p.trim().split_once('=')).find(|p| p.0 == name).map(|p| p.1);
+        let profile_matches = match profile_level_id {
+            Some(id) => param("profile-level-id").is_some_and(|x| x.eq_ignore_ascii_case(id)),
+            None => true,
+        };
+        let mode_matches = match packetization_mode {
+            Some(mode) => param("packetization-mode").unwrap_or("0") == mode.to_string(),
+            None => true,
+        };
+        profile_matches && mode_matches
+    })
+}
+
+/// Returns the given SDP with the format parameters of the given payload type replaced by the given ones, or added
+/// right after its `rtpmap` line if it didn't have any.
+pub fn with_fmtp(sdp: &str, payload_type: i32, params: &str) -> String {
+    let attribute = format!("a=fmtp:{} {}", payload_type, params);
+    let rtpmap = format!("a=rtpmap:{} ", payload_type);
+    let fmtp = format!("a=fmtp:{} ", payload_type);
+    let has_fmtp = sdp.split_terminator('\n').any(|line| line.starts_with(&fmtp));
+    let mut result = String::with_capacity(sdp.len() + attribute.len() + 2);
+    for line in sdp.split_terminator('\n').map(|line| line.trim_end_matches('\r')) {
+        result.push_str(if line.starts_with(&fmtp) { &attribute } else { line });
+        result.push_str("\r\n");
+        if !has_fmtp && line.starts_with(&rtpmap) {
+            result.push_str(&attribute);
+            result.push_str("\r\n");
+        }
+    }
+    result
+}
+
+/// Returns the given SDP with its data channel section, if it has one, advertising the given maximum message size in
+/// place of any it advertised before. See <https://tools.ietf.org/html/rfc8841#section-6>.
+pub fn with_max_message_size(sdp: &str, bytes: usize) -> String {
+    let attribute = format!("a=max-message-size:{}\r\n", bytes);
+    let mut result = String::with_capacity(sdp.len() + attribute.len());
+    let mut in_application = false;
+    for line in sdp.split_terminator('\n').map(|line| line.trim_end_matches('\r')) {
+        if line.starts_with("m=") {
+            if in_application {
+                result.push_str(&attribute);
+            }
+            in_application = line.starts_with("m=application ");
+        } else if in_application && line.starts_with("a=max-message-size:") {
+            continue;
+        }
+        result.push_str(line);
+        result.push_str("\r\n");
+    }
+    if in_application {
+        result.push_str(&attribute);
+    }
+    result
+}
+
+/// Returns the payload type that the given SDP offers redundant Opus audio (RED) with, if it offers it.
+pub fn red_payload_type(sdp: &str) -> Option<i32> {
+    attributes(sdp, "rtpmap").find(|&(_, codec)| codec.to_ascii_lowercase().starts_with("red/48000")).map(|(pt, _)| pt)
+}
+
+/// Returns the given SDP with redundant audio (RED) added to its audio section as the most preferred payload type,
+/// carrying the given primary payload type. See <https://tools.ietf.org/html/rfc2198#section-5>.
+pub fn with_red(sdp: &str, red_payload_type: i32, primary_payload_type: i32) -> String {
+    let attributes = format!("a=rtpmap:{0} red/48000/2\r\na=fmtp:{0} {1}/{1}\r\n", red_payload_type, primary_payload_type);
+    let mut result = String::with_capacity(sdp.len() + attributes.len() + 4);
+    let mut in_audio = false;
+    for line in sdp.split_terminator('\n').map(|line| line.trim_end_matches('\r')) {
+        if line.starts_with("m=") {
+            if in_audio {
+                result.push_str(&attributes);
+            }
+            in_audio = line.starts_with("m=audio ");
+            if in_audio {
+                // m=audio <port> <proto> <payload types...>
+                let mut fields: Vec<&str> = line.split(' ').collect();
+                let red = red_payload_type.to_string();
+                if fields.len() >= 3 && !fields[3..].contains(&red.as_str()) {
+                    fields.insert(3, &red);
+                }
+                result.push_str(&fields.join(" "));
+                result.push_str("\r\n");
+                continue;
+            }
+        }
+        result.push_str(line);
+        result.push_str("\r\n");
+    }
+    if in_audio {
+        result.push_str(&attributes);
+    }
+    result
+}
+
+/// Returns the given RTP packet carrying redundant audio (RED) rewritten to carry only its primary encoding, under that
+/// encoding's payload type, for recipients who can't decode RED. See <https://tools.ietf.org/html/rfc2198#section-3>.
+pub fn red_primary(packet: &[u8]) -> Option<Vec<u8>> {
+    let range = payload_range(packet)?;
+    let payload = &packet[range.clone()];
+    let mut offset = 0;
+    let mut redundant_len = 0;
+    let primary_payload_type = loop {
+        let first = *payload.get(offset)?;
+        if first & 0x80 == 0 {
+            offset += 1;
+            break first & 0x7f;
+        }
+        // each redundant block's header ends with a 10-bit length
+        redundant_len += ((*payload.get(offset + 2)? & 0x03) as usize) << 8 | *payload.get(offset + 3)? as usize;
+        offset += 4;
+    };
+    let primary = payload.get(offset + redundant_len..)?;
+    let mut result = Vec::with_capacity(range.start + primary.len());
+    result.extend_from_slice(&packet[..range.start]);
+    result.extend_from_slice(primary);
+    // the padding went with the rest of the payload
+    result[0] &= !0x20;
+    set_payload_type(&mut result, primary_payload_type);
+    Some(result)
+}
+
+/// The kind of each media section in an SDP, in order, with the codecs it offers.
+pub type MediaLines = Vec<(String, Vec<String>)>;
+
+/// Returns the kind of each media section in the given SDP, e.g. "audio" or "video", in order, with the codecs it offers,
+/// sorted. Nothing which changes on an ICE restart is included, so this only differs between two SDPs for the same
+/// connection if the media itself was renegotiated.
+pub fn media_lines(sdp: &str) -> MediaLines {
+    sdp.split("m=").skip(1).map(|section| {
+        let kind = section.split_whitespace().next().unwrap_or("").to_owned();
+        let mut codecs: Vec<String> = attributes(section, "rtpmap").map(|(_, codec)| codec.trim().to_ascii_lowercase()).collect();
+        codecs.sort();
+        (kind, codecs)
+    }).collect()
+}
+
+/// Returns whether the given SDP has a media section of the given kind, e.g. "audio" or "video", which isn't rejected
+/// and which will send media.
+pub fn sends_media(sdp: &str, kind: &str) -> bool {
+    let mut sections = sdp.split("m=").skip(1);
+    sections.any(|section| {
+        let mut fields = section.split_whitespace();
+        if fields.next() != Some(kind) || fields.next() == Some("0") {
+            return false;
+        }
+        !section.lines().map(str::trim).any(|line| line == "a=recvonly" || line == "a=inactive")
+    })
+}
+
+/// Returns the distinct types of the ICE candidates in the given SDP, e.g. "host", "srflx", or "relay", in the order they
+/// first appear. Candidates which are trickled separately aren't in the SDP, so this may well be empty.
+pub fn candidate_types(sdp: &str) -> Vec<String> {
+    let mut types: Vec<String> = Vec::new();
+    for candidate in sdp.lines().filter_map(|line| line.trim().strip_prefix("a=candidate:")) {
+        let mut fields = candidate.split_whitespace().skip_while(|&field| field != "typ").skip(1);
+        if let Some(kind) = fields.next() {
+            if !types.iter().any(|t| t == kind) {
+                types.push(kind.to_owned());
+            }
+        }
+    }
+    types
+}
+
+/// Returns the RIDs of the simulcast layers the given SDP offers to send, in order of preference, or nothing if it
+/// doesn't offer simulcast. Layers which are offered paused are included, but layers without a matching `a=rid` line
+/// are left out.
+pub fn simulcast_rids(sdp: &str) -> Vec<String> {
+    let lines = || sdp.lines().map(str::trim);
+    let sending: Vec<&str> = lines().filter_map(|line| line.strip_prefix("a=rid:")).filter_map(|rid| {
+        let mut parts = rid.split_whitespace();
+        let id = parts.next()?;
+        if parts.next()? == "send" { Some(id) } else { None }
+    }).collect();
+    let layers = lines().filter_map(|line| line.strip_prefix("a=simulcast:")).find_map(|value| {
+        let mut parts = value.split_whitespace();
+        if parts.next()? == "send" { parts.next() } else { None }
+    });
+    match layers {
+        None => Vec::new(),
+        Some(layers) => layers.split([';', ','])
+            .map(|rid| rid.trim_start_matches('~'))
+            .filter(|rid| sending.contains(rid))
+            .map(String::from)
+            .collect(),
+    }
+}
+
+/// Returns the payload type and the rest of the value of every SDP attribute with the given name that's specific to
+/// a payload type, like rtpmap and fmtp.
+fn attributes<'a>(sdp: &'a str, name: &'a str) -> impl Iterator<Item=(i32, &'a str)> {
+    sdp.lines().filter_map(move |line| line.trim().strip_prefix("a=")?.strip_prefix(name)?.strip_prefix(':')).filter_map(|value| {
+        let mut parts = value.splitn(2, ' ');
+        Some((parts.next()?.parse().ok()?, parts.next().unwrap_or("")))
+    })
+}
+
+/// Returns the RID in the given RTP packet's RTP stream ID header extension, if present.
+pub fn rid(packet: &[u8], extension_id: u8) -> Option<&str> {
+    str::from_utf8(extension(packet, extension_id)?).ok()
+}
+
+/// Returns the data of the one-byte header extension element with the given ID in the given RTP packet, if present.
+fn extension(packet: &[u8], id: u8) -> Option<&[u8]> {
+    if packet.len() < FIXED_HEADER_LEN || packet[0] & 0x10 == 0 {
+        return None;
+    }
+    let start = FIXED_HEADER_LEN + 4 * (packet[0] & 0x0f) as usize;
+    let header = packet.get(start..start + 4)?;
+    if header[0..2] != [0xbe, 0xde] {
+        return None; // not the one-byte header format
+    }
+    let words = (header[2] as usize) << 8 | header[3] as usize;
+    let elements = packet.get(start + 4..start + 4 + 4 * words)?;
+    let mut offset = 0;
+    while offset < elements.len() {
+        let (element_id, len) = (elements[offset] >> 4, (elements[offset] & 0x0f) as usize + 1);
+        match element_id {
+            0 => offset += 1, // padding
+            15 => return None, // the rest of the extension is to be ignored
+            _ if element_id == id => return elements.get(offset + 1..offset + 1 + len),
+            _ => offset += 1 + len,
+        }
+    }
+    None
+}
+
+/// Returns a copy of the given RTP packet with the IDs of its one-byte header extension elements changed according to
+/// the given (from, to) pairs. Elements which aren't being changed, but which already have one of the new IDs, are
+/// dropped, so that nobody mistakes them for the ones which were. Returns None if the packet has no one-byte header
+/// extension.
+pub fn remap_extension_ids(packet: &[u8], ids: &[(u8, u8)]) -> Option<Vec<u8>> {
+    if packet.len() < FIXED_HEADER_LEN || packet[0] & 0x10 == 0 {
+        return None;
+    }
+    let start = FIXED_HEADER_LEN + 4 * (packet[0] & 0x0f) as usize;
+    let header = packet.get(start..start + 4)?;
+    if header[0..2] != [0xbe, 0xde] {
+        return None; // not the one-byte header format
+    }
+    let end = start + 4 + 4 * ((header[2] as usize) << 8 | header[3] as usize);
+    let elements = packet.get(start + 4..end)?;
+    let mut remapped = Vec::with_capacity(elements.len());
+    let mut offset = 0;
+    while offset < elements.len() {
+        let (id, len) = (elements[offset] >> 4, (elements[offset] & 0x0f) as usize + 1);
+        match id {
+            0 => offset += 1, // padding, which gets redone at the end
+            15 => break, // the rest of the extension is to be ignored
+            _ => {
+                let data = elements.get(offset + 1..offset + 1 + len)?;
+                offset += 1 + len;
+                let new_id = match ids.iter().find(|&&(from, _)| from == id) {
+                    Some(&(_, to)) => to,
+                    None if ids.iter().any(|&(_, to)| to == id) => continue,
+                    None => id,
+                };
+                remapped.push(new_id << 4 | (len - 1) as u8);
+                remapped.extend_from_slice(data);
+            }
+        }
+    }
+    while remapped.len() % 4 != 0 {
+        remapped.push(0);
+    }
+    let words = remapped.len() / 4;
+    let mut result = Vec::with_capacity(packet.len());
+    result.extend_from_slice(&packet[..start]);
+    result.extend_from_slice(&[0xbe, 0xde, (words >> 8) as u8, words as u8]);
+    result.extend_from_slice(&remapped);
+    result.extend_from_slice(&packet[end..]);
+    Some(result)
+}
+
+/// Reads the audio level out of the given RTP packet, given the ID that its sender negotiated for the audio level
+/// header extension. The level is in -dBov, from 0 (loudest) to 127 (silence).
+pub fn audio_level(packet: &[u8], extension_id: u8) -> Option<u8> {
+    extension(packet, extension_id)?.first().map(|level| level & 0x7f)
+}
+
+/// A DTMF digit, as sent in a telephone-event RTP payload. See <https://tools.ietf.org/html/rfc4733#section-2.3>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DtmfEvent {
+    /// The key that was pressed: a digit, `*`, `#`, or a letter from `A` to `D`.
+    pub digit: char,
+    /// Whether this packet marks the end of the key press. Senders repeat the end packet in case of loss.
+    pub end: bool,
+}
+
+/// Reads the DTMF digit out of the given telephone-event payload, if it's a digit and not some other kind of event.
+pub fn dtmf_event(payload: &[u8]) -> Option<DtmfEvent> {
+    if payload.len() < 4 {
+        return None;
+    }
+    let digit = match payload[0] {
+        n @ 0..=9 => (b'0' + n) as char,
+        10 => '*',
+        11 => '#',
+        n @ 12..=15 => (b'A' + n - 12) as char,
+        _ => return None,
+    };
+    Some(DtmfEvent { digit, end: payload[1] & 0x80 != 0 })
+}
+
+/// Reads the temporal layer ID out of a VP8 payload descriptor, if the sender included one.
+/// See <https://tools.ietf.org/html/rfc7741#section-4.2>.
+fn vp8_temporal_layer(payload: &[u8]) -> Option<u8> {
+    let extended = *payload.first()? & 0x80 != 0;
+    if !extended {
+        return None;
+    }
+    let flags = *payload.get(1)?;
+    let (has_picture_id, has_tl0_index, has_tid) = (flags & 0x80 != 0, flags & 0x40 != 0, flags & 0x20 != 0);
+    let mut offset = 2;
+    if has_picture_id {
+        offset += if *payload.get(offset)? & 0x80 != 0 { 2 } else { 1 };
+    }
+    if has_tl0_index {
+        offset += 1;
+    }
+    if has_tid {
+        Some(*payload.get(offset)? >> 6)
+    } else {
+        None
+    }
+}
+
+/// Reads the temporal layer ID out of a VP9 payload descriptor, if the sender included one.
+/// See <https://tools.ietf.org/html/draft-ietf-payload-vp9-05#section-4.2>.
+fn vp9_temporal_layer(payload: &[u8]) -> Option<u8> {
+    let flags = *payload.first()?;
+    let (has_picture_id, has_layer_indices) = (flags & 0x80 != 0, flags & 0x20 != 0);
+    if !has_layer_indices {
+        return None;
+    }
+    let mut offset = 1;
+    if has_picture_id {
+        offset += if *payload.get(offset)? & 0x80 != 0 { 2 } else { 1 };
+    }
+    Some(*payload.get(offset)? >> 5)
+}
+
+/// Returns whether the given VP8 payload starts a key frame.
+/// See <https://tools.ietf.org/html/rfc7741#section-4.2> and <https://tools.ietf.org/html/rfc6386#section-9.1>.
+fn vp8_is_keyframe(payload: &[u8]) -> bool {
+    // the P bit of the frame header is zero for key frames
+    vp8_frame_header(payload).and_then(|header| header.first()).is_some_and(|b| b & 0x01 == 0)
+}
+
+/// Returns the VP8 frame header in the given payload, past the payload descriptor, if the payload starts a frame.
+fn vp8_frame_header(payload: &[u8]) -> Option<&[u8]> {
+    let first = *payload.first()?;
+    let (extended, start, partition) = (first & 0x80 != 0, first & 0x10 != 0, first & 0x07);
+    if !start || partition != 0 {
+        return None;
+    }
+    let mut offset = 1;
+    if extended {
+        let flags = *payload.get(1)?;
+        offset += 1;
+        if flags & 0x80 != 0 {
+            offset += if payload.get(offset)? & 0x80 != 0 { 2 } else { 1 };
+        }
+        if flags & 0x40 != 0 {
+            offset += 1;
+        }
+        if flags & 0x30 != 0 {
+            offset += 1;
+        }
+    }
+    payload.get(offset..)
+}
+
+/// Returns the width and height of the frame that the given video payload starts, if it's a key frame which says.
+/// Only VP8 key frames are read; VP9's and H.264's sizes are buried too deep in their headers to be worth digging out.
+/// See <https://tools.ietf.org/html/rfc6386#section-9.1>.
+pub fn keyframe_resolution(codec: VideoCodec, payload: &[u8]) -> Option<(u16, u16)> {
+    if codec != VideoCodec::Vp8 {
+        return None;
+    }
+    let header = vp8_frame_header(payload)?;
+    // after the 3-byte frame tag, key frames have a start code, then the width and height in 14 bits each
+    if header.len() < 10 || header[0] & 0x01 != 0 || header[3..6] != [0x9d, 0x01, 0x2a] {
+        return None;
+    }
+    let width = u16::from(header[6]) | u16::from(header[7] & 0x3f) << 8;
+    let height = u16::from(header[8]) | u16::from(header[9] & 0x3f) << 8;
+    Some((width, height))
+}
+
+/// Returns whether the given VP9 payload starts a frame which isn't predicted from any other frame.
+/// See <https://tools.ietf.org/html/draft-ietf-payload-vp9-05#section-4.2>.
+fn vp9_is_keyframe(payload: &[u8]) -> bool {
+    payload.first().is_some_and(|flags| flags & 0x40 == 0 && flags & 0x08 != 0)
+}
+
+/// Returns whether the given H.264 payload carries an IDR slice or the parameter sets that precede one, looking inside
+/// aggregation and fragmentation units. See <https://tools.ietf.org/html/rfc6184#section-5.2>.
+fn h264_is_keyframe(payload: &[u8]) -> bool {
+    let is_key_nal = |nal_type: u8| nal_type == 5 || nal_type == 7;
+    match payload.first().map(|b| b & 0x1f) {
+        Some(24) => {
+            // STAP-A: each NAL unit is preceded by its 16-bit size
+            let mut offset = 1;
+            while let (Some(&hi), Some(&lo), Some(&nal)) = (payload.get(offset), payload.get(offset + 1), payload.get(offset + 2)) {
+                if is_key_nal(nal & 0x1f) {
+                    return true;
+                }
+                offset += 2 + ((hi as usize) << 8 | lo as usize);
+            }
+            false
+        }
+        // FU-A: only the first fragment says what it's a fragment of
+        Some(28) => payload.get(1).is_some_and(|fu| fu & 0x80 != 0 && is_key_nal(fu & 0x1f)),
+        Some(nal_type) => is_key_nal(nal_type),
+        None => false,
+    }
+}
+
+/// Returns whether the given video payload is the start of a key frame, i.e. one which can be decoded on its own.
+pub fn is_keyframe(codec: VideoCodec, payload: &[u8]) -> bool {
+    match codec {
+        VideoCodec::Vp8 => vp8_is_keyframe(payload),
+        VideoCodec::Vp9 => vp9_is_keyframe(payload),
+        VideoCodec::H264 => h264_is_keyframe(payload),
+    }
+}
+
+/// Returns the temporal layer that the given video payload belongs to, if the codec supports temporal layers and
+/// the sender marked it with one.
+pub fn temporal_layer(codec: VideoCodec, payload: &[u8]) -> Option<u8> {
+    match codec {
+        VideoCodec::Vp8 => vp8_temporal_layer(payload),
+        VideoCodec::Vp9 => vp9_temporal_layer(payload),
+        VideoCodec::H264 => None,
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0]) << 24 | u32::from(bytes[1]) << 16 | u32::from(bytes[2]) << 8 | u32::from(bytes[3])
+}
+
+fn write_u32(bytes: &mut [u8], val: u32) {
+    bytes[0] = (val >> 24) as u8;
+    bytes[1] = (val >> 16) as u8;
+    bytes[2] = (val >> 8) as u8;
+    bytes[3] = val as u8;
+}
+
+/// Overwrites the payload type in the header of the given RTP packet, keeping its marker bit.
+pub fn set_payload_type(packet: &mut [u8], payload_type: u8) {
+    packet[1] = (packet[1] & 0x80) | (payload_type & 0x7f);
+}
+
+/// Overwrites the sequence number and timestamp in the header of the given RTP packet.
+pub fn set_seq_and_timestamp(packet: &mut [u8], seq: u16, timestamp: u32) {
+    packet[2] = (seq >> 8) as u8;
+    packet[3] = seq as u8;
+    write_u32(&mut packet[4..8], timestamp);
+}
+
+/// Returns whether sequence number `a` comes after `b`, accounting for wraparound.
+fn is_seq_newer(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < 0x8000
+}
+
+/// Per-subscriber state which keeps the sequence numbers and timestamps of a forwarded RTP stream continuous when
+/// the source of the stream changes (e.g. the subscriber is switched to a different publisher or simulcast layer.)
+/// Without this, the receiver sees the numbering jump and its decoder stalls until it catches up.
+#[derive(Debug, Default)]
+pub struct SwitchingContext {
+    /// The SSRC of the source we are currently forwarding, if we've forwarded anything.
+    source_ssrc: Option<u32>,
+    /// The offset added to the source's sequence numbers.
+    seq_offset: u16,
+    /// The offset added to the source's timestamps.
+    timestamp_offset: u32,
+    /// The newest sequence number we've sent.
+    last_seq: u16,
+    /// The timestamp of the newest packet we've sent.
+    last_timestamp: u32,
+}
+
+impl SwitchingContext {
+    /// Records a packet going out to this subscriber and returns the sequence number and timestamp it should be
+    /// forwarded with. `timestamp_step` is how far to advance the timestamp if this packet starts a new source.
+    pub fn update(&mut self, header: &RtpHeader, timestamp_step: u32) -> (u16, u32) {
+        self.sync_source(header, timestamp_step);
+        let seq = header.seq.wrapping_add(self.seq_offset);
+        let timestamp = header.timestamp.wrapping_add(self.timestamp_offset);
+        if is_seq_newer(seq, self.last_seq) {
+            self.last_seq = seq;
+            self.last_timestamp = timestamp;
+        }
+        (seq, timestamp)
+    }
+
+    /// Records a packet that we decided not to forward to this subscriber, so that the sequence numbers of
+    /// subsequent packets close the gap it would otherwise leave.
+    pub fn skip(&mut self, header: &RtpHeader, timestamp_step: u32) {
+        self.sync_source(header, timestamp_step);
+        let seq = header.seq.wrapping_add(self.seq_offset);
+        if is_seq_newer(seq, self.last_seq) {
+            self.seq_offset = self.seq_offset.wrapping_sub(1);
+        }
+    }
+
+    fn sync_source(&mut self, header: &RtpHeader, timestamp_step: u32) {
+        match self.source_ssrc {
+            Some(ssrc) if ssrc == header.ssrc => {}
+            Some(_) => {
+                self.seq_offset = self.last_seq.wrapping_add(1).wrapping_sub(header.seq);
+                self.timestamp_offset = self.last_timestamp.wrapping_add(timestamp_step).wrapping_sub(header.timestamp);
+                self.source_ssrc = Some(header.ssrc);
+            }
+            None => {
+                self.source_ssrc = Some(header.ssrc);
+                self.last_seq = header.seq.wrapping_sub(1);
+            }
+        }
+    }
+}
+
+/// Per-publisher state which puts the publisher's RTP packets back in sequence number order before they're forwarded,
+/// holding on to packets that arrive early until the gap before them is filled, at most `depth` packets are waiting,
+/// or the oldest has waited for `REORDER_TIMEOUT`. Packets arriving too late to be put in order are passed through.
+/// Switching to a different SSRC flushes everything waiting, so this only helps with a single stream at a time.
+#[derive(Debug)]
+pub struct ReorderBuffer {
+    /// The most packets to hold on to at once.
+    depth: usize,
+    /// The SSRC of the stream we're currently putting in order.
+    ssrc: Option<u32>,
+    /// The sequence number of the next packet we expect to release.
+    next_seq: u16,
+    /// Packets we're holding on to, with when they arrived, in sequence number order.
+    pending: Vec<(u16, Instant, Vec<u8>)>,
+}
+
+impl ReorderBuffer {
+    pub fn new(depth: usize) -> Self {
+        Self { depth, ssrc: None, next_seq: 0, pending: Vec::new() }
+    }
+
+    /// Takes a packet which arrived at time `now`, and returns all of the packets which are ready to be forwarded,
+    /// in order.
+    pub fn push(&mut self, header: &RtpHeader, packet: Vec<u8>, now: Instant) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        if self.ssrc != Some(header.ssrc) {
+            ready.extend(self.pending.drain(..).map(|(_, _, p)| p));
+            self.ssrc = Some(header.ssrc);
+            self.next_seq = header.seq;
+        }
+        if !is_seq_newer(header.seq, self.next_seq) && header.seq != self.next_seq {
+            ready.push(packet);
+        } else if !self.pending.iter().any(|p| p.0 == header.seq) {
+            let index = self.pending.iter().position(|p| is_seq_newer(p.0, header.seq)).unwrap_or(self.pending.len());
+            self.pending.insert(index, (header.seq, now, packet));
+        }
+        self.release_in_order(&mut ready);
+        self.release_past_gaps(&mut ready, now);
+        ready
+    }
+
+    /// Returns the packets which are ready to be forwarded at time `now`, in order, because the gap before them has
+    /// been waited on too long, for when no packet has arrived since.
+    pub fn flush(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut ready = Vec::new();
+        self.release_past_gaps(&mut ready, now);
+        ready
+    }
+
+    /// Gives up on any gap which we've waited too long for, or which we don't have room to wait for.
+    fn release_past_gaps(&mut self, ready: &mut Vec<Vec<u8>>, now: Instant) {
+        while !self.pending.is_empty() && (self.pending.len() > self.depth || now.duration_since(self.pending[0].1) >= REORDER_TIMEOUT) {
+            self.next_seq = self.pending[0].0;
+            self.release_in_order(ready);
+        }
+    }
+
+    fn release_in_order(&mut self, ready: &mut Vec<Vec<u8>>) {
+        while !self.pending.is_empty() && self.pending[0].0 == self.next_seq {
+            ready.push(self.pending.remove(0).2);
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+    }
+}
+
+/// What subscribers are told about one of a publisher's video layers, so that they can pick one. Each measurement is
+/// absent until we've had the chance to make it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct LayerDescriptor {
+    pub ssrc: u32,
+    /// In bits per second.
+    pub bitrate: Option<u64>,
+    /// In frames per second.
+    pub framerate: Option<u32>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+}
+
+/// One video layer that a publisher is sending, and what we've measured of it.
+#[derive(Debug)]
+struct Layer {
+    ssrc: u32,
+    last_seen: Instant,
+    /// When the current measurement started, and how many bytes and frames have arrived since.
+    window_start: Instant,
+    window_bytes: u64,
+    window_frames: u32,
+    /// The timestamp of the newest packet, which tells us when a new frame starts.
+    last_timestamp: Option<u32>,
+    bitrate: Option<u64>,
+    framerate: Option<u32>,
+    resolution: Option<(u16, u16)>,
+    /// The highest VP8/VP9 temporal layer we've seen in this layer, if any.
+    top_temporal_layer: Option<u8>,
+    /// The bitrate we last told subscribers about.
+    reported_bitrate: Option<u64>,
+}
+
+/// Per-publisher state tracking which video streams, i.e. simulcast layers, the publisher is currently sending, and
+/// how big and fast each one is. Layers are identified by SSRC, since that's what we can see on every packet.
+#[derive(Debug, Default)]
+pub struct ActiveLayers {
+    layers: Vec<Layer>,
+}
+
+impl ActiveLayers {
+    /// Records a video packet of `len` bytes from the given SSRC arriving at time `now`, with the given RTP timestamp
+    /// and, if it starts a key frame which says, the frame's resolution. Forgets any layers which haven't been seen in
+    /// `LAYER_TIMEOUT`, and returns whether subscribers should be told about the layers again, i.e. whether the set of
+    /// active layers or a layer's resolution changed, or a layer's bitrate changed by more than `LAYER_BITRATE_CHANGE`.
+    pub fn update(&mut self, ssrc: u32, len: usize, timestamp: u32, resolution: Option<(u16, u16)>, now: Instant) -> bool {
+        let count = self.layers.len();
+        self.layers.retain(|layer| layer.ssrc == ssrc || now.duration_since(layer.last_seen) < LAYER_TIMEOUT);
+        let mut changed = self.layers.len() != count;
+        let index = match self.layers.iter().position(|layer| layer.ssrc == ssrc) {
+            Some(index) => index,
+            None => {
+                self.layers.push(Layer {
+                    ssrc,
+                    last_seen: now,
+                    window_start: now,
+                    window_bytes: 0,
+                    window_frames: 0,
+                    last_timestamp: None,
+                    bitrate: None,
+                    framerate: None,
+                    resolution: None,
+                    top_temporal_layer: None,
+                    reported_bitrate: None,
+                });
+                changed = true;
+                self.layers.len() - 1
+            }
+        };
+        let layer = &mut self.layers[index];
+        layer.last_seen = now;
+        layer.window_bytes += len as u64;
+        let new_frame = layer.last_timestamp != Some(timestamp);
+        if new_frame {
+            layer.window_frames += 1;
+            layer.last_timestamp = Some(timestamp);
+        }
+        if resolution.is_some() && resolution != layer.resolution {
+            layer.resolution = resolution;
+            changed = true;
+        }
+        let elapsed = now.duration_since(layer.window_start);
+        if elapsed >= LAYER_STATS_INTERVAL {
+            let secs = elapsed.as_secs_f64();
+            let bitrate = (layer.window_bytes as f64 * 8.0 / secs) as u64;
+            layer.bitrate = Some(bitrate);
+            // a frame starting right as the window ends belongs to the next window
+            let carried = new_frame as u32;
+            layer.framerate = Some((f64::from(layer.window_frames - carried) / secs).round() as u32);
+            layer.window_start = now;
+            layer.window_bytes = 0;
+            layer.window_frames = carried;
+            let significant = match layer.reported_bitrate {
+                Some(reported) => (bitrate as f64 - reported as f64).abs() > reported as f64 * LAYER_BITRATE_CHANGE,
+                None => true,
+            };
+            if significant {
+                layer.reported_bitrate = Some(bitrate);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    /// Records a video packet from the given SSRC in the given temporal layer, after it was passed to `update`. Returns
+    /// whether that's a higher temporal layer than we'd seen in that SSRC before.
+    pub fn note_temporal_layer(&mut self, ssrc: u32, temporal_layer: u8) -> bool {
+        match self.layers.iter_mut().find(|layer| layer.ssrc == ssrc) {
+            Some(layer) if layer.top_temporal_layer.is_none_or(|top| temporal_layer > top) => {
+                layer.top_temporal_layer = Some(temporal_layer);
+                true
+            }
+            _ => false
+        }
+    }
+
+    /// The highest temporal layer we've seen in the given SSRC, if it's being sent and has temporal layers.
+    pub fn top_temporal_layer(&self, ssrc: u32) -> Option<u8> {
+        self.layers.iter().find(|layer| layer.ssrc == ssrc).and_then(|layer| layer.top_temporal_layer)
+    }
+
+    /// The SSRCs of the layers currently being sent, in ascending order.
+    pub fn ssrcs(&self) -> Vec<u32> {
+        self.descriptors().iter().map(|layer| layer.ssrc).collect()
+    }
+
+    /// Describes the layers currently being sent, in ascending order of SSRC.
+    pub fn descriptors(&self) -> Vec<LayerDescriptor> {
+        let mut result: Vec<_> = self.layers.iter().map(|layer| LayerDescriptor {
+            ssrc: layer.ssrc,
+            bitrate: layer.bitrate,
+            framerate: layer.framerate,
+            width: layer.resolution.map(|(width, _)| width),
+            height: layer.resolution.map(|(_, height)| height),
+        }).collect();
+        result.sort_by_key(|layer| layer.ssrc);
+        result
+    }
+}
+
+/// The most of a publisher's video that a subscriber wants to be sent, e.g. because it's shown in a small window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VideoConstraints {
+    pub max_width: Option<u16>,
+    pub max_height: Option<u16>,
+    pub max_fps: Option<u32>,
+}
+
+impl VideoConstraints {
+    /// Whether the given layer's resolution is within these constraints, as far as we know it.
+    fn allows_resolution(&self, layer: &LayerDescriptor) -> bool {
+        let within = |size: Option<u16>, max: Option<u16>| size.zip(max).is_none_or(|(size, max)| size <= max);
+        within(layer.width, self.max_width) && within(layer.height, self.max_height)
+    }
+}
+
+/// Picks which of the given simulcast layers to forward to a subscriber with the given constraints: the biggest one
+/// that doesn't exceed them, or if they all do, the smallest. Layers whose resolution we don't know yet are taken not
+/// to exceed them, and layers of the same resolution are told apart by bitrate. Returns `None`, meaning that every
+/// layer should be forwarded, if there's nothing to choose between or the constraints don't limit the resolution.
+pub fn choose_layer(layers: &[LayerDescriptor], constraints: &VideoConstraints) -> Option<u32> {
+    if layers.len() < 2 || (constraints.max_width.is_none() && constraints.max_height.is_none()) {
+        return None;
+    }
+    let size = |layer: &&LayerDescriptor| {
+        let pixels = layer.width.zip(layer.height).map(|(width, height)| u32::from(width) * u32::from(height));
+        (pixels, layer.bitrate)
+    };
+    let (fitting, exceeding): (Vec<_>, Vec<_>) = layers.iter().partition(|layer| constraints.allows_resolution(layer));
+    match fitting.into_iter().max_by_key(size) {
+        Some(layer) => Some(layer.ssrc),
+        None => exceeding.into_iter().min_by_key(size).map(|layer| layer.ssrc),
+    }
+}
+
+/// Returns the highest VP8/VP9 temporal layer to forward of video arriving at `framerate`, out of layers up to
+/// `top_layer`, so that at most `max_fps` frames per second are forwarded, given that each layer doubles the framerate
+/// of the ones below it. Returns `usize::MAX`, meaning every layer, if the video is slow enough already.
+pub fn temporal_layer_within(framerate: u32, max_fps: u32, top_layer: usize) -> usize {
+    if framerate <= max_fps {
+        return usize::MAX;
+    }
+    let (mut layer, mut fps) = (top_layer, framerate);
+    while fps > max_fps && layer > 0 {
+        fps /= 2;
+        layer -= 1;
+    }
+    layer
+}
+
+/// Per-subscriber state tracking which of a publisher's simulcast layers we're forwarding. Switching layers waits for
+/// a key frame on the new one, since the subscriber can't decode the new layer before that, and the old one keeps
+/// being forwarded meanwhile so that the picture doesn't freeze.
+#[derive(Debug, Default)]
+pub struct LayerSelection {
+    /// The SSRC of the layer we're forwarding, if we're forwarding just one.
+    forwarding: Option<u32>,
+    /// The SSRC of the layer we want to forward, if we want just one.
+    target: Option<u32>,
+}
+
+impl LayerSelection {
+    /// Changes which layer we want to forward, given the layers currently being sent. Returns whether we're now
+    /// waiting for a key frame to switch on.
+    pub fn retarget(&mut self, target: Option<u32>, active: &[u32]) -> bool {
+        self.target = target;
+        if target.is_none() || self.forwarding.is_none_or(|ssrc| !active.contains(&ssrc)) {
+            // there's nothing worth holding on to until the switch
+            self.forwarding = None;
+        }
+        target.is_some() && self.forwarding != target
+    }
+
+    /// Returns whether to forward a video packet from the given SSRC, which starts a key frame if `keyframe`.
+    pub fn forward(&mut self, ssrc: u32, keyframe: bool) -> bool {
+        if self.target == Some(ssrc) && keyframe {
+            self.forwarding = Some(ssrc);
+        }
+        match (self.forwarding, self.target) {
+            (Some(forwarding), _) => forwarding == ssrc,
+            (None, target) => target.is_none(),
+        }
+    }
+}
+
+/// Per-publisher state which caps the framerate of the video we forward by dropping whole frames from VP8/VP9 temporal
+/// layers above the base layer, which nothing in a lower layer depends on. Frames are told apart by their timestamps.
+#[derive(Debug, Default)]
+pub struct FramerateLimiter {
+    /// The timestamp of the frame we're in the middle of, and whether it's being forwarded.
+    current_frame: Option<(u32, bool)>,
+    /// The timestamp of the newest frame we forwarded.
+    last_forwarded: Option<u32>,
+    /// The temporal layer of a frame we dropped, if frames in the layers above it may still depend on it.
+    dropped_layer: Option<u8>,
+}
+
+impl FramerateLimiter {
+    /// Returns whether to forward a video packet with the given timestamp from the given temporal layer, given that
+    /// we want to forward at most `max_fps` frames per second. Packets without a temporal layer are always forwarded.
+    pub fn forward(&mut self, timestamp: u32, layer: Option<u8>, max_fps: u32) -> bool {
+        match self.current_frame {
+            Some((current, forward)) if current == timestamp => return forward,
+            _ => {}
+        }
+        let forward = match layer {
+            None | Some(0) => true,
+            Some(layer) => {
+                if self.dropped_layer.is_some_and(|dropped| layer > dropped) {
+                    false
+                } else {
+                    let interval = VIDEO_CLOCK_RATE / max_fps.max(1);
+                    self.last_forwarded.is_none_or(|last| timestamp.wrapping_sub(last) >= interval)
+                }
+            }
+        };
+        match (forward, layer) {
+            (true, Some(layer)) if self.dropped_layer.is_some_and(|dropped| layer <= dropped) => self.dropped_layer = None,
+            (false, Some(layer)) if self.dropped_layer.is_none() => self.dropped_layer = Some(layer),
+            _ => {}
+        }
+        if forward {
+            self.last_forwarded = Some(timestamp);
+        }
+        self.current_frame = Some((timestamp, forward));
+        forward
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn header(ssrc: u32, seq: u16, timestamp: u32) -> RtpHeader {
+        RtpHeader { payload_type: 100, seq, timestamp, ssrc }
+    }
+
+    #[test]
+    fn parse_header() {
+        let packet = [0x80, 0xe4, 0x12, 0x34, 0, 0, 0x0b, 0xb8, 0xde, 0xad, 0xbe, 0xef, 0xff];
+        assert_eq!(RtpHeader::parse(&packet), Some(header(0xdeadbeef, 0x1234, 3000)));
+        assert_eq!(RtpHeader::parse(&packet[..11]), None);
+    }
+
+    #[test]
+    fn rewrite_header() {
+        let mut packet = [0x80, 0xe4, 0x12, 0x34, 0, 0, 0x0b, 0xb8, 0xde, 0xad, 0xbe, 0xef];
+        set_seq_and_timestamp(&mut packet, 7, 0x01020304);
+        assert_eq!(RtpHeader::parse(&packet), Some(header(0xdeadbeef, 7, 0x01020304)));
+        set_payload_type(&mut packet, 111);
+        assert_eq!(packet[1], 0x80 | 111);
+    }
+
+    #[test]
+    fn passthrough_single_source() {
+        let mut context = SwitchingContext::default();
+        assert_eq!(context.update(&header(1, 500, 10000), VIDEO_TIMESTAMP_STEP), (500, 10000));
+        assert_eq!(context.update(&header(1, 501, 13000), VIDEO_TIMESTAMP_STEP), (501, 13000));
+        assert_eq!(context.update(&header(1, 502, 16000), VIDEO_TIMESTAMP_STEP), (502, 16000));
+    }
+
+    #[test]
+    fn continuous_across_switch() {
+        let mut context = SwitchingContext::default();
+        context.update(&header(1, 500, 10000), VIDEO_TIMESTAMP_STEP);
+        context.update(&header(1, 501, 13000), VIDEO_TIMESTAMP_STEP);
+        assert_eq!(context.update(&header(2, 40000, 999999), VIDEO_TIMESTAMP_STEP), (502, 16000));
+        assert_eq!(context.update(&header(2, 40001, 1002999), VIDEO_TIMESTAMP_STEP), (503, 19000));
+        assert_eq!(context.update(&header(1, 502, 16000), VIDEO_TIMESTAMP_STEP), (504, 22000));
+    }
+
+    #[test]
+    fn skipped_packets_leave_no_gap() {
+        let mut context = SwitchingContext::default();
+        assert_eq!(context.update(&header(1, 10, 0), VIDEO_TIMESTAMP_STEP), (10, 0));
+        context.skip(&header(1, 11, 3000), VIDEO_TIMESTAMP_STEP);
+        context.skip(&header(1, 12, 3000), VIDEO_TIMESTAMP_STEP);
+        assert_eq!(context.update(&header(1, 13, 6000), VIDEO_TIMESTAMP_STEP), (11, 6000));
+        assert_eq!(context.update(&header(1, 14, 9000), VIDEO_TIMESTAMP_STEP), (12, 9000));
+    }
+
+    #[test]
+    fn payload_skips_csrcs_extensions_and_padding() {
+        let mut packet = vec![0xb1, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(&[0, 0, 0, 9]); // one CSRC
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 0x10, 0xff, 0, 0]); // one-word extension
+        packet.extend_from_slice(&[0xaa, 0xbb]); // payload
+        packet.extend_from_slice(&[0, 0, 3]); // three bytes of padding
+        assert_eq!(payload(&packet), Some(&[0xaa, 0xbb][..]));
+    }
+
+    #[test]
+    fn blanking_keeps_header_and_padding() {
+        let mut packet = vec![0xb0, 0x60, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 0x10, 0xff, 0, 0]); // one-word extension
+        packet.extend_from_slice(&[0xaa, 0xbb, 0xcc]); // payload
+        packet.extend_from_slice(&[0, 2]); // two bytes of padding
+        let mut expected = packet.clone();
+        expected[20..23].copy_from_slice(&[0, 0, 0]);
+        blank_payload(&mut packet);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn vp8_temporal_layers() {
+        // X=1; I=1, T=1; 15-bit picture ID; TID=2
+        assert_eq!(temporal_layer(VideoCodec::Vp8, &[0x90, 0xa0, 0x81, 0x23, 0x80]), Some(2));
+        // X=1; L=1, T=1; no picture ID; TID=1
+        assert_eq!(temporal_layer(VideoCodec::Vp8, &[0x90, 0x60, 0x05, 0x40]), Some(1));
+        // X=1; K=1 only, so no TID
+        assert_eq!(temporal_layer(VideoCodec::Vp8, &[0x90, 0x10, 0x1f]), None);
+        // no extension at all
+        assert_eq!(temporal_layer(VideoCodec::Vp8, &[0x10, 0x00]), None);
+    }
+
+    #[test]
+    fn vp9_temporal_layers() {
+        // I=1, L=1; 7-bit picture ID; TID=3
+        assert_eq!(temporal_layer(VideoCodec::Vp9, &[0xa0, 0x12, 0x60]), Some(3));
+        // L=0
+        assert_eq!(temporal_layer(VideoCodec::Vp9, &[0x80, 0x12]), None);
+        assert_eq!(temporal_layer(VideoCodec::H264, &[0xa0, 0x12, 0x60]), None);
+    }
+
+    #[test]
+    fn detects_keyframes() {
+        // S=1, PID=0; P=0
+        assert!(is_keyframe(VideoCodec::Vp8, &[0x10, 0x50]));
+        // X=1; I=1, T=1; 15-bit picture ID; TID; S=1; P=0
+        assert!(is_keyframe(VideoCodec::Vp8, &[0x90, 0xa0, 0x81, 0x23, 0x80, 0x50]));
+        // P=1
+        assert!(!is_keyframe(VideoCodec::Vp8, &[0x10, 0x51]));
+        // S=0, so not the start of the frame
+        assert!(!is_keyframe(VideoCodec::Vp8, &[0x00, 0x50]));
+        // P=0, B=1
+        assert!(is_keyframe(VideoCodec::Vp9, &[0x88]));
+        // P=1, B=1
+        assert!(!is_keyframe(VideoCodec::Vp9, &[0xc8]));
+        // IDR slice; SPS; non-IDR slice
+        assert!(is_keyframe(VideoCodec::H264, &[0x65, 0x00]));
+        assert!(is_keyframe(VideoCodec::H264, &[0x67, 0x00]));
+        assert!(!is_keyframe(VideoCodec::H264, &[0x41, 0x00]));
+        // STAP-A holding an SEI and then an SPS
+        assert!(is_keyframe(VideoCodec::H264, &[0x78, 0x00, 0x02, 0x06, 0x00, 0x00, 0x02, 0x67, 0x00]));
+        assert!(!is_keyframe(VideoCodec::H264, &[0x78, 0x00, 0x02, 0x06, 0x00]));
+        // FU-A starting an IDR slice, then continuing it
+        assert!(is_keyframe(VideoCodec::H264, &[0x7c, 0x85, 0x00]));
+        assert!(!is_keyframe(VideoCodec::H264, &[0x7c, 0x05, 0x00]));
+    }
+
+    #[test]
+    fn reads_keyframe_resolution() {
+        // S=1, PID=0; key frame tag; start code; 1280 wide and 720 high, with the scaling bits set
+        let key = [0x10, 0x50, 0x00, 0x00, 0x9d, 0x01, 0x2a, 0x00, 0x45, 0xd0, 0x02];
+        assert_eq!(keyframe_resolution(VideoCodec::Vp8, &key), Some((1280, 720)));
+        assert_eq!(keyframe_resolution(VideoCodec::Vp8, &[0x10, 0x51, 0x00, 0x00]), None);
+        assert_eq!(keyframe_resolution(VideoCodec::Vp8, &key[..8]), None);
+        assert_eq!(keyframe_resolution(VideoCodec::H264, &[0x65, 0x00]), None);
+    }
+
+    #[test]
+    fn reordered_packets_keep_offsets() {
+        let mut context = SwitchingContext::default();
+        context.update(&header(1, 65535, 10000), AUDIO_TIMESTAMP_STEP);
+        assert_eq!(context.update(&header(2, 100, 5000), AUDIO_TIMESTAMP_STEP), (0, 10960));
+        assert_eq!(context.update(&header(2, 102, 6920), AUDIO_TIMESTAMP_STEP), (2, 12880));
+        assert_eq!(context.update(&header(2, 101, 5960), AUDIO_TIMESTAMP_STEP), (1, 11920));
+        assert_eq!(context.update(&header(2, 103, 7880), AUDIO_TIMESTAMP_STEP), (3, 13840));
+    }
+
+    #[test]
+    fn finds_extmap_ids() {
+        let sdp = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=extmap:3/sendrecv urn:ietf:params:rtp-hdrext:sdes:mid\r\n\
+                   a=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n";
+        assert_eq!(extmap_id(sdp, AUDIO_LEVEL_URI), Some(1));
+        assert_eq!(extmap_id(sdp, "urn:ietf:params:rtp-hdrext:sdes:mid"), Some(3));
+        assert_eq!(extmap_id(sdp, "urn:ietf:params:rtp-hdrext:toffset"), None);
+    }
+
+    #[test]
+    fn finds_sending_media_sections() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=sendrecv\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=recvonly\r\n\
+                   m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n";
+        assert!(sends_media(sdp, "audio"));
+        assert!(!sends_media(sdp, "video"));
+        assert!(sends_media("m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n", "video"));
+        assert!(!sends_media("m=video 0 UDP/TLS/RTP/SAVPF 96\r\n", "video"));
+        assert!(!sends_media("m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=inactive\r\n", "audio"));
+    }
+
+    #[test]
+    fn media_lines_only_change_with_the_media() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111 0\r\na=ice-ufrag:abcd\r\na=ice-pwd:efgh\r\na=rtpmap:111 opus/48000/2\r\n\
+                   a=rtpmap:0 PCMU/8000\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(media_lines(sdp), vec![
+            ("audio".to_owned(), vec!["opus/48000/2".to_owned(), "pcmu/8000".to_owned()]),
+            ("video".to_owned(), vec!["vp8/90000".to_owned()]),
+        ]);
+        // an ICE restart, with the codecs listed in a different order
+        let restarted = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 0 111\r\na=ice-ufrag:wxyz\r\na=ice-pwd:ijkl\r\na=rtpmap:0 PCMU/8000\r\n\
+                         a=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(media_lines(restarted), media_lines(sdp));
+        // a downgrade to PCMU only
+        let downgraded = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 0\r\na=rtpmap:0 PCMU/8000\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+                          a=rtpmap:96 VP8/90000\r\n";
+        assert_ne!(media_lines(downgraded), media_lines(sdp));
+        // an extra media line
+        let extra = format!("{}m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n", sdp);
+        assert_ne!(media_lines(&extra), media_lines(sdp));
+    }
+
+    #[test]
+    fn adds_red() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111 63\r\na=rtpmap:111 opus/48000/2\r\na=rtpmap:63 red/48000/2\r\n\
+                   a=fmtp:63 111/111\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n";
+        assert_eq!(red_payload_type(sdp), Some(63));
+        let answer = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=rtpmap:111 opus/48000/2\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n";
+        assert_eq!(red_payload_type(answer), None);
+        assert_eq!(with_red(answer, 63, 111), "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 63 111\r\na=rtpmap:111 opus/48000/2\r\n\
+                   a=rtpmap:63 red/48000/2\r\na=fmtp:63 111/111\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n");
+    }
+
+    #[test]
+    fn unwraps_red() {
+        let mut packet = vec![0x80, 63, 0x00, 0x01, 0x00, 0x00, 0x03, 0xc0, 0x00, 0x00, 0x00, 0x01];
+        // one redundant block of 2 bytes from 960 ticks earlier, then the primary block, both Opus
+        packet.extend_from_slice(&[0x80 | 111, 0x0f, 0x00, 0x02, 111, 0xaa, 0xbb, 0xcc, 0xdd, 0xee]);
+        let unwrapped = red_primary(&packet).unwrap();
+        assert_eq!(RtpHeader::parse(&unwrapped).unwrap().payload_type, 111);
+        assert_eq!(payload(&unwrapped), Some(&[0xcc, 0xdd, 0xee][..]));
+        // no redundancy at all
+        let mut packet = packet[..12].to_vec();
+        packet.extend_from_slice(&[111, 0xcc]);
+        assert_eq!(payload(&red_primary(&packet).unwrap()), Some(&[0xcc][..]));
+        // a redundant block running past the end
+        let mut packet = packet[..12].to_vec();
+        packet.extend_from_slice(&[0x80 | 111, 0x0f, 0x00, 0x09, 111, 0xaa]);
+        assert_eq!(red_primary(&packet), None);
+    }
+
+    #[test]
+    fn sets_fmtp() {
+        let sdp = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 98\r\na=rtpmap:96 H264/90000\r\n\
+                   a=fmtp:96 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\na=rtpmap:98 VP8/90000\r\n";
+        assert_eq!(with_fmtp(sdp, 96, "profile-level-id=42e01f;packetization-mode=1"), "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 98\r\n\
+                   a=rtpmap:96 H264/90000\r\na=fmtp:96 profile-level-id=42e01f;packetization-mode=1\r\na=rtpmap:98 VP8/90000\r\n");
+        let no_fmtp = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 H264/90000\r\na=sendonly\r\n";
+        assert_eq!(with_fmtp(no_fmtp, 96, "packetization-mode=1"), "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+                   a=rtpmap:96 H264/90000\r\na=fmtp:96 packetization-mode=1\r\na=sendonly\r\n");
+    }
+
+    #[test]
+    fn sets_max_message_size() {
+        let sdp = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=sendrecv\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\n\
+                   a=sctp-port:5000\r\na=max-message-size:262144\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=sendrecv\r\n";
+        assert_eq!(with_max_message_size(sdp, 1048576), "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=sendrecv\r\n\
+                   m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=sctp-port:5000\r\na=max-message-size:1048576\r\n\
+                   m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=sendrecv\r\n");
+        let data_last = "v=0\r\nm=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\na=sctp-port:5000\r\n";
+        assert_eq!(with_max_message_size(data_last, 2048), format!("{}a=max-message-size:2048\r\n", data_last));
+        let no_data = "v=0\r\nm=audio 9 UDP/TLS/RTP/SAVPF 111\r\n";
+        assert_eq!(with_max_message_size(no_data, 2048), no_data);
+    }
+
+    #[test]
+    fn finds_h264_profiles() {
+        let sdp = "m=video 9 UDP/TLS/RTP/SAVPF 96 102 127\r\na=rtpmap:96 VP8/90000\r\na=rtpmap:102 H264/90000\r\n\
+                   a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42001f\r\n\
+                   a=rtpmap:127 H264/90000\r\na=fmtp:127 profile-level-id=42E01F\r\n";
+        assert_eq!(h264_payload_type(sdp, None, None), Some(102));
+        assert_eq!(h264_payload_type(sdp, Some("42e01f"), None), Some(127));
+        assert_eq!(h264_payload_type(sdp, Some("42e01f"), Some(1)), None);
+        assert_eq!(h264_payload_type(sdp, None, Some(0)), Some(127));
+        assert_eq!(h264_payload_type(sdp, Some("640032"), None), None);
+    }
+
+    #[test]
+    fn rewriting_preserves_extensions() {
+        // a video packet carrying the video orientation extension with ID 4, saying the camera is rotated 90 degrees
+        let mut packet = vec![0x90, 100, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0xbe, 0xde, 0, 1, 0x40, 0x01, 0, 0, 0xaa];
+        let original = packet.clone();
+        set_seq_and_timestamp(&mut packet, 500, 9000);
+        assert_eq!(RtpHeader::parse(&packet).map(|h| (h.seq, h.timestamp)), Some((500, 9000)));
+        assert_eq!(extension(&packet, 4), Some(&[0x01][..]));
+        assert_eq!(&packet[8..], &original[8..]);
+    }
+
+    #[test]
+    fn reads_audio_level() {
+        let mut packet = vec![0x90, 111, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        // padding, then a two-byte element with ID 2, then the one-byte audio level with ID 1 and the voice bit set
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 2, 0x00, 0x21, 0xaa, 0xbb, 0x10, 0x80 | 42, 0, 0]);
+        packet.extend_from_slice(&[0xde, 0xad]);
+        assert_eq!(audio_level(&packet, 1), Some(42));
+        assert_eq!(audio_level(&packet, 3), None);
+        packet[0] = 0x80;
+        assert_eq!(audio_level(&packet, 1), None);
+    }
+
+    #[test]
+    fn reads_dtmf_events() {
+        assert_eq!(dtmf_event(&[5, 0x0a, 0x01, 0x40]), Some(DtmfEvent { digit: '5', end: false }));
+        assert_eq!(dtmf_event(&[11, 0x8a, 0x03, 0x20]), Some(DtmfEvent { digit: '#', end: true }));
+        assert_eq!(dtmf_event(&[13, 0x8a, 0x03, 0x20]), Some(DtmfEvent { digit: 'B', end: true }));
+        assert_eq!(dtmf_event(&[16, 0x8a, 0x03, 0x20]), None);
+        assert_eq!(dtmf_event(&[5, 0x8a]), None);
+    }
+
+    #[test]
+    fn remaps_extension_ids() {
+        let mut packet = vec![0x90, 96, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        // a one-byte element with ID 3, padding, a two-byte element with ID 5, and a one-byte element with ID 7
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 2, 0x30, 0xaa, 0x00, 0x51, 0xbb, 0xcc, 0x70, 0xdd]);
+        packet.extend_from_slice(&[0xde, 0xad]);
+        let remapped = remap_extension_ids(&packet, &[(3, 7), (5, 5)]).unwrap();
+        let mut expected = vec![0x90, 96, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        expected.extend_from_slice(&[0xbe, 0xde, 0, 2, 0x70, 0xaa, 0x51, 0xbb, 0xcc, 0, 0, 0]);
+        expected.extend_from_slice(&[0xde, 0xad]);
+        assert_eq!(remapped, expected);
+        assert_eq!(payload(&remapped), Some(&[0xde, 0xad][..]));
+        packet[0] = 0x80;
+        assert_eq!(remap_extension_ids(&packet, &[(3, 7)]), None);
+    }
+
+    #[test]
+    fn finds_extmap_ids_per_media_section() {
+        let sdp = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=extmap:1 urn:ietf:params:rtp-hdrext:ssrc-audio-level\r\n\
+                   m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=extmap:12 http://www.webrtc.org/experiments/rtp-hdrext/playout-delay\r\n";
+        let playout_delay = "http://www.webrtc.org/experiments/rtp-hdrext/playout-delay";
+        assert_eq!(media_extmap_id(sdp, "video", playout_delay), Some(12));
+        assert_eq!(media_extmap_id(sdp, "audio", playout_delay), None);
+        assert_eq!(media_extmap_id(sdp, "audio", AUDIO_LEVEL_URI), Some(1));
+    }
+
+    #[test]
+    fn reads_rid() {
+        let mut packet = vec![0x90, 96, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        // a three-byte RID with ID 4, then padding
+        packet.extend_from_slice(&[0xbe, 0xde, 0, 1, 0x42, b'm', b'i', b'd']);
+        assert_eq!(rid(&packet, 4), Some("mid"));
+        assert_eq!(rid(&packet, 5), None);
+    }
+
+    #[test]
+    fn finds_candidate_types() {
+        let sdp = "m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+                   a=candidate:1 1 udp 2122260223 192.168.1.2 51234 typ host generation 0\r\n\
+                   a=candidate:2 1 udp 1686052607 203.0.113.7 51234 typ srflx raddr 192.168.1.2 rport 51234\r\n\
+                   a=candidate:3 1 udp 2122260223 192.168.1.3 51235 typ host generation 0\r\n\
+                   a=candidate:4 1 udp 41885439 198.51.100.9 3478 typ relay raddr 203.0.113.7 rport 51234\r\n";
+        assert_eq!(candidate_types(sdp), vec!["host", "srflx", "relay"]);
+        assert!(candidate_types("m=audio 9 UDP/TLS/RTP/SAVPF 111\r\na=end-of-candidates\r\n").is_empty());
+    }
+
+    #[test]
+    fn finds_simulcast_rids() {
+        let sdp = "m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rid:h send\r\na=rid:m send max-width=640\r\na=rid:l send\r\n\
+                   a=rid:x recv\r\na=simulcast:send h;~m,l;x;q\r\n";
+        assert_eq!(simulcast_rids(sdp), vec!["h", "m", "l"]);
+        assert!(simulcast_rids("m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rid:h send\r\n").is_empty());
+        assert!(simulcast_rids("m=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rid:h recv\r\na=simulcast:recv h\r\n").is_empty());
+    }
+
+    #[test]
+    fn tracks_active_layers() {
+        let start = Instant::now();
+        let mut layers = ActiveLayers::default();
+        assert!(layers.update(2, 100, 0, None, start));
+        assert!(layers.update(1, 100, 0, None, start));
+        assert!(!layers.update(2, 100, 0, None, start + Duration::from_millis(100)));
+        assert_eq!(layers.ssrcs(), vec![1, 2]);
+
+        // layer 1 stops arriving
+        assert!(layers.update(2, 100, 0, None, start + Duration::from_secs(1))); // layer 2's first measurement
+        assert!(layers.update(2, 100, 0, None, start + Duration::from_secs(3)));
+        assert_eq!(layers.ssrcs(), vec![2]);
+    }
+
+    fn layer(ssrc: u32, resolution: Option<(u16, u16)>, bitrate: u64) -> LayerDescriptor {
+        LayerDescriptor {
+            ssrc,
+            bitrate: Some(bitrate),
+            framerate: Some(30),
+            width: resolution.map(|(width, _)| width),
+            height: resolution.map(|(_, height)| height),
+        }
+    }
+
+    #[test]
+    fn chooses_layers_within_constraints() {
+        let layers = [
+            layer(1, Some((1280, 720)), 2_500_000),
+            layer(2, Some((640, 360)), 800_000),
+            layer(3, Some((320, 180)), 200_000),
+        ];
+        let constrain = |max_width, max_height| VideoConstraints { max_width, max_height, max_fps: None };
+        assert_eq!(choose_layer(&layers, &constrain(Some(640), None)), Some(2));
+        assert_eq!(choose_layer(&layers, &constrain(Some(1000), Some(400))), Some(2));
+        assert_eq!(choose_layer(&layers, &constrain(None, Some(2160))), Some(1));
+        // nothing fits, so the closest
+        assert_eq!(choose_layer(&layers, &constrain(Some(100), None)), Some(3));
+        // no limit on resolution, or nothing to choose between
+        assert_eq!(choose_layer(&layers, &VideoConstraints { max_fps: Some(15), ..Default::default() }), None);
+        assert_eq!(choose_layer(&layers[..1], &constrain(Some(640), None)), None);
+        // without resolutions, go by bitrate
+        let unknown = [layer(1, None, 2_500_000), layer(2, None, 200_000)];
+        assert_eq!(choose_layer(&unknown, &constrain(Some(640), None)), Some(1));
+    }
+
+    #[test]
+    fn caps_temporal_layers_by_framerate() {
+        assert_eq!(temporal_layer_within(30, 30, 2), usize::MAX);
+        assert_eq!(temporal_layer_within(30, 15, 2), 1);
+        assert_eq!(temporal_layer_within(30, 10, 2), 0);
+        assert_eq!(temporal_layer_within(30, 1, 2), 0);
+    }
+
+    #[test]
+    fn switches_layers_on_key_frames() {
+        let mut selection = LayerSelection::default();
+        assert!(selection.forward(1, false) && selection.forward(2, false));
+        // nothing to hold on to yet, so wait for the new layer's key frame
+        assert!(selection.retarget(Some(1), &[1, 2]));
+        assert!(!selection.forward(1, false));
+        assert!(selection.forward(1, true));
+        assert!(!selection.forward(2, true));
+        // keep forwarding the old layer until the new one has a key frame
+        assert!(selection.retarget(Some(2), &[1, 2]));
+        assert!(selection.forward(1, false));
+        assert!(!selection.forward(2, false));
+        assert!(selection.forward(2, true));
+        assert!(!selection.forward(1, false));
+        assert!(!selection.retarget(Some(2), &[1, 2]));
+        // no constraints any more
+        assert!(!selection.retarget(None, &[1, 2]));
+        assert!(selection.forward(1, false) && selection.forward(2, false));
+    }
+
+    #[test]
+    fn finds_top_temporal_layers() {
+        let start = Instant::now();
+        let mut layers = ActiveLayers::default();
+        layers.update(1, 100, 0, None, start);
+        assert_eq!(layers.top_temporal_layer(1), None);
+        assert!(layers.note_temporal_layer(1, 0));
+        assert!(layers.note_temporal_layer(1, 1));
+        assert!(!layers.note_temporal_layer(1, 0));
+        assert_eq!(layers.top_temporal_layer(1), Some(1));
+        // nothing to note for layers we haven't seen
+        assert!(!layers.note_temporal_layer(2, 2));
+        assert_eq!(layers.top_temporal_layer(2), None);
+    }
+
+    #[test]
+    fn measures_layers() {
+        let start = Instant::now();
+        let mut layers = ActiveLayers::default();
+        assert!(layers.update(1, 1000, 0, Some((640, 360)), start));
+        assert_eq!(layers.descriptors(), vec![LayerDescriptor {
+            ssrc: 1,
+            bitrate: None,
+            framerate: None,
+            width: Some(640),
+            height: Some(360),
+        }]);
+        // 30 frames of two packets each over a second
+        for frame in 1..30u32 {
+            let now = start + Duration::from_millis(u64::from(frame) * 1000 / 30);
+            assert!(!layers.update(1, 1000, frame * 3000, None, now));
+            assert!(!layers.update(1, 1000, frame * 3000, None, now));
+        }
+        assert!(layers.update(1, 1000, 30 * 3000, None, start + Duration::from_secs(1)));
+        let layer = layers.descriptors()[0];
+        assert_eq!((layer.bitrate, layer.framerate), (Some(60 * 8000), Some(30)));
+
+        // a similar bitrate isn't worth mentioning, but a much lower one or a new resolution is
+        for tenth in 1..=10 {
+            assert!(!layers.update(1, 5500, 0, None, start + Duration::from_millis(1000 + tenth * 100)));
+        }
+        assert!(!layers.update(1, 1000, 0, Some((640, 360)), start + Duration::from_millis(2100)));
+        assert!(layers.update(1, 1000, 0, Some((320, 180)), start + Duration::from_millis(2200)));
+        assert!(layers.update(1, 100, 0, None, start + Duration::from_secs(3)));
+        assert_eq!(layers.descriptors()[0].width, Some(320));
+    }
+
+    fn push(buffer: &mut ReorderBuffer, seq: u16, now: Instant) -> Vec<u8> {
+        let released = buffer.push(&header(1, seq, 0), vec![seq as u8], now);
+        released.into_iter().map(|p| p[0]).collect()
+    }
+
+    #[test]
+    fn reorders_packets() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(push(&mut buffer, 10, start), vec![10]);
+        assert_eq!(push(&mut buffer, 12, start), Vec::<u8>::new());
+        assert_eq!(push(&mut buffer, 13, start), Vec::<u8>::new());
+        assert_eq!(push(&mut buffer, 11, start), vec![11, 12, 13]);
+        // too late to put in order
+        assert_eq!(push(&mut buffer, 9, start), vec![9]);
+    }
+
+    #[test]
+    fn gives_up_on_gaps() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(2);
+        assert_eq!(push(&mut buffer, 1, start), vec![1]);
+        assert_eq!(push(&mut buffer, 3, start), Vec::<u8>::new());
+        assert_eq!(push(&mut buffer, 4, start), Vec::<u8>::new());
+        assert_eq!(push(&mut buffer, 6, start), vec![3, 4]);
+        assert_eq!(push(&mut buffer, 7, start + REORDER_TIMEOUT), vec![6, 7]);
+        assert_eq!(push(&mut buffer, 8, start + REORDER_TIMEOUT), vec![8]);
+    }
+
+    #[test]
+    fn flushes_gaps_without_new_packets() {
+        let start = Instant::now();
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(push(&mut buffer, 1, start), vec![1]);
+        assert_eq!(push(&mut buffer, 3, start), Vec::<u8>::new());
+        assert!(buffer.flush(start + REORDER_TIMEOUT / 2).is_empty());
+        assert_eq!(buffer.flush(start + REORDER_TIMEOUT), vec![vec![3]]);
+        assert!(buffer.flush(start + REORDER_TIMEOUT * 2).is_empty());
+    }
+
+    #[test]
+    fn limits_framerate_by_dropping_upper_layers() {
+        // 30fps in three temporal layers: 0, 2, 1, 2, 0, ...
+        let layers = [0, 2, 1, 2, 0, 2, 1, 2];
+        let mut limiter = FramerateLimiter::default();
+        let forwarded: Vec<_> = layers.iter().enumerate()
+            .filter(|&(i, &layer)| {
+                let timestamp = i as u32 * VIDEO_TIMESTAMP_STEP;
+                // two packets per frame, which should get the same treatment
+                let first = limiter.forward(timestamp, Some(layer), 15);
+                assert_eq!(limiter.forward(timestamp, Some(layer), 15), first);
+                first
+            })
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(forwarded, vec![0, 2, 4, 6]);
+
+        let mut limiter = FramerateLimiter::default();
+        assert!(limiter.forward(0, None, 1));
+        assert!(limiter.forward(3000, None, 1));
+    }
+
+    #[test]
+    fn drops_frames_depending_on_dropped_frames() {
+        let mut limiter = FramerateLimiter::default();
+        assert!(limiter.forward(0, Some(0), 10));
+        assert!(!limiter.forward(3000, Some(1), 10));
+        // far enough along to be allowed, but it refers to the dropped frame
+        assert!(!limiter.forward(9000, Some(2), 10));
+        assert!(limiter.forward(12000, Some(1), 10));
+        assert!(limiter.forward(21000, Some(2), 10));
+    }
+}
@@ -1,24 +1,66 @@
 /// Types for representing Janus session state.
 use atom::AtomSetOnce;
-use std::sync::atomic::{AtomicIsize, AtomicBool};
-use std::sync::{Arc, Mutex};
-use messages::{RoomId, UserId, Subscription};
-use janus::sdp::Sdp;
+use std::sync::atomic::{AtomicIsize, AtomicBool, AtomicUsize, Ordering};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::os::raw::c_int;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use messages::{ClientStats, RoomId, UserId, Subscription};
+use serde_json::Value as JsonValue;
+use janus::sdp::{AudioCodec, Sdp, VideoCodec};
 use janus::session::SessionWrapper;
+use config::PublishCodecs;
+use ratelimit::{Pacer, TokenBucket};
+use rtcp::Goodbyes;
+use rtp::{ActiveLayers, FramerateLimiter, LayerSelection, MediaLines, ReorderBuffer, SwitchingContext, VideoConstraints};
+use switchboard::Connection;
 
 /// State pertaining to this session's join of a particular room as a particular user ID.
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct JoinState {
-    /// The room ID that this session is in.
-    pub room_id: RoomId,
+    /// The room ID that this session is in. Only changes if the session is moved to another room.
+    pub room_id: RwLock<RoomId>,
 
     /// An opaque ID uniquely identifying this user.
     pub user_id: UserId,
+
+    /// Whether this session negotiates media in both directions, receiving its own media back.
+    pub sendrecv: bool,
+
+    /// Whether this user currently has their hand raised in the room.
+    pub hand_raised: AtomicBool,
+
+    /// Whether this user is currently a moderator of the room.
+    pub moderator: AtomicBool,
+
+    /// Whether this session joined with a token with the `kick_users` claim, which lets it into any room.
+    pub moderator_token: AtomicBool,
+
+    /// Whether this session joined with a token issued for its user ID, rather than just claiming the user ID.
+    pub owns_user_id: AtomicBool,
+
+    /// Whether a moderator pinned this user, so that their media is never demoted for being idle or degraded for
+    /// subscribers whose links are congested.
+    pub pinned: AtomicBool,
+
+    /// Arbitrary data the client attached to its join, e.g. an avatar or display name, which we pass along to peers.
+    pub metadata: JsonValue,
 }
 
 impl JoinState {
-    pub fn new(room_id: RoomId, user_id: UserId) -> Self {
-        Self { room_id, user_id }
+    pub fn new(room_id: RoomId, user_id: UserId, sendrecv: bool, metadata: JsonValue) -> Self {
+        Self {
+            room_id: RwLock::new(room_id),
+            user_id,
+            sendrecv,
+            hand_raised: AtomicBool::new(false),
+            moderator: AtomicBool::new(false),
+            moderator_token: AtomicBool::new(false),
+            owns_user_id: AtomicBool::new(false),
+            pinned: AtomicBool::new(false),
+            metadata,
+        }
     }
 }
 
@@ -31,15 +73,263 @@ pub struct SessionState {
     /// Information pertaining to this session's user and room, if joined.
     pub join_state: AtomSetOnce<Box<JoinState>>,
 
-    /// The subscription this user has established, if any.
-    pub subscription: AtomSetOnce<Box<Subscription>>,
+    /// An opaque ID which whoever created this session gave us when it joined, to find its activity in our logs.
+    pub correlation_id: Mutex<Option<String>>,
+
+    /// Whether this session may send data to the room it's observing, if it's an observer.
+    pub observer_sends_data: AtomicBool,
+
+    /// The subscription this user has established, if any. Can be changed by subscribing again.
+    pub subscription: Mutex<Option<Subscription>>,
+
+    /// How many JSEP offers we've received from this session so far, counting ones still waiting to be processed.
+    pub offer_version: AtomicUsize,
+
+    /// How many JSEP offers we've sent this session so far, so that a retry of an older one can tell it's been overtaken.
+    /// Held while sending one, so that an older offer can't go out after a newer one.
+    pub offers_sent: Mutex<usize>,
+
+    /// The media lines and codecs of the first offer this session published with, if its room doesn't allow
+    /// renegotiating them.
+    pub published_media: Mutex<Option<MediaLines>>,
 
     /// If this is a publisher, the offer for subscribing to it.
     pub subscriber_offer: Arc<Mutex<Option<Sdp>>>,
 
     /// The current FIR sequence number for this session's video.
     pub fir_seq: AtomicIsize,
+
+    /// The highest VP8/VP9 temporal layer of video to forward to this session. Packets from higher layers are
+    /// dropped, reducing the framerate and bitrate of the video this session receives.
+    pub max_temporal_layer: AtomicUsize,
+
+    /// The priority of this session's media subscription, relative to its user's other media subscriptions.
+    pub media_priority: AtomicIsize,
+
+    /// Whether we've stopped forwarding video to this session because its user's connection is congested.
+    pub video_suspended: AtomicBool,
+
+    /// The label of the alternative audio track this session publishes for its user, if it's labeled one.
+    pub audio_label: Mutex<Option<String>>,
+
+    /// Whether this session has an `audio_label`, so that we only have to look at it for labeled tracks' packets.
+    pub audio_labeled: AtomicBool,
+
+    /// If this session publishes a labeled audio track, the audio payload type that its user's main publisher offered
+    /// subscribers, which we rewrite the track's audio to for them, or zero if we don't know it yet.
+    pub track_payload_type: AtomicUsize,
+
+    /// The label of the audio track this session chose to receive from the user it subscribes to, if not their main one.
+    pub selected_audio_label: Mutex<Option<String>>,
+
+    /// Whether this session has a `selected_audio_label`, so that we only have to look at it when it has one.
+    pub selects_audio_label: AtomicBool,
+
+    /// Whether to forward only key frames of video to this session. See `Subscription::keyframes_only`.
+    pub keyframes_only: AtomicBool,
+
+    /// The most video this session wants to be sent. See `Subscription::max_width`.
+    pub video_constraints: Mutex<VideoConstraints>,
+
+    /// Which of its publisher's simulcast layers this session is sent, going by its video constraints.
+    pub layer_selection: Mutex<LayerSelection>,
+
+    /// Whether this session is sent just one of its publisher's simulcast layers, so that `layer_selection` has to be
+    /// asked about every video packet.
+    pub selects_layer: AtomicBool,
+
+    /// The highest temporal layer to forward to this session to keep within its video constraints' framerate, on top
+    /// of `max_temporal_layer`.
+    pub constrained_temporal_layer: AtomicUsize,
+
+    /// The RTP timestamp of the last key frame of video this session published, if any, while it has subscribers who
+    /// only want key frames.
+    pub keyframe_timestamp: Mutex<Option<u32>>,
+
+    /// Limits how quickly this session may send us signalling messages.
+    pub message_bucket: Mutex<TokenBucket>,
+
+    /// The types of the ICE candidates in the last SDP this session sent us which had any, e.g. "host" or "relay".
+    pub candidate_types: Mutex<Vec<String>>,
+
+    /// Limits how many RTP packets per second this session may publish, if the server is configured to.
+    pub packet_bucket: Mutex<Option<TokenBucket>>,
+
+    /// Spaces out the RTP we send this session, if the server is configured to, holding each packet with whether
+    /// it's video.
+    pub pacer: Mutex<Option<Pacer<c_int>>>,
+
+    /// Whether the last video packet for this session was dropped because its pacer's queue was full.
+    pub paced_video_dropped: AtomicBool,
+
+    /// Whether this session is currently publishing RTP faster than its packet bucket allows.
+    pub flooding: AtomicBool,
+
+    /// How many bytes of video this session has published since its room's bitrate was last estimated.
+    pub video_bytes_published: AtomicUsize,
+
+    /// When this session last sent us a video packet, if it has since it last started publishing and we detect stalled
+    /// video.
+    pub last_video_at: Mutex<Option<Instant>>,
+
+    /// Whether we told this session's subscribers that its video stalled, and haven't told them it resumed since.
+    pub video_stalled: AtomicBool,
+
+    /// Sequence number and timestamp continuity for the audio we forward to this session.
+    pub audio_context: Mutex<SwitchingContext>,
+
+    /// Sequence number and timestamp continuity for the video we forward to this session.
+    pub video_context: Mutex<SwitchingContext>,
+
+    /// The ID this session negotiated for the RTP audio level header extension, or zero if it didn't.
+    pub audio_level_extension: AtomicUsize,
+
+    /// The payload type this session negotiated for publishing audio, or zero if it didn't.
+    pub audio_payload_type: AtomicUsize,
+
+    /// The payload type this session negotiated for publishing redundant audio (RED), or zero if it didn't.
+    pub red_payload_type: AtomicUsize,
+
+    /// Whether this session accepted redundant audio (RED) in its answer to the last offer we sent it. If not, we only
+    /// forward it the primary audio out of RED packets.
+    pub receives_red: AtomicBool,
+
+    /// The payload type this session negotiated for DTMF digits (telephone-event), or zero if it didn't.
+    pub dtmf_payload_type: AtomicUsize,
+
+    /// The RTP timestamp of the last DTMF digit this session sent which we told its room about.
+    pub last_dtmf_timestamp: Mutex<Option<u32>>,
+
+    /// When this session last published audio loud enough to count as speaking, or started publishing.
+    pub last_spoke: Mutex<Instant>,
+
+    /// Whether this session stopped being a publisher because it was silent for too long.
+    pub demoted: AtomicBool,
+
+    /// Whether this session asked to stop publishing, while staying connected and in its room.
+    pub unpublished: AtomicBool,
+
+    /// Until when we're holding back the media this session just started publishing, so that everyone who subscribes to
+    /// it in the meantime starts getting it at the same time, if its room is configured with a delay.
+    pub held_until: Mutex<Option<Instant>>,
+
+    /// The audio codec we negotiated with this session, if it's publishing audio.
+    pub audio_codec: Mutex<Option<AudioCodec>>,
+
+    /// The audio codecs this session's client offered to publish with, i.e. the ones it can handle.
+    pub offered_audio_codecs: Mutex<Vec<AudioCodec>>,
+
+    /// The video codec we negotiated with this session, if it's publishing video.
+    pub video_codec: Mutex<Option<VideoCodec>>,
+
+    /// The video codecs this session's client offered to publish with, i.e. the ones it can handle.
+    pub offered_video_codecs: Mutex<Vec<VideoCodec>>,
+
+    /// The video codecs this session's client said it handles well when it joined, in order of preference, if it said.
+    pub codec_capabilities: Mutex<Option<Vec<VideoCodec>>>,
+
+    /// The codecs this session's join token lets it publish with.
+    pub publish_codecs: Mutex<PublishCodecs>,
+
+    /// The ID this session negotiated for the RTP stream ID header extension, or zero if it didn't.
+    pub rid_extension: AtomicUsize,
+
+    /// The SSRC this session last published audio with since it last offered, or `usize::MAX` if none.
+    pub audio_ssrc: AtomicUsize,
+
+    /// Which of the sources this session is sending have said goodbye with an RTCP BYE since it last offered.
+    pub said_bye: Mutex<Goodbyes>,
+
+    /// The simulcast layers this session negotiated to send us, by RID, and the SSRC we've seen each one sent with.
+    pub simulcast_rids: Mutex<Vec<(String, Option<u32>)>>,
+
+    /// The users whose media this session is subscribed to, but has asked us to stop forwarding for now.
+    pub paused_publishers: Mutex<HashSet<UserId>>,
+
+    /// The payload types this session publishes with which its subscribers were offered a different payload type for,
+    /// each with the payload type to rewrite it to.
+    pub payload_type_rewrites: Mutex<Vec<(u8, u8)>>,
+
+    /// The IDs of the forwarded header extensions this session publishes with, each with the ID its subscribers were
+    /// offered for the same extension.
+    pub extension_id_rewrites: Mutex<Vec<(u8, u8)>>,
+
+    /// Caps the framerate of the video this session publishes, if its room has a maximum framerate.
+    pub framerate_limiter: Mutex<FramerateLimiter>,
+
+    /// Which video layers this session is sending us, if it's publishing.
+    pub video_layers: Mutex<ActiveLayers>,
+
+    /// Puts the audio this session publishes back in order, if reordering is enabled.
+    pub audio_reorder: Mutex<ReorderBuffer>,
+
+    /// Puts the video this session publishes back in order, if reordering is enabled.
+    pub video_reorder: Mutex<ReorderBuffer>,
+
+    /// The most recent statistics this session's client reported about the media it's receiving, oldest first.
+    pub recent_stats: Mutex<VecDeque<ClientStats>>,
+
+    /// When we last stepped the video going to this session's user down or back up because of congestion on its
+    /// link, if that video hasn't been fully restored since.
+    pub video_degraded_at: Mutex<Option<Instant>>,
+
+    /// When we last sent this session any media or keepalive, if we're keeping track.
+    pub last_sent: Mutex<Instant>,
 }
 
 /// Rust representation of a single Janus session, i.e. a single `RTCPeerConnection`.
 pub type Session = SessionWrapper<SessionState>;
+
+/// How a session is named in log messages: by its handle, followed by the correlation ID it joined with, if any.
+pub struct LogName<'a>(&'a Session);
+
+impl<'a> fmt::Display for LogName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:p}", self.0.handle)?;
+        match *self.0.correlation_id.lock().unwrap() {
+            Some(ref id) => write!(f, " ({})", id),
+            None => Ok(())
+        }
+    }
+}
+
+/// Lets sessions be named in log messages, however they're held.
+pub trait LogNamed {
+    fn log_name(&self) -> LogName;
+}
+
+impl LogNamed for Session {
+    fn log_name(&self) -> LogName {
+        LogName(self)
+    }
+}
+
+impl Connection for Session {
+    fn handle_id(&self) -> String {
+        format!("{:p}", self.handle)
+    }
+
+    fn join_state(&self) -> Option<&JoinState> {
+        self.join_state.get()
+    }
+
+    fn is_publishing(&self) -> bool {
+        self.subscriber_offer.lock().unwrap().is_some() && !self.unpublished.load(Ordering::Relaxed)
+    }
+
+    fn audio_codec(&self) -> Option<AudioCodec> {
+        *self.audio_codec.lock().unwrap()
+    }
+
+    fn video_codec(&self) -> Option<VideoCodec> {
+        *self.video_codec.lock().unwrap()
+    }
+
+    fn wants_notifications(&self) -> bool {
+        self.subscription.lock().unwrap().as_ref().is_some_and(|s| s.notifications)
+    }
+
+    fn forget_offer(&self) {
+        *self.subscriber_offer.lock().unwrap() = None;
+    }
+}
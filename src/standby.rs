@@ -0,0 +1,100 @@
+//! Room state which is saved to disk when the plugin is destroyed and loaded again when it's initialized, so that users
+//! who reconnect soon after a reload get back what they had.
+
+use messages::{RoomId, UserId};
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+use switchboard::Mute;
+
+/// What we remember about a user who was in a room.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedUser {
+    pub user_id: UserId,
+    pub room_id: RoomId,
+    pub moderator: bool,
+    pub hand_raised: bool,
+}
+
+/// Everything which is saved across a reload. Handles aren't valid across a reload, so everything is keyed on user IDs,
+/// and media subscriptions, which are between handles, aren't saved at all.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// When the snapshot was taken, in milliseconds since the Unix epoch.
+    pub saved_at_ms: u64,
+    /// Every user who was in a room.
+    pub users: Vec<SavedUser>,
+    /// Every block which was in effect, as (blocker, blocked) pairs.
+    pub blocks: Vec<(UserId, UserId)>,
+    /// Every mute which was in effect, as (muter, muted) pairs.
+    pub mutes: Vec<(UserId, UserId, Mute)>,
+    /// Every room which was locked.
+    pub locked_rooms: Vec<RoomId>,
+    /// Every room which had a secret, with its secret.
+    #[serde(default)]
+    pub room_secrets: Vec<(RoomId, String)>,
+}
+
+/// Writes the given snapshot to the given path, replacing anything there. Snapshots have room secrets in them, so only
+/// the owner can read the file. It's written next to the path and then moved into place, so that a crash partway
+/// through can't leave a truncated snapshot behind.
+pub fn save<P: AsRef<Path>>(path: P, snapshot: &Snapshot) -> Result<(), Box<Error>> {
+    let path = path.as_ref();
+    let name = path.file_name().ok_or("the path has no file name")?;
+    let staged = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), process::id()));
+    let result = write_new(&staged, snapshot).and_then(|()| Ok(fs::rename(&staged, path)?));
+    if result.is_err() {
+        let _ = fs::remove_file(&staged);
+    }
+    result
+}
+
+/// Writes the given snapshot to a new file at the given path, readable and writable only by its owner, and waits for
+/// it to reach the disk.
+fn write_new(path: &Path, snapshot: &Snapshot) -> Result<(), Box<Error>> {
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    serde_json::to_writer(&mut file, snapshot)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Reads the snapshot at the given path and removes it, so that it's only ever restored once.
+pub fn take<P: AsRef<Path>>(path: P) -> Result<Snapshot, Box<Error>> {
+    let snapshot = serde_json::from_reader(File::open(&path)?)?;
+    fs::remove_file(&path)?;
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn snapshots_are_taken_once() {
+        let path = env::temp_dir().join(format!("janus-plugin-sfu-standby-{}.json", process::id()));
+        let snapshot = Snapshot {
+            saved_at_ms: 1234,
+            users: vec![SavedUser {
+                user_id: "alice".into(),
+                room_id: "room".into(),
+                moderator: true,
+                hand_raised: false,
+            }],
+            blocks: vec![("alice".into(), "bob".into())],
+            mutes: vec![("bob".into(), "carol".into(), Mute { audio: true, video: false })],
+            locked_rooms: vec!["room".into()],
+            room_secrets: vec![("room".into(), "hunter2".into())],
+        };
+        save(&path, &snapshot).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        // saving again replaces the snapshot, rather than failing on the file that's there
+        save(&path, &snapshot).unwrap();
+        assert_eq!(take(&path).unwrap(), snapshot);
+        assert!(take(&path).is_err());
+    }
+}
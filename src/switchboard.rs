@@ -1,12 +1,16 @@
 /// Tools for managing the set of subscriptions between connections.
+use janus::sdp::{AudioCodec, VideoCodec};
 use messages::{RoomId, UserId};
-use sessions::Session;
-use std::collections::{HashMap, HashSet};
+use sessions::{JoinState, Session};
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 use std::hash::Hash;
 use std::fmt::Debug;
 use std::borrow::Borrow;
+use std::error::Error;
 use multimap::MultiMap;
 
 #[derive(Debug)]
@@ -81,49 +85,188 @@ impl<K, V> BidirectionalMultimap<K, V> where K: Eq + Hash + Clone + Debug, V: Eq
         self.forward_mapping.get_vec(k).map(Vec::as_slice).unwrap_or(&[])
     }
 
+    /// Returns every key and value which are associated with each other.
+    pub fn pairs(&self) -> Vec<(&K, &V)> {
+        self.forward_mapping.iter_all().flat_map(|(k, vs)| vs.iter().map(move |v| (k, v))).collect()
+    }
+
     pub fn get_keys<U>(&self, v: &U) -> &[K]
         where V: Borrow<U>,
               U: Hash + Eq
     {
         self.inverse_mapping.get_vec(v).map(Vec::as_slice).unwrap_or(&[])
     }
+
+    /// Counts the associations which are only recorded in one direction, which should never happen.
+    pub fn one_sided_pairs(&self) -> usize {
+        let forward = self.forward_mapping.iter_all()
+            .flat_map(|(k, vs)| vs.iter().map(move |v| (k, v)))
+            .filter(|&(k, v)| !self.get_keys(v).contains(k))
+            .count();
+        let inverse = self.inverse_mapping.iter_all()
+            .flat_map(|(v, ks)| ks.iter().map(move |k| (k, v)))
+            .filter(|&(k, v)| !self.get_values(k).contains(v))
+            .count();
+        forward + inverse
+    }
+
+    /// Rebuilds the inverse mapping from the forward one, so that every association is recorded in both directions.
+    pub fn rebuild_inverse(&mut self) {
+        self.inverse_mapping = MultiMap::new();
+        for (k, vs) in self.forward_mapping.iter_all() {
+            for v in vs {
+                self.inverse_mapping.insert(v.clone(), k.clone());
+            }
+        }
+    }
+}
+
+/// A single connection which the switchboard routes traffic to and from. In practice this is always a `Session`, but
+/// abstracting over it lets the routing logic be exercised without a running Janus.
+pub trait Connection: Eq + Hash + Debug {
+    /// An ID uniquely identifying this connection, as it appears in the logs.
+    fn handle_id(&self) -> String;
+
+    /// Information about the room and user this connection joined as, if it's joined.
+    fn join_state(&self) -> Option<&JoinState>;
+
+    /// Whether this connection is publishing media which others can subscribe to.
+    fn is_publishing(&self) -> bool;
+
+    /// The audio codec this connection negotiated to publish with, if it's publishing audio.
+    fn audio_codec(&self) -> Option<AudioCodec>;
+
+    /// The video codec this connection negotiated to publish with, if it's publishing video.
+    fn video_codec(&self) -> Option<VideoCodec>;
+
+    /// Whether this connection subscribed to notifications about its room.
+    fn wants_notifications(&self) -> bool;
+
+    /// Drops the offer this connection's subscribers would be sent, so that it isn't publishing until it offers again.
+    fn forget_offer(&self);
+}
+
+/// Which kinds of media one user has muted from another.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mute {
+    pub audio: bool,
+    pub video: bool,
+}
+
+impl Mute {
+    /// Whether this mutes audio or video, whichever is given.
+    pub fn mutes(&self, video: bool) -> bool {
+        if video { self.video } else { self.audio }
+    }
+}
+
+/// The most users and publishers a room has had in it at once since it was last empty.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RoomPeaks {
+    pub users: usize,
+    pub publishers: usize,
+}
+
+/// Everything that `Switchboard::verify` found out of place, or that `Switchboard::prune` cleaned up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ConsistencyReport {
+    /// Room occupants and observers which aren't connected anymore.
+    pub orphaned_occupants: usize,
+    /// Connections waiting for space in a room which aren't connected anymore.
+    pub orphaned_waiters: usize,
+    /// Media subscriptions whose publisher or subscriber isn't connected anymore.
+    pub orphaned_subscriptions: usize,
+    /// Media subscriptions which are only recorded in one direction.
+    pub one_sided_subscriptions: usize,
+    /// Rooms with nobody in them for which recent events, a lock, peaks, a bitrate, or a secret are still remembered,
+    /// other than rooms whose state is meant to be retained.
+    pub stale_rooms: usize,
+}
+
+/// Why a connection can't be let into a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomRefusal {
+    /// The room already has more than the maximum number of occupants.
+    Full,
+    /// Nobody is in the room, and letting someone in would make too many rooms.
+    TooManyRooms,
 }
 
 /// A data structure for storing the state of all active connections and providing fast access to which
 /// connections should be sending traffic to which other connections.
 #[derive(Debug)]
-pub struct Switchboard {
+pub struct Switchboard<T: Connection = Session> {
     /// All active connections.
-    sessions: Vec<Box<Arc<Session>>>,
+    sessions: Vec<Box<Arc<T>>>,
     /// Connections which have joined a room, per room.
-    occupants: HashMap<RoomId, Vec<Arc<Session>>>,
+    occupants: HashMap<RoomId, Vec<Arc<T>>>,
     /// Which connections are subscribing to traffic from which other connections.
-    publisher_to_subscribers: BidirectionalMultimap<Arc<Session>, Arc<Session>>,
+    publisher_to_subscribers: BidirectionalMultimap<Arc<T>, Arc<T>>,
     /// Which users have explicitly blocked traffic to and from other users.
     blockers_to_miscreants: BidirectionalMultimap<UserId, UserId>,
+    /// Which kinds of media users have muted from other users, keyed by muter and then mutee.
+    mutes: HashMap<UserId, HashMap<UserId, Mute>>,
+    /// The most recent notifications sent to each occupied room, oldest first.
+    room_events: HashMap<RoomId, VecDeque<JsonValue>>,
+    /// Connections which were turned away from a full room and want to hear when there's space, per room.
+    room_waiters: HashMap<RoomId, Vec<Arc<T>>>,
+    /// Rooms which are locked against new joins, and the users who were let in since each was locked.
+    room_locks: HashMap<RoomId, HashSet<UserId>>,
+    /// The peak occupancy of each occupied room, as of the last time someone joined it or started publishing in it.
+    room_peaks: HashMap<RoomId, RoomPeaks>,
+    /// The estimated video bitrate, in bits per second, that each occupied room's publishers are sending between them.
+    room_bitrates: HashMap<RoomId, u32>,
+    /// The secret which anyone joining each occupied room has to give, for rooms whose moderators have set one.
+    room_secrets: HashMap<RoomId, String>,
+    /// Rooms whose recent events, lock, and secret are kept while nobody is in them, instead of being forgotten.
+    retained_rooms: HashSet<RoomId>,
+    /// Rooms whose lock or secret was put back after the plugin was reloaded, which are retained the same way until the
+    /// standby window for their old occupants to come back has passed.
+    standby_rooms: HashSet<RoomId>,
+    /// Connections which get all of the data sent in each room without being in it, per room.
+    observers: HashMap<RoomId, Vec<Arc<T>>>,
 }
 
-impl Switchboard {
+impl<T> Switchboard<T> where T: Connection {
     pub fn new() -> Self {
         Self {
             sessions: Vec::new(),
             occupants: HashMap::new(),
             publisher_to_subscribers: BidirectionalMultimap::new(),
             blockers_to_miscreants: BidirectionalMultimap::new(),
+            mutes: HashMap::new(),
+            room_events: HashMap::new(),
+            room_waiters: HashMap::new(),
+            room_locks: HashMap::new(),
+            room_peaks: HashMap::new(),
+            room_bitrates: HashMap::new(),
+            room_secrets: HashMap::new(),
+            retained_rooms: HashSet::new(),
+            standby_rooms: HashSet::new(),
+            observers: HashMap::new(),
         }
     }
 
-    pub fn connect(&mut self, session: Box<Arc<Session>>) {
+    pub fn connect(&mut self, session: Box<Arc<T>>) {
         self.sessions.push(session);
     }
 
     pub fn is_connected(&self, user: &UserId) -> bool {
-        self.sessions.iter().any(|s| {
-            match s.join_state.get() {
-                None => false,
-                Some(other_state) => user == &other_state.user_id
-            }
-        })
+        !self.sessions_for_user(user).is_empty()
+    }
+
+    /// Returns every connection which joined as the given user, in any room, e.g. their data connection and all of
+    /// their media subscriptions.
+    pub fn sessions_for_user(&self, user: &UserId) -> Vec<Arc<T>> {
+        self.sessions.iter()
+            .filter(|s| s.join_state().is_some_and(|joined| &joined.user_id == user))
+            .map(|s| Arc::clone(s))
+            .collect()
+    }
+
+    /// Finds the connection with the given handle ID.
+    pub fn get_session_by_handle(&self, handle_id: &str) -> Option<&Arc<T>> {
+        self.sessions.iter().map(|s| s.as_ref()).find(|s| s.handle_id() == handle_id)
     }
 
     pub fn establish_block(&mut self, from: UserId, target: UserId) {
@@ -134,50 +277,443 @@ impl Switchboard {
         self.blockers_to_miscreants.disassociate(from, target);
     }
 
-    pub fn join_room(&mut self, session: Arc<Session>, room: RoomId) {
-        self.occupants.entry(room).or_insert_with(Vec::new).push(session);
+    /// Returns every block in effect, as (blocker, blocked) pairs.
+    pub fn blocks(&self) -> Vec<(UserId, UserId)> {
+        self.blockers_to_miscreants.pairs().into_iter().map(|(from, target)| (from.clone(), target.clone())).collect()
+    }
+
+    /// Sets which kinds of media from the target user the given user doesn't want to receive. Like blocks, mutes belong
+    /// to users and stay in effect across reconnects.
+    pub fn set_mute(&mut self, from: UserId, target: UserId, mute: Mute) {
+        if mute == Mute::default() {
+            if let Some(muted) = self.mutes.get_mut(&from) {
+                muted.remove(&target);
+                if muted.is_empty() {
+                    self.mutes.remove(&from);
+                }
+            }
+        } else {
+            self.mutes.entry(from).or_default().insert(target, mute);
+        }
+    }
+
+    /// Returns which kinds of media from the target user the given user has muted.
+    pub fn mute_between(&self, from: &UserId, target: &UserId) -> Mute {
+        self.mutes.get(from).and_then(|muted| muted.get(target)).cloned().unwrap_or_default()
+    }
+
+    /// Returns every mute in effect, as (muter, muted, what's muted) triples.
+    pub fn mutes(&self) -> Vec<(UserId, UserId, Mute)> {
+        self.mutes.iter()
+            .flat_map(|(from, muted)| muted.iter().map(move |(target, mute)| (from.clone(), target.clone(), *mute)))
+            .collect()
+    }
+
+    /// Tears down all media subscriptions between any connections of the two given users, in either direction,
+    /// returning the (publisher, subscriber) pairs that were removed.
+    pub fn end_subscriptions_between(&mut self, a: &UserId, b: &UserId) -> Vec<(Arc<T>, Arc<T>)> {
+        let (a_sessions, b_sessions) = (self.sessions_for_user(a), self.sessions_for_user(b));
+        let mut ended = Vec::new();
+        for (publishers, subscribers) in [(&a_sessions, &b_sessions), (&b_sessions, &a_sessions)] {
+            for publisher in publishers {
+                for subscriber in self.subscribers_to(publisher).iter().filter(|s| subscribers.contains(s)) {
+                    ended.push((Arc::clone(publisher), Arc::clone(subscriber)));
+                }
+            }
+        }
+        for (publisher, subscriber) in &ended {
+            self.publisher_to_subscribers.disassociate(publisher, subscriber);
+        }
+        ended
+    }
+
+    /// Returns the users which the given user is blocking. Blocks belong to users, not sessions, so they stay in
+    /// effect across reconnects.
+    pub fn blocks_from(&self, user: &UserId) -> &[UserId] {
+        self.blockers_to_miscreants.get_values(user)
+    }
+
+    pub fn join_room(&mut self, session: Arc<T>, room: RoomId) {
+        if let (Some(admitted), Some(joined)) = (self.room_locks.get_mut(&room), session.join_state()) {
+            admitted.insert(joined.user_id.clone());
+        }
+        self.occupants.entry(room.clone()).or_insert_with(Vec::new).push(session);
+        self.update_room_peaks(&room);
+    }
+
+    /// Takes the given room's peak occupancy into account as it is now, e.g. after someone in it starts publishing.
+    pub fn update_room_peaks(&mut self, room: &RoomId) {
+        if !self.occupants.contains_key(room) {
+            return;
+        }
+        let users = self.get_users(room).len();
+        let publishers = self.occupants_of(room).iter().filter(|s| s.is_publishing()).count();
+        let peaks = self.room_peaks.entry(room.clone()).or_default();
+        peaks.users = peaks.users.max(users);
+        peaks.publishers = peaks.publishers.max(publishers);
+    }
+
+    /// Returns the peak occupancy of the given room since it was last empty.
+    pub fn room_peaks(&self, room: &RoomId) -> RoomPeaks {
+        self.room_peaks.get(room).cloned().unwrap_or_default()
+    }
+
+    /// Records the estimated video bitrate that the given room's publishers are sending between them. Estimates for
+    /// rooms with nobody in them are discarded.
+    pub fn set_room_bitrate(&mut self, room: &RoomId, bitrate: u32) {
+        if self.occupants.contains_key(room) {
+            self.room_bitrates.insert(room.clone(), bitrate);
+        }
+    }
+
+    /// Returns the most recently estimated video bitrate of the given room, in bits per second.
+    pub fn room_bitrate(&self, room: &RoomId) -> u32 {
+        self.room_bitrates.get(room).cloned().unwrap_or_default()
+    }
+
+    /// Sets the secret which anyone joining the given room has to give, replacing any old one. Secrets are forgotten once
+    /// everyone has left the room.
+    pub fn set_room_secret(&mut self, room: &RoomId, secret: String) {
+        if self.occupants.contains_key(room) {
+            self.room_secrets.insert(room.clone(), secret);
+        }
+    }
+
+    pub fn room_secret(&self, room: &RoomId) -> Option<&str> {
+        self.room_secrets.get(room).map(String::as_str)
+    }
+
+    /// Returns every room which has a secret, with its secret.
+    pub fn room_secrets(&self) -> Vec<(RoomId, String)> {
+        self.room_secrets.iter().map(|(room, secret)| (room.clone(), secret.clone())).collect()
+    }
+
+    /// Puts back the lock and secret a room had before the plugin was reloaded, whether or not anyone is in it yet, so
+    /// that they apply to the first people to rejoin it too. They're kept while the room is empty until `end_standby`.
+    pub fn restore_room(&mut self, room: RoomId, admitted: Option<HashSet<UserId>>, secret: Option<String>) {
+        self.standby_rooms.insert(room.clone());
+        if let Some(admitted) = admitted {
+            self.room_locks.insert(room.clone(), admitted);
+        }
+        if let Some(secret) = secret {
+            self.room_secrets.insert(room, secret);
+        }
+    }
+
+    /// Stops retaining the state of the rooms put back with `restore_room`, forgetting it for the ones nobody came back to.
+    pub fn end_standby(&mut self) {
+        self.standby_rooms.clear();
+        self.forget_empty_rooms();
+    }
+
+    /// Whether the given room's recent events, lock, and secret are kept while nobody is in it.
+    fn is_retained(&self, room: &RoomId) -> bool {
+        self.retained_rooms.contains(room) || self.standby_rooms.contains(room)
+    }
+
+    /// Forgets the recent events, lock, and secret of every room with nobody in it, other than rooms whose state is
+    /// meant to be retained.
+    pub fn forget_empty_rooms(&mut self) {
+        let (occupants, retained, standby) = (&self.occupants, &self.retained_rooms, &self.standby_rooms);
+        let keep = |room: &RoomId| occupants.contains_key(room) || retained.contains(room) || standby.contains(room);
+        self.room_events.retain(|room, _| keep(room));
+        self.room_locks.retain(|room, _| keep(room));
+        self.room_secrets.retain(|room, _| keep(room));
+    }
+
+    /// Makes the given connection an observer of the given room, which gets all of the data sent in the room without
+    /// being one of its occupants.
+    pub fn observe_room(&mut self, session: Arc<T>, room: RoomId) {
+        self.observers.entry(room).or_default().push(session);
+    }
+
+    /// Returns the room the given connection is observing, if it's an observer.
+    pub fn observed_room(&self, session: &T) -> Option<&RoomId> {
+        self.observers.iter().find(|(_, observers)| observers.iter().any(|o| o.as_ref() == session)).map(|(room, _)| room)
+    }
+
+    pub fn observers_of(&self, room: &RoomId) -> &[Arc<T>] {
+        self.observers.get(room).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Keeps the given room's recent events, lock, and secret while nobody is in it, e.g. because it's provisioned ahead
+    /// of time, instead of forgetting them when the last person leaves.
+    pub fn retain_room_state(&mut self, room: RoomId) {
+        self.retained_rooms.insert(room);
+    }
+
+    /// Locks the given room against new joins. Everyone in it right now, and anyone let in later, is remembered as
+    /// having been admitted. Locks are forgotten once everyone has left the room.
+    pub fn lock_room(&mut self, room: &RoomId) {
+        if self.occupants.contains_key(room) && !self.room_locks.contains_key(room) {
+            let admitted = self.get_users(room).into_iter().cloned().collect();
+            self.room_locks.insert(room.clone(), admitted);
+        }
+    }
+
+    pub fn locked_rooms(&self) -> impl Iterator<Item=&RoomId> {
+        self.room_locks.keys()
+    }
+
+    pub fn unlock_room(&mut self, room: &RoomId) {
+        self.room_locks.remove(room);
+    }
+
+    pub fn is_room_locked(&self, room: &RoomId) -> bool {
+        self.room_locks.contains_key(room)
     }
 
-    pub fn leave_room(&mut self, session: &Session, room: RoomId) {
+    /// Whether the given user was in the given locked room at some point since it was locked.
+    pub fn was_admitted(&self, room: &RoomId, user: &UserId) -> bool {
+        self.room_locks.get(room).is_some_and(|admitted| admitted.contains(user))
+    }
+
+    pub fn leave_room(&mut self, session: &T, room: RoomId) {
         if let Entry::Occupied(mut cohabitators) = self.occupants.entry(room) {
             cohabitators.get_mut().retain(|x| x.as_ref() != session);
             if cohabitators.get().is_empty() {
-                cohabitators.remove_entry();
+                let (room, _) = cohabitators.remove_entry();
+                self.room_peaks.remove(&room);
+                self.room_bitrates.remove(&room);
+                if !self.is_retained(&room) {
+                    self.room_events.remove(&room);
+                    self.room_locks.remove(&room);
+                    self.room_secrets.remove(&room);
+                }
             }
         }
     }
 
-    pub fn remove_session(&mut self, session: &Session) {
+    /// Remembers a notification sent to a room, keeping at most `capacity` of the most recent ones per room. Events
+    /// for rooms with nobody in them are discarded.
+    pub fn record_event(&mut self, room: &RoomId, event: JsonValue, capacity: usize) {
+        if capacity == 0 || !self.occupants.contains_key(room) {
+            return;
+        }
+        let events = self.room_events.entry(room.clone()).or_default();
+        while events.len() >= capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub fn recent_events(&self, room: &RoomId) -> Vec<&JsonValue> {
+        self.room_events.get(room).map(|events| events.iter().collect()).unwrap_or_default()
+    }
+
+    /// Remembers that the given connection wants to know when the given room has space for it.
+    pub fn wait_for_room(&mut self, session: Arc<T>, room: RoomId) {
+        let waiters = self.room_waiters.entry(room).or_default();
+        if !waiters.contains(&session) {
+            waiters.push(session);
+        }
+    }
+
+    /// Forgets and returns all of the connections waiting for space in the given room.
+    pub fn take_room_waiters(&mut self, room: &RoomId) -> Vec<Arc<T>> {
+        self.room_waiters.remove(room).unwrap_or_default()
+    }
+
+    pub fn remove_session(&mut self, session: &T) {
         self.publisher_to_subscribers.remove_key(session);
         self.publisher_to_subscribers.remove_value(session);
-        self.sessions.retain(|s| s.handle != session.handle);
-        if let Some(joined) = session.join_state.get() {
-            self.leave_room(session, joined.room_id.clone());
+        self.sessions.retain(|s| s.as_ref().as_ref() != session);
+        for waiters in self.room_waiters.values_mut() {
+            waiters.retain(|w| w.as_ref() != session);
+        }
+        for observers in self.observers.values_mut() {
+            observers.retain(|o| o.as_ref() != session);
+        }
+        self.observers.retain(|_, observers| !observers.is_empty());
+        if let Some(joined) = session.join_state() {
+            let room_id = joined.room_id.read().unwrap().clone();
+            self.leave_room(session, room_id);
+        }
+    }
+
+    /// Checks that everything the switchboard refers to is still connected, and that its records agree with each other.
+    pub fn verify(&self) -> ConsistencyReport {
+        let connected: HashSet<&T> = self.sessions.iter().map(|s| s.as_ref().as_ref()).collect();
+        let stale_rooms: HashSet<&RoomId> = self.room_events.keys()
+            .chain(self.room_locks.keys())
+            .chain(self.room_peaks.keys())
+            .chain(self.room_bitrates.keys())
+            .chain(self.room_secrets.keys())
+            .filter(|room| !self.occupants.contains_key(*room) && !self.is_retained(room))
+            .collect();
+        ConsistencyReport {
+            orphaned_occupants: self.occupants.values().chain(self.observers.values()).flatten()
+                .filter(|s| !connected.contains(s.as_ref()))
+                .count(),
+            orphaned_waiters: self.room_waiters.values().flatten().filter(|s| !connected.contains(s.as_ref())).count(),
+            orphaned_subscriptions: self.publisher_to_subscribers.pairs().into_iter()
+                .filter(|(publisher, subscriber)| !connected.contains(publisher.as_ref()) || !connected.contains(subscriber.as_ref()))
+                .count(),
+            one_sided_subscriptions: self.publisher_to_subscribers.one_sided_pairs(),
+            stale_rooms: stale_rooms.len(),
         }
     }
 
-    pub fn subscribe_to_user(&mut self, subscriber: Arc<Session>, publisher: Arc<Session>) {
+    /// Cleans up everything that `verify` finds, returning what it found.
+    pub fn prune(&mut self) -> ConsistencyReport {
+        let report = self.verify();
+        if report == ConsistencyReport::default() {
+            return report;
+        }
+        self.publisher_to_subscribers.rebuild_inverse();
+        let connected: HashSet<Arc<T>> = self.sessions.iter().map(|s| Arc::clone(s)).collect();
+        let orphans: Vec<Arc<T>> = self.publisher_to_subscribers.pairs().into_iter()
+            .flat_map(|(publisher, subscriber)| vec![publisher, subscriber])
+            .filter(|s| !connected.contains(*s))
+            .cloned()
+            .collect();
+        for orphan in orphans {
+            self.publisher_to_subscribers.remove_key(orphan.as_ref());
+            self.publisher_to_subscribers.remove_value(orphan.as_ref());
+        }
+        for occupants in self.occupants.values_mut() {
+            occupants.retain(|s| connected.contains(s));
+        }
+        for waiters in self.room_waiters.values_mut() {
+            waiters.retain(|s| connected.contains(s));
+        }
+        for observers in self.observers.values_mut() {
+            observers.retain(|s| connected.contains(s));
+        }
+        self.observers.retain(|_, observers| !observers.is_empty());
+        self.occupants.retain(|_, occupants| !occupants.is_empty());
+        self.room_waiters.retain(|_, waiters| !waiters.is_empty());
+        self.forget_empty_rooms();
+        let occupants = &self.occupants;
+        self.room_peaks.retain(|room, _| occupants.contains_key(room));
+        self.room_bitrates.retain(|room, _| occupants.contains_key(room));
+        report
+    }
+
+    /// Relocates a joined session into another room, tearing down any media subscriptions to or from it, since
+    /// those were established with other sessions in the old room.
+    pub fn move_session(&mut self, session: &Arc<T>, to: RoomId) {
+        if let Some(joined) = session.join_state() {
+            self.publisher_to_subscribers.remove_key(session.as_ref());
+            self.publisher_to_subscribers.remove_value(session.as_ref());
+            let mut room_id = joined.room_id.write().unwrap();
+            let was_occupant = self.occupants_of(&room_id).iter().any(|s| s == session);
+            self.leave_room(session, room_id.clone());
+            if was_occupant {
+                self.join_room(Arc::clone(session), to.clone());
+            }
+            joined.hand_raised.store(false, Ordering::Relaxed);
+            joined.moderator.store(false, Ordering::Relaxed);
+            joined.pinned.store(false, Ordering::Relaxed);
+            *room_id = to;
+        }
+    }
+
+    /// Subscribes the subscriber to the publisher's media. Since we only ever forward media one hop, the only way for
+    /// a subscription to route media back where it came from is for a user to subscribe to themselves from a different
+    /// connection, so that's rejected; a sendrecv connection subscribing to itself is an intentional loopback.
+    pub fn subscribe_to_user(&mut self, subscriber: Arc<T>, publisher: Arc<T>) -> Result<(), Box<Error>> {
+        if subscriber != publisher && Self::same_user(&subscriber, &publisher) {
+            janus_warn!("Rejecting looped subscription from {} to {}.", subscriber.handle_id(), publisher.handle_id());
+            return Err(From::from("Users can't subscribe to their own media."));
+        }
+        // a connection gets one audio m-line and one video m-line, so everything it receives has to be in the same codecs
+        if let Some(codec) = publisher.audio_codec() {
+            let existing = self.publishers_to(&subscriber).iter().filter(|p| **p != publisher).find_map(|p| p.audio_codec());
+            if let Some(other) = existing.filter(|other| *other != codec) {
+                let msg = format!("Can't receive {} audio on a connection which is receiving {} audio.", codec.to_str(), other.to_str());
+                return Err(From::from(msg));
+            }
+        }
+        if let Some(codec) = publisher.video_codec() {
+            let existing = self.publishers_to(&subscriber).iter().filter(|p| **p != publisher).find_map(|p| p.video_codec());
+            if let Some(other) = existing.filter(|other| *other != codec) {
+                let msg = format!("Can't receive {} video on a connection which is receiving {} video.", codec.to_str(), other.to_str());
+                return Err(From::from(msg));
+            }
+        }
         self.publisher_to_subscribers.associate(publisher, subscriber);
+        Ok(())
+    }
+
+    fn same_user(a: &T, b: &T) -> bool {
+        match (a.join_state(), b.join_state()) {
+            (Some(x), Some(y)) => x.user_id == y.user_id,
+            _ => false,
+        }
+    }
+
+    /// Tears down the given subscriber's media subscription to the given publisher, leaving its others alone.
+    pub fn unsubscribe_from_user(&mut self, subscriber: &T, publisher: &T) {
+        self.publisher_to_subscribers.disassociate(publisher, subscriber);
     }
 
-    pub fn subscribers_to(&self, publisher: &Session) -> &[Arc<Session>] {
+    /// Tears down all media subscriptions to the given publisher, and has it forget its offer, since the media it
+    /// described isn't being forwarded anymore.
+    pub fn unpublish(&mut self, publisher: &T) {
+        self.publisher_to_subscribers.remove_key(publisher);
+        publisher.forget_offer();
+    }
+
+    pub fn subscribers_to(&self, publisher: &T) -> &[Arc<T>] {
         self.publisher_to_subscribers.get_values(publisher)
     }
 
-    pub fn publishers_to(&self, subscriber: &Session) -> &[Arc<Session>] {
+    pub fn publishers_to(&self, subscriber: &T) -> &[Arc<T>] {
         self.publisher_to_subscribers.get_keys(subscriber)
     }
 
-    pub fn sessions(&self) -> &Vec<Box<Arc<Session>>> {
+    /// Counts the media subscriptions held by all of the given user's connections together, not counting connections
+    /// looping back their own media.
+    pub fn subscription_count(&self, user: &UserId) -> usize {
+        self.sessions_for_user(user).iter().map(|s| self.publishers_to(s).iter().filter(|p| *p != s).count()).sum()
+    }
+
+    pub fn sessions(&self) -> &Vec<Box<Arc<T>>> {
         &self.sessions
     }
 
-    pub fn occupants_of(&self, room: &RoomId) -> &[Arc<Session>] {
+    /// Checks whether there's space for another occupant in the given room, and, if the room would be new, for another
+    /// room. Callers must join the room under the same lock as this check, so that nobody else can take the space.
+    pub fn check_capacity(&self, room: &RoomId, max_room_size: usize, max_rooms: usize) -> Result<(), RoomRefusal> {
+        let occupants = self.occupants_of(room).len();
+        if occupants >= max_room_size {
+            Err(RoomRefusal::Full)
+        } else if occupants == 0 && self.room_count() >= max_rooms {
+            Err(RoomRefusal::TooManyRooms)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns every room that has anyone in it.
+    pub fn rooms(&self) -> impl Iterator<Item=&RoomId> {
+        self.occupants.keys()
+    }
+
+    /// How many rooms have anyone in them.
+    pub fn room_count(&self) -> usize {
+        self.occupants.len()
+    }
+
+    pub fn occupants_of(&self, room: &RoomId) -> &[Arc<T>] {
         self.occupants.get(room).map(Vec::as_slice).unwrap_or(&[])
     }
 
-    pub fn media_recipients_for(&self, sender: &Session) -> impl Iterator<Item=&Arc<Session>> {
-        let (forward_blocks, reverse_blocks) = match sender.join_state.get() {
+    /// Returns the occupants of the given room who should get a notification about it: the ones who subscribed to
+    /// notifications, or, if it's mandatory, all of them.
+    pub fn notification_recipients(&self, room: &RoomId, mandatory: bool) -> impl Iterator<Item=&Arc<T>> {
+        self.occupants_of(room).iter().filter(move |s| mandatory || s.wants_notifications())
+    }
+
+    /// Returns the occupants of the given room who should hear about something the given user did there: the ones who
+    /// subscribed to notifications, other than the user's own connections.
+    pub fn roommates_to_notify<'s>(&'s self, room: &RoomId, user: &'s UserId) -> impl Iterator<Item=&'s Arc<T>> {
+        self.notification_recipients(room, false).filter(move |s| s.join_state().is_some_and(|joined| &joined.user_id != user))
+    }
+
+    pub fn media_recipients_for(&self, sender: &T) -> impl Iterator<Item=&Arc<T>> {
+        let (forward_blocks, reverse_blocks) = match sender.join_state() {
             None => (&[] as &[_], &[] as &[_]),
             Some(joined) => (
                 self.blockers_to_miscreants.get_keys(&joined.user_id),
@@ -185,7 +721,7 @@ impl Switchboard {
             )
         };
         self.subscribers_to(sender).iter().filter(move |subscriber| {
-            match subscriber.join_state.get() {
+            match subscriber.join_state() {
                 None => true,
                 Some(other) => {
                     let blocks = forward_blocks.contains(&other.user_id);
@@ -196,8 +732,19 @@ impl Switchboard {
         })
     }
 
-    pub fn media_senders_to(&self, recipient: &Session) -> impl Iterator<Item=&Arc<Session>> {
-        let (forward_blocks, reverse_blocks) = match recipient.join_state.get() {
+    /// Like `media_recipients_for`, but only the recipients who haven't muted the given kind of media from the sender.
+    pub fn media_recipients_of_kind<'s>(&'s self, sender: &'s T, video: bool) -> impl Iterator<Item=&'s Arc<T>> {
+        let sender_user = sender.join_state().map(|joined| &joined.user_id);
+        self.media_recipients_for(sender).filter(move |subscriber| {
+            match (subscriber.join_state(), sender_user) {
+                (Some(other), Some(sender_user)) => !self.mute_between(&other.user_id, sender_user).mutes(video),
+                _ => true
+            }
+        })
+    }
+
+    pub fn media_senders_to(&self, recipient: &T) -> impl Iterator<Item=&Arc<T>> {
+        let (forward_blocks, reverse_blocks) = match recipient.join_state() {
             None => (&[] as &[_], &[] as &[_]),
             Some(joined) => (
                 self.blockers_to_miscreants.get_values(&joined.user_id),
@@ -205,7 +752,7 @@ impl Switchboard {
             )
         };
         self.publishers_to(recipient).iter().filter(move |publisher| {
-            match publisher.join_state.get() {
+            match publisher.join_state() {
                 None => true,
                 Some(other) => {
                     let blocks = forward_blocks.contains(&other.user_id);
@@ -216,17 +763,26 @@ impl Switchboard {
         })
     }
 
-    pub fn data_recipients_for<'s>(&'s self, session: &'s Session) -> impl Iterator<Item=&'s Arc<Session>> {
-        let (forward_blocks, reverse_blocks, cohabitators) = match session.join_state.get() {
-            None => (&[] as &[_], &[] as &[_], &[] as &[_]),
-            Some(joined) => (
-                self.blockers_to_miscreants.get_keys(&joined.user_id),
-                self.blockers_to_miscreants.get_values(&joined.user_id),
-                self.occupants_of(&joined.room_id)
-            )
+    /// Returns everyone who should get data sent by the given connection: the other occupants of its room, except any
+    /// blocked in either direction, and the room's observers. Data from an observer goes to the room it's observing.
+    pub fn data_recipients_for<'s>(&'s self, session: &'s T) -> impl Iterator<Item=&'s Arc<T>> {
+        let (forward_blocks, reverse_blocks, cohabitators, observers) = match session.join_state() {
+            None => match self.observed_room(session) {
+                None => (&[] as &[_], &[] as &[_], &[] as &[_], &[] as &[_]),
+                Some(room) => (&[] as &[_], &[] as &[_], self.occupants_of(room), self.observers_of(room)),
+            },
+            Some(joined) => {
+                let room = joined.room_id.read().unwrap();
+                (
+                    self.blockers_to_miscreants.get_keys(&joined.user_id),
+                    self.blockers_to_miscreants.get_values(&joined.user_id),
+                    self.occupants_of(&room),
+                    self.observers_of(&room)
+                )
+            }
         };
-        cohabitators.iter().filter(move |cohabitator| {
-            cohabitator.handle != session.handle && match cohabitator.join_state.get() {
+        let cohabitants = cohabitators.iter().filter(move |cohabitator| {
+            cohabitator.as_ref() != session && match cohabitator.join_state() {
                 None => true,
                 Some(other) => {
                     let blocks = forward_blocks.contains(&other.user_id);
@@ -234,14 +790,15 @@ impl Switchboard {
                     !blocks && !is_blocked
                 }
             }
-        })
+        });
+        cohabitants.chain(observers.iter().filter(move |observer| observer.as_ref() != session))
     }
 
     pub fn get_users(&self, room: &RoomId) -> HashSet<&UserId> {
         let mut result = HashSet::new();
         if let Some(sessions) = self.occupants.get(room) {
             for session in sessions {
-                if let Some(joined) = session.join_state.get() {
+                if let Some(joined) = session.join_state() {
                     result.insert(&joined.user_id);
                 }
             }
@@ -249,28 +806,686 @@ impl Switchboard {
         result
     }
 
-    pub fn get_publisher(&self, user_id: &UserId) -> Option<&Arc<Session>> {
+    /// Returns the join metadata of every user in the room who provided some.
+    pub fn get_user_metadata(&self, room: &RoomId) -> HashMap<&UserId, &JsonValue> {
+        let mut result = HashMap::new();
+        if let Some(sessions) = self.occupants.get(room) {
+            for session in sessions {
+                if let Some(joined) = session.join_state() {
+                    if !joined.metadata.is_null() {
+                        result.insert(&joined.user_id, &joined.metadata);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_raised_hands(&self, room: &RoomId) -> HashSet<&UserId> {
+        let mut result = HashSet::new();
+        if let Some(sessions) = self.occupants.get(room) {
+            for session in sessions {
+                if let Some(joined) = session.join_state() {
+                    if joined.hand_raised.load(Ordering::Relaxed) {
+                        result.insert(&joined.user_id);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_pinned(&self, room: &RoomId) -> HashSet<&UserId> {
+        let mut result = HashSet::new();
+        if let Some(sessions) = self.occupants.get(room) {
+            for session in sessions {
+                if let Some(joined) = session.join_state() {
+                    if joined.pinned.load(Ordering::Relaxed) {
+                        result.insert(&joined.user_id);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_moderators(&self, room: &RoomId) -> HashSet<&UserId> {
+        let mut result = HashSet::new();
+        if let Some(sessions) = self.occupants.get(room) {
+            for session in sessions {
+                if let Some(joined) = session.join_state() {
+                    if joined.moderator.load(Ordering::Relaxed) {
+                        result.insert(&joined.user_id);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn get_publisher(&self, user_id: &UserId) -> Option<&Arc<T>> {
         self.sessions.iter()
             .find(|s| {
-                let subscriber_offer = s.subscriber_offer.lock().unwrap();
-                let join_state = s.join_state.get();
-                match (subscriber_offer.as_ref(), join_state) {
-                    (Some(_), Some(state)) if &state.user_id == user_id => true,
+                match s.join_state() {
+                    Some(state) if s.is_publishing() => &state.user_id == user_id,
                     _ => false
                 }
             })
             .map(Box::as_ref)
     }
 
-    pub fn get_sessions(&self, room_id: &RoomId, user_id: &UserId) -> Vec<&Box<Arc<Session>>> {
-        self.sessions.iter()
-            .filter(|s| {
-                let join_state = s.join_state.get();
-                match join_state {
-                    Some(state) if &state.user_id == user_id && &state.room_id == room_id => true,
-                    _ => false
-                }
-            }).collect::<_>()
+    /// Returns every connection which joined as the given user and is in the given room.
+    pub fn get_sessions(&self, room_id: &RoomId, user_id: &UserId) -> Vec<Arc<T>> {
+        self.sessions_for_user(user_id).into_iter()
+            .filter(|s| s.join_state().is_some_and(|joined| &*joined.room_id.read().unwrap() == room_id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use serde_json::Value as JsonValue;
+    use std::hash::Hasher;
+    use std::sync::RwLock;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    #[derive(Debug)]
+    pub struct MockSession {
+        id: usize,
+        join_state: Option<JoinState>,
+        publishing: AtomicBool,
+        audio_codec: Option<AudioCodec>,
+        video_codec: Option<VideoCodec>,
+        notifications: bool,
+    }
+
+    impl MockSession {
+        /// Creates a session which hasn't joined a room, isn't publishing, and doesn't want notifications.
+        pub fn new(id: usize) -> Self {
+            Self { id, join_state: None, publishing: AtomicBool::new(false), audio_codec: None, video_codec: None, notifications: false }
+        }
+
+        /// Gives the session a join state for the given user in the given room.
+        pub fn joined(self, room: &str, user: &str) -> Self {
+            let join_state = JoinState::new(room.into(), user.into(), false, JsonValue::Null);
+            Self { join_state: Some(join_state), ..self }
+        }
+
+        /// Makes the session a publisher sending the given codecs.
+        pub fn publishing(self, audio_codec: AudioCodec, video_codec: VideoCodec) -> Self {
+            Self { publishing: AtomicBool::new(true), audio_codec: Some(audio_codec), video_codec: Some(video_codec), ..self }
+        }
+
+        /// Makes the session want notifications.
+        pub fn with_notifications(self) -> Self {
+            Self { notifications: true, ..self }
+        }
+
+        /// Has the session offer to publish again after it's been unpublished.
+        pub fn offer(&self) {
+            self.publishing.store(true, Ordering::Relaxed);
+        }
+    }
+
+    impl PartialEq for MockSession {
+        fn eq(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    impl Eq for MockSession {}
+
+    impl Hash for MockSession {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.id.hash(state);
+        }
+    }
+
+    impl Connection for MockSession {
+        fn handle_id(&self) -> String {
+            format!("mock-{}", self.id)
+        }
+
+        fn join_state(&self) -> Option<&JoinState> {
+            self.join_state.as_ref()
+        }
+
+        fn is_publishing(&self) -> bool {
+            self.publishing.load(Ordering::Relaxed)
+        }
+
+        fn audio_codec(&self) -> Option<AudioCodec> {
+            self.audio_codec
+        }
+
+        fn video_codec(&self) -> Option<VideoCodec> {
+            self.video_codec
+        }
+
+        fn wants_notifications(&self) -> bool {
+            self.notifications
+        }
+
+        fn forget_offer(&self) {
+            self.publishing.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Creates a session for the given user, connects it, and joins it to the given room.
+    pub fn join(switchboard: &mut Switchboard<MockSession>, id: usize, room: &str, user: &str) -> Arc<MockSession> {
+        let session = Arc::new(MockSession::new(id).joined(room, user).publishing(AudioCodec::Opus, VideoCodec::H264).with_notifications());
+        switchboard.connect(Box::new(Arc::clone(&session)));
+        switchboard.join_room(Arc::clone(&session), room.into());
+        session
+    }
+
+    fn ids<'a>(sessions: impl Iterator<Item=&'a Arc<MockSession>>) -> Vec<usize> {
+        let mut result: Vec<_> = sessions.map(|s| s.id).collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn join_and_leave() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        assert_eq!(ids(switchboard.occupants_of(&"room".into()).iter()), vec![1, 2]);
+        assert_eq!(switchboard.room_count(), 1);
+        assert!(switchboard.is_connected(&"alice".into()));
+
+        switchboard.remove_session(&alice);
+        assert_eq!(ids(switchboard.occupants_of(&"room".into()).iter()), vec![2]);
+        assert!(!switchboard.is_connected(&"alice".into()));
+        assert_eq!(switchboard.get_users(&"room".into()).into_iter().collect::<Vec<_>>(), vec!["bob"]);
+
+        switchboard.remove_session(&bob);
+        assert!(switchboard.occupants_of(&"room".into()).is_empty());
+        assert_eq!(switchboard.room_count(), 0);
+        assert!(switchboard.sessions().is_empty());
+    }
+
+    #[test]
+    fn finds_all_sessions_for_user() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        let alice_subs = [join(&mut switchboard, 4, "room", "alice"), join(&mut switchboard, 5, "room", "alice")];
+        switchboard.subscribe_to_user(Arc::clone(&alice_subs[0]), Arc::clone(&bob)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice_subs[1]), Arc::clone(&carol)).unwrap();
+        switchboard.move_session(&alice, "elsewhere".into());
+
+        assert_eq!(ids(switchboard.sessions_for_user(&"alice".into()).iter()), vec![1, 4, 5]);
+        assert_eq!(ids(switchboard.get_sessions(&"room".into(), &"alice".into()).iter()), vec![4, 5]);
+        assert_eq!(ids(switchboard.sessions_for_user(&"bob".into()).iter()), vec![2]);
+        assert!(switchboard.sessions_for_user(&"dave".into()).is_empty());
+    }
+
+    #[test]
+    fn media_recipients_for_subscribers() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![2, 3]);
+        assert_eq!(ids(switchboard.media_senders_to(&bob)), vec![1]);
+        assert!(ids(switchboard.media_recipients_for(&bob)).is_empty());
     }
 
+    #[test]
+    fn resubscribing_to_another_publisher() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+
+        switchboard.unsubscribe_from_user(&carol, &alice);
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&bob)).unwrap();
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+        assert_eq!(ids(switchboard.media_recipients_for(&bob)), vec![3]);
+        assert_eq!(ids(switchboard.media_senders_to(&carol)), vec![2]);
+    }
+
+    #[test]
+    fn blocks_stop_media_and_data_both_ways() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+
+        switchboard.establish_block("bob".into(), "alice".into());
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![3]);
+        assert!(ids(switchboard.media_recipients_for(&bob)).is_empty());
+        assert!(ids(switchboard.media_senders_to(&bob)).is_empty());
+        assert_eq!(ids(switchboard.data_recipients_for(&alice)), vec![3]);
+        assert_eq!(ids(switchboard.data_recipients_for(&carol)), vec![1, 2]);
+
+        switchboard.lift_block(&"bob".into(), &"alice".into());
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![2, 3]);
+        assert_eq!(ids(switchboard.data_recipients_for(&alice)), vec![2, 3]);
+    }
+
+    #[test]
+    fn mutes_stop_one_kind_one_way() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+
+        switchboard.set_mute("bob".into(), "alice".into(), Mute { audio: true, video: false });
+        assert_eq!(ids(switchboard.media_recipients_of_kind(&alice, false)), vec![3]);
+        assert_eq!(ids(switchboard.media_recipients_of_kind(&alice, true)), vec![2, 3]);
+        assert_eq!(ids(switchboard.media_recipients_of_kind(&bob, false)), vec![1]);
+        assert_eq!(ids(switchboard.data_recipients_for(&alice)), vec![2, 3]);
+
+        switchboard.set_mute("bob".into(), "alice".into(), Mute::default());
+        assert_eq!(switchboard.mute_between(&"bob".into(), &"alice".into()), Mute::default());
+        assert_eq!(ids(switchboard.media_recipients_of_kind(&alice, false)), vec![2, 3]);
+    }
+
+    #[test]
+    fn media_muted_by_everyone_goes_nowhere() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        assert!(switchboard.media_recipients_of_kind(&alice, false).next().is_none());
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+
+        switchboard.set_mute("bob".into(), "alice".into(), Mute { audio: true, video: false });
+        assert!(switchboard.media_recipients_of_kind(&alice, false).next().is_some());
+        switchboard.set_mute("carol".into(), "alice".into(), Mute { audio: true, video: false });
+        assert!(switchboard.media_recipients_of_kind(&alice, false).next().is_none());
+        assert!(switchboard.media_recipients_of_kind(&alice, true).next().is_some());
+
+        switchboard.set_mute("carol".into(), "alice".into(), Mute::default());
+        assert!(switchboard.media_recipients_of_kind(&alice, false).next().is_some());
+    }
+
+    #[test]
+    fn ending_subscriptions_on_block() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        let bob_sub = join(&mut switchboard, 3, "room", "bob");
+        let carol = join(&mut switchboard, 4, "room", "carol");
+        switchboard.subscribe_to_user(Arc::clone(&bob_sub), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&alice)).unwrap();
+
+        switchboard.establish_block("alice".into(), "bob".into());
+        let ended = switchboard.end_subscriptions_between(&"alice".into(), &"bob".into());
+        let mut ended: Vec<_> = ended.iter().map(|(p, s)| (p.id, s.id)).collect();
+        ended.sort();
+        assert_eq!(ended, vec![(1, 3), (2, 1)]);
+        assert_eq!(ids(switchboard.subscribers_to(&alice).iter()), vec![4]);
+        assert!(switchboard.subscribers_to(&bob).is_empty());
+        assert!(switchboard.publishers_to(&bob_sub).is_empty());
+
+        switchboard.lift_block(&"alice".into(), &"bob".into());
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![4]);
+    }
+
+    #[test]
+    fn reconnect_preserves_block() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.establish_block("alice".into(), "bob".into());
+        switchboard.remove_session(&alice);
+
+        let alice = join(&mut switchboard, 3, "room", "alice");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        assert_eq!(switchboard.blocks_from(&"alice".into()), &["bob".to_owned()]);
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+        assert!(ids(switchboard.data_recipients_for(&bob)).is_empty());
+    }
+
+    #[test]
+    fn unpublish_drops_subscribers() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        assert_eq!(switchboard.get_publisher(&"alice".into()).map(|s| s.id), Some(1));
+
+        switchboard.unpublish(&alice);
+        assert!(!alice.is_publishing());
+        assert!(switchboard.get_publisher(&"alice".into()).is_none());
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+        assert!(ids(switchboard.media_senders_to(&bob)).is_empty());
+        assert_eq!(ids(switchboard.media_recipients_for(&bob)), vec![1]);
+    }
+
+    #[test]
+    fn unpublished_publishers_stay_in_their_room() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+
+        switchboard.unpublish(&alice);
+        assert!(switchboard.is_connected(&"alice".into()));
+        assert_eq!(ids(switchboard.occupants_of(&"room".into()).iter()), vec![1, 2]);
+        assert!(ids(switchboard.publishers_to(&bob).iter()).is_empty());
+
+        // publishing again takes a new offer, and subscribing again
+        alice.offer();
+        assert_eq!(switchboard.get_publisher(&"alice".into()).map(|s| s.id), Some(1));
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![2]);
+    }
+
+    #[test]
+    fn room_waiters_are_forgotten() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "alpha", "alice");
+        let bob = join(&mut switchboard, 2, "beta", "bob");
+        switchboard.wait_for_room(Arc::clone(&alice), "gamma".into());
+        switchboard.wait_for_room(Arc::clone(&alice), "gamma".into());
+        switchboard.wait_for_room(Arc::clone(&bob), "gamma".into());
+        switchboard.remove_session(&bob);
+        assert_eq!(ids(switchboard.take_room_waiters(&"gamma".into()).iter()), vec![1]);
+        assert!(switchboard.take_room_waiters(&"gamma".into()).is_empty());
+    }
+
+    #[test]
+    fn move_session_drops_media() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "alpha", "alice");
+        let bob = join(&mut switchboard, 2, "alpha", "bob");
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&alice)).unwrap();
+        alice.join_state().unwrap().moderator.store(true, Ordering::Relaxed);
+        alice.join_state().unwrap().pinned.store(true, Ordering::Relaxed);
+        assert_eq!(switchboard.get_moderators(&"alpha".into()).into_iter().collect::<Vec<_>>(), vec!["alice"]);
+        assert_eq!(switchboard.get_pinned(&"alpha".into()).into_iter().collect::<Vec<_>>(), vec!["alice"]);
+
+        switchboard.move_session(&alice, "beta".into());
+        assert_eq!(ids(switchboard.occupants_of(&"alpha".into()).iter()), vec![2]);
+        assert_eq!(ids(switchboard.occupants_of(&"beta".into()).iter()), vec![1]);
+        assert_eq!(*alice.join_state().unwrap().room_id.read().unwrap(), "beta");
+        assert!(switchboard.get_moderators(&"beta".into()).is_empty());
+        assert!(switchboard.get_pinned(&"beta".into()).is_empty());
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+        assert!(ids(switchboard.data_recipients_for(&alice)).is_empty());
+    }
+
+    #[test]
+    fn rejects_subscriptions_to_self() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let alice_again = join(&mut switchboard, 2, "room", "alice");
+        assert!(switchboard.subscribe_to_user(Arc::clone(&alice_again), Arc::clone(&alice)).is_err());
+        assert!(ids(switchboard.media_recipients_for(&alice)).is_empty());
+
+        // a connection looping back its own media is fine
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&alice)).unwrap();
+        assert_eq!(ids(switchboard.media_recipients_for(&alice)), vec![1]);
+    }
+
+    #[test]
+    fn rejects_mixed_audio_codecs_on_one_connection() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let carol = join(&mut switchboard, 3, "room", "carol");
+        let bob = Arc::new(MockSession::new(2).joined("room", "bob").publishing(AudioCodec::Pcmu, VideoCodec::H264).with_notifications());
+        switchboard.connect(Box::new(Arc::clone(&bob)));
+        switchboard.join_room(Arc::clone(&bob), "room".into());
+
+        // alice loops back her own opus audio, so she can't also get bob's PCMU on the same connection
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&alice)).unwrap();
+        assert!(switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).is_err());
+        assert_eq!(ids(switchboard.media_senders_to(&alice)), vec![1]);
+
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&bob)).unwrap();
+        assert_eq!(ids(switchboard.media_senders_to(&carol)), vec![2]);
+    }
+
+    #[test]
+    fn rejects_mixed_video_codecs_on_one_connection() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = Arc::new(MockSession::new(2).joined("room", "bob").publishing(AudioCodec::Opus, VideoCodec::Vp8).with_notifications());
+        switchboard.connect(Box::new(Arc::clone(&bob)));
+        switchboard.join_room(Arc::clone(&bob), "room".into());
+
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&alice)).unwrap();
+        assert!(switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).is_err());
+        assert_eq!(ids(switchboard.media_senders_to(&alice)), vec![1]);
+    }
+
+    #[test]
+    fn counts_subscriptions_per_user() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let alice_sub = join(&mut switchboard, 2, "room", "alice");
+        let bob = join(&mut switchboard, 3, "room", "bob");
+        let carol = join(&mut switchboard, 4, "room", "carol");
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&alice)).unwrap();
+        assert_eq!(switchboard.subscription_count(&"alice".into()), 0);
+
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&alice_sub), Arc::clone(&carol)).unwrap();
+        switchboard.subscribe_to_user(Arc::clone(&bob), Arc::clone(&carol)).unwrap();
+        assert_eq!(switchboard.subscription_count(&"alice".into()), 2);
+        assert_eq!(switchboard.subscription_count(&"bob".into()), 1);
+        assert_eq!(switchboard.subscription_count(&"carol".into()), 0);
+    }
+
+    #[test]
+    fn room_peaks_last_until_the_room_empties() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let alice_sub = join(&mut switchboard, 2, "room", "alice");
+        let bob = join(&mut switchboard, 3, "room", "bob");
+        assert_eq!(switchboard.room_peaks(&"room".into()), RoomPeaks { users: 2, publishers: 3 });
+
+        switchboard.leave_room(&bob, "room".into());
+        switchboard.leave_room(&alice_sub, "room".into());
+        assert_eq!(switchboard.room_peaks(&"room".into()), RoomPeaks { users: 2, publishers: 3 });
+
+        switchboard.leave_room(&alice, "room".into());
+        assert_eq!(switchboard.room_peaks(&"room".into()), RoomPeaks::default());
+        join(&mut switchboard, 4, "room", "carol");
+        assert_eq!(switchboard.room_peaks(&"room".into()), RoomPeaks { users: 1, publishers: 1 });
+    }
+
+    #[test]
+    fn room_secrets_last_until_the_room_empties() {
+        let mut switchboard = Switchboard::new();
+        switchboard.set_room_secret(&"room".into(), "ignored".into());
+        assert_eq!(switchboard.room_secret(&"room".into()), None);
+
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        switchboard.set_room_secret(&"room".into(), "first".into());
+        switchboard.set_room_secret(&"room".into(), "second".into());
+        assert_eq!(switchboard.room_secret(&"room".into()), Some("second"));
+
+        switchboard.leave_room(&alice, "room".into());
+        assert_eq!(switchboard.room_secret(&"room".into()), None);
+    }
+
+    #[test]
+    fn empty_rooms_are_forgotten_unless_retained() {
+        let mut switchboard = Switchboard::new();
+        switchboard.retain_room_state("provisioned".into());
+        for (id, room) in [(1, "transient"), (2, "provisioned")].iter() {
+            let room_id: RoomId = (*room).into();
+            let session = join(&mut switchboard, *id, room, "alice");
+            switchboard.record_event(&room_id, json!({ "event": "join" }), 10);
+            switchboard.lock_room(&room_id);
+            switchboard.set_room_secret(&room_id, "secret".into());
+            switchboard.set_room_bitrate(&room_id, 1000);
+            switchboard.leave_room(&session, room_id);
+        }
+
+        assert!(switchboard.occupants.is_empty());
+        assert!(switchboard.room_peaks.is_empty());
+        assert!(switchboard.room_bitrates.is_empty());
+        assert_eq!(switchboard.room_events.keys().collect::<Vec<_>>(), vec!["provisioned"]);
+        assert_eq!(switchboard.room_locks.keys().collect::<Vec<_>>(), vec!["provisioned"]);
+        assert_eq!(switchboard.room_secret(&"provisioned".into()), Some("secret"));
+        assert_eq!(switchboard.room_secrets.len(), 1);
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+    }
+
+    #[test]
+    fn restored_rooms_apply_before_anyone_rejoins() {
+        let mut switchboard = Switchboard::<MockSession>::new();
+        let admitted = ["alice".to_owned()].iter().cloned().collect();
+        switchboard.restore_room("room".into(), Some(admitted), Some("secret".into()));
+        assert!(switchboard.is_room_locked(&"room".into()));
+        assert!(switchboard.was_admitted(&"room".into(), &"alice".into()));
+        assert_eq!(switchboard.room_secret(&"room".into()), Some("secret"));
+
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        switchboard.restore_room("other".into(), None, Some("secret".into()));
+        switchboard.end_standby();
+        assert!(switchboard.is_room_locked(&"room".into()));
+        assert_eq!(switchboard.room_secret(&"other".into()), None);
+
+        switchboard.remove_session(&alice);
+        assert!(!switchboard.is_room_locked(&"room".into()));
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+    }
+
+    #[test]
+    fn restored_rooms_survive_pruning_during_standby() {
+        let mut switchboard = Switchboard::<MockSession>::new();
+        switchboard.restore_room("room".into(), Some(HashSet::new()), Some("secret".into()));
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        switchboard.remove_session(&alice);
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+        switchboard.prune();
+        switchboard.forget_empty_rooms();
+        assert!(switchboard.is_room_locked(&"room".into()));
+        assert_eq!(switchboard.room_secret(&"room".into()), Some("secret"));
+
+        switchboard.end_standby();
+        assert!(!switchboard.is_room_locked(&"room".into()));
+        assert_eq!(switchboard.room_secret(&"room".into()), None);
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+    }
+
+    #[test]
+    fn observers_get_room_data_without_being_occupants() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        join(&mut switchboard, 2, "room", "bob");
+        join(&mut switchboard, 3, "other", "carol");
+        let observer = Arc::new(MockSession::new(4));
+        switchboard.connect(Box::new(Arc::clone(&observer)));
+        switchboard.observe_room(Arc::clone(&observer), "room".into());
+
+        assert_eq!(switchboard.observed_room(&observer), Some(&"room".into()));
+        assert_eq!(switchboard.get_users(&"room".into()).len(), 2);
+        assert_eq!(ids(switchboard.occupants_of(&"room".into()).iter()), vec![1, 2]);
+        assert_eq!(ids(switchboard.data_recipients_for(&alice)), vec![2, 4]);
+        assert_eq!(ids(switchboard.data_recipients_for(&observer)), vec![1, 2]);
+
+        switchboard.remove_session(&observer);
+        assert_eq!(switchboard.observed_room(&observer), None);
+        assert_eq!(ids(switchboard.data_recipients_for(&alice)), vec![2]);
+    }
+
+    #[test]
+    fn verify_and_prune_orphans() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.subscribe_to_user(Arc::clone(&alice), Arc::clone(&bob)).unwrap();
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+
+        // carol's session went away without being removed from the room or her subscription
+        let carol = Arc::new(MockSession::new(3).joined("room", "carol").publishing(AudioCodec::Opus, VideoCodec::H264).with_notifications());
+        switchboard.join_room(Arc::clone(&carol), "room".into());
+        switchboard.subscribe_to_user(Arc::clone(&carol), Arc::clone(&bob)).unwrap();
+        switchboard.room_locks.insert("ghost".into(), HashSet::new());
+        let expected = ConsistencyReport { orphaned_occupants: 1, orphaned_subscriptions: 1, stale_rooms: 1, ..Default::default() };
+        assert_eq!(switchboard.verify(), expected);
+
+        assert_eq!(switchboard.prune(), expected);
+        assert_eq!(switchboard.verify(), ConsistencyReport::default());
+        assert_eq!(ids(switchboard.occupants_of(&"room".into()).iter()), vec![1, 2]);
+        assert_eq!(ids(switchboard.subscribers_to(&bob).iter()), vec![1]);
+    }
+
+    #[test]
+    fn mandatory_notifications_reach_everyone() {
+        let mut switchboard = Switchboard::new();
+        join(&mut switchboard, 1, "room", "alice");
+        join(&mut switchboard, 3, "other", "carol");
+        let bob = Arc::new(MockSession::new(2).joined("room", "bob"));
+        switchboard.connect(Box::new(Arc::clone(&bob)));
+        switchboard.join_room(Arc::clone(&bob), "room".into());
+
+        assert_eq!(ids(switchboard.notification_recipients(&"room".into(), false)), vec![1]);
+        assert_eq!(ids(switchboard.notification_recipients(&"room".into(), true)), vec![1, 2]);
+        assert!(ids(switchboard.roommates_to_notify(&"room".into(), &"alice".into())).is_empty());
+    }
+
+    #[test]
+    fn locked_rooms_remember_admitted_users() {
+        let mut switchboard = Switchboard::new();
+        let alice = join(&mut switchboard, 1, "room", "alice");
+        switchboard.lock_room(&"room".into());
+        assert!(switchboard.is_room_locked(&"room".into()));
+        assert!(switchboard.was_admitted(&"room".into(), &"alice".into()));
+        assert!(!switchboard.was_admitted(&"room".into(), &"bob".into()));
+
+        // someone let in while it's locked can come back, too
+        let bob = join(&mut switchboard, 2, "room", "bob");
+        switchboard.remove_session(&bob);
+        assert!(switchboard.was_admitted(&"room".into(), &"bob".into()));
+
+        switchboard.remove_session(&alice);
+        assert!(!switchboard.is_room_locked(&"room".into()));
+    }
+
+    #[test]
+    fn concurrent_joins_respect_limits() {
+        let switchboard = Arc::new(RwLock::new(Switchboard::new()));
+        let threads: Vec<_> = (0..40).map(|id| {
+            let switchboard = Arc::clone(&switchboard);
+            thread::spawn(move || {
+                let room = format!("room-{}", id % 4);
+                let session = Arc::new(MockSession::new(id).joined(&room, &format!("user-{}", id)).with_notifications());
+                let mut switchboard = switchboard.write().unwrap();
+                let result = switchboard.check_capacity(&room, 4, 2);
+                if result.is_ok() {
+                    switchboard.connect(Box::new(Arc::clone(&session)));
+                    switchboard.join_room(session, room);
+                }
+                result
+            })
+        }).collect();
+        let results: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+
+        let switchboard = switchboard.read().unwrap();
+        let admitted = results.iter().filter(|r| r.is_ok()).count();
+        assert_eq!(switchboard.room_count(), 2);
+        // a room is full once it has the maximum, so each room ends up with exactly that many
+        assert_eq!(admitted, 8);
+        assert_eq!(switchboard.sessions().len(), admitted);
+        let occupied: Vec<_> = (0..4).map(|i| switchboard.occupants_of(&format!("room-{}", i)).len()).filter(|&n| n > 0).collect();
+        assert_eq!(occupied, vec![4, 4]);
+        assert_eq!(results.iter().filter(|&&r| r == Err(RoomRefusal::TooManyRooms)).count(), 20);
+    }
 }